@@ -1,17 +1,26 @@
-use std::io::{Error, ErrorKind};
-use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::fs::File;
+use std::time::{Duration, SystemTime};
 
-use clap::{App, Arg, ArgGroup};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
 use glob::Pattern;
+use log::warn;
 use crate::config::ManifestMode::TimestampTest;
 use std::fmt::Display;
 use serde::export::Formatter;
+use serde::{Serialize, Deserialize};
+use crate::file_transfer::sparse::{DEFAULT_MIN_HOLE, MAX_MIN_HOLE};
+use crate::util::{convert_error, Timestamp, DEFAULT_IO_BUFFER_SIZE, DEFAULT_PIPE_CAPACITY, MAX_IO_BUFFER_SIZE};
+use crate::tree::{ChecksumKey, ChecksumKeyId};
 use crate::config::PathDefinition::{Remote, Local, Server};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PathDefinition {
     Local(PathBuf),
-    Server(String),
+    /// A usync server's address, and the module to use (`None` for the server's default tree)
+    Server(String, Option<String>),
     Remote(String, String),
 }
 
@@ -21,9 +30,12 @@ impl Display for PathDefinition {
             Local(pb) => {
                 f.write_str(&format!("Local({})", pb.to_string_lossy()))
             }
-            Server(s) => {
+            Server(s, None) => {
                 f.write_str(&format!("Server({})", s))
             }
+            Server(s, Some(module)) => {
+                f.write_str(&format!("Server({},module={})", s, module))
+            }
             Remote(host, path) => {
                 f.write_str(&format!("Remote(host={},path={})", host, path))
             }
@@ -37,40 +49,100 @@ mod test_paths {
 
     #[test]
     fn parse_local() {
-        let path = PathDefinition::parse("/a/local/path");
+        let path = PathDefinition::parse("/a/local/path").unwrap();
         assert_eq!(Local(PathBuf::from("/a/local/path")), path)
     }
 
     #[test]
     fn parse_remote() {
-        let path = PathDefinition::parse("remote://user@a.host.name:remote/path");
+        let path = PathDefinition::parse("remote://user@a.host.name:remote/path").unwrap();
         assert_eq!(Remote("user@a.host.name".to_owned(), "remote/path".to_owned()), path);
     }
 
+    #[test]
+    fn parse_remote_without_path() {
+        let error = PathDefinition::parse("remote://a.host.name").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn parse_server() {
-        let path = PathDefinition::parse("server://server.name:1991");
-        assert_eq!(Server("server.name:1991".to_owned()), path);
+        let path = PathDefinition::parse("server://server.name:1991").unwrap();
+        assert_eq!(Server("server.name:1991".to_owned(), None), path);
+    }
+
+    #[test]
+    fn parse_server_module() {
+        let path = PathDefinition::parse("server://server.name:1991/photos").unwrap();
+        assert_eq!(Server("server.name:1991".to_owned(), Some("photos".to_owned())), path);
+    }
+
+    #[test]
+    fn parse_server_ipv6() {
+        let path = PathDefinition::parse("server://[::1]:9715").unwrap();
+        assert_eq!(Server("[::1]:9715".to_owned(), None), path);
+        let path = PathDefinition::parse("server://[fe80::1%eth0]:9715/photos").unwrap();
+        assert_eq!(Server("[fe80::1%eth0]:9715".to_owned(), Some("photos".to_owned())), path);
+    }
+
+    #[test]
+    fn parse_server_without_port() {
+        for address in &["server://server.name", "server://::1:9715", "server://[::1]", "server://[::1]9715", "server://host:port"] {
+            let error = PathDefinition::parse(address).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{}", address);
+        }
     }
 }
 
 impl PathDefinition {
-    fn parse(string: &str) -> Self {
+    fn parse(string: &str) -> Result<Self, Error> {
         if string.starts_with("remote://") {
             let src = &string[9..];
-            let path_sep = src.find(":").unwrap();
+            let path_sep = src.find(":").ok_or_else(|| Error::new(
+                ErrorKind::InvalidInput,
+                format!("Remote path {} must have the form remote://host:path", string)))?;
             let remote = &src[..path_sep];
             let remote_path = &src[path_sep + 1..];
-            Remote(String::from(remote), String::from(remote_path))
+            Ok(Remote(String::from(remote), String::from(remote_path)))
         } else if string.starts_with("server://") {
-            Server(String::from(&string[9..]))
+            let src = &string[9..];
+            let (address, module) = match src.find('/') {
+                Some(module_sep) => (&src[..module_sep], Some(String::from(&src[module_sep + 1..]))),
+                None => (src, None),
+            };
+            if !is_server_address(address) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Server path {} must have the form server://host:port, with IPv6 addresses in brackets (server://[::1]:port)", string)));
+            }
+            Ok(Server(String::from(address), module))
         } else {
-            Local(PathBuf::from(string))
+            Ok(Local(PathBuf::from(string)))
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Checks that `address` is a host and a port, where the host is a name, an IPv4 address or
+/// a bracketed IPv6 address, whose colons would otherwise be taken for the port separator.
+fn is_server_address(address: &str) -> bool {
+    let host_end = if address.starts_with('[') {
+        match address.find(']') {
+            Some(bracket) => bracket + 1,
+            None => return false,
+        }
+    } else {
+        match address.find(':') {
+            Some(colon) => colon,
+            None => return false,
+        }
+    };
+    match address[host_end..].strip_prefix(':') {
+        Some(port) => host_end > 0 && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ManifestMode {
     TimestampTest,
     Hash,
@@ -86,6 +158,81 @@ impl Display for ManifestMode {
     }
 }
 
+/// Where `Manifest::create_persistent` stores the manifest of a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestLocation {
+    /// In this file, relative to the root of the tree unless absolute
+    File(PathBuf),
+    /// In the user's cache directory, named after the canonicalized root of the tree
+    Cache,
+}
+
+/// How a file on the target is decided to be the same as its source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    /// Same size and content hash, and modification times at most the window apart
+    Timestamps(Duration),
+    /// Same size and content hash, whatever the modification times (`--ignore-times`)
+    ContentOnly,
+}
+
+/// Which of the source's files that differ from the target a copy transfers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// All of them
+    All,
+    /// Only those missing on the target, leaving existing files alone (`--ignore-existing`)
+    Missing,
+    /// Only those already on the target, creating neither files nor directories (`--existing`)
+    Existing,
+}
+
+/// Limits after which a sync stops, leaving the remaining files for a later run
+/// (`--max-transfer`, `--max-files`).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TransferLimits {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+}
+
+/// Digest used for content and directory hashes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let str = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        };
+        f.write_str(str)
+    }
+}
+
+/// How to handle metadata (such as file ownership) that can only be applied with privileges.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrivilegeMode {
+    /// Apply privileged metadata if running as root, skip it with a warning otherwise
+    Auto,
+    /// Always apply privileged metadata, failing if that is not permitted
+    Super,
+    /// Record privileged metadata in an extended attribute instead of applying it
+    FakeSuper,
+}
+
+/// How `--append` treats a target file shorter than its source, taken to be the start of it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AppendMode {
+    /// Send only the part of the source beyond the target's length, trusting the rest to match
+    Trust,
+    /// Like `Trust`, but check that the target really holds the start of the source, and
+    /// transfer the file whole if it doesn't
+    Verify,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ProcessRole {
     Sender,
@@ -93,11 +240,81 @@ pub enum ProcessRole {
     Server,
 }
 
+/// An `--include` or `--exclude` glob. A glob ending in `/` only matches directories, a glob
+/// starting with `/` is anchored: it matches the path relative to the sync root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterRule {
+    include: bool,
+    pattern: Pattern,
+    directories_only: bool,
+    anchored: bool,
+}
+
+impl FilterRule {
+    pub fn new(include: bool, glob: &str) -> Result<FilterRule, glob::PatternError> {
+        let directories_only = glob.len() > 1 && glob.ends_with('/');
+        let glob = if directories_only { &glob[..glob.len() - 1] } else { glob };
+        let anchored = glob.len() > 1 && glob.starts_with('/');
+        let glob = if anchored { &glob[1..] } else { glob };
+
+        Ok(FilterRule { include, pattern: Pattern::new(glob)?, directories_only, anchored })
+    }
+
+    pub fn exclude(pattern: Pattern) -> FilterRule {
+        FilterRule { include: false, pattern, directories_only: false, anchored: false }
+    }
+
+    #[inline]
+    pub fn is_include(&self) -> bool {
+        self.include
+    }
+
+    /// Matches against `path`, or if anchored, against `relative`, the same path relative to
+    /// the sync root. Names that aren't UTF-8 are matched lossily, so that they can be
+    /// excluded as well.
+    fn matches(&self, path: &Path, relative: &Path, is_dir: bool) -> bool {
+        let subject = if self.anchored { relative } else { path };
+        (is_dir || !self.directories_only) && self.pattern.matches(&subject.to_string_lossy())
+    }
+}
+
+impl Display for FilterRule {
+    /// Formats the glob as it was given on the command line.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.anchored {
+            f.write_str("/")?;
+        }
+        f.write_str(self.pattern.as_str())?;
+        if self.directories_only {
+            f.write_str("/")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HashSettings {
     force_rebuild: bool,
     mode: ManifestMode,
-    exclude_patterns: Vec<Pattern>,
+    algorithm: HashAlgorithm,
+    threads: usize,
+    rules: Vec<FilterRule>,
+    exclude_paths: Vec<PathBuf>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<Timestamp>,
+    older_than: Option<Timestamp>,
+    ignore_files: bool,
+    max_depth: Option<usize>,
+    fail_on_special_files: bool,
+    modify_window: Duration,
+    ignore_times: bool,
+    one_file_system: bool,
+    hard_links: bool,
+    perms: bool,
+    checksum_key: Option<ChecksumKey>,
+    copy_links: bool,
+    io_buffer_size: usize,
 }
 
 #[cfg(test)]
@@ -108,7 +325,25 @@ pub mod test_support {
         HashSettings {
             force_rebuild: false,
             mode: ManifestMode::Hash,
-            exclude_patterns: vec![],
+            algorithm: HashAlgorithm::Sha256,
+            threads: 1,
+            rules: vec![],
+            exclude_paths: vec![],
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            ignore_files: false,
+            max_depth: None,
+            fail_on_special_files: false,
+            modify_window: Duration::from_secs(0),
+            ignore_times: false,
+            one_file_system: false,
+            hard_links: false,
+            perms: false,
+            checksum_key: None,
+            copy_links: false,
+            io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
         }
     }
 }
@@ -119,16 +354,90 @@ pub struct Configuration {
     source: Option<PathDefinition>,
     target: Option<PathDefinition>,
     verbose: bool,
+    stats: bool,
+    human_readable: bool,
+    itemize_changes: u64,
+    progress: bool,
+    events: bool,
+    json_summary: bool,
     hash: HashSettings,
-    manifest_path: Option<PathBuf>,
-    server_port: Option<u16>,
+    manifest_location: ManifestLocation,
+    server_port: u16,
     force_pipeline: bool,
+    pipe_capacity: usize,
+    privilege: PrivilegeMode,
+    auth_token: Option<String>,
+    checksum_seed: Option<String>,
+    cache_limit: Option<u64>,
+    max_connections: Option<usize>,
+    bwlimit: Option<u64>,
+    retries: u32,
+    batch_size: usize,
+    checksum: bool,
+    keep_going: bool,
+    selection: Selection,
+    limits: TransferLimits,
+    stage_then_swap: bool,
+    delay_updates: bool,
+    prune_empty_dirs: bool,
+    subpath: Option<PathBuf>,
+    append: Option<AppendMode>,
+    whole_file: Option<bool>,
+    accept_push: bool,
+    shutdown_timeout: Option<Duration>,
+    refresh_interval: Option<Duration>,
+    io_timeout: Option<Duration>,
+    rsh: Vec<String>,
+    remote_usync_path: String,
+    temp_dir: Option<PathBuf>,
+    min_free: Option<u64>,
+    min_hole: Option<u64>,
+    modules: Vec<ModuleDefinition>,
+    list_only: bool,
+    list_remote: bool,
+    write_manifest: Option<PathBuf>,
+    read_manifest: Option<PathBuf>,
+    plan: Option<PathBuf>,
+    apply_plan: Option<PathBuf>,
+    benchmark: bool,
+    strict_clock: bool,
+    xattrs: bool,
+    crtimes: bool,
+}
+
+impl Default for HashSettings {
+    fn default() -> Self {
+        HashSettings {
+            force_rebuild: false,
+            mode: ManifestMode::Hash,
+            algorithm: HashAlgorithm::Sha256,
+            threads: 1,
+            rules: vec![],
+            exclude_paths: vec![],
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            ignore_files: false,
+            max_depth: None,
+            fail_on_special_files: false,
+            modify_window: Duration::from_secs(0),
+            ignore_times: false,
+            one_file_system: false,
+            hard_links: false,
+            perms: false,
+            checksum_key: None,
+            copy_links: false,
+            io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+        }
+    }
 }
 
 impl HashSettings {
+    /// Include and exclude rules, in the order they are evaluated
     #[inline]
-    pub fn exclude_patterns(&self) -> &Vec<Pattern> {
-        &self.exclude_patterns
+    pub fn filter_rules(&self) -> &Vec<FilterRule> {
+        &self.rules
     }
 
     #[inline]
@@ -140,213 +449,2605 @@ impl HashSettings {
         self.mode
     }
 
-    pub fn is_excluded(&self, str: &Path) -> bool {
-        for pattern in &self.exclude_patterns {
-            if pattern.matches_path(str) {
-                return true;
-            }
-        }
+    #[inline]
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
 
-        false
+    /// Maximum number of files hashed concurrently
+    #[inline]
+    pub fn hash_threads(&self) -> usize {
+        self.threads
     }
 
-    pub fn with_additional_exclusion(&self, exclude: &Path) -> Self {
+    pub fn with_hash_threads(&self, threads: usize) -> Self {
         let mut copy = self.clone();
-        let pattern = Pattern::new(exclude.to_string_lossy().as_ref()).unwrap();
-        copy.exclude_patterns.push(pattern);
+        copy.threads = threads.max(1);
+        copy
+    }
 
+    pub fn with_hash_algorithm(&self, algorithm: HashAlgorithm) -> Self {
+        let mut copy = self.clone();
+        copy.algorithm = algorithm;
         copy
     }
-}
 
-#[cfg(test)]
-mod test_excludes {
-    use super::*;
-    use glob::PatternError;
+    pub fn with_manifest_mode(&self, mode: ManifestMode) -> Self {
+        let mut copy = self.clone();
+        copy.mode = mode;
+        copy
+    }
 
-    #[test]
-    fn apply_excludes() -> Result<(), PatternError> {
-        let settings = HashSettings {
-            force_rebuild: false,
-            mode: ManifestMode::TimestampTest,
-            exclude_patterns: vec![Pattern::new("ab*ca")?],
-        };
+    pub fn with_exclude_pattern(&self, pattern: Pattern) -> Self {
+        let mut copy = self.clone();
+        copy.rules.push(FilterRule::exclude(pattern));
+        copy
+    }
 
-        assert_eq!(settings.is_excluded(&PathBuf::from("abnahfpaclca")), true);
-        assert_eq!(settings.is_excluded(&PathBuf::from("anotherfile.txt")), false);
+    /// Appends `rule`, which takes precedence over all rules added before it.
+    pub fn with_filter_rule(&self, rule: FilterRule) -> Self {
+        let mut copy = self.clone();
+        copy.rules.push(rule);
+        copy
+    }
 
-        Ok(())
+    /// Checks whether the file at `path`, relative to the sync root, is excluded. The last
+    /// matching rule decides; paths no rule matches are included.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.is_excluded_below(Path::new(""), path, false)
     }
 
-    #[test]
-    fn apply_excludes_with_additional() -> Result<(), PatternError> {
-        let settings = HashSettings {
-            force_rebuild: false,
-            mode: ManifestMode::TimestampTest,
-            exclude_patterns: vec![Pattern::new("ab*ca")?],
-        }.with_additional_exclusion(&PathBuf::from("anotherfile.txt"));
+    /// Like `is_excluded`, for the directory at `path`. Excluded directories are not descended.
+    pub fn is_excluded_directory(&self, path: &Path) -> bool {
+        self.is_excluded_below(Path::new(""), path, true)
+    }
 
-        assert_eq!(settings.is_excluded(&PathBuf::from("abnahfpaclca")), true);
-        assert_eq!(settings.is_excluded(&PathBuf::from("anotherfile.txt")), true);
+    /// Checks whether the entry at `path`, which lies below the sync root `root`, is excluded.
+    /// Anchored rules match the part of `path` following `root`.
+    pub fn is_excluded_below(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        if self.exclude_paths.iter().any(|p| p == path) {
+            return true;
+        }
 
-        Ok(())
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        match self.rules.iter().rev().find(|rule| rule.matches(path, relative, is_dir)) {
+            Some(rule) => !rule.include,
+            None => false,
+        }
     }
-}
 
-impl Configuration {
+    /// Smallest file size included in the manifest, in bytes
     #[inline]
-    pub fn force_pipeline(&self) -> bool {
-        self.force_pipeline
+    pub fn min_size(&self) -> Option<u64> {
+        self.min_size
     }
 
+    /// Largest file size included in the manifest, in bytes
     #[inline]
-    pub fn server_port(&self) -> u16 {
-        self.server_port.unwrap()
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn with_size_range(&self, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        let mut copy = self.clone();
+        copy.min_size = min_size;
+        copy.max_size = max_size;
+        copy
     }
 
+    /// True if a file of `size` bytes is outside the configured size thresholds.
+    pub fn is_excluded_size(&self, size: u64) -> bool {
+        self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max)
+    }
+
+    /// Earliest modification time of files included in the manifest
     #[inline]
-    pub fn manifest_path(&self) -> &Path {
-        &self.manifest_path.as_ref().unwrap()
+    pub fn newer_than(&self) -> Option<Timestamp> {
+        self.newer_than
     }
 
+    /// Latest modification time of files included in the manifest
     #[inline]
-    pub fn role(&self) -> Option<ProcessRole> {
-        self.role
+    pub fn older_than(&self) -> Option<Timestamp> {
+        self.older_than
+    }
+
+    pub fn with_age_range(&self, newer_than: Option<Timestamp>, older_than: Option<Timestamp>) -> Self {
+        let mut copy = self.clone();
+        copy.newer_than = newer_than;
+        copy.older_than = older_than;
+        copy
     }
 
+    /// True if a file last modified at `mtime` is outside the configured age window. Files
+    /// modified in the future count as the newest of all.
+    pub fn is_excluded_age(&self, mtime: Timestamp) -> bool {
+        self.newer_than.is_some_and(|oldest| mtime < oldest) || self.older_than.is_some_and(|newest| mtime > newest)
+    }
 
+    /// Whether `.usyncignore` files found in the tree add exclusions for their directory
     #[inline]
-    pub fn target(&self) -> &PathDefinition {
-        &self.target.as_ref().unwrap()
+    pub fn ignore_files(&self) -> bool {
+        self.ignore_files
+    }
+
+    pub fn with_ignore_files(&self, ignore_files: bool) -> Self {
+        let mut copy = self.clone();
+        copy.ignore_files = ignore_files;
+        copy
     }
 
+    /// Number of directory levels below the root the walk descends into, if limited
     #[inline]
-    pub fn source(&self) -> &PathDefinition {
-        &self.source.as_ref().unwrap()
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
     }
 
-    pub fn hash_settings(&self) -> &HashSettings {
-        &self.hash
+    pub fn with_max_depth(&self, max_depth: Option<usize>) -> Self {
+        let mut copy = self.clone();
+        copy.max_depth = max_depth;
+        copy
     }
 
+    /// Whether fifos, sockets and device nodes in the tree are an error, rather than skipped
     #[inline]
-    pub fn verbose(&self) -> bool {
-        self.verbose
+    pub fn fail_on_special_files(&self) -> bool {
+        self.fail_on_special_files
     }
 
-    pub fn parse() -> Result<Configuration, Error> {
-        let args = App::new("usync")
-            .version("1.0")
-            .author("Elisabeth 'TerraNova' Schulz")
-            .arg(Arg::with_name("force-pipeline")
-                .hidden(true)
-                .long("force-pipeline")
-            )
-            .arg(
-                Arg::with_name("rebuild manifest")
-                    .help("rebuild the required manifest(s), even if it already exists")
-                    .long("force-rebuild-manifest")
-            )
-            .arg(
-                Arg::with_name("role")
-                    .help("Role of a remote-spawned instance.")
-                    .long("role")
-                    .takes_value(true)
-                    .possible_values(&["sender", "receiver", "server"])
-            )
-            .arg(
-                Arg::with_name("source")
-                    .help("Sync source directory")
-                    .long("source")
-                    .takes_value(true)
-            )
-            .arg(
-                Arg::with_name("target")
-                    .help("Sync target directory")
-                    .long("target")
-                    .takes_value(true)
-            )
-            .arg(
-                Arg::with_name("manifest file")
-                    .long("manifest-file")
-                    .help("Stored manifest file (relative to source directory)")
-                    .takes_value(true)
-                    .default_value(".usync.manifest")
-            )
-            .arg(Arg::with_name("hash-mode")
-                .help("hashing mode")
-                .long("hash-mode")
-                .takes_value(true)
-                .default_value("hash")
-                .possible_values(&["hash", "timestamp"])
-            )
-            .arg(
-                Arg::with_name("verbose")
-                    .help("Verbose output")
-                    .long("verbose")
-                    .short("v")
-                    .takes_value(false)
-            )
-            .group(ArgGroup::with_name("server")
-                .arg("server-port")
-            )
-            .arg(Arg::with_name("server-port")
-                .help("Port for the server to listen on")
-                .long("server-port")
-                .takes_value(true)
-                .default_value("9715")
-            )
-            .arg(
-                Arg::with_name("exclude")
-                    .help("exclude glob (specify multiple times for several patterns")
-                    .multiple(true)
-                    .number_of_values(1)
-                    .long("exclude")
-                    .takes_value(true)
-            )
-            .get_matches();
-        let source = args.value_of("source").map(PathDefinition::parse);
-        let target = args.value_of("target").map(PathDefinition::parse);
-        let server_port = args.value_of("server-port").and_then(|v| {
-            return match v.parse::<u16>() {
-                Ok(v) => Some(v),
-                Err(_) => None
-            };
-        });
+    pub fn with_fail_on_special_files(&self, fail_on_special_files: bool) -> Self {
+        let mut copy = self.clone();
+        copy.fail_on_special_files = fail_on_special_files;
+        copy
+    }
 
-        let mut exclude_patterns = Vec::new();
+    /// How far apart two modification times may be and still count as equal
+    #[inline]
+    pub fn modify_window(&self) -> Duration {
+        self.modify_window
+    }
 
-        if args.values_of("exclude").is_some() {
-            for pattern in args.values_of("exclude").unwrap() {
-                exclude_patterns.push(Pattern::new(pattern).map_err(|pe| Error::new(ErrorKind::Other, pe))?)
-            }
-        }
-        let role = args.value_of("role");
-        let role = match role {
-            Some("sender") => Some(ProcessRole::Sender),
-            Some("receiver") => Some(ProcessRole::Receiver),
-            Some("server") => Some(ProcessRole::Server),
-            _ => None
-        };
+    pub fn with_modify_window(&self, modify_window: Duration) -> Self {
+        let mut copy = self.clone();
+        copy.modify_window = modify_window;
+        copy
+    }
 
+    /// Whether files with the same size and content hash count as equal regardless of their
+    /// modification times. Only meaningful in hash mode.
+    #[inline]
+    pub fn ignore_times(&self) -> bool {
+        self.ignore_times
+    }
 
-        Ok(Configuration {
-            hash: HashSettings {
-                force_rebuild: args.is_present("rebuild manifest"),
-                mode: if args.value_of("hash-mode").unwrap() == "hash" {
-                    ManifestMode::Hash
-                } else {
-                    ManifestMode::TimestampTest
-                },
-                exclude_patterns,
-            },
-            source,
-            target,
-            verbose: (role == Some(ProcessRole::Server) || role.is_none()) && args.is_present("verbose"),
-            manifest_path: args.value_of("manifest file").map(PathBuf::from),
-            role,
-            server_port,
-            force_pipeline: args.is_present("force-pipeline"),
-        })
+    pub fn with_ignore_times(&self, ignore_times: bool) -> Self {
+        let mut copy = self.clone();
+        copy.ignore_times = ignore_times;
+        copy
+    }
+
+    /// How source and target files are compared, from `ignore_times` and `modify_window`
+    pub fn comparison(&self) -> Comparison {
+        if self.ignore_times {
+            Comparison::ContentOnly
+        } else {
+            Comparison::Timestamps(self.modify_window)
+        }
+    }
+
+    /// Whether the walk stays on the filesystem of the root (only supported on Unix)
+    #[inline]
+    pub fn one_file_system(&self) -> bool {
+        self.one_file_system
     }
-}
\ No newline at end of file
+
+    pub fn with_one_file_system(&self, one_file_system: bool) -> Self {
+        let mut copy = self.clone();
+        copy.one_file_system = one_file_system;
+        copy
+    }
+
+    /// Whether the manifest records which files are hard links to each other (Unix only)
+    #[inline]
+    pub fn hard_links(&self) -> bool {
+        self.hard_links
+    }
+
+    pub fn with_hard_links(&self, hard_links: bool) -> Self {
+        let mut copy = self.clone();
+        copy.hard_links = hard_links;
+        copy
+    }
+
+    /// Whether the manifest records the permissions of files, so that a change of them alone
+    /// counts as a change of the file, and they are preserved on the target
+    #[inline]
+    pub fn perms(&self) -> bool {
+        self.perms
+    }
+
+    pub fn with_perms(&self, perms: bool) -> Self {
+        let mut copy = self.clone();
+        copy.perms = perms;
+        copy
+    }
+
+    /// Key content hashes are keyed with, derived from `--checksum-seed`
+    #[inline]
+    pub fn checksum_key(&self) -> Option<&ChecksumKey> {
+        self.checksum_key.as_ref()
+    }
+
+    /// What manifests record about the `checksum_key`, see `ChecksumKey::id`
+    pub fn checksum_key_id(&self) -> Option<ChecksumKeyId> {
+        self.checksum_key.as_ref().map(ChecksumKey::id)
+    }
+
+    pub fn with_checksum_key(&self, key: Option<ChecksumKey>) -> Self {
+        let mut copy = self.clone();
+        copy.checksum_key = key;
+        copy
+    }
+
+    /// Whether symlinks are followed, and synced as the file or directory they point to
+    #[inline]
+    pub fn copy_links(&self) -> bool {
+        self.copy_links
+    }
+
+    pub fn with_copy_links(&self, copy_links: bool) -> Self {
+        let mut copy = self.clone();
+        copy.copy_links = copy_links;
+        copy
+    }
+
+    /// Size of the buffers files are hashed and copied through, in bytes
+    #[inline]
+    pub fn io_buffer_size(&self) -> usize {
+        self.io_buffer_size
+    }
+
+    pub fn with_io_buffer_size(&self, io_buffer_size: usize) -> Self {
+        let mut copy = self.clone();
+        copy.io_buffer_size = io_buffer_size.max(1);
+        copy
+    }
+
+    /// Excludes exactly the path `exclude`, which is matched literally rather than as a glob.
+    pub fn with_additional_exclusion(&self, exclude: &Path) -> Self {
+        let mut copy = self.clone();
+        copy.exclude_paths.push(exclude.to_owned());
+
+        copy
+    }
+}
+
+/// A tree served under a name, defined in the file given with `--modules`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleDefinition {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether clients are refused when they push files
+    pub read_only: bool,
+    /// Token clients of this module must present, instead of the server's `--auth-token`
+    pub auth_token: Option<String>,
+}
+
+/// Reads module definitions in the style of `rsyncd.conf`: each module starts with its name in
+/// brackets, followed by `key = value` lines. `path` is required, `read only` (default `yes`)
+/// and `auth token` are optional. Blank lines and lines starting with `#` are ignored.
+fn read_modules<R: BufRead>(source: &str, input: R) -> Result<Vec<ModuleDefinition>, Error> {
+    let mut modules: Vec<ModuleDefinition> = Vec::new();
+    let invalid = |line: usize, message: String| Error::new(ErrorKind::InvalidInput, format!("{}:{}: {}", source, line + 1, message));
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim();
+            if name.is_empty() || name.contains('/') {
+                return Err(invalid(index, format!("invalid module name '{}'", name)));
+            }
+            if modules.iter().any(|module| module.name == name) {
+                return Err(invalid(index, format!("module {} is defined twice", name)));
+            }
+            modules.push(ModuleDefinition { name: String::from(name), path: PathBuf::new(), read_only: true, auth_token: None });
+            continue;
+        }
+
+        let module = modules.last_mut().ok_or_else(|| invalid(index, String::from("setting outside of a [module] section")))?;
+        let (key, value) = match line.find('=') {
+            Some(sep) => (line[..sep].trim(), line[sep + 1..].trim()),
+            None => return Err(invalid(index, format!("expected 'key = value', found '{}'", line))),
+        };
+        match key {
+            "path" => module.path = PathBuf::from(value),
+            "read only" => module.read_only = match value {
+                "yes" | "true" => true,
+                "no" | "false" => false,
+                _ => return Err(invalid(index, format!("invalid value '{}' for read only, expected yes or no", value))),
+            },
+            "auth token" => module.auth_token = Some(String::from(value)),
+            _ => return Err(invalid(index, format!("unknown setting '{}'", key))),
+        }
+    }
+
+    if let Some(module) = modules.iter().find(|module| module.path.as_os_str().is_empty()) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}: module {} has no path", source, module.name)));
+    }
+
+    Ok(modules)
+}
+
+/// Name of the per-directory ignore files honored with `--ignore-files`.
+pub const IGNORE_FILE: &str = ".usyncignore";
+
+/// Exclusions collected from the ignore files of the directories currently being walked. Each
+/// scope applies to the subtree of the directory its ignore file was found in; its patterns
+/// are matched against paths relative to that directory. Like in `.gitignore`, a pattern
+/// without a `/` matches a file name at any depth below.
+pub(crate) struct IgnoreStack {
+    scopes: Vec<(PathBuf, Vec<Pattern>)>,
+}
+
+impl IgnoreStack {
+    pub fn new() -> IgnoreStack {
+        IgnoreStack { scopes: Vec::new() }
+    }
+
+    /// Reads the ignore file of `dir`, if there is one. Returns true if a scope was entered,
+    /// which must be left again once the walk leaves `dir`.
+    pub fn enter(&mut self, dir: &Path) -> Result<bool, Error> {
+        let file = dir.join(IGNORE_FILE);
+        let input = match File::open(&file) {
+            Ok(input) => input,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::new(e.kind(), format!("Cannot open ignore file {}: {}", file.to_string_lossy(), e))),
+        };
+
+        let patterns = read_exclude_patterns(&file.to_string_lossy(), BufReader::new(input))?;
+        self.scopes.push((dir.to_owned(), patterns));
+        Ok(true)
+    }
+
+    pub fn leave(&mut self) {
+        self.scopes.pop();
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.scopes.iter().any(|(dir, patterns)| {
+            let relative = match path.strip_prefix(dir) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            };
+
+            patterns.iter().any(|pattern| {
+                if pattern.as_str().contains('/') {
+                    pattern.matches(&relative.to_string_lossy())
+                } else {
+                    path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+                }
+            })
+        })
+    }
+}
+
+/// Splits a command line into words at whitespace. Single or double quotes group words
+/// containing whitespace, a backslash escapes the next character outside of single quotes.
+pub fn split_command(command: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("Trailing backslash in command {}", command))),
+            },
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Unterminated quote in command {}", command)));
+    }
+    words.extend(word);
+    if words.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Empty remote shell command"));
+    }
+
+    Ok(words)
+}
+
+/// Quotes `word` for the POSIX shell that runs commands on remote hosts, so it reaches the
+/// command as a single word without any expansion.
+pub fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+=,:@%".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes a path on a remote host like `shell_quote`, but leaves a leading `~`, `~user` or
+/// `$HOME` unquoted, so the remote shell expands it to the home directory there. The slash
+/// after it stays unquoted as well, a `~` is only expanded if followed by an unquoted one.
+pub fn shell_quote_path(path: &str) -> String {
+    let (prefix, rest) = match path.find('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => (path, ""),
+    };
+    let is_home = prefix == "$HOME" || prefix.strip_prefix('~')
+        .is_some_and(|user| user.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)));
+    if !is_home {
+        shell_quote(path)
+    } else if path.len() == prefix.len() {
+        prefix.to_string()
+    } else if rest.is_empty() {
+        format!("{}/", prefix)
+    } else {
+        format!("{}/{}", prefix, shell_quote(rest))
+    }
+}
+
+/// Parses a size in bytes, optionally followed by one of the binary suffixes `K`, `M`, `G` or
+/// `T` (e.g. `10M` for ten mebibytes).
+/// Options given on the command line, layered over those of a `--config` file. An option of the
+/// file applies unless the command line gives it as well; environment variables and defaults
+/// only apply if neither does.
+struct Options {
+    command_line: ArgMatches<'static>,
+    file: Option<ArgMatches<'static>>,
+}
+
+impl Options {
+    fn source(&self, name: &str) -> &ArgMatches<'static> {
+        match &self.file {
+            Some(file) if self.command_line.occurrences_of(name) == 0 && file.occurrences_of(name) > 0 => file,
+            _ => &self.command_line,
+        }
+    }
+
+    fn value_of(&self, name: &str) -> Option<&str> {
+        self.source(name).value_of(name)
+    }
+
+    fn is_present(&self, name: &str) -> bool {
+        self.source(name).is_present(name)
+    }
+
+    fn occurrences_of(&self, name: &str) -> u64 {
+        self.source(name).occurrences_of(name)
+    }
+}
+
+/// Collects the include and exclude rules of one source of options, in the order they were
+/// given, so that the last matching one wins.
+fn filter_rules(args: &ArgMatches) -> Result<Vec<FilterRule>, Error> {
+    // rules are collected along with their position, so that includes and excludes keep their order
+    let mut rules: Vec<(usize, FilterRule)> = Vec::new();
+
+    for (include, arg) in &[(true, "include"), (false, "exclude")] {
+        if let (Some(values), Some(indices)) = (args.values_of(arg), args.indices_of(arg)) {
+            for (pattern, index) in values.zip(indices) {
+                rules.push((index, FilterRule::new(*include, pattern).map_err(|pe| Error::new(ErrorKind::Other, pe))?));
+            }
+        }
+    }
+
+    if let (Some(files), Some(indices)) = (args.values_of("exclude-from"), args.indices_of("exclude-from")) {
+        for (file, index) in files.zip(indices) {
+            let input = File::open(file).map_err(|e| {
+                Error::new(e.kind(), format!("Cannot open exclude file {}: {}", file, e))
+            })?;
+            for pattern in read_exclude_patterns(file, BufReader::new(input))? {
+                // parsed again, so that anchored and directory-only globs work like on the command line
+                let rule = FilterRule::new(false, pattern.as_str()).map_err(|pe| Error::new(ErrorKind::Other, pe))?;
+                rules.push((index, rule));
+            }
+        }
+    }
+
+    // stable, so the patterns of an exclude file stay in order
+    rules.sort_by_key(|(index, _)| *index);
+    Ok(rules.into_iter().map(|(_, rule)| rule).collect())
+}
+
+/// Reads the seed a spawning usync writes ahead of the protocol for `--checksum-seed-stdin`.
+/// Only the line itself is consumed, the protocol data after it stays buffered in `input`.
+fn read_seed_line<R: BufRead>(mut input: R) -> Result<String, Error> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Input ended before the checksum seed"));
+    }
+    line.pop();
+    Ok(line)
+}
+
+/// Reads a `--config` file. Its keys are turned into the command line options of the same name,
+/// in the order they appear, and parsed like those, so the file accepts exactly what the
+/// command line does.
+fn read_config_file(path: &str) -> Result<ArgMatches<'static>, Error> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidInput, format!("Invalid config file {}: {}", path, message));
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        Error::new(e.kind(), format!("Cannot read config file {}: {}", path, e))
+    })?;
+    let table = match text.parse::<toml::Value>().map_err(|e| invalid(e.to_string()))? {
+        toml::Value::Table(table) => table,
+        _ => return Err(invalid(String::from("expected a table"))),
+    };
+
+    let mut args = vec![String::from("usync")];
+    for (key, value) in &table {
+        if key == "config" || key == "role" {
+            return Err(invalid(format!("{} can't be set in a config file", key)));
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{}", key)),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(s) => args.push(format!("--{}={}", key, s)),
+                toml::Value::Integer(i) => args.push(format!("--{}={}", key, i)),
+                _ => return Err(invalid(format!("unsupported value for {}: {}", key, value))),
+            }
+        }
+    }
+
+    Configuration::app().setting(AppSettings::ColorNever).get_matches_from_safe(args).map_err(|e| {
+        invalid(e.message.lines().next().unwrap_or_default().trim_start_matches("error: ").to_string())
+    })
+}
+
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid size {}", size));
+    let (digits, shift) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    let value = digits.parse::<u64>().map_err(|_| invalid())?;
+    value.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// Parses the cutoff of `--newer-than` or `--older-than`: an age such as `7d` (with one of the
+/// units `s`, `m`, `h`, `d` or `w`, seconds without one) counted back from `now`, a UTC date
+/// as `2024-01-31` or `2024-01-31T12:00:00`, or seconds since the epoch as `@1706702400`.
+pub fn parse_age(age: &str, now: SystemTime) -> Result<Timestamp, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid time {}", age));
+    if let Some(seconds) = age.strip_prefix('@') {
+        return Ok(Timestamp::from_unix_time(seconds.parse::<i64>().map_err(|_| invalid())?, 0));
+    }
+    if age.contains('-') {
+        return parse_date(age).ok_or_else(invalid);
+    }
+
+    let (digits, unit) = match age.chars().last() {
+        Some('s') => (&age[..age.len() - 1], 1),
+        Some('m') => (&age[..age.len() - 1], 60),
+        Some('h') => (&age[..age.len() - 1], 3600),
+        Some('d') => (&age[..age.len() - 1], 86400),
+        Some('w') => (&age[..age.len() - 1], 7 * 86400),
+        _ => (age, 1),
+    };
+    let seconds = digits.parse::<i64>().ok()
+        .filter(|value| *value >= 0)
+        .and_then(|value| value.checked_mul(unit))
+        .ok_or_else(invalid)?;
+    Ok(Timestamp::from_unix_time(Timestamp::from(now).unix_seconds().saturating_sub(seconds), 0))
+}
+
+/// Parses `YYYY-MM-DD`, optionally followed by `THH:MM:SS` or ` HH:MM:SS`, as UTC.
+fn parse_date(date: &str) -> Option<Timestamp> {
+    let (day, time) = match date.find(['T', ' ']) {
+        Some(split) => (&date[..split], Some(&date[split + 1..])),
+        None => (date, None),
+    };
+    let mut fields = day.splitn(3, '-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (fields.next()??, fields.next()??, fields.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = match time {
+        Some(time) => {
+            let mut fields = time.splitn(3, ':').map(|field| field.parse::<i64>().ok());
+            let (hours, minutes, seconds) = (fields.next()??, fields.next()??, fields.next()??);
+            if !(0..24).contains(&hours) || !(0..60).contains(&minutes) || !(0..60).contains(&seconds) {
+                return None;
+            }
+            hours * 3600 + minutes * 60 + seconds
+        }
+        None => 0,
+    };
+    Some(Timestamp::from_unix_time(days_from_civil(year, month, day) * 86400 + seconds, 0))
+}
+
+/// Days since 1970-01-01 of the given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Reads exclude globs from `input`, one per line. Blank lines and lines starting with `#` are
+/// ignored. `source` is only used to name the origin of a malformed pattern in the error.
+fn read_exclude_patterns<R: BufRead>(source: &str, input: R) -> Result<Vec<Pattern>, Error> {
+    let mut patterns = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let pattern = Pattern::new(line).map_err(|pe| {
+            Error::new(ErrorKind::Other, format!("{}:{}: invalid exclude pattern '{}': {}", source, index + 1, line, pe))
+        })?;
+        patterns.push(pattern);
+    }
+
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod test_excludes {
+    use super::*;
+    use glob::PatternError;
+
+    #[test]
+    fn apply_excludes() -> Result<(), PatternError> {
+        let settings = HashSettings {
+            force_rebuild: false,
+            mode: ManifestMode::TimestampTest,
+            algorithm: HashAlgorithm::Sha256,
+            threads: 1,
+            rules: vec![FilterRule::new(false, "ab*ca")?],
+            exclude_paths: vec![],
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            ignore_files: false,
+            max_depth: None,
+            fail_on_special_files: false,
+            modify_window: Duration::from_secs(0),
+            ignore_times: false,
+            one_file_system: false,
+            hard_links: false,
+            perms: false,
+            checksum_key: None,
+            copy_links: false,
+            io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+        };
+
+        assert_eq!(settings.is_excluded(&PathBuf::from("abnahfpaclca")), true);
+        assert_eq!(settings.is_excluded(&PathBuf::from("anotherfile.txt")), false);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_excludes_with_additional() -> Result<(), PatternError> {
+        let settings = HashSettings {
+            force_rebuild: false,
+            mode: ManifestMode::TimestampTest,
+            algorithm: HashAlgorithm::Sha256,
+            threads: 1,
+            rules: vec![FilterRule::new(false, "ab*ca")?],
+            exclude_paths: vec![],
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            ignore_files: false,
+            max_depth: None,
+            fail_on_special_files: false,
+            modify_window: Duration::from_secs(0),
+            ignore_times: false,
+            one_file_system: false,
+            hard_links: false,
+            perms: false,
+            checksum_key: None,
+            copy_links: false,
+            io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+        }.with_additional_exclusion(&PathBuf::from("anotherfile.txt"));
+
+        assert_eq!(settings.is_excluded(&PathBuf::from("abnahfpaclca")), true);
+        assert_eq!(settings.is_excluded(&PathBuf::from("anotherfile.txt")), true);
+
+        Ok(())
+    }
+
+    #[test]
+    fn last_matching_rule_wins() -> Result<(), PatternError> {
+        let settings = test_support::default_settings()
+            .with_filter_rule(FilterRule::new(false, "*")?)
+            .with_filter_rule(FilterRule::new(true, "*/")?)
+            .with_filter_rule(FilterRule::new(true, "*.rs")?)
+            .with_filter_rule(FilterRule::new(false, "*/generated/*")?);
+
+        assert!(!settings.is_excluded_directory(Path::new("src")));
+        assert!(!settings.is_excluded(Path::new("src/main.rs")));
+        assert!(settings.is_excluded(Path::new("src/main.o")));
+        assert!(settings.is_excluded(Path::new("src/generated/parser.rs")));
+        Ok(())
+    }
+
+    #[test]
+    fn anchored_rules_match_below_root() -> Result<(), PatternError> {
+        let settings = test_support::default_settings()
+            .with_filter_rule(FilterRule::new(false, "/build")?)
+            .with_filter_rule(FilterRule::new(false, "*/cache")?);
+        let root = Path::new("/data/tree");
+
+        assert!(settings.is_excluded_below(root, Path::new("/data/tree/build"), true));
+        assert!(!settings.is_excluded_below(root, Path::new("/data/tree/src/build"), true));
+        assert!(settings.is_excluded_below(root, Path::new("/data/tree/src/cache"), true));
+        assert_eq!(FilterRule::new(false, "/build/")?.to_string(), "/build/");
+        Ok(())
+    }
+
+    #[test]
+    fn rules_keep_command_line_order() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b",
+            "--exclude", "*", "--include", "*/", "--exclude", "*.o", "--include", "keep.o"])?;
+        let rules: Vec<(bool, String)> = cfg.hash_settings().filter_rules().iter()
+            .map(|rule| (rule.is_include(), rule.to_string()))
+            .collect();
+
+        assert_eq!(rules, vec![
+            (false, String::from("*")),
+            (true, String::from("*/")),
+            (false, String::from("*.o")),
+            (true, String::from("keep.o")),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn additional_exclusion_is_literal() {
+        let settings = test_support::default_settings()
+            .with_additional_exclusion(&PathBuf::from("/data/[weird]*?/.usync.manifest"));
+
+        assert_eq!(settings.is_excluded(&PathBuf::from("/data/[weird]*?/.usync.manifest")), true);
+        assert_eq!(settings.is_excluded(&PathBuf::from("/data/w/.usync.manifest")), false);
+        assert_eq!(settings.is_excluded(&PathBuf::from("/data/[weird]xy/.usync.manifest")), false);
+    }
+
+    #[test]
+    fn split_commands() -> Result<(), Error> {
+        assert_eq!(split_command("ssh")?, vec!["ssh"]);
+        assert_eq!(split_command("  ssh -p 2222\t-i key ")?, vec!["ssh", "-p", "2222", "-i", "key"]);
+        assert_eq!(split_command("ssh -i '/my keys/id' -o \"ProxyJump a b\" x\\ y ''")?,
+                   vec!["ssh", "-i", "/my keys/id", "-o", "ProxyJump a b", "x y", ""]);
+        assert!(split_command("ssh 'open").is_err());
+        assert!(split_command("   ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn quote_words() {
+        assert_eq!(shell_quote("--exclude"), "--exclude");
+        assert_eq!(shell_quote("*.tmp"), "'*.tmp'");
+        assert_eq!(shell_quote("my files/it's"), "'my files/it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote_path("~"), "~");
+        assert_eq!(shell_quote_path("~/my data"), "~/'my data'");
+        assert_eq!(shell_quote_path("~/"), "~/");
+        assert_eq!(shell_quote_path("~backup/data"), "~backup/data");
+        assert_eq!(shell_quote_path("$HOME/data"), "$HOME/data");
+        assert_eq!(shell_quote_path("~$(reboot)/data"), "'~$(reboot)/data'");
+        assert_eq!(shell_quote_path("data/~"), "'data/~'");
+    }
+
+    #[test]
+    fn parse_sizes() -> Result<(), Error> {
+        assert_eq!(parse_size("512")?, 512);
+        assert_eq!(parse_size("10k")?, 10 * 1024);
+        assert_eq!(parse_size("10M")?, 10 * 1024 * 1024);
+        assert_eq!(parse_size("2G")?, 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_ages() -> Result<(), Error> {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(parse_age("7d", now)?, Timestamp::from_unix_time(1_000_000 - 7 * 86400, 0));
+        assert_eq!(parse_age("90", now)?, Timestamp::from_unix_time(999_910, 0));
+        assert_eq!(parse_age("2w", now)?.unix_seconds(), 1_000_000 - 14 * 86400);
+        assert_eq!(parse_age("@-5", now)?, Timestamp::from_unix_time(-5, 0));
+        assert_eq!(parse_age("1970-01-01", now)?, Timestamp::UNIX_EPOCH);
+        assert_eq!(parse_age("2024-01-31", now)?.unix_seconds(), 1_706_659_200);
+        assert_eq!(parse_age("2024-02-29T12:30:15", now)?.unix_seconds(), 1_709_209_815);
+        assert_eq!(parse_age("1969-12-31 23:59:59", now)?.unix_seconds(), -1);
+        assert!(parse_age("", now).is_err());
+        assert!(parse_age("-3d", now).is_err());
+        assert!(parse_age("7y", now).is_err());
+        assert!(parse_age("2024-13-01", now).is_err());
+        assert!(parse_age("2024-01-31T25:00:00", now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn age_window() {
+        let settings = test_support::default_settings()
+            .with_age_range(Some(Timestamp::from_unix_time(100, 0)), Some(Timestamp::from_unix_time(200, 0)));
+
+        assert!(settings.is_excluded_age(Timestamp::from_unix_time(99, 999_999_999)));
+        assert!(!settings.is_excluded_age(Timestamp::from_unix_time(100, 0)));
+        assert!(!settings.is_excluded_age(Timestamp::from_unix_time(200, 0)));
+        assert!(settings.is_excluded_age(Timestamp::from_unix_time(200, 1)));
+        // a file from the future is newer than any cutoff
+        let newer = test_support::default_settings().with_age_range(Some(Timestamp::from(SystemTime::now())), None);
+        assert!(!newer.is_excluded_age(Timestamp::from(SystemTime::now() + Duration::from_secs(3600))));
+    }
+
+    #[test]
+    fn size_thresholds() {
+        let settings = test_support::default_settings().with_size_range(Some(10), Some(100));
+
+        assert!(settings.is_excluded_size(9));
+        assert!(!settings.is_excluded_size(10));
+        assert!(!settings.is_excluded_size(100));
+        assert!(settings.is_excluded_size(101));
+        assert!(!test_support::default_settings().is_excluded_size(u64::MAX));
+    }
+
+    #[test]
+    fn ignore_files_are_scoped() -> Result<(), Error> {
+        let root = tempfile::TempDir::new()?;
+        let sub = root.path().join("sub");
+        std::fs::create_dir(&sub)?;
+        std::fs::write(root.path().join(IGNORE_FILE), "*.tmp\n")?;
+        std::fs::write(sub.join(IGNORE_FILE), "# local\nbuild/*\n")?;
+
+        let mut stack = IgnoreStack::new();
+        assert!(stack.enter(root.path())?);
+        assert!(stack.enter(&sub)?);
+        assert!(stack.is_ignored(&sub.join("deep/x.tmp")));
+        assert!(stack.is_ignored(&sub.join("build/out.o")));
+        assert!(!stack.is_ignored(&root.path().join("build/out.o")));
+
+        stack.leave();
+        assert!(!stack.is_ignored(&sub.join("build/out.o")));
+        assert!(!stack.enter(&sub.join("missing"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn read_exclude_file() -> Result<(), Error> {
+        let input = "# build output\n*.o\n\n   \ntarget/*\n";
+        let patterns = read_exclude_patterns("excludes", input.as_bytes())?;
+
+        assert_eq!(patterns, vec![Pattern::new("*.o").unwrap(), Pattern::new("target/*").unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_module_file() -> Result<(), Error> {
+        let input = "# served trees\n[photos]\npath = /srv/photos\n\n[upload]\npath = /srv/in\nread only = no\nauth token = secret\n";
+        let modules = read_modules("modules", input.as_bytes())?;
+
+        assert_eq!(modules, vec![
+            ModuleDefinition { name: String::from("photos"), path: PathBuf::from("/srv/photos"), read_only: true, auth_token: None },
+            ModuleDefinition { name: String::from("upload"), path: PathBuf::from("/srv/in"), read_only: false, auth_token: Some(String::from("secret")) },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_module_file_rejects_mistakes() {
+        for input in &["path = /a\n", "[a]\npath = /a\n[a]\npath = /b\n", "[a]\n", "[a]\npath = /a\nreadonly = yes\n", "[a/b]\npath = /a\n"] {
+            assert!(read_modules("modules", input.as_bytes()).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn read_exclude_file_reports_line() {
+        let input = "*.o\n# comment\n[broken\n";
+        let error = read_exclude_patterns("excludes", input.as_bytes()).unwrap_err();
+
+        assert!(error.to_string().starts_with("excludes:3:"), "{}", error);
+    }
+}
+
+impl Configuration {
+    #[inline]
+    pub fn force_pipeline(&self) -> bool {
+        self.force_pipeline
+    }
+
+    /// Number of writes the in-process pipe between sender and receiver holds before the
+    /// sender has to wait. Local syncs only use that pipe from an archive, with delayed updates
+    /// or with `--force-pipeline`
+    #[inline]
+    pub fn pipe_capacity(&self) -> usize {
+        self.pipe_capacity
+    }
+
+    #[inline]
+    pub fn privilege(&self) -> PrivilegeMode {
+        self.privilege
+    }
+
+    #[inline]
+    pub fn auth_token(&self) -> Option<&str> {
+        self.auth_token.as_deref()
+    }
+
+    /// Seed of the `HashSettings::checksum_key`, passed on to the remote usync
+    #[inline]
+    pub fn checksum_seed(&self) -> Option<&str> {
+        self.checksum_seed.as_deref()
+    }
+
+    /// Bandwidth limit for file payloads, in bytes per second, if there is one
+    #[inline]
+    pub fn bwlimit(&self) -> Option<u64> {
+        self.bwlimit
+    }
+
+    #[inline]
+    pub fn cache_limit(&self) -> Option<u64> {
+        self.cache_limit
+    }
+
+    /// Number of connections the server handles at once, further clients wait until one of
+    /// them finishes
+    #[inline]
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Whether the server accepts clients pushing files into its directory
+    #[inline]
+    pub fn accept_push(&self) -> bool {
+        self.accept_push
+    }
+
+    /// Whether the target is checked against the source by content hash after syncing
+    #[inline]
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// Whether the sync writes to a staging copy of the target, which replaces the target once
+    /// the sync succeeded
+    #[inline]
+    pub fn stage_then_swap(&self) -> bool {
+        self.stage_then_swap
+    }
+
+    /// Whether received files are kept staged until all of them arrived, and only then
+    /// renamed into place together
+    #[inline]
+    pub fn delay_updates(&self) -> bool {
+        self.delay_updates
+    }
+
+    /// Whether directories of the source without files below them (after filtering) are left
+    /// out, rather than created on the target
+    #[inline]
+    pub fn prune_empty_dirs(&self) -> bool {
+        self.prune_empty_dirs
+    }
+
+    /// Directory of the source (relative to its root) the sync is restricted to, if any
+    #[inline]
+    pub fn subpath(&self) -> Option<&Path> {
+        self.subpath.as_deref()
+    }
+
+    /// Whether a sync goes on with the remaining files after one fails to transfer
+    #[inline]
+    pub fn keep_going(&self) -> bool {
+        self.keep_going
+    }
+
+    /// Which of the files that differ a sync transfers, see `--ignore-existing` and `--existing`
+    #[inline]
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    /// How much a sync transfers at most before it stops
+    #[inline]
+    pub fn transfer_limits(&self) -> TransferLimits {
+        self.limits
+    }
+
+    /// Whether files that grew on the source are appended to on the target instead of being
+    /// transferred whole, and if so, whether what the target holds is checked first
+    #[inline]
+    pub fn append(&self) -> Option<AppendMode> {
+        self.append
+    }
+
+    /// Whether files are always transferred whole, rather than building on what the target
+    /// holds of them already: a partial transfer to resume, or a shorter file to append to.
+    /// Unless `--whole-file` or `--no-whole-file` says otherwise, this is the case for syncs
+    /// between local directories (without `--append`), where sending the data is cheap.
+    #[inline]
+    pub fn whole_file(&self) -> bool {
+        self.whole_file.unwrap_or_else(|| self.append.is_none() && matches!((&self.source, &self.target), (Some(Local(_)), Some(Local(_)))))
+    }
+
+    /// Whether a connection fails if the peers' clocks differ too much for timestamp mode
+    #[inline]
+    pub fn strict_clock(&self) -> bool {
+        self.strict_clock
+    }
+
+    /// Whether extended attributes of files are transferred
+    #[inline]
+    pub fn xattrs(&self) -> bool {
+        self.xattrs
+    }
+
+    /// Whether creation times of files are transferred, where the platforms of both sides
+    /// support them
+    #[inline]
+    pub fn crtimes(&self) -> bool {
+        self.crtimes
+    }
+
+    /// Number of times a dropped connection to a remote sender is re-established
+    #[inline]
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Number of files requested from a remote sender with a single command
+    #[inline]
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// How long the server waits for open connections when shutting down, if bounded
+    #[inline]
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        self.shutdown_timeout
+    }
+
+    /// Age after which the server rebuilds a manifest before using it, if it does at all
+    #[inline]
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval
+    }
+
+    /// Remote shell used to start usync on remote hosts: the program followed by its arguments
+    #[inline]
+    pub fn rsh(&self) -> &[String] {
+        &self.rsh
+    }
+
+    /// Path of the usync binary on remote hosts
+    #[inline]
+    pub fn remote_usync_path(&self) -> &str {
+        &self.remote_usync_path
+    }
+
+    /// Directory received files are staged in before they are moved into place
+    #[inline]
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.temp_dir.as_deref()
+    }
+
+    /// Free space (in bytes) received files must leave on the target filesystem
+    #[inline]
+    pub fn min_free(&self) -> Option<u64> {
+        self.min_free
+    }
+
+    /// Smallest run of zero bytes left as a hole in received files, if they are saved sparse
+    #[inline]
+    pub fn sparse(&self) -> Option<u64> {
+        self.min_hole
+    }
+
+    /// Read and write timeout for network connections, if any
+    #[inline]
+    pub fn io_timeout(&self) -> Option<Duration> {
+        self.io_timeout
+    }
+
+    #[inline]
+    pub fn server_port(&self) -> u16 {
+        self.server_port
+    }
+
+    /// Where stored manifests are kept
+    #[inline]
+    pub fn manifest_location(&self) -> &ManifestLocation {
+        &self.manifest_location
+    }
+
+    #[inline]
+    pub fn role(&self) -> Option<ProcessRole> {
+        self.role
+    }
+
+
+    /// The sync target. Present for every role except sender and server, which `parse` checks.
+    #[inline]
+    pub fn target(&self) -> &PathDefinition {
+        self.target.as_ref().expect("target is validated by Configuration::parse")
+    }
+
+    /// The sync target, if the role has one
+    #[inline]
+    pub fn target_definition(&self) -> Option<&PathDefinition> {
+        self.target.as_ref()
+    }
+
+    /// The sync source. Present for every role except receiver, which `parse` checks, and
+    /// the server if it serves modules.
+    #[inline]
+    pub fn source(&self) -> &PathDefinition {
+        self.source.as_ref().expect("source is validated by Configuration::parse")
+    }
+
+    /// The server's default tree, if it has one besides its modules
+    #[inline]
+    pub fn default_source(&self) -> Option<&PathDefinition> {
+        self.source.as_ref()
+    }
+
+    /// Named trees the server offers in addition to its default tree
+    #[inline]
+    pub fn modules(&self) -> &[ModuleDefinition] {
+        &self.modules
+    }
+
+    /// Checks that the paths the configured role works with were given.
+    fn validate(&self) -> Result<(), Error> {
+        let (needs_source, needs_target) = match self.role {
+            Some(ProcessRole::Sender) => (true, false),
+            Some(ProcessRole::Server) => (self.modules.is_empty(), false),
+            Some(ProcessRole::Receiver) => (false, true),
+            None if self.benchmark => (false, false),
+            None => (true, !self.list_only && !self.list_remote && self.write_manifest.is_none()),
+        };
+
+        if needs_source && self.source.is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, "--source is required"));
+        }
+        if needs_target && self.target.is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, "--target is required"));
+        }
+        if self.role == Some(ProcessRole::Server) && self.source.as_ref().is_some_and(|source| !matches!(source, Local(_))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "The server requires a local --source directory"));
+        }
+        if self.source_glob().is_some() && (self.role.is_some() || self.list_only || self.write_manifest.is_some() || self.read_manifest.is_some() || self.plan.is_some() || self.apply_plan.is_some()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "A --source pattern can only be synced, not listed, served, planned or read as manifest"));
+        }
+        if self.source_glob().is_some() && !matches!(self.target, Some(Local(_))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "A --source pattern requires a local --target directory"));
+        }
+        if self.benchmark && (self.source.is_some() || self.target.is_some()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--benchmark syncs a tree it generates, it takes no --source or --target"));
+        }
+        if self.list_only && !matches!(self.source, Some(Local(_))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--list-only requires a local --source directory"));
+        }
+        if self.list_remote && !matches!(self.source, Some(Remote(_, _)) | Some(Server(_, _))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--list-remote requires a remote:// or server:// --source"));
+        }
+        if self.stage_then_swap && matches!(self.target, Some(Server(_, _))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--stage-then-swap requires a local or remote:// --target"));
+        }
+        if self.subpath.as_ref().is_some_and(|subpath| subpath.components().any(|part| !matches!(part, Component::Normal(_) | Component::CurDir))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--subpath must be a path within the source, relative to its root"));
+        }
+        if self.checksum && self.selection != Selection::All {
+            return Err(Error::new(ErrorKind::InvalidInput, "--checksum verifies all files, which --ignore-existing and --existing leave out some of"));
+        }
+        if self.whole_file == Some(true) && self.append.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "--whole-file transfers files whole, which --append does not"));
+        }
+        if self.delay_updates && self.append.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "--append writes into files of the target, which --delay-updates only replaces at the end"));
+        }
+        if self.delay_updates && self.temp_dir.is_some() {
+            // moving a file in from another filesystem at the end would lose its attributes
+            return Err(Error::new(ErrorKind::InvalidInput, "--delay-updates stages files next to their target, it can't be combined with --temp-dir"));
+        }
+        if self.stage_then_swap && self.append.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "--append writes into files of the target, which --stage-then-swap must leave alone"));
+        }
+        if self.hash.ignore_times && self.hash.mode != ManifestMode::Hash {
+            return Err(Error::new(ErrorKind::InvalidInput, "--ignore-times requires --hash-mode hash"));
+        }
+        if (self.plan.is_some() || self.apply_plan.is_some()) && !matches!(self.target, Some(Local(_))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--plan and --apply-plan require a local --target directory"));
+        }
+        if self.plan.is_some() && (self.checksum || self.stage_then_swap) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--plan changes nothing, so there is nothing to --checksum or --stage-then-swap"));
+        }
+        if (self.write_manifest.is_some() || self.read_manifest.is_some()) && !matches!(self.source, Some(Local(_))) {
+            return Err(Error::new(ErrorKind::InvalidInput, "--write-manifest and --read-manifest require a local --source directory"));
+        }
+
+        Ok(())
+    }
+
+    pub fn hash_settings(&self) -> &HashSettings {
+        &self.hash
+    }
+
+    #[inline]
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// Whether to print the summary of the sync when done, independent of the log level. Only
+    /// the controller and a receiver it started (which prints to stderr) ever print it.
+    #[inline]
+    pub fn stats(&self) -> bool {
+        self.stats
+    }
+
+    /// Whether sizes in the summary, progress and listings are given with binary suffixes
+    /// rather than in bytes
+    #[inline]
+    pub fn human_readable(&self) -> bool {
+        self.human_readable
+    }
+
+    /// How often `--itemize-changes` was given: once to list what the sync changes, twice to
+    /// list unchanged files as well. Like the summary, only the controller and a receiver it
+    /// started list them.
+    #[inline]
+    pub fn itemize_changes(&self) -> u64 {
+        self.itemize_changes
+    }
+
+    #[inline]
+    pub fn progress(&self) -> bool {
+        self.progress
+    }
+
+    /// Whether to write a JSON line per transferred file or created directory to stderr
+    #[inline]
+    pub fn events(&self) -> bool {
+        self.events
+    }
+
+    #[inline]
+    pub fn json_summary(&self) -> bool {
+        self.json_summary
+    }
+
+    /// Whether to print the manifest of the source instead of syncing
+    #[inline]
+    pub fn list_only(&self) -> bool {
+        self.list_only
+    }
+
+    /// Whether to print the manifest a remote source offers instead of syncing
+    #[inline]
+    pub fn list_remote(&self) -> bool {
+        self.list_remote
+    }
+
+    /// Where to write the manifest of the source to instead of syncing (`-` for stdout)
+    #[inline]
+    pub fn write_manifest(&self) -> Option<&Path> {
+        self.write_manifest.as_deref()
+    }
+
+    /// Where to write what the sync would do to instead of doing it (`-` for stdout), see
+    /// `Plan`
+    #[inline]
+    pub fn plan(&self) -> Option<&Path> {
+        self.plan.as_deref()
+    }
+
+    /// The plan file whose actions the sync carries out, instead of comparing source and target
+    #[inline]
+    pub fn apply_plan(&self) -> Option<&Path> {
+        self.apply_plan.as_deref()
+    }
+
+    /// Prebuilt manifest to use for the source instead of walking it (`-` for stdin)
+    #[inline]
+    pub fn read_manifest(&self) -> Option<&Path> {
+        self.read_manifest.as_deref()
+    }
+
+    /// Whether to time manifest building and a local sync of a generated tree instead of
+    /// syncing
+    #[inline]
+    pub fn benchmark(&self) -> bool {
+        self.benchmark
+    }
+
+    /// The pattern a local `--source` holds, if it is one rather than a path: it contains
+    /// glob metacharacters, and nothing of that name exists. See `expand_source_glob`.
+    pub fn source_glob(&self) -> Option<&Path> {
+        match &self.source {
+            Some(Local(path)) if path.to_str().is_some_and(|path| path.contains(['*', '?', '['])) && !path.exists() => Some(path),
+            _ => None,
+        }
+    }
+
+    /// One configuration for each directory the `--source` pattern (see `source_glob`)
+    /// matches, syncing it into the subdirectory of the target named like the part of its
+    /// path the pattern's wildcards matched: `/srv/*/public` syncs `/srv/a/public` into
+    /// `a/public` below the target. Matches that aren't directories are skipped. Fails if
+    /// no directory matches.
+    pub fn expand_source_glob(&self, pattern: &Path) -> Result<Vec<Configuration>, Error> {
+        let text = pattern.to_str().expect("source_glob only returns UTF-8 patterns");
+        let matches = glob::glob(text).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid --source pattern {}: {}", text, e)))?;
+        // the leading components without wildcards are left out of the target subdirectories
+        let base: PathBuf = pattern.components()
+            .take_while(|part| !part.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect();
+        let target = match &self.target {
+            Some(Local(target)) => target,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "A --source pattern requires a local --target directory")),
+        };
+
+        let mut expanded = Vec::new();
+        for source in matches {
+            let source = match source {
+                Ok(source) => source,
+                Err(e) => {
+                    warn!("Skipping {}, it can't be read: {}", e.path().to_string_lossy(), e.error());
+                    continue;
+                }
+            };
+            if !source.is_dir() {
+                warn!("Skipping {}, it matches --source but is not a directory", source.to_string_lossy());
+                continue;
+            }
+            let below = source.strip_prefix(&base).unwrap_or(&source);
+            expanded.push(self.with_local_roots(&source, &target.join(below)));
+        }
+        if expanded.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("--source {} matches no directories", text)));
+        }
+        Ok(expanded)
+    }
+
+    /// This configuration with `source` and `target` as local roots, for the sync `--benchmark`
+    /// runs between directories it creates itself, and those of a `--source` pattern.
+    pub fn with_local_roots(&self, source: &Path, target: &Path) -> Configuration {
+        let mut copy = self.clone();
+        copy.source = Some(Local(source.to_owned()));
+        copy.target = Some(Local(target.to_owned()));
+        copy.benchmark = false;
+        copy
+    }
+
+    pub fn parse() -> Result<Configuration, Error> {
+        Configuration::parse_from(std::env::args_os())
+    }
+
+    pub fn parse_from<I, T>(args: I) -> Result<Configuration, Error>
+        where I: IntoIterator<Item=T>, T: Into<std::ffi::OsString> + Clone {
+        let command_line = Configuration::app().get_matches_from(args);
+        let file = command_line.value_of("config").map(read_config_file).transpose()?;
+        Configuration::from_options(&Options { command_line, file })
+    }
+
+    fn app() -> App<'static, 'static> {
+        App::new("usync")
+            .version("1.0")
+            .author("Elisabeth 'TerraNova' Schulz")
+            .arg(Arg::with_name("config")
+                .help("Read options from this TOML file, with the long option names as keys (flags set to true, repeatable options as arrays). Options on the command line take precedence")
+                .long("config")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("force-pipeline")
+                .hidden(true)
+                .long("force-pipeline")
+            )
+            .arg(Arg::with_name("benchmark")
+                .help("Time building manifests of a generated tree and syncing it locally, with the given hash and buffer settings, and print the throughput of each")
+                .hidden(true)
+                .long("benchmark")
+                .conflicts_with_all(&["list-only", "list-remote", "write-manifest", "read-manifest"])
+            )
+            .arg(Arg::with_name("pipe-capacity")
+                .help("Number of writes the in-process pipe of a local sync holds before the sending side waits for the receiving one. Only matters where a local sync runs through that pipe: from an archive, with --delay-updates or with --force-pipeline")
+                .long("pipe-capacity")
+                .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("rebuild manifest")
+                    .help("Rebuild the required manifest(s), even if it already exists")
+                    .long("force-rebuild-manifest")
+            )
+            .arg(
+                Arg::with_name("role")
+                    .help("Role of a remote-spawned instance.")
+                    .long("role")
+                    .takes_value(true)
+                    .possible_values(&["sender", "receiver", "server"])
+            )
+            .arg(
+                Arg::with_name("source")
+                    .help("Sync source directory, or an uncompressed tar archive to sync from without unpacking it. A local glob pattern like '/srv/*/public' syncs every directory it matches into the subdirectory of the target named after what the wildcards matched (here, <target>/<name>/public). On a remote host (remote://host:path), a leading ~ expands to the remote user's home directory and relative paths are relative to it")
+                    .long("source")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("target")
+                    .help("Sync target directory. On a remote host (remote://host:path), a leading ~ expands to the remote user's home directory and relative paths are relative to it")
+                    .long("target")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("manifest file")
+                    .long("manifest-file")
+                    .help("Stored manifest file (relative to source directory)")
+                    .takes_value(true)
+                    .default_value(".usync.manifest")
+            )
+            .arg(
+                Arg::with_name("manifest-cache")
+                    .long("manifest-cache")
+                    .help("Store manifests in the user's cache directory ($XDG_CACHE_HOME/usync) instead of inside the synced tree, which works for read-only sources as well")
+                    .takes_value(false)
+            )
+            .arg(Arg::with_name("hash-mode")
+                .help("Hashing mode")
+                .long("hash-mode")
+                .takes_value(true)
+                .default_value("hash")
+                .possible_values(&["hash", "timestamp"])
+            )
+            .arg(Arg::with_name("hash-algo")
+                .help("Hashing algorithm used in hash mode")
+                .long("hash-algo")
+                .takes_value(true)
+                .default_value("sha256")
+                .possible_values(&["sha256", "blake3"])
+            )
+            .arg(Arg::with_name("hash-threads")
+                .help("Number of files to hash concurrently (defaults to the number of CPUs)")
+                .long("hash-threads")
+                .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .help("Verbose output (log level info, USYNC_LOG overrides)")
+                    .long("verbose")
+                    .short("v")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("stats")
+                    .help("Print a summary of the sync when done, without the rest of --verbose")
+                    .long("stats")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("human-readable")
+                    .help("Give sizes in the summary, progress and listings as KiB, MiB, GiB... instead of bytes")
+                    .long("human-readable")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("super")
+                    .help("Apply privileged metadata (file ownership), failing if not permitted")
+                    .long("super")
+                    .conflicts_with("fake-super")
+            )
+            .arg(
+                Arg::with_name("fake-super")
+                    .help("Store privileged metadata in an extended attribute instead of applying it")
+                    .long("fake-super")
+            )
+            .arg(
+                Arg::with_name("itemize-changes")
+                    .help("List every transferred file and created directory with a code of what changed, in rsync's format: '>f+++++++++' for a new file, '>fcst......' for one differing in content, size and/or time, 'hf' instead of '>f' for a hard link and 'cd+++++++++' for a directory. Give twice to list unchanged files as '.f' as well")
+                    .long("itemize-changes")
+                    .short("i")
+                    .multiple(true)
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("progress")
+                    .help("Show the number of files and bytes transferred so far")
+                    .long("progress")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("events")
+                    .help("Write a JSON line to stderr for every transferred file, created directory and failed transfer")
+                    .long("events")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("checksum")
+                    .help("After syncing, verify that all files on the target hash the same as on the source")
+                    .long("checksum")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("stage-then-swap")
+                    .help("Sync into a staging directory next to the target, which takes the place of the target once the sync succeeded. Where the system supports it (Linux), the two are exchanged in one step, elsewhere the target is briefly missing between two renames. On failure the target is left as it was")
+                    .long("stage-then-swap")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("delay-updates")
+                    .help("Keep received files staged until the whole transfer succeeded, then rename them into place in one final pass. On failure, none of them replace their target")
+                    .long("delay-updates")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("subpath")
+                    .help("Only sync this directory of the source (relative to its root), using the manifest of the whole source")
+                    .long("subpath")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("append")
+                    .help("Append to target files that are shorter than their source instead of replacing them, sending only the data the target is missing. Meant for files that only grow, like logs")
+                    .long("append")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("append-verify")
+                    .help("Like --append, but check that the target file holds the start of the source, and transfer the file whole if it doesn't (needs --hash-mode hash for remote sources)")
+                    .long("append-verify")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("whole-file")
+                    .help("Always transfer files whole, never resuming an interrupted transfer. The default for syncs between local directories")
+                    .long("whole-file")
+                    .short("W")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("no-whole-file")
+                    .help("Resume interrupted transfers even in syncs between local directories")
+                    .long("no-whole-file")
+                    .takes_value(false)
+                    .conflicts_with("whole-file")
+            )
+            .arg(
+                Arg::with_name("prune-empty-dirs")
+                    .help("Don't create directories on the target that have no files below them, e.g. because all of them are excluded")
+                    .long("prune-empty-dirs")
+                    .short("m")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("keep-going")
+                    .help("Go on with the remaining files if one can't be transferred, failing only at the end")
+                    .long("keep-going")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("max-transfer")
+                    .help("Stop the sync once this much was transferred, leaving the remaining files for a later run (accepts suffixes K, M, G and T). The file reaching the limit is still transferred completely")
+                    .long("max-transfer")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("max-files")
+                    .help("Stop the sync once this many files were transferred, leaving the remaining files for a later run")
+                    .long("max-files")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("ignore-existing")
+                    .help("Only transfer files missing on the target, never replacing existing ones, even if they differ")
+                    .long("ignore-existing")
+                    .takes_value(false)
+                    .conflicts_with("existing")
+            )
+            .arg(
+                Arg::with_name("existing")
+                    .help("Only update files that exist on the target already, never creating new files or directories")
+                    .long("existing")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("list-only")
+                    .help("Print the files in the manifest of the source with size, modification time and hash, without syncing")
+                    .long("list-only")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("list-remote")
+                    .help("Like --list-only, for the manifest a remote:// or server:// source offers")
+                    .long("list-remote")
+                    .takes_value(false)
+                    .conflicts_with("list-only")
+            )
+            .arg(
+                Arg::with_name("write-manifest")
+                    .help("Build the manifest of the source and write it to this file ('-' for stdout), without syncing")
+                    .long("write-manifest")
+                    .takes_value(true)
+                    .conflicts_with("read-manifest")
+            )
+            .arg(
+                Arg::with_name("read-manifest")
+                    .help("Use the manifest in this file ('-' for stdin) for the source, instead of walking it")
+                    .long("read-manifest")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("plan")
+                    .help("Write what the sync would do (directories to create, files to transfer, overwrite, link or change permissions of) to this file ('-' for stdout) without doing it, for review and --apply-plan")
+                    .long("plan")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("apply-plan")
+                    .help("Carry out the actions of a plan written with --plan, instead of comparing source and target again. Files are transferred as the source holds them now")
+                    .long("apply-plan")
+                    .takes_value(true)
+                    .conflicts_with("plan")
+            )
+            .arg(
+                Arg::with_name("xattrs")
+                    .help("Transfer extended attributes of files (attributes that can't be set are skipped with a warning)")
+                    .long("xattrs")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("crtimes")
+                    .help("Transfer creation times of files where the platform records them (setting them is only possible on macOS, elsewhere they are skipped with a warning)")
+                    .long("crtimes")
+                    .short("N")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("strict-clock")
+                    .help("In timestamp mode, refuse to sync if the clocks of sender and receiver differ by more than 2 seconds")
+                    .long("strict-clock")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("json-summary")
+                    .help("Print a summary of the sync as JSON when done")
+                    .long("json-summary")
+                    .takes_value(false)
+            )
+            .group(ArgGroup::with_name("server")
+                .arg("server-port")
+            )
+            .arg(Arg::with_name("server-port")
+                .help("Port for the server to listen on")
+                .long("server-port")
+                .takes_value(true)
+                .default_value("9715")
+            )
+            .arg(Arg::with_name("accept-push")
+                .help("Let clients push files into the served directory (server role only)")
+                .long("accept-push")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("shutdown-timeout")
+                .help("Seconds the server waits for open connections to finish after SIGINT (default: no limit)")
+                .long("shutdown-timeout")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("refresh-interval")
+                .help("Rescan a served tree for a client once its manifest is older than this many seconds, so that changes made to it after startup are served (server role only)")
+                .long("refresh-interval")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("io-timeout")
+                .help("Abort a TCP connection if the peer sends or accepts no data for this many seconds (not applied to ssh, use its ServerAliveInterval)")
+                .long("io-timeout")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("bwlimit")
+                .help("Limit file transfer bandwidth to the given number of KiB per second, 0 for no limit")
+                .long("bwlimit")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("retries")
+                .help("Reconnect up to this many times if the connection to the sender fails")
+                .long("retries")
+                .takes_value(true)
+                .default_value("0")
+            )
+            .arg(Arg::with_name("batch-size")
+                .help("Request up to this many files from a remote sender at once, saving a round trip per file")
+                .long("batch-size")
+                .takes_value(true)
+                .default_value("64")
+            )
+            .arg(Arg::with_name("cache-limit")
+                .help("Maximum total size of the files the server keeps open in its file cache")
+                .long("cache-limit")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("max-connections")
+                .help("Handle at most this many connections at once, further clients wait until one of them finishes (server role only)")
+                .long("max-connections")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("rsh")
+                .help("Remote shell command with its arguments, e.g. \"ssh -p 2222 -i key\" (quote arguments containing spaces)")
+                .long("rsh")
+                .short("e")
+                .takes_value(true)
+                .env("USYNC_RSH")
+                .default_value("ssh")
+            )
+            .arg(Arg::with_name("remote-usync-path")
+                .help("Path of the usync binary on the remote host")
+                .long("remote-usync-path")
+                .takes_value(true)
+                .default_value("usync")
+            )
+            .arg(Arg::with_name("temp-dir")
+                .help("Stage copied and received files in this directory instead of next to their target")
+                .long("temp-dir")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("sparse")
+                .help("Leave holes in received files where they contain runs of zero bytes (files from a remote sender only)")
+                .long("sparse")
+                .short("S")
+                .takes_value(false)
+            )
+            .arg(Arg::with_name("min-hole")
+                .help("Smallest run of zero bytes --sparse leaves as a hole, at most 4M (accepts suffixes K, M, G and T, default 4K)")
+                .long("min-hole")
+                .takes_value(true)
+                .requires("sparse")
+            )
+            .arg(Arg::with_name("io-buffer-size")
+                .help("Size of the buffers used to hash files and to send or receive them over the network, at most 64M (accepts suffixes K, M, G and T, default 64K). Local copies are left to the system and don't use it")
+                .long("io-buffer-size")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("min-free")
+                .help("Stop before a received file would leave less than this much free space on the target (accepts suffixes K, M, G and T)")
+                .long("min-free")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("modules")
+                .help("File defining named trees the server offers, in the style of rsyncd.conf (server role only)")
+                .long("modules")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("auth-token")
+                .help("Shared secret a client must present to the server")
+                .long("auth-token")
+                .takes_value(true)
+                .env("USYNC_AUTH_TOKEN")
+                .hide_env_values(true)
+            )
+            .arg(Arg::with_name("checksum-seed")
+                .help("Shared secret content hashes are keyed with, so that they can't be precomputed. Client and server must use the same seed")
+                .long("checksum-seed")
+                .takes_value(true)
+                .env("USYNC_CHECKSUM_SEED")
+                .hide_env_values(true)
+            )
+            .arg(Arg::with_name("checksum-seed-stdin")
+                .help("Read the checksum seed from the first line of stdin, before the protocol starts. Used when spawning the remote usync, to keep the seed off its command line")
+                .hidden(true)
+                .long("checksum-seed-stdin")
+                .requires("role")
+                .conflicts_with("checksum-seed")
+            )
+            .arg(
+                Arg::with_name("exclude")
+                    .help("Exclude glob (specify multiple times for several patterns)")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .long("exclude")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("ignore-files")
+                    .help("Honor exclude globs from .usyncignore files in the tree, relative to their directory")
+                    .long("ignore-files")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("min-size")
+                    .help("Skip files smaller than this size (accepts suffixes K, M, G and T)")
+                    .long("min-size")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("max-size")
+                    .help("Skip files larger than this size (accepts suffixes K, M, G and T)")
+                    .long("max-size")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("newer-than")
+                    .help("Skip files last modified before this time, given as age (e.g. 7d, with units s, m, h, d and w), UTC date (2024-01-31 or 2024-01-31T12:00:00) or @seconds since the epoch. Files modified in the future count as newest")
+                    .long("newer-than")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("older-than")
+                    .help("Skip files last modified after this time, given like --newer-than")
+                    .long("older-than")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("max-depth")
+                    .help("Do not descend more than this many directory levels below the root")
+                    .long("max-depth")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("hard-links")
+                    .help("Transfer files hard linked to each other once, and link them on the target as well (Unix only)")
+                    .long("hard-links")
+                    .short("H")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("perms")
+                    .help("Preserve the permissions of files, and update them on the target when they alone changed")
+                    .long("perms")
+                    .short("p")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("copy-links")
+                    .help("Follow symlinks, syncing what they point to instead of skipping them")
+                    .long("copy-links")
+                    .short("L")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("one-file-system")
+                    .help("Do not descend into directories on a different filesystem than the root (Unix only)")
+                    .long("one-file-system")
+                    .short("x")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("modify-window")
+                    .help("Treat modification times at most this many seconds apart as equal, e.g. 1 for FAT filesystems")
+                    .long("modify-window")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("ignore-times")
+                    .help("Consider files with the same size and content hash equal even if their modification times differ, requires --hash-mode hash")
+                    .long("ignore-times")
+                    .alias("size-and-hash-only")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("error-on-special-files")
+                    .help("Fail when the tree contains fifos, sockets or device nodes, instead of skipping them with a warning")
+                    .long("error-on-special-files")
+                    .takes_value(false)
+            )
+            .arg(
+                Arg::with_name("include")
+                    .help("Include glob, overriding earlier excludes (the last matching --include/--exclude wins, a trailing '/' only matches directories, a leading '/' anchors the glob to the source root)")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .long("include")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("exclude-from")
+                    .help("Read exclude globs from a file, one per line ('#' starts a comment)")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .long("exclude-from")
+                    .takes_value(true)
+            )
+    }
+
+    /// Builds the configuration from the options of the command line and the config file.
+    fn from_options(args: &Options) -> Result<Configuration, Error> {
+        let source = args.value_of("source").map(PathDefinition::parse).transpose()?;
+        let target = args.value_of("target").map(PathDefinition::parse).transpose()?;
+        let server_port = args.value_of("server-port").unwrap();
+        let server_port = server_port.parse::<u16>().map_err(|e| {
+            Error::new(ErrorKind::InvalidInput, format!("Invalid server port {}: {}", server_port, e))
+        })?;
+
+        let cache_limit = match args.value_of("cache-limit") {
+            Some(v) => Some(v.parse::<u64>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid cache limit {}: {}", v, e))
+            })?),
+            None => None
+        };
+
+        let max_connections = match args.value_of("max-connections").map(str::parse::<usize>) {
+            None => None,
+            Some(Ok(max)) if max > 0 => Some(max),
+            Some(_) => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "Invalid number of connections {}, expected a positive number", args.value_of("max-connections").unwrap()))),
+        };
+
+        let checksum_seed = if args.is_present("checksum-seed-stdin") {
+            Some(read_seed_line(std::io::stdin().lock())?)
+        } else {
+            args.value_of("checksum-seed").map(String::from)
+        };
+        if checksum_seed.as_deref() == Some("") {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid checksum seed, it must not be empty"));
+        }
+        if checksum_seed.as_deref().is_some_and(|seed| seed.contains('\n')) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid checksum seed, it must be a single line"));
+        }
+
+        let shutdown_timeout = match args.value_of("shutdown-timeout") {
+            Some(v) => Some(Duration::from_secs(v.parse::<u64>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid shutdown timeout {}: {}", v, e))
+            })?)),
+            None => None
+        };
+
+        let refresh_interval = match args.value_of("refresh-interval") {
+            Some(v) => Some(Duration::from_secs(v.parse::<u64>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid refresh interval {}: {}", v, e))
+            })?)),
+            None => None
+        };
+
+        let io_timeout = match args.value_of("io-timeout") {
+            Some(v) => Some(Duration::from_secs(v.parse::<u64>().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid I/O timeout {}: expected a positive number of seconds", v))
+            })?)),
+            None => None
+        };
+
+        let rsh = split_command(args.value_of("rsh").unwrap())?;
+
+        let modules = match args.value_of("modules") {
+            Some(file) => {
+                let input = File::open(file).map_err(|e| {
+                    Error::new(e.kind(), format!("Cannot open module file {}: {}", file, e))
+                })?;
+                read_modules(file, BufReader::new(input))?
+            }
+            None => vec![]
+        };
+
+        let retries = args.value_of("retries").unwrap().parse::<u32>().map_err(|e| {
+            Error::new(ErrorKind::InvalidInput, format!("Invalid number of retries {}: {}", args.value_of("retries").unwrap(), e))
+        })?;
+
+        let batch_size = match args.value_of("batch-size").unwrap().parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "Invalid batch size {}, expected a positive number", args.value_of("batch-size").unwrap()))),
+        };
+
+        let pipe_capacity = match args.value_of("pipe-capacity").map(str::parse::<usize>) {
+            None => DEFAULT_PIPE_CAPACITY,
+            Some(Ok(capacity)) if capacity > 0 => capacity,
+            Some(_) => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "Invalid pipe capacity {}, expected a positive number", args.value_of("pipe-capacity").unwrap()))),
+        };
+
+        let hash_threads = match args.value_of("hash-threads") {
+            Some(v) => v.parse::<usize>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid number of hash threads {}: {}", v, e))
+            })?.max(1),
+            None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        };
+
+        let bwlimit = match args.value_of("bwlimit") {
+            Some(v) => {
+                let kib = v.parse::<u64>().map_err(|e| {
+                    Error::new(ErrorKind::InvalidInput, format!("Invalid bandwidth limit {}: {}", v, e))
+                })?;
+                let limit = kib.checked_mul(1024).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("Invalid bandwidth limit {}: at most {} KiB/s are possible", v, u64::MAX / 1024))
+                })?;
+                // like rsync, a limit of 0 means there is none
+                Some(limit).filter(|&limit| limit > 0)
+            }
+            None => None
+        };
+
+        let min_size = args.value_of("min-size").map(parse_size).transpose()?;
+        let max_size = args.value_of("max-size").map(parse_size).transpose()?;
+        let now = SystemTime::now();
+        let newer_than = args.value_of("newer-than").map(|age| parse_age(age, now)).transpose()?;
+        let older_than = args.value_of("older-than").map(|age| parse_age(age, now)).transpose()?;
+        let min_free = args.value_of("min-free").map(parse_size).transpose()?;
+        let io_buffer_size = match args.value_of("io-buffer-size") {
+            Some(size) => match parse_size(size)? {
+                0 => return Err(Error::new(ErrorKind::InvalidInput, "--io-buffer-size must not be 0")),
+                size if size > MAX_IO_BUFFER_SIZE as u64 => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "Invalid I/O buffer size {}, at most {} bytes are possible", size, MAX_IO_BUFFER_SIZE))),
+                size => usize::try_from(size).map_err(convert_error)?,
+            },
+            None => DEFAULT_IO_BUFFER_SIZE,
+        };
+        let min_hole = match args.value_of("min-hole") {
+            Some(size) => match parse_size(size)? {
+                size if size > MAX_MIN_HOLE => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "Invalid minimum hole size {}, at most {} bytes are possible", size, MAX_MIN_HOLE))),
+                size => Some(size.max(1)),
+            },
+            None if args.is_present("sparse") => Some(DEFAULT_MIN_HOLE),
+            None => None,
+        };
+        let modify_window = match args.value_of("modify-window") {
+            Some(v) => Duration::from_secs(v.parse::<u64>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid modify window {}: {}", v, e))
+            })?),
+            None => Duration::from_secs(0)
+        };
+
+        let max_depth = match args.value_of("max-depth") {
+            Some(v) => Some(v.parse::<usize>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid maximum depth {}: {}", v, e))
+            })?),
+            None => None
+        };
+
+        // rules of the config file come first, so that those on the command line win
+        let mut rules = Vec::new();
+        for layer in args.file.iter().chain(std::iter::once(&args.command_line)) {
+            rules.extend(filter_rules(layer)?);
+        }
+        let role = args.value_of("role");
+        let role = match role {
+            Some("sender") => Some(ProcessRole::Sender),
+            Some("receiver") => Some(ProcessRole::Receiver),
+            Some("server") => Some(ProcessRole::Server),
+            _ => None
+        };
+
+        let receives = matches!(role, None | Some(ProcessRole::Receiver));
+        let limits = TransferLimits {
+            bytes: args.value_of("max-transfer").filter(|_| receives).map(parse_size).transpose()?,
+            files: match args.value_of("max-files").filter(|_| receives).map(str::parse::<u64>) {
+                None => None,
+                Some(Ok(max)) => Some(max),
+                Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "Invalid number of files {}: {}", args.value_of("max-files").unwrap(), e))),
+            },
+        };
+
+        let cfg = Configuration {
+            hash: HashSettings {
+                force_rebuild: args.is_present("rebuild manifest"),
+                mode: if args.value_of("hash-mode").unwrap() == "hash" {
+                    ManifestMode::Hash
+                } else {
+                    ManifestMode::TimestampTest
+                },
+                algorithm: if args.value_of("hash-algo").unwrap() == "blake3" {
+                    HashAlgorithm::Blake3
+                } else {
+                    HashAlgorithm::Sha256
+                },
+                threads: hash_threads,
+                rules,
+                exclude_paths: vec![],
+                min_size,
+                max_size,
+                newer_than,
+                older_than,
+                ignore_files: args.is_present("ignore-files"),
+                max_depth,
+                fail_on_special_files: args.is_present("error-on-special-files"),
+                modify_window,
+                ignore_times: args.is_present("ignore-times"),
+                one_file_system: args.is_present("one-file-system"),
+                hard_links: args.is_present("hard-links"),
+                perms: args.is_present("perms"),
+                checksum_key: checksum_seed.as_deref().map(ChecksumKey::from_seed),
+                copy_links: args.is_present("copy-links"),
+                io_buffer_size,
+            },
+            source,
+            target,
+            verbose: (role == Some(ProcessRole::Server) || role.is_none()) && args.is_present("verbose"),
+            stats: matches!(role, None | Some(ProcessRole::Receiver)) && args.is_present("stats"),
+            human_readable: matches!(role, None | Some(ProcessRole::Receiver)) && args.is_present("human-readable"),
+            itemize_changes: if matches!(role, None | Some(ProcessRole::Receiver)) { args.occurrences_of("itemize-changes") } else { 0 },
+            progress: role.is_none() && args.is_present("progress"),
+            events: role.is_none() && args.is_present("events"),
+            json_summary: role.is_none() && args.is_present("json-summary"),
+            list_only: role.is_none() && args.is_present("list-only"),
+            list_remote: role.is_none() && args.is_present("list-remote"),
+            write_manifest: args.value_of("write-manifest").filter(|_| role.is_none()).map(PathBuf::from),
+            read_manifest: args.value_of("read-manifest").filter(|_| role.is_none()).map(PathBuf::from),
+            plan: args.value_of("plan").filter(|_| role.is_none()).map(PathBuf::from),
+            apply_plan: args.value_of("apply-plan").filter(|_| role.is_none()).map(PathBuf::from),
+            benchmark: role.is_none() && args.is_present("benchmark"),
+            manifest_location: if args.is_present("manifest-cache") {
+                // clap counts the default value as present, so the conflict is checked here
+                if args.occurrences_of("manifest file") > 0 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "--manifest-cache and --manifest-file can't be used together"));
+                }
+                ManifestLocation::Cache
+            } else {
+                ManifestLocation::File(PathBuf::from(args.value_of("manifest file").unwrap()))
+            },
+            role,
+            server_port,
+            force_pipeline: args.is_present("force-pipeline"),
+            pipe_capacity,
+            auth_token: args.value_of("auth-token").map(String::from),
+            checksum_seed,
+            cache_limit,
+            max_connections,
+            bwlimit,
+            retries,
+            batch_size,
+            checksum: args.is_present("checksum"),
+            keep_going: args.is_present("keep-going"),
+            limits,
+            selection: match role {
+                None | Some(ProcessRole::Receiver) if args.is_present("ignore-existing") => Selection::Missing,
+                None | Some(ProcessRole::Receiver) if args.is_present("existing") => Selection::Existing,
+                _ => Selection::All,
+            },
+            stage_then_swap: matches!(role, None | Some(ProcessRole::Receiver)) && args.is_present("stage-then-swap"),
+            // a server applies it to pushes it receives
+            delay_updates: role != Some(ProcessRole::Sender) && args.is_present("delay-updates"),
+            prune_empty_dirs: matches!(role, None | Some(ProcessRole::Receiver)) && args.is_present("prune-empty-dirs"),
+            subpath: args.value_of("subpath").filter(|_| matches!(role, None | Some(ProcessRole::Receiver))).map(PathBuf::from),
+            append: if args.is_present("append-verify") {
+                Some(AppendMode::Verify)
+            } else if args.is_present("append") {
+                Some(AppendMode::Trust)
+            } else {
+                None
+            },
+            whole_file: if !matches!(role, None | Some(ProcessRole::Receiver)) {
+                None
+            } else if args.is_present("whole-file") {
+                Some(true)
+            } else if args.is_present("no-whole-file") {
+                Some(false)
+            } else {
+                None
+            },
+            strict_clock: args.is_present("strict-clock"),
+            xattrs: args.is_present("xattrs"),
+            crtimes: args.is_present("crtimes"),
+            accept_push: args.is_present("accept-push"),
+            shutdown_timeout,
+            refresh_interval,
+            io_timeout,
+            rsh,
+            remote_usync_path: String::from(args.value_of("remote-usync-path").unwrap()),
+            temp_dir: args.value_of("temp-dir").map(PathBuf::from),
+            min_free,
+            min_hole,
+            modules,
+            privilege: if args.is_present("super") {
+                PrivilegeMode::Super
+            } else if args.is_present("fake-super") {
+                PrivilegeMode::FakeSuper
+            } else {
+                PrivilegeMode::Auto
+            },
+        };
+
+        cfg.validate()?;
+        Ok(cfg)
+    }
+}
+#[cfg(test)]
+mod test_configuration {
+    use super::*;
+
+    #[test]
+    fn controller_requires_both_paths() {
+        let error = Configuration::parse_from(&["usync", "--source", "/a"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--target"), "{}", error);
+    }
+
+    #[test]
+    fn roles_require_their_path() -> Result<(), Error> {
+        assert!(Configuration::parse_from(&["usync", "--role", "server"]).is_err());
+        assert!(Configuration::parse_from(&["usync", "--role", "server", "--source", "server://host:1"]).is_err());
+        assert!(Configuration::parse_from(&["usync", "--role", "sender", "--target", "/b"]).is_err());
+
+        let cfg = Configuration::parse_from(&["usync", "--role", "receiver", "--target", "/b"])?;
+        assert_eq!(cfg.target(), &Local(PathBuf::from("/b")));
+        assert_eq!(cfg.manifest_location(), &ManifestLocation::File(PathBuf::from(".usync.manifest")));
+        assert_eq!(cfg.server_port(), 9715);
+        Ok(())
+    }
+
+    #[test]
+    fn bwlimit_in_kib() -> Result<(), Error> {
+        let bwlimit = |limit: &str| Configuration::parse_from(&["usync", "--bwlimit", limit, "--source", "/a", "--target", "/b"]);
+        assert_eq!(bwlimit("100")?.bwlimit(), Some(102_400));
+        assert_eq!(bwlimit("0")?.bwlimit(), None);
+        assert_eq!(bwlimit(&(u64::MAX / 1024).to_string())?.bwlimit(), Some(u64::MAX / 1024 * 1024));
+
+        let error = bwlimit(&(u64::MAX / 1024 + 1).to_string()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("at most"), "{}", error);
+        assert_eq!(bwlimit("fast").unwrap_err().kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_seed_read_from_input() -> Result<(), Error> {
+        let mut input = &b"seed\nprotocol"[..];
+        assert_eq!(read_seed_line(&mut input)?, "seed");
+        assert_eq!(input, b"protocol");
+        assert_eq!(read_seed_line(&b"seed"[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let error = Configuration::parse_from(&["usync", "--checksum-seed", "two\nlines", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_cache_replaces_manifest_file() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--manifest-cache", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.manifest_location(), &ManifestLocation::Cache);
+
+        assert!(Configuration::parse_from(&["usync", "--manifest-cache", "--manifest-file", "m", "--source", "/a", "--target", "/b"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stats_are_printed_by_controller() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--stats", "--source", "/a", "--target", "/b"])?;
+        assert!(cfg.stats());
+        assert!(!cfg.verbose());
+
+        // pushing to a remote target, the summary comes from the remote receiver
+        let cfg = Configuration::parse_from(&["usync", "--stats", "--role", "receiver", "--target", "/b"])?;
+        assert!(cfg.stats());
+        let cfg = Configuration::parse_from(&["usync", "--stats", "--role", "sender", "--source", "/a"])?;
+        assert!(!cfg.stats());
+        Ok(())
+    }
+
+    #[test]
+    fn io_buffer_size_accepts_suffixes() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.hash_settings().io_buffer_size(), DEFAULT_IO_BUFFER_SIZE);
+        let cfg = Configuration::parse_from(&["usync", "--io-buffer-size", "1M", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.hash_settings().io_buffer_size(), 1 << 20);
+        assert!(Configuration::parse_from(&["usync", "--io-buffer-size", "0", "--source", "/a", "--target", "/b"]).is_err());
+        assert!(Configuration::parse_from(&["usync", "--io-buffer-size", "64M", "--source", "/a", "--target", "/b"]).is_ok());
+        assert!(Configuration::parse_from(&["usync", "--io-buffer-size", "1G", "--source", "/a", "--target", "/b"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn min_hole_is_bounded() -> Result<(), Error> {
+        let min_hole = |size: &str| Configuration::parse_from(&["usync", "--sparse", "--min-hole", size, "--source", "/a", "--target", "/b"]);
+        assert_eq!(min_hole("4M")?.sparse(), Some(MAX_MIN_HOLE));
+        let error = min_hole("5M").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("at most"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn list_only_needs_local_source() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--list-only", "--source", "/a"])?;
+        assert!(cfg.list_only());
+
+        let error = Configuration::parse_from(&["usync", "--list-only", "--source", "remote://host:/a"]).unwrap_err();
+        assert!(error.to_string().contains("--list-only"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_needs_local_target() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--plan", "plan.jsonl", "--source", "remote://host:/a", "--target", "/b"])?;
+        assert_eq!(cfg.plan(), Some(Path::new("plan.jsonl")));
+        assert_eq!(cfg.apply_plan(), None);
+        let cfg = Configuration::parse_from(&["usync", "--apply-plan", "plan.jsonl", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.apply_plan(), Some(Path::new("plan.jsonl")));
+
+        let error = Configuration::parse_from(&["usync", "--plan", "-", "--source", "/a", "--target", "remote://host:/b"]).unwrap_err();
+        assert!(error.to_string().contains("local --target"), "{}", error);
+        let error = Configuration::parse_from(&["usync", "--plan", "-", "--checksum", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert!(error.to_string().contains("--checksum"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn benchmark_needs_no_paths() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--benchmark", "--hash-mode", "timestamp"])?;
+        assert!(cfg.benchmark());
+        assert!(cfg.default_source().is_none());
+
+        let local = cfg.with_local_roots(Path::new("/a"), Path::new("/b"));
+        assert!(!local.benchmark());
+        assert_eq!(local.source(), &Local(PathBuf::from("/a")));
+        assert_eq!(local.target(), &Local(PathBuf::from("/b")));
+        assert_eq!(local.hash_settings().manifest_mode(), ManifestMode::TimestampTest);
+
+        let error = Configuration::parse_from(&["usync", "--benchmark", "--source", "/a"]).unwrap_err();
+        assert!(error.to_string().contains("--benchmark"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn source_pattern_expands_to_matching_directories() -> Result<(), Error> {
+        let dir = tempfile::TempDir::new()?;
+        for site in &["a", "b"] {
+            std::fs::create_dir_all(dir.path().join(site).join("public"))?;
+        }
+        std::fs::create_dir(dir.path().join("c"))?;
+        std::fs::create_dir(dir.path().join("d"))?;
+        File::create(dir.path().join("d").join("public"))?;
+        let pattern = dir.path().join("*").join("public");
+        let pattern = pattern.to_str().unwrap();
+
+        let cfg = Configuration::parse_from(&["usync", "--source", pattern, "--target", "/t"])?;
+        let glob = cfg.source_glob().expect("pattern is recognized");
+        let roots: Vec<_> = cfg.expand_source_glob(glob)?.into_iter()
+            .map(|root| (root.source().clone(), root.target().clone()))
+            .collect();
+        assert_eq!(roots, vec![
+            (Local(dir.path().join("a/public")), Local(PathBuf::from("/t/a/public"))),
+            (Local(dir.path().join("b/public")), Local(PathBuf::from("/t/b/public"))),
+        ]);
+
+        let pattern = dir.path().join("*").join("private");
+        let cfg = Configuration::parse_from(&["usync", "--source", pattern.to_str().unwrap(), "--target", "/t"])?;
+        let error = cfg.expand_source_glob(cfg.source_glob().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        let error = Configuration::parse_from(&["usync", "--source", pattern.to_str().unwrap(), "--target", "remote://host:/t"]).unwrap_err();
+        assert!(error.to_string().contains("local --target"), "{}", error);
+        assert!(Configuration::parse_from(&["usync", "--source", "/a", "--target", "/t"])?.source_glob().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn list_remote_needs_remote_source() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--list-remote", "--source", "server://host:1234/module"])?;
+        assert!(cfg.list_remote());
+        assert!(Configuration::parse_from(&["usync", "--list-remote", "--source", "remote://host:/a"])?.list_remote());
+
+        let error = Configuration::parse_from(&["usync", "--list-remote", "--source", "/a"]).unwrap_err();
+        assert!(error.to_string().contains("--list-remote"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_times_needs_hash_mode() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--ignore-times", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.hash_settings().comparison(), Comparison::ContentOnly);
+        assert!(Configuration::parse_from(&["usync", "--size-and-hash-only", "--source", "/a", "--target", "/b"])?.hash_settings().ignore_times());
+
+        let error = Configuration::parse_from(&["usync", "--ignore-times", "--hash-mode", "timestamp", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert!(error.to_string().contains("--ignore-times"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn stage_then_swap_needs_own_target() -> Result<(), Error> {
+        assert!(Configuration::parse_from(&["usync", "--stage-then-swap", "--source", "/a", "--target", "/b"])?.stage_then_swap());
+        assert!(Configuration::parse_from(&["usync", "--stage-then-swap", "--source", "/a", "--target", "remote://host:/b"])?.stage_then_swap());
+
+        let error = Configuration::parse_from(&["usync", "--stage-then-swap", "--source", "/a", "--target", "server://host:1234"]).unwrap_err();
+        assert!(error.to_string().contains("--stage-then-swap"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn subpath_stays_within_source() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--subpath", "a/b", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.subpath(), Some(Path::new("a/b")));
+        assert!(Configuration::parse_from(&["usync", "--subpath", "a/b", "--role", "sender", "--source", "/a"])?.subpath().is_none());
+
+        for subpath in &["/a", "a/../../b"] {
+            let error = Configuration::parse_from(&["usync", "--subpath", subpath, "--source", "/a", "--target", "/b"]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+            assert!(error.to_string().contains("--subpath"), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn select_existing_or_missing_files() -> Result<(), Error> {
+        assert_eq!(Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?.selection(), Selection::All);
+        assert_eq!(Configuration::parse_from(&["usync", "--ignore-existing", "--source", "/a", "--target", "/b"])?.selection(), Selection::Missing);
+        assert_eq!(Configuration::parse_from(&["usync", "--existing", "--role", "receiver", "--target", "/b"])?.selection(), Selection::Existing);
+
+        let error = Configuration::parse_from(&["usync", "--existing", "--checksum", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--checksum"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_limits_reach_receiver() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--max-transfer", "2M", "--max-files", "10", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.transfer_limits(), TransferLimits { bytes: Some(2 * 1024 * 1024), files: Some(10) });
+        let cfg = Configuration::parse_from(&["usync", "--max-files", "10", "--role", "sender", "--source", "/a"])?;
+        assert_eq!(cfg.transfer_limits(), TransferLimits::default());
+
+        let error = Configuration::parse_from(&["usync", "--max-files", "many", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn append_verify_implies_append() -> Result<(), Error> {
+        assert_eq!(Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?.append(), None);
+        assert_eq!(Configuration::parse_from(&["usync", "--append", "--source", "/a", "--target", "/b"])?.append(), Some(AppendMode::Trust));
+        assert_eq!(Configuration::parse_from(&["usync", "--append", "--append-verify", "--source", "/a", "--target", "/b"])?.append(), Some(AppendMode::Verify));
+
+        let error = Configuration::parse_from(&["usync", "--append", "--stage-then-swap", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--append"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn whole_file_is_chosen_for_local_syncs() -> Result<(), Error> {
+        assert!(Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?.whole_file());
+        assert!(!Configuration::parse_from(&["usync", "--append", "--source", "/a", "--target", "/b"])?.whole_file());
+        assert!(!Configuration::parse_from(&["usync", "--no-whole-file", "--source", "/a", "--target", "/b"])?.whole_file());
+        assert!(!Configuration::parse_from(&["usync", "--source", "remote://host:/a", "--target", "/b"])?.whole_file());
+        assert!(Configuration::parse_from(&["usync", "-W", "--source", "remote://host:/a", "--target", "/b"])?.whole_file());
+        assert!(Configuration::parse_from(&["usync", "--whole-file", "--role", "receiver", "--target", "/b"])?.whole_file());
+
+        let error = Configuration::parse_from(&["usync", "--whole-file", "--append", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--whole-file"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn delay_updates_excludes_append() -> Result<(), Error> {
+        assert!(!Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?.delay_updates());
+        assert!(Configuration::parse_from(&["usync", "--delay-updates", "--source", "/a", "--target", "/b"])?.delay_updates());
+        assert!(Configuration::parse_from(&["usync", "--delay-updates", "--role", "receiver", "--target", "/b"])?.delay_updates());
+        assert!(!Configuration::parse_from(&["usync", "--delay-updates", "--role", "sender", "--source", "/a"])?.delay_updates());
+
+        let error = Configuration::parse_from(&["usync", "--delay-updates", "--append", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--delay-updates"), "{}", error);
+        let error = Configuration::parse_from(&["usync", "--delay-updates", "--temp-dir", "/tmp", "--source", "/a", "--target", "/b"]).unwrap_err();
+        assert!(error.to_string().contains("--temp-dir"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn human_readable_reaches_receiver() -> Result<(), Error> {
+        assert!(Configuration::parse_from(&["usync", "--human-readable", "--source", "/a", "--target", "/b"])?.human_readable());
+        assert!(Configuration::parse_from(&["usync", "--human-readable", "--role", "receiver", "--target", "/b"])?.human_readable());
+        assert!(!Configuration::parse_from(&["usync", "--human-readable", "--role", "sender", "--source", "/a"])?.human_readable());
+        Ok(())
+    }
+
+    #[test]
+    fn itemize_changes_counts_occurrences() -> Result<(), Error> {
+        assert_eq!(Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b"])?.itemize_changes(), 0);
+        assert_eq!(Configuration::parse_from(&["usync", "-i", "--source", "/a", "--target", "/b"])?.itemize_changes(), 1);
+        assert_eq!(Configuration::parse_from(&["usync", "-ii", "--role", "receiver", "--target", "/b"])?.itemize_changes(), 2);
+        assert_eq!(Configuration::parse_from(&["usync", "-i", "--role", "sender", "--source", "/a"])?.itemize_changes(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_files_need_local_source() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--write-manifest", "-", "--source", "/a"])?;
+        assert_eq!(cfg.write_manifest(), Some(Path::new("-")));
+
+        let cfg = Configuration::parse_from(&["usync", "--read-manifest", "m", "--source", "/a", "--target", "/b"])?;
+        assert_eq!(cfg.read_manifest(), Some(Path::new("m")));
+
+        let error = Configuration::parse_from(&["usync", "--read-manifest", "m", "--source", "server://host:1", "--target", "/b"]).unwrap_err();
+        assert!(error.to_string().contains("--read-manifest"), "{}", error);
+        assert!(Configuration::parse_from(&["usync", "--read-manifest", "m", "--source", "/a"]).is_err());
+        Ok(())
+    }
+
+    fn config_file(contents: &str) -> Result<tempfile::NamedTempFile, Error> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut file, contents.as_bytes())?;
+        Ok(file)
+    }
+
+    #[test]
+    fn config_file_below_command_line() -> Result<(), Error> {
+        let file = config_file(r#"
+            source = "/a"
+            target = "/b"
+            hash-mode = "timestamp"
+            server-port = 9000
+            verbose = true
+            stats = false
+            exclude = ["*.tmp", "*.bak"]
+            include = "keep.tmp"
+        "#)?;
+        let path = file.path().to_str().unwrap();
+
+        let cfg = Configuration::parse_from(&["usync", "--config", path])?;
+        assert_eq!(cfg.source(), &Local(PathBuf::from("/a")));
+        assert_eq!(cfg.hash_settings().manifest_mode(), ManifestMode::TimestampTest);
+        assert_eq!(cfg.server_port(), 9000);
+        assert!(cfg.verbose());
+        assert!(!cfg.stats());
+        assert!(cfg.hash_settings().is_excluded(Path::new("x.tmp")));
+        assert!(!cfg.hash_settings().is_excluded(Path::new("keep.tmp")));
+
+        let cfg = Configuration::parse_from(&["usync", "--config", path, "--hash-mode", "hash", "--target", "/c", "--exclude", "keep.tmp"])?;
+        assert_eq!(cfg.hash_settings().manifest_mode(), ManifestMode::Hash);
+        assert_eq!(cfg.target(), &Local(PathBuf::from("/c")));
+        assert_eq!(cfg.server_port(), 9000);
+        // the command line's rules come last, and win
+        assert!(cfg.hash_settings().is_excluded(Path::new("keep.tmp")));
+        assert!(cfg.hash_settings().is_excluded(Path::new("x.bak")));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_config_file() -> Result<(), Error> {
+        for (contents, message) in &[
+            ("no-such-option = true", "no-such-option"),
+            ("hash-mode = \"fast\"", "fast"),
+            ("verbose = 1.5", "verbose"),
+            ("config = \"other.toml\"", "config"),
+            ("source = ", "Invalid config file"),
+        ] {
+            let file = config_file(contents)?;
+            let error = Configuration::parse_from(&["usync", "--config", file.path().to_str().unwrap(), "--source", "/a", "--target", "/b"]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{}", error);
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+
+        let error = Configuration::parse_from(&["usync", "--config", "/no/such/file.toml"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn max_connections_must_be_positive() -> Result<(), Error> {
+        let cfg = Configuration::parse_from(&["usync", "--role", "server", "--source", "/a", "--max-connections", "8"])?;
+        assert_eq!(cfg.max_connections(), Some(8));
+        assert_eq!(Configuration::parse_from(&["usync", "--role", "server", "--source", "/a"])?.max_connections(), None);
+        assert!(Configuration::parse_from(&["usync", "--role", "server", "--source", "/a", "--max-connections", "0"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_port() {
+        let error = Configuration::parse_from(&["usync", "--source", "/a", "--target", "/b", "--server-port", "99999"]).unwrap_err();
+        assert!(error.to_string().contains("Invalid server port"), "{}", error);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+
+/// Failures of usync itself, as opposed to those of the filesystem or the connection. They are
+/// passed around as `io::Error` like all others, carrying the `UsyncError` inside, see
+/// `UsyncError::of`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsyncError {
+    /// A local path is required, but the given one is remote (as described)
+    NonLocalPath(String),
+    /// A stored or given manifest can't be used for the tree, or with the configuration
+    ManifestValidation(String),
+    /// The peer sent something the protocol doesn't allow, or is configured incompatibly
+    Protocol(String),
+    /// The peer speaks another version of the protocol, or a manifest is in another format
+    VersionMismatch(String),
+    /// The peer did not accept the session, or was not accepted
+    Auth(String),
+    /// A file's content does not match the hash it is expected to have
+    Verification(String),
+}
+
+impl UsyncError {
+    /// The `ErrorKind` the `io::Error` carrying this error has.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            UsyncError::NonLocalPath(_) | UsyncError::ManifestValidation(_) => ErrorKind::Other,
+            UsyncError::Protocol(_) | UsyncError::VersionMismatch(_) | UsyncError::Verification(_) => ErrorKind::InvalidData,
+            UsyncError::Auth(_) => ErrorKind::PermissionDenied,
+        }
+    }
+
+    /// The `UsyncError` `error` carries, if it is one.
+    pub fn of(error: &Error) -> Option<&UsyncError> {
+        error.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl Display for UsyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsyncError::NonLocalPath(path) => write!(f, "Non-local path where local context is required: {}", path),
+            UsyncError::ManifestValidation(message) |
+            UsyncError::Protocol(message) |
+            UsyncError::VersionMismatch(message) |
+            UsyncError::Auth(message) |
+            UsyncError::Verification(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for UsyncError {}
+
+impl From<UsyncError> for Error {
+    fn from(error: UsyncError) -> Error {
+        Error::new(error.kind(), error)
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    #[test]
+    fn survives_as_io_error() {
+        let error = Error::from(UsyncError::Auth(String::from("Server rejected the authentication token")));
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "Server rejected the authentication token");
+        assert!(matches!(UsyncError::of(&error), Some(UsyncError::Auth(_))));
+
+        let error = Error::from(UsyncError::NonLocalPath(String::from("remote://host:/a")));
+        assert_eq!(error.to_string(), "Non-local path where local context is required: remote://host:/a");
+        assert!(UsyncError::of(&Error::new(ErrorKind::Other, "plain message")).is_none());
+    }
+}
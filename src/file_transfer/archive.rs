@@ -0,0 +1,245 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Take};
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, warn};
+use tar::EntryType;
+
+use crate::config::{HashSettings, PrivilegeMode};
+use crate::tree::{ListedFile, Manifest};
+use crate::util::Timestamp;
+use super::{FileAccess, FileStat};
+use super::ownership::Ownership;
+use super::xattrs::ExtendedAttribute;
+
+/// A regular file stored in the archive
+#[derive(Clone)]
+struct Member {
+    /// Where its contents start in the archive
+    position: u64,
+    stat: FileStat,
+    /// Permission bits, as `file_mode` gives them for a file on disk
+    mode: u32,
+}
+
+/// Serves the regular files of an uncompressed tar archive, so that it can be the source of a
+/// sync without being unpacked. The archive itself takes the place of the root directory: a
+/// member is accessed as the archive's path joined with the member's path.
+pub struct TarFileAccess {
+    archive: PathBuf,
+    members: HashMap<PathBuf, Member>,
+    directories: Vec<PathBuf>,
+}
+
+impl TarFileAccess {
+    /// Reads the index of the archive at `archive`. Hard links are served with the contents of
+    /// the member they link to, symlinks, devices and other special members are skipped, like
+    /// they are in a directory tree. Of several members with the same path, the last one
+    /// counts, as it would when extracting the archive.
+    pub fn open<P: AsRef<Path>>(archive: P) -> Result<TarFileAccess> {
+        let archive = archive.as_ref().to_path_buf();
+        let mut members = HashMap::new();
+        let mut directories = Vec::new();
+        let mut tar = tar::Archive::new(File::open(&archive)?);
+
+        for entry in tar.entries_with_seek()? {
+            let entry = entry?;
+            let path = member_path(&entry.path()?)?;
+            let header = entry.header();
+            match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => {}
+                EntryType::Directory => {
+                    if !path.as_os_str().is_empty() {
+                        directories.push(path);
+                    }
+                    continue;
+                }
+                EntryType::Link => {
+                    let target = entry.link_name()?.map(|name| member_path(&name)).transpose()?;
+                    match target.and_then(|target| members.get(&target).cloned()) {
+                        Some(member) => {
+                            members.insert(path, member);
+                        }
+                        None => warn!("Skipping {} in {}, the file it links to is not in the archive before it", path.to_string_lossy(), archive.to_string_lossy()),
+                    }
+                    continue;
+                }
+                other => {
+                    debug!("Skipping {} in {}, it is of type {:?}", path.to_string_lossy(), archive.to_string_lossy(), other);
+                    continue;
+                }
+            }
+
+            // some archivers leave the owner fields empty
+            let stat = FileStat {
+                size: entry.size(),
+                modified: Timestamp::from_unix_time(header.mtime()? as i64, 0),
+                owner: Ownership { uid: header.uid().unwrap_or(0) as u32, gid: header.gid().unwrap_or(0) as u32 },
+                created: None,
+            };
+            let mode = header.mode().unwrap_or(0o644) & 0o7777;
+            members.insert(path, Member { position: entry.raw_file_position(), stat, mode });
+        }
+
+        Ok(TarFileAccess { archive, members, directories })
+    }
+
+    /// The manifest of the files in the archive, see `Manifest::create_from_files`.
+    pub fn manifest(&self, settings: &HashSettings) -> Result<Manifest> {
+        let files: Vec<ListedFile> = self.members.iter()
+            .map(|(path, member)| ListedFile {
+                path: path.clone(),
+                size: member.stat.size,
+                modification_time: member.stat.modified,
+                mode: member.mode,
+            })
+            .collect();
+
+        Manifest::create_from_files(&files, &self.directories, settings, |path| self.read(&self.archive.join(path), 0))
+    }
+
+    fn member(&self, path: &Path) -> Result<&Member> {
+        let relative = path.strip_prefix(&self.archive).unwrap_or(path);
+        self.members.get(relative).ok_or_else(|| Error::new(ErrorKind::NotFound, format!(
+            "{} is not a file in {}", relative.to_string_lossy(), self.archive.to_string_lossy())))
+    }
+}
+
+/// The path of a member relative to the archive root, without any leading `./`. Members that
+/// would be extracted outside of it are refused.
+fn member_path(path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!(
+                "Archive member {} lies outside of the archive root", path.to_string_lossy()))),
+        }
+    }
+    Ok(relative)
+}
+
+impl FileAccess for TarFileAccess {
+    type Read = Take<File>;
+
+    fn stat(&self, path: &Path, _privilege: PrivilegeMode) -> Result<FileStat> {
+        Ok(self.member(path)?.stat)
+    }
+
+    fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+        let member = self.member(path)?;
+        let offset = min(offset, member.stat.size);
+        let mut file = File::open(&self.archive)?;
+        file.seek(SeekFrom::Start(member.position + offset))?;
+        Ok(file.take(member.stat.size - offset))
+    }
+
+    /// Archive members have no extended attributes of their own.
+    fn xattrs(&self, _path: &Path) -> Result<Vec<ExtendedAttribute>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test_archive {
+    use super::*;
+    use crate::config::test_support;
+    use tempfile::TempDir;
+
+    /// Writes an archive of `files`, with their path, contents and modification time.
+    fn archive(dir: &TempDir, files: &[(&str, &[u8], u64)]) -> Result<PathBuf> {
+        let path = dir.path().join("source.tar");
+        let mut builder = tar::Builder::new(File::create(&path)?);
+        for (name, contents, mtime) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mtime(*mtime);
+            header.set_mode(0o644);
+            header.set_entry_type(EntryType::Regular);
+            builder.append_data(&mut header, name, *contents)?;
+        }
+        builder.finish()?;
+        Ok(path)
+    }
+
+    #[test]
+    fn serves_members() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = archive(&dir, &[("./a.txt", b"hello", 1000), ("sub/b.txt", b"world!", 2000)])?;
+        let access = TarFileAccess::open(&path)?;
+
+        let stat = access.stat(&path.join("sub/b.txt"), PrivilegeMode::Auto)?;
+        assert_eq!(stat.size, 6);
+        assert_eq!(stat.modified, Timestamp::from_unix_time(2000, 0));
+
+        let mut contents = String::new();
+        access.read(&path.join("a.txt"), 1)?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "ello");
+        assert_eq!(access.stat(&path.join("missing.txt"), PrivilegeMode::Auto).unwrap_err().kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_matches_unpacked_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = archive(&dir, &[("a.txt", b"hello", 1000), ("sub/b.txt", b"world!", 2000), ("sub/c.log", b"", 3000)])?;
+        let settings = test_support::default_settings().with_exclude_pattern(glob::Pattern::new("*.log").unwrap());
+        let manifest = TarFileAccess::open(&path)?.manifest(&settings)?;
+
+        let unpacked = TempDir::new()?;
+        std::fs::create_dir(unpacked.path().join("sub"))?;
+        std::fs::write(unpacked.path().join("a.txt"), b"hello")?;
+        std::fs::write(unpacked.path().join("sub/b.txt"), b"world!")?;
+        let files = manifest.files();
+        let expected = Manifest::create_ephemeral(unpacked.path(), &settings)?;
+
+        assert_eq!(files.iter().map(|file| (&file.path, file.size, file.hash)).collect::<Vec<_>>(),
+                   expected.files().iter().map(|file| (&file.path, file.size, file.hash)).collect::<Vec<_>>());
+        assert_eq!(files[1].modification_time, Timestamp::from_unix_time(2000, 0));
+        assert!(expected.mismatches(&manifest).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn hard_links_and_empty_directories() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("source.tar");
+        let mut builder = tar::Builder::new(File::create(&path)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mtime(1000);
+        builder.append_data(&mut header, "a.txt", &b"hello"[..])?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "sub/linked.txt", "a.txt")?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, "empty/", std::io::empty())?;
+        builder.finish()?;
+
+        let access = TarFileAccess::open(&path)?;
+        let mut contents = String::new();
+        access.read(&path.join("sub/linked.txt"), 0)?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "hello");
+
+        let manifest = access.manifest(&test_support::default_settings())?;
+        assert_eq!(manifest.files().iter().map(|file| file.path.clone()).collect::<Vec<_>>(),
+                   vec![PathBuf::from("a.txt"), PathBuf::from("sub/linked.txt")]);
+        let mut chunks = 0;
+        manifest.chunks(&mut |_| { chunks += 1; Ok(()) })?;
+        assert_eq!(chunks, 3, "root, empty and sub");
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_members_outside_root() {
+        assert_eq!(member_path(Path::new("./a/b")).unwrap(), PathBuf::from("a/b"));
+        assert!(member_path(Path::new("../a")).is_err());
+        assert!(member_path(Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::Metadata;
+use std::io::Result;
+use std::path::Path;
+
+use log::{debug, warn};
+
+use crate::util::Timestamp;
+
+/// The creation time of the file `meta` describes, transferred with `--crtimes`. `None` where
+/// the platform or filesystem doesn't record it.
+pub fn read(meta: &Metadata) -> Option<Timestamp> {
+    meta.created().ok().map(Timestamp::from)
+}
+
+/// Sets creation times on received files. Where that isn't possible (Linux has no call for
+/// it), files keep the creation time they got when received, warning once per applier.
+#[derive(Default)]
+pub struct CrtimeApplier {
+    warned: bool,
+}
+
+impl CrtimeApplier {
+    pub fn new() -> CrtimeApplier {
+        CrtimeApplier::default()
+    }
+
+    /// Sets the creation time of `path` to `created`, if the source recorded one.
+    pub fn apply(&mut self, path: &Path, created: Option<Timestamp>) {
+        let created = match created {
+            Some(created) => created,
+            None => return,
+        };
+        if let Err(e) = set(path, created) {
+            if !self.warned {
+                warn!("Could not set the creation time of {}: {} (further failures are only logged at debug level)",
+                      path.to_string_lossy(), e);
+                self.warned = true;
+            } else {
+                debug!("Could not set the creation time of {}: {}", path.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set(path: &Path, created: Timestamp) -> Result<()> {
+    use std::ffi::CString;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut list = libc::attrlist {
+        bitmapcount: libc::ATTR_BIT_MAP_COUNT,
+        reserved: 0,
+        commonattr: libc::ATTR_CMN_CRTIME,
+        volattr: 0,
+        dirattr: 0,
+        fileattr: 0,
+        forkattr: 0,
+    };
+    let mut time = libc::timespec { tv_sec: created.unix_seconds() as libc::time_t, tv_nsec: created.nanoseconds() as libc::c_long };
+    let list_ptr = &mut list as *mut libc::attrlist as *mut libc::c_void;
+    let buffer = &mut time as *mut libc::timespec as *mut libc::c_void;
+    if unsafe { libc::setattrlist(c_path.as_ptr(), list_ptr, buffer, std::mem::size_of::<libc::timespec>(), 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set(_path: &Path, _created: Timestamp) -> Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "creation times can't be set on this platform"))
+}
+
+#[cfg(test)]
+mod test_crtimes {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn missing_creation_time_is_left_alone() -> Result<()> {
+        let target = NamedTempFile::new()?;
+        let mut applier = CrtimeApplier::new();
+        applier.apply(target.path(), None);
+        assert!(!applier.warned);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn apply_sets_creation_time() -> Result<()> {
+        let target = NamedTempFile::new()?;
+        let created = Timestamp::from_unix_time(1_000_000_000, 0);
+        let mut applier = CrtimeApplier::new();
+        applier.apply(target.path(), Some(created));
+        assert!(!applier.warned);
+        assert_eq!(read(&target.path().metadata()?), Some(created));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn apply_warns_where_unsupported() -> Result<()> {
+        let target = NamedTempFile::new()?;
+        let mut applier = CrtimeApplier::new();
+        applier.apply(target.path(), Some(Timestamp::from_unix_time(1_000_000_000, 0)));
+        assert!(applier.warned);
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use tempfile::{TempDir, TempPath};
+
+use super::{link_below, move_into_place, set_mode};
+
+/// A received file waiting to be moved to its target.
+pub enum Staged {
+    /// A tempfile, removed if it is dropped before being moved into place
+    Temporary(TempPath),
+    /// A completed partial transfer, left in place if dropped so that a later sync finds it
+    Partial(PathBuf),
+}
+
+impl Staged {
+    pub fn path(&self) -> &Path {
+        match self {
+            Staged::Temporary(path) => path,
+            Staged::Partial(path) => path,
+        }
+    }
+
+    /// Replaces `target` with the staged file.
+    pub fn move_to(self, target: &Path) -> Result<()> {
+        move_into_place(self.path(), target)?;
+        if let Staged::Temporary(path) = self {
+            // it's gone, nothing left to remove
+            let _ = path.keep();
+        }
+        Ok(())
+    }
+}
+
+/// A change to the target held back by `DelayedUpdates`. Paths are relative to the root.
+enum Update {
+    Replace { path: PathBuf, staged: Staged },
+    Link { existing: PathBuf, path: PathBuf },
+}
+
+/// Received files held back from the target until the whole transfer went through, see
+/// `--delay-updates`. `apply` moves them into place in one pass at the end, keeping the time
+/// the target holds a mix of old and new files short, and puts the previous files back if one
+/// of them fails. If it is dropped before, the staged files are discarded and the target keeps
+/// what it had.
+pub struct DelayedUpdates {
+    root: PathBuf,
+    pending: Vec<Update>,
+}
+
+impl DelayedUpdates {
+    pub fn new(root: &Path) -> DelayedUpdates {
+        DelayedUpdates { root: root.to_owned(), pending: Vec::new() }
+    }
+
+    /// Holds back replacing `path` with the `staged` file.
+    pub fn replace(&mut self, path: &Path, staged: Staged) {
+        self.pending.push(Update::Replace { path: path.to_owned(), staged });
+    }
+
+    /// Holds back making `path` a hard link to `existing`, which may be held back itself.
+    pub fn link(&mut self, existing: &Path, path: &Path) {
+        self.pending.push(Update::Link { existing: existing.to_owned(), path: path.to_owned() });
+    }
+
+    /// Sets the permissions of the file held back to replace `path`. Returns false if there
+    /// is none.
+    pub fn set_permissions(&self, path: &Path, mode: u32) -> Result<bool> {
+        // the file was usually the last one received
+        let staged = self.pending.iter().rev().find_map(|update| match update {
+            Update::Replace { path: replaced, staged } if replaced == path => Some(staged),
+            _ => None,
+        });
+        match staged {
+            Some(staged) => set_mode(staged.path(), mode).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Applies the held back updates in the order they were made, returning how many. The
+    /// files they replace are kept as hard links until all of them went through. If one fails,
+    /// those applied before it are undone and the rest are discarded, so the target keeps what
+    /// it had.
+    pub fn apply(&mut self) -> Result<usize> {
+        let count = self.pending.len();
+        let mut backups = Backups::default();
+        for update in std::mem::take(&mut self.pending) {
+            let path = match &update {
+                Update::Replace { path, .. } | Update::Link { path, .. } => path.clone(),
+            };
+            let applied = backups.keep(&self.root, &path).and_then(|()| match update {
+                Update::Replace { path, staged } => staged.move_to(&self.root.join(path)),
+                Update::Link { existing, path } => link_below(&self.root, &existing, &path),
+            });
+            if let Err(e) = applied {
+                backups.restore(&self.root);
+                return Err(e);
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// What `DelayedUpdates::apply` replaced so far, to put it back if a later update fails.
+#[derive(Default)]
+struct Backups {
+    /// Each replaced path with the hard link to its previous file, `None` if it had none
+    replaced: Vec<(PathBuf, Option<PathBuf>)>,
+    /// A directory for the links next to each directory with replaced files, on the same
+    /// filesystem. Removed with the links once dropped.
+    dirs: HashMap<PathBuf, TempDir>,
+}
+
+impl Backups {
+    /// Links the file at `path` (relative to `root`) into a backup directory, if there is one.
+    fn keep(&mut self, root: &Path, path: &Path) -> Result<()> {
+        let target = root.join(path);
+        if std::fs::symlink_metadata(&target).is_err() {
+            self.replaced.push((target, None));
+            return Ok(());
+        }
+        let parent = target.parent().unwrap().to_owned();
+        let dir = match self.dirs.get(&parent) {
+            Some(dir) => dir,
+            None => {
+                let dir = tempfile::Builder::new().prefix(".usync-backup").tempdir_in(&parent)?;
+                self.dirs.entry(parent).or_insert(dir)
+            }
+        };
+        let backup = dir.path().join(self.replaced.len().to_string());
+        std::fs::hard_link(&target, &backup)?;
+        self.replaced.push((target, Some(backup)));
+        Ok(())
+    }
+
+    /// Undoes the updates in reverse order, restoring what each path had before.
+    fn restore(&mut self, root: &Path) {
+        for (target, backup) in self.replaced.drain(..).rev() {
+            let restored = match backup {
+                Some(backup) => std::fs::rename(&backup, &target),
+                None => match std::fs::remove_file(&target) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                    removed => removed,
+                },
+            };
+            match restored {
+                Ok(()) => debug!("Restored {}", target.strip_prefix(root).unwrap_or(&target).to_string_lossy()),
+                Err(e) => warn!("Could not restore {} after a failed update: {}", target.to_string_lossy(), e),
+            }
+        }
+    }
+}
+
+impl Drop for DelayedUpdates {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            warn!("Discarding {} updates held back for the end of the transfer, their targets are left as they were", self.pending.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_delayed {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn staged(root: &Path, contents: &str) -> Result<Staged> {
+        let file = NamedTempFile::new_in(root)?;
+        write(file.path(), contents)?;
+        Ok(Staged::Temporary(file.into_temp_path()))
+    }
+
+    #[test]
+    fn updates_wait_for_apply() -> Result<()> {
+        let root = TempDir::new()?;
+        write(root.path().join("a"), "old")?;
+        let mut delayed = DelayedUpdates::new(root.path());
+        delayed.replace(Path::new("a"), staged(root.path(), "new")?);
+        delayed.link(Path::new("a"), Path::new("b"));
+        assert_eq!(read_to_string(root.path().join("a"))?, "old");
+        assert!(!root.path().join("b").exists());
+
+        assert_eq!(delayed.apply()?, 2);
+        assert_eq!(read_to_string(root.path().join("a"))?, "new");
+        assert_eq!(read_to_string(root.path().join("b"))?, "new");
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_apply_restores_target() -> Result<()> {
+        let root = TempDir::new()?;
+        write(root.path().join("a"), "old")?;
+        let mut delayed = DelayedUpdates::new(root.path());
+        delayed.replace(Path::new("a"), staged(root.path(), "new")?);
+        delayed.replace(Path::new("b"), staged(root.path(), "new")?);
+        delayed.link(Path::new("missing"), Path::new("c"));
+        delayed.replace(Path::new("d"), staged(root.path(), "new")?);
+
+        assert!(delayed.apply().is_err());
+        assert_eq!(read_to_string(root.path().join("a"))?, "old");
+        assert!(!root.path().join("b").exists());
+        assert!(!root.path().join("d").exists());
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 1, "staged files and backups are gone");
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_updates_leave_target_alone() -> Result<()> {
+        let root = TempDir::new()?;
+        write(root.path().join("a"), "old")?;
+        let partial = root.path().join("b.usync-partial");
+        write(&partial, "resumable")?;
+        let mut delayed = DelayedUpdates::new(root.path());
+        delayed.replace(Path::new("a"), staged(root.path(), "new")?);
+        delayed.replace(Path::new("b"), Staged::Partial(partial.clone()));
+        drop(delayed);
+
+        assert_eq!(read_to_string(root.path().join("a"))?, "old");
+        assert!(partial.exists());
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 2);
+        Ok(())
+    }
+}
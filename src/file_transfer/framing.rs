@@ -0,0 +1,250 @@
+use std::io::{Read, Result, Write, Error, ErrorKind};
+use std::fmt::{Display, Formatter};
+
+/// Largest frame accepted from a peer, which also bounds the size of a single message.
+pub const MAX_FRAME_SIZE: usize = 1 << 26;
+
+/// File contents are sent in frames of up to this many bytes.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Checksum of a frame, covering the length as well, so that a corrupted length is caught
+/// rather than throwing off the rest of the stream unnoticed.
+fn checksum(length: [u8; 4], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&length);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// A frame failing its checks. It leaves the stream in an unknown state, so unlike a file that
+/// hashes wrong (`InvalidData`), the session can't go on after it.
+#[derive(Debug)]
+struct Corrupted(String);
+
+impl Display for Corrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Corrupted data from peer: {}", self.0)
+    }
+}
+
+impl std::error::Error for Corrupted {}
+
+fn corrupted(message: String) -> Error {
+    Error::new(ErrorKind::Other, Corrupted(message))
+}
+
+/// True if `error` comes from a frame that failed its checks, or from reading on after one.
+pub fn is_corrupted(error: &Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Corrupted>())
+}
+
+/// Writes `payload` as one frame: its length and checksum, followed by the payload itself.
+pub fn write_frame<W: Write>(output: &mut W, payload: &[u8]) -> Result<()> {
+    let length = (payload.len() as u32).to_le_bytes();
+    output.write_all(&length)?;
+    output.write_all(&checksum(length, payload).to_le_bytes())?;
+    output.write_all(payload)
+}
+
+/// Reads the next frame into `buffer`, replacing its contents, and verifies its checksum.
+pub fn read_frame<R: Read>(input: &mut R, buffer: &mut Vec<u8>) -> Result<()> {
+    let mut header = [0u8; 8];
+    input.read_exact(&mut header)?;
+    let length = [header[0], header[1], header[2], header[3]];
+    let expected = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let size = u32::from_le_bytes(length) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(corrupted(format!("frame of {} bytes exceeds the limit of {} bytes", size, MAX_FRAME_SIZE)));
+    }
+
+    buffer.resize(size, 0);
+    input.read_exact(buffer)?;
+    let actual = checksum(length, buffer);
+    if actual != expected {
+        return Err(corrupted(format!("checksum {:08x} of a {} byte frame does not match {:08x}", actual, size, expected)));
+    }
+    Ok(())
+}
+
+/// Writer sending everything written to it as a sequence of frames, ended by an empty frame
+/// on `finish`.
+pub struct FramedWrite<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> FramedWrite<W> {
+    pub fn new(inner: W) -> FramedWrite<W> {
+        FramedWrite { inner, buffer: Vec::with_capacity(CHUNK_SIZE) }
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            write_frame(&mut self.inner, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Sends what is still buffered and marks the end of the data.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_pending()?;
+        write_frame(&mut self.inner, &[])?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FramedWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let take = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_pending()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+/// Reader for data sent by a `FramedWrite`, reaching its end at the empty frame.
+pub struct FramedRead<R: Read> {
+    inner: R,
+    frame: Vec<u8>,
+    offset: usize,
+    ended: bool,
+    failed: bool,
+}
+
+impl<R: Read> FramedRead<R> {
+    pub fn new(inner: R) -> FramedRead<R> {
+        FramedRead { inner, frame: Vec::new(), offset: 0, ended: false, failed: false }
+    }
+
+    /// Skips whatever the reader has not consumed, up to the end of the data, so that the
+    /// next message can be read. Fails if reading the data failed before, since the stream
+    /// can't be trusted to be in sync any more.
+    pub fn finish(mut self) -> Result<()> {
+        if self.failed {
+            return Err(corrupted(String::from("framed data could not be read to its end")));
+        }
+        let mut discard = [0u8; 4096];
+        while self.read(&mut discard)? > 0 {}
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FramedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.offset == self.frame.len() {
+            if self.ended || buf.is_empty() {
+                return Ok(0);
+            }
+            if let Err(e) = read_frame(&mut self.inner, &mut self.frame) {
+                self.failed = true;
+                return Err(e);
+            }
+            self.offset = 0;
+            self.ended = self.frame.is_empty();
+        }
+
+        let remaining = &self.frame[self.offset..];
+        let take = buf.len().min(remaining.len());
+        buf[..take].copy_from_slice(&remaining[..take]);
+        self.offset += take;
+        Ok(take)
+    }
+}
+
+#[cfg(test)]
+mod test_framing {
+    use super::*;
+
+    fn framed(data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = FramedWrite::new(Vec::new());
+        output.write_all(data)?;
+        output.finish()
+    }
+
+    #[test]
+    fn frame_round_trip() -> Result<()> {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"hello")?;
+        write_frame(&mut stream, b"")?;
+
+        let mut input = stream.as_slice();
+        let mut buffer = Vec::new();
+        read_frame(&mut input, &mut buffer)?;
+        assert_eq!(buffer, b"hello");
+        read_frame(&mut input, &mut buffer)?;
+        assert!(buffer.is_empty());
+        assert!(input.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn flipped_bit_is_detected() -> Result<()> {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"hello")?;
+
+        for bit in 0..stream.len() * 8 {
+            let mut damaged = stream.clone();
+            damaged[bit / 8] ^= 1 << (bit % 8);
+            let error = read_frame(&mut damaged.as_slice(), &mut Vec::new()).unwrap_err();
+            assert_ne!(error.kind(), ErrorKind::InvalidData, "bit {}", bit);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut stream = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes().to_vec();
+        stream.extend_from_slice(&[0u8; 4]);
+
+        let error = read_frame(&mut stream.as_slice(), &mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("exceeds the limit"), "{}", error);
+    }
+
+    #[test]
+    fn data_spans_several_frames() -> Result<()> {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 17).map(|i| i as u8).collect();
+        let mut stream = framed(&data)?;
+        stream.extend_from_slice(b"next message");
+
+        let mut input = stream.as_slice();
+        let mut received = Vec::new();
+        FramedRead::new(&mut input).read_to_end(&mut received)?;
+        assert_eq!(received, data);
+        assert_eq!(input, b"next message");
+        Ok(())
+    }
+
+    #[test]
+    fn finish_skips_unread_data() -> Result<()> {
+        let mut stream = framed(b"0123456789")?;
+        stream.extend_from_slice(b"next message");
+
+        let mut input = stream.as_slice();
+        let mut reader = FramedRead::new(&mut input);
+        let mut head = [0u8; 4];
+        reader.read_exact(&mut head)?;
+        reader.finish()?;
+        assert_eq!(&head, b"0123");
+        assert_eq!(input, b"next message");
+        Ok(())
+    }
+
+    #[test]
+    fn finish_fails_after_corruption() -> Result<()> {
+        let mut stream = framed(b"0123456789")?;
+        stream[9] ^= 1;
+
+        let mut reader = FramedRead::new(stream.as_slice());
+        assert!(is_corrupted(&reader.read(&mut [0u8; 4]).unwrap_err()));
+        assert!(is_corrupted(&reader.finish().unwrap_err()));
+        Ok(())
+    }
+}
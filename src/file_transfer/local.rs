@@ -1,22 +1,104 @@
 use super::*;
+use super::ownership::{Ownership, OwnershipApplier};
+use super::xattrs::{self, XattrApplier};
+use super::crtimes::{self, CrtimeApplier};
+use super::space::SpaceGuard;
+use crate::config::{AppendMode, PrivilegeMode};
+use crate::util::Timestamp;
 use filetime::{set_file_mtime, FileTime};
+use log::debug;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read};
 
 pub struct LocalTransmitter<'a> {
     source: &'a Path,
     target: &'a Path,
+    privilege: PrivilegeMode,
+    ownership: OwnershipApplier,
+    xattrs: Option<XattrApplier>,
+    crtimes: Option<CrtimeApplier>,
+    space: Option<SpaceGuard>,
+    append: Option<AppendMode>,
+    unshare: bool,
+    temp_dir: Option<PathBuf>,
 }
 
 impl LocalTransmitter<'_> {
-    pub fn new<'a>(from: &'a Path, to: &'a Path) -> LocalTransmitter<'a> {
+    pub fn new<'a>(from: &'a Path, to: &'a Path, privilege: PrivilegeMode) -> LocalTransmitter<'a> {
         LocalTransmitter {
             source: from,
             target: to,
+            privilege,
+            ownership: OwnershipApplier::new(privilege),
+            xattrs: None,
+            crtimes: None,
+            space: None,
+            append: None,
+            unshare: false,
+            temp_dir: None,
         }
     }
+
+    /// Copies files into `temp_dir` (if given) instead of next to their target, before they
+    /// are moved into place.
+    pub fn with_temp_dir(mut self, temp_dir: Option<&Path>) -> Self {
+        self.temp_dir = temp_dir.map(Path::to_owned);
+        self
+    }
+
+    /// Copies extended attributes along with the files.
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = if xattrs { Some(XattrApplier::new()) } else { None };
+        self
+    }
+
+    /// Copies creation times along with the files, where the platform allows setting them.
+    pub fn with_crtimes(mut self, crtimes: bool) -> Self {
+        self.crtimes = if crtimes { Some(CrtimeApplier::new()) } else { None };
+        self
+    }
+
+    /// Refuses to copy files that would leave less than `min_free` bytes on the target.
+    pub fn with_min_free(mut self, min_free: Option<u64>) -> Self {
+        self.space = min_free.map(SpaceGuard::new);
+        self
+    }
+
+    /// Appends to target files shorter than their source (if `append` is given) instead of
+    /// copying them whole, see `--append`.
+    pub fn with_append(mut self, append: Option<AppendMode>) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Copies target files that have other hard links before changing their permissions (if
+    /// `linked_target` is set), as with `--stage-then-swap` those links are the live target.
+    pub fn with_linked_target(mut self, linked_target: bool) -> Self {
+        self.unshare = linked_target;
+        self
+    }
+
+    /// How many bytes of `source` (which is `size` bytes long) the file at `target` holds
+    /// already, if it can be appended to.
+    fn appendable(&self, source: &Path, size: u64, target: &Path) -> Result<Option<u64>> {
+        let mode = match self.append {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        let offset = match target.symlink_metadata() {
+            Ok(meta) if meta.is_file() && meta.len() > 0 && meta.len() < size => meta.len(),
+            _ => return Ok(None),
+        };
+        if mode == AppendMode::Verify && !same_prefix(source, target, offset)? {
+            debug!("{} does not start like its source, copying it whole", target.to_string_lossy());
+            return Ok(None);
+        }
+        Ok(Some(offset))
+    }
 }
 
 impl Transmitter for LocalTransmitter<'_> {
-    fn transmit(&mut self, path: &Path) -> Result<()> {
+    fn transmit(&mut self, path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
         let source = self.source.join(path);
         let target = self.target.join(path);
         let parent = target.parent().unwrap();
@@ -25,9 +107,218 @@ impl Transmitter for LocalTransmitter<'_> {
             create_dir_all(parent)?;
         }
 
-        std::fs::copy(&source, &target)?;
-        let time = source.metadata()?.modified()?;
-        set_file_mtime(&target, FileTime::from(time))?;
+        let meta = source.metadata()?;
+        let copied = match self.appendable(&source, meta.len(), &target)? {
+            Some(offset) => {
+                if let Some(guard) = &self.space {
+                    guard.check(self.target, meta.len() - offset)?;
+                }
+                append_tail(&source, &target, offset)?
+            }
+            None => {
+                if let Some(guard) = &self.space {
+                    guard.check(self.target, meta.len())?;
+                }
+                // copied aside and moved over the target, so that the target is never missing
+                // or half written, and other hard links to it keep their content
+                let copy = tempfile::NamedTempFile::new_in(self.temp_dir.as_deref().unwrap_or(parent))?.into_temp_path();
+                let copied = match clone_file(&source, &copy)? {
+                    Some(cloned) => cloned,
+                    None => std::fs::copy(&source, &copy)?,
+                };
+                move_into_place(&copy, &target)?;
+                // it's gone, nothing left to remove
+                let _ = copy.keep();
+                copied
+            }
+        };
+        set_file_mtime(&target, FileTime::from(Timestamp::modified(&meta)))?;
+        self.ownership.apply(&target, &Ownership::of(&source, &meta, self.privilege))?;
+        if let Some(applier) = self.xattrs.as_mut() {
+            applier.apply(&target, &xattrs::read(&source)?);
+        }
+        if let Some(applier) = self.crtimes.as_mut() {
+            applier.apply(&target, crtimes::read(&meta));
+        }
+        Ok(copied)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        create_dir_all(self.target.join(path))
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        link_below(self.target, existing, path)
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        set_mode_below(self.target, path, mode, self.unshare)
+    }
+}
+
+/// Whether the first `len` bytes of the files at `a` and `b` are the same.
+fn same_prefix(a: &Path, b: &Path, len: u64) -> Result<bool> {
+    let mut a = BufReader::new(File::open(a)?.take(len));
+    let mut b = BufReader::new(File::open(b)?.take(len));
+    loop {
+        let (read_a, read_b) = (a.fill_buf()?, b.fill_buf()?);
+        let common = read_a.len().min(read_b.len());
+        if common == 0 {
+            return Ok(read_a.is_empty() && read_b.is_empty());
+        }
+        if read_a[..common] != read_b[..common] {
+            return Ok(false);
+        }
+        a.consume(common);
+        b.consume(common);
+    }
+}
+
+/// Appends what `source` holds beyond its first `offset` bytes to `target`, which is written
+/// into (unlike a regular copy) and cut to `offset` bytes first. Returns the bytes appended.
+fn append_tail(source: &Path, target: &Path, offset: u64) -> Result<u64> {
+    let mut input = File::open(source)?;
+    input.seek(SeekFrom::Start(offset))?;
+    let mut output = OpenOptions::new().write(true).open(target)?;
+    output.set_len(offset)?;
+    output.seek(SeekFrom::End(0))?;
+    std::io::copy(&mut input, &mut output)
+}
+
+/// Tries to create `target` as a copy-on-write clone of `source`, which shares the already
+/// hashed data blocks instead of reading and writing the content again. Returns `None` if the
+/// filesystem does not support cloning, in which case the caller falls back to a regular copy.
+#[cfg(target_os = "linux")]
+fn clone_file(source: &Path, target: &Path) -> Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let src = File::open(source)?;
+    let dst = File::create(target)?;
+    let rc = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if rc != 0 {
+        return Ok(None);
+    }
+
+    let meta = src.metadata()?;
+    dst.set_permissions(meta.permissions())?;
+    Ok(Some(meta.len()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_source: &Path, _target: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test_local {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn transmit_copies_content_and_mtime() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::create_dir(source.path().join("dir"))?;
+        let file = source.path().join("dir/file.txt");
+        File::create(&file)?.write_all(b"some content")?;
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        set_file_mtime(&file, mtime)?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        let copied = transmitter.transmit(Path::new("dir/file.txt"), None)?;
+
+        let copy = target.path().join("dir/file.txt");
+        assert_eq!(copied, 12);
+        assert_eq!(std::fs::read(&copy)?, b"some content");
+        assert_eq!(FileTime::from_last_modification_time(&copy.metadata()?), mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_copies_through_temp_dir() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let temp = TempDir::new()?;
+        std::fs::write(source.path().join("file.txt"), b"new content")?;
+        std::fs::write(target.path().join("file.txt"), b"old content")?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto).with_temp_dir(Some(temp.path()));
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), None)?, 11);
+        assert_eq!(std::fs::read(target.path().join("file.txt"))?, b"new content");
+        assert_eq!(std::fs::read_dir(temp.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_copy_keeps_target() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        // a directory where a file is expected fails the copy once the target is prepared
+        std::fs::create_dir(source.path().join("file.txt"))?;
+        std::fs::write(target.path().join("file.txt"), b"old content")?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        assert!(transmitter.transmit(Path::new("file.txt"), None).is_err());
+        assert_eq!(std::fs::read(target.path().join("file.txt"))?, b"old content");
+        assert_eq!(std::fs::read_dir(target.path())?.count(), 1, "no copy is left behind");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_appends_to_shorter_file() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::write(source.path().join("log.txt"), b"first\nsecond\n")?;
+        std::fs::write(target.path().join("log.txt"), b"first\n")?;
+        std::fs::write(source.path().join("other.txt"), b"new content")?;
+        std::fs::write(target.path().join("other.txt"), b"old")?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto)
+            .with_append(Some(AppendMode::Trust));
+        assert_eq!(transmitter.transmit(Path::new("log.txt"), None)?, 7);
+        assert_eq!(std::fs::read(target.path().join("log.txt"))?, b"first\nsecond\n");
+        // trusted, even though the target does not hold the start of the source
+        assert_eq!(transmitter.transmit(Path::new("other.txt"), None)?, 8);
+        assert_eq!(std::fs::read(target.path().join("other.txt"))?, b"old content");
+
+        std::fs::write(target.path().join("other.txt"), b"old")?;
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto)
+            .with_append(Some(AppendMode::Verify));
+        assert_eq!(transmitter.transmit(Path::new("other.txt"), None)?, 11);
+        assert_eq!(std::fs::read(target.path().join("other.txt"))?, b"new content");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_leaves_other_links_alone() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::write(source.path().join("file.txt"), b"new")?;
+        std::fs::write(target.path().join("file.txt"), b"old")?;
+        std::fs::hard_link(target.path().join("file.txt"), target.path().join("link.txt"))?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        transmitter.transmit(Path::new("file.txt"), None)?;
+
+        assert_eq!(std::fs::read(target.path().join("file.txt"))?, b"new");
+        assert_eq!(std::fs::read(target.path().join("link.txt"))?, b"old");
+        Ok(())
+    }
+
+    #[test]
+    fn hard_link_stays_in_the_target() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::write(target.path().join("file.txt"), b"inside")?;
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        assert!(transmitter.hard_link(Path::new("../secret"), Path::new("link.txt")).is_err());
+        assert!(transmitter.hard_link(Path::new("file.txt"), Path::new("../link.txt")).is_err());
+        assert!(!target.path().join("link.txt").exists());
+        transmitter.hard_link(Path::new("file.txt"), Path::new("link.txt"))?;
+        assert_eq!(std::fs::read(target.path().join("link.txt"))?, b"inside");
         Ok(())
     }
 }
@@ -1,14 +1,59 @@
-use std::path::Path;
-use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom};
 use std::fs::{create_dir_all, File, Metadata};
+use crate::config::PrivilegeMode;
+use crate::error::UsyncError;
+use crate::tree::{Change, ShaSum};
+use crate::util::{stays_below, Timestamp};
+use ownership::Ownership;
+use xattrs::ExtendedAttribute;
 
 pub mod local;
 pub mod remote;
+pub mod ownership;
+pub mod retry;
+pub mod xattrs;
+pub mod crtimes;
+pub mod delayed;
+pub mod framing;
+pub mod space;
+pub mod sparse;
+pub mod archive;
+
+/// What the sender reports about a file besides its contents.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub modified: Timestamp,
+    pub owner: Ownership,
+    /// Creation time, where the platform records it
+    pub created: Option<Timestamp>,
+}
+
+impl FileStat {
+    /// Attributes of the file at `path` on disk, with its ownership as `Ownership::of` reports it.
+    pub fn of(path: &Path, meta: &Metadata, privilege: PrivilegeMode) -> Result<FileStat> {
+        Ok(FileStat {
+            size: meta.len(),
+            modified: Timestamp::modified(meta),
+            owner: Ownership::of(path, meta, privilege),
+            created: crtimes::read(meta),
+        })
+    }
+}
 
 pub trait FileAccess {
     type Read: std::io::Read;
-    fn metadata(&self, path: &Path) -> Result<Metadata>;
-    fn read(&self, path: &Path) -> Result<Self::Read>;
+    /// Size, modification time and ownership of `path`. `privilege` decides where the
+    /// ownership comes from, see `Ownership::of`.
+    fn stat(&self, path: &Path, privilege: PrivilegeMode) -> Result<FileStat>;
+    /// Opens `path` for reading, positioned `offset` bytes into the file.
+    fn read(&self, path: &Path, offset: u64) -> Result<Self::Read>;
+
+    /// Extended attributes of `path`, sent with `--xattrs`.
+    fn xattrs(&self, path: &Path) -> Result<Vec<ExtendedAttribute>> {
+        xattrs::read(path)
+    }
 }
 
 pub struct DefaultFileAccess;
@@ -16,15 +61,218 @@ pub struct DefaultFileAccess;
 impl FileAccess for DefaultFileAccess {
     type Read = File;
 
-    fn metadata(&self, path: &Path) -> Result<Metadata> {
-        path.metadata()
+    fn stat(&self, path: &Path, privilege: PrivilegeMode) -> Result<FileStat> {
+        FileStat::of(path, &path.metadata()?, privilege)
     }
 
-    fn read(&self, path: &Path) -> Result<Self::Read> {
-        File::open(path)
+    fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+        let mut file = File::open(path)?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(file)
     }
 }
 
 pub trait Transmitter {
-    fn transmit(&mut self, path: &Path) -> Result<()>;
+    /// Transfers the file at `path` (relative to the sync roots), returning the number of
+    /// payload bytes moved. If `expected` is given, the received content must hash to it.
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64>;
+
+    /// Creates the directory at `path` (relative to the target root), so that directories
+    /// exist on the target even if they contain no files.
+    fn create_directory(&mut self, path: &Path) -> Result<()>;
+
+    /// Makes `path` a hard link to the already transferred `existing` (both relative to the
+    /// target root), replacing whatever is at `path`.
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()>;
+
+    /// Sets the permissions of the file at `path` (relative to the target root) to `mode`, as
+    /// `file_mode` reports them (see `--perms`).
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()>;
+
+    /// How many files `transmit_batch` should be given at once. Transmitters that gain nothing
+    /// from batching keep the default of one.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Whether the file at `path` was already transferred by an earlier attempt of the same
+    /// sync (see `retry::ResumingTransmitter`). The copy leaves such files out, without
+    /// counting them again.
+    fn already_transferred(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Transfers several files, returning the payload bytes moved for each of them, or why it
+    /// failed. Errors that don't concern a single file (see `is_file_error`) fail the batch.
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        let mut transferred = Vec::with_capacity(files.len());
+        for (path, expected) in files {
+            match self.transmit(path, *expected) {
+                Err(e) if !is_file_error(&e) => return Err(e),
+                result => transferred.push(result),
+            }
+        }
+        Ok(transferred)
+    }
+
+    /// Reports what the copy did with the item at `path` (relative to the sync roots), or why
+    /// it left it alone. Only decorators listing changes act on it, see `--itemize-changes`.
+    fn itemize(&mut self, _path: &Path, _change: Change) {}
+
+    /// Called once the copy went through, to apply what the transmitter held back until then
+    /// (see `--delay-updates`). Not called if the copy fails, so held back changes are dropped.
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// True for errors concerning a single file, after which other files can still be transferred.
+/// Losing the connection (see `retry::is_transient`), receiving corrupted data or running out
+/// of space on the target isn't one.
+pub fn is_file_error(error: &Error) -> bool {
+    !retry::is_transient(error) && !framing::is_corrupted(error) && error.kind() != ErrorKind::StorageFull
+}
+
+impl<T: Transmitter + ?Sized> Transmitter for &mut T {
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        (**self).transmit(path, expected)
+    }
+
+    fn batch_size(&self) -> usize {
+        (**self).batch_size()
+    }
+
+    fn already_transferred(&self, path: &Path) -> bool {
+        (**self).already_transferred(path)
+    }
+
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        (**self).transmit_batch(files)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        (**self).create_directory(path)
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        (**self).hard_link(existing, path)
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        (**self).set_permissions(path, mode)
+    }
+
+    fn itemize(&mut self, path: &Path, change: Change) {
+        (**self).itemize(path, change)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        (**self).commit()
+    }
+}
+
+/// The permission bits of a file (including setuid, setgid and sticky), as recorded with
+/// `--perms`. Elsewhere only whether the file is read-only is known, which gives 0o444 or 0o644.
+#[cfg(unix)]
+pub fn file_mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(meta: &Metadata) -> u32 {
+    if meta.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+/// Sets the permissions of the file at `path` below `root` to `mode`, for
+/// `Transmitter::set_permissions`. With `unshare`, a file that has other hard links is
+/// replaced by a copy of itself first, so that they keep their mode.
+fn set_mode_below(root: &Path, path: &Path, mode: u32, unshare: bool) -> Result<()> {
+    let target = root.join(path);
+    if unshare {
+        unshare_file(&target)?;
+    }
+    set_mode(&target, mode)
+}
+
+/// Replaces `target` with a copy of itself if it has other hard links, keeping its contents,
+/// mode, mtime and (where allowed) owner. Used with `--stage-then-swap`, where the staged
+/// files are links to the live target.
+#[cfg(unix)]
+fn unshare_file(target: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = target.symlink_metadata()?;
+    if !meta.is_file() || meta.nlink() < 2 {
+        return Ok(());
+    }
+    let copy = tempfile::NamedTempFile::new_in(target.parent().unwrap())?.into_temp_path();
+    std::fs::copy(target, &copy)?;
+    // only a privileged process may hand the copy back to another owner
+    let _ = std::os::unix::fs::chown(&copy, Some(meta.uid()), Some(meta.gid()));
+    filetime::set_file_mtime(&copy, filetime::FileTime::from_last_modification_time(&meta))?;
+    copy.persist(target).map_err(|it| it.error)
+}
+
+#[cfg(not(unix))]
+fn unshare_file(target: &Path) -> Result<()> {
+    let meta = target.symlink_metadata()?;
+    if !meta.is_file() {
+        return Ok(());
+    }
+    let copy = tempfile::NamedTempFile::new_in(target.parent().unwrap())?.into_temp_path();
+    std::fs::copy(target, &copy)?;
+    filetime::set_file_mtime(&copy, filetime::FileTime::from_last_modification_time(&meta))?;
+    copy.persist(target).map_err(|it| it.error)
+}
+
+#[cfg(unix)]
+fn set_mode(target: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(target, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(target: &Path, mode: u32) -> Result<()> {
+    let mut permissions = target.metadata()?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(target, permissions)
+}
+
+/// Creates `path` as a hard link to `existing` below `root`, for `Transmitter::hard_link`.
+/// Both must be relative paths staying below `root`, since they may come from a peer.
+fn link_below(root: &Path, existing: &Path, path: &Path) -> Result<()> {
+    for relative in &[existing, path] {
+        if !stays_below(relative) {
+            return Err(UsyncError::Protocol(format!("Refusing to link {}, it leaves the tree", relative.to_string_lossy())).into());
+        }
+    }
+    let target = root.join(path);
+    let parent = target.parent().unwrap();
+    if !parent.exists() {
+        create_dir_all(parent)?;
+    }
+    match std::fs::remove_file(&target) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    std::fs::hard_link(root.join(existing), target)
+}
+
+/// Renames the staged file to `target`. A file staged on another filesystem is copied next
+/// to `target` first, so that `target` is still replaced atomically.
+fn move_into_place(staged: &Path, target: &Path) -> Result<()> {
+    match std::fs::rename(staged, target) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            let mut local = tempfile::NamedTempFile::new_in(target.parent().unwrap())?;
+            std::io::copy(&mut File::open(staged)?, local.as_file_mut())?;
+            local.persist(target).map_err(|it| it.error)?;
+            std::fs::remove_file(staged)
+        }
+        moved => moved,
+    }
 }
@@ -0,0 +1,179 @@
+use std::fs::Metadata;
+use std::io::Result;
+use std::path::Path;
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::config::PrivilegeMode;
+
+/// Extended attribute used by `--fake-super` to record ownership that could not be applied.
+pub const FAKE_SUPER_XATTR: &str = "user.usync.owner";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ownership {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Ownership {
+    /// Ownership of a file as the sender should report it. In fake-super mode, an ownership
+    /// previously stored in the xattr takes precedence over the real owner.
+    #[cfg(unix)]
+    pub fn of(path: &Path, meta: &Metadata, mode: PrivilegeMode) -> Ownership {
+        use std::os::unix::fs::MetadataExt;
+
+        if mode == PrivilegeMode::FakeSuper {
+            if let Some(stored) = Ownership::stored(path) {
+                return stored;
+            }
+        }
+
+        Ownership { uid: meta.uid(), gid: meta.gid() }
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_path: &Path, _meta: &Metadata, _mode: PrivilegeMode) -> Ownership {
+        Ownership { uid: 0, gid: 0 }
+    }
+
+    #[cfg(unix)]
+    fn stored(path: &Path) -> Option<Ownership> {
+        let value = xattr::get(path, FAKE_SUPER_XATTR).ok()??;
+        let value = String::from_utf8(value).ok()?;
+        let sep = value.find(':')?;
+
+        Some(Ownership {
+            uid: value[..sep].parse().ok()?,
+            gid: value[sep + 1..].parse().ok()?,
+        })
+    }
+
+    #[cfg(unix)]
+    fn store(&self, path: &Path) -> Result<()> {
+        xattr::set(path, FAKE_SUPER_XATTR, format!("{}:{}", self.uid, self.gid).as_bytes())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Action {
+    Apply,
+    Skip,
+    Store,
+}
+
+fn decide(mode: PrivilegeMode, privileged: bool) -> Action {
+    match mode {
+        PrivilegeMode::Super => Action::Apply,
+        PrivilegeMode::FakeSuper => Action::Store,
+        PrivilegeMode::Auto if privileged => Action::Apply,
+        PrivilegeMode::Auto => Action::Skip,
+    }
+}
+
+/// Returns true if the current process may change file ownership.
+#[cfg(unix)]
+pub fn is_privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_privileged() -> bool {
+    false
+}
+
+/// Applies privileged metadata to received files according to the configured `PrivilegeMode`.
+/// Operations that require privileges we don't have are skipped, warning once per applier.
+pub struct OwnershipApplier {
+    mode: PrivilegeMode,
+    privileged: bool,
+    warned: bool,
+}
+
+impl OwnershipApplier {
+    pub fn new(mode: PrivilegeMode) -> OwnershipApplier {
+        OwnershipApplier::with_privilege(mode, is_privileged())
+    }
+
+    fn with_privilege(mode: PrivilegeMode, privileged: bool) -> OwnershipApplier {
+        OwnershipApplier { mode, privileged, warned: false }
+    }
+
+    #[cfg(unix)]
+    pub fn apply(&mut self, path: &Path, owner: &Ownership) -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = path.metadata()?;
+        if meta.uid() == owner.uid && meta.gid() == owner.gid {
+            return Ok(());
+        }
+
+        match decide(self.mode, self.privileged) {
+            Action::Apply => std::os::unix::fs::chown(path, Some(owner.uid), Some(owner.gid)),
+            Action::Store => owner.store(path),
+            Action::Skip => {
+                if !self.warned {
+                    warn!("Not running privileged, file ownership will not be preserved (use --fake-super to record it)");
+                }
+                debug!("Not changing owner of {} to {}:{} (insufficient privileges)", path.to_string_lossy(), owner.uid, owner.gid);
+                self.warned = true;
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&mut self, _path: &Path, _owner: &Ownership) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test_ownership {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::NamedTempFile;
+
+    fn foreign_owner(meta: &Metadata) -> Ownership {
+        Ownership { uid: meta.uid() + 1, gid: meta.gid() + 1 }
+    }
+
+    #[test]
+    fn decisions() {
+        assert_eq!(decide(PrivilegeMode::Auto, true), Action::Apply);
+        assert_eq!(decide(PrivilegeMode::Auto, false), Action::Skip);
+        assert_eq!(decide(PrivilegeMode::Super, false), Action::Apply);
+        assert_eq!(decide(PrivilegeMode::FakeSuper, true), Action::Store);
+    }
+
+    #[test]
+    fn unprivileged_skips_ownership() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let before = file.as_file().metadata()?;
+
+        let mut applier = OwnershipApplier::with_privilege(PrivilegeMode::Auto, false);
+        applier.apply(file.path(), &foreign_owner(&before))?;
+
+        let after = file.path().metadata()?;
+        assert!(applier.warned);
+        assert_eq!((before.uid(), before.gid()), (after.uid(), after.gid()));
+        Ok(())
+    }
+
+    #[test]
+    fn fake_super_stores_ownership() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let before = file.as_file().metadata()?;
+        let owner = foreign_owner(&before);
+
+        let mut applier = OwnershipApplier::with_privilege(PrivilegeMode::FakeSuper, false);
+        applier.apply(file.path(), &owner)?;
+
+        let after = file.path().metadata()?;
+        assert_eq!((before.uid(), before.gid()), (after.uid(), after.gid()));
+        assert_eq!(Ownership::stored(file.path()), Some(owner));
+        assert_eq!(Ownership::of(file.path(), &after, PrivilegeMode::FakeSuper), owner);
+        assert_eq!(Ownership::of(file.path(), &after, PrivilegeMode::Auto), Ownership { uid: after.uid(), gid: after.gid() });
+        Ok(())
+    }
+}
@@ -1,26 +1,43 @@
-use std::io::{Read, Result, Write, BufReader, BufWriter};
+use std::collections::HashSet;
+use std::io::{Read, Result, Write, BufReader, BufWriter, Error, ErrorKind, Seek, SeekFrom};
 use serde::de::DeserializeOwned;
-use crate::util::convert_error;
+use crate::util::{convert_error, is_plain_name, RateLimiter, ThrottledWrite, Timestamp, DEFAULT_IO_BUFFER_SIZE};
 use serde::{Serialize, Deserialize};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 use filetime::{FileTime, set_file_mtime};
-use crate::tree::Manifest;
+use crate::tree::{ChecksumKey, Manifest, ShaSum, hash, hash_keyed, random_nonce};
+use crate::config::HashAlgorithm;
+use crate::error::UsyncError;
+use crate::config::{AppendMode, Configuration, PrivilegeMode};
+use super::ownership::{Ownership, OwnershipApplier};
+use super::xattrs::{ExtendedAttribute, XattrApplier};
+use super::crtimes::CrtimeApplier;
+use super::delayed::{DelayedUpdates, Staged};
+use super::framing::{FramedRead, FramedWrite, MAX_FRAME_SIZE, read_frame, write_frame};
+use super::space::SpaceGuard;
+use super::sparse::{copy_to_file, CopySettings};
 
 use lazy_static::lazy_static;
+use log::{debug, warn};
 
 use super::*;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempPath};
+use std::fs::OpenOptions;
 
 lazy_static! {
-    // defines a bincode configuration that allows a maximum object size of 64 megabytes, in LE
-    // encoding. If larger manifests eventually become a thing, we need to reconsider this.
-    static ref CONFIG: bincode::Config = bincode::config().limit(1 << 26).little_endian().clone();
+    // defines a bincode configuration that allows a maximum object size of 64 megabytes (the
+    // largest frame), in LE encoding. Manifests are sent one directory at a time, so this bounds
+    // the size of a single directory listing rather than the whole tree.
+    static ref CONFIG: bincode::Config = bincode::config().limit(MAX_FRAME_SIZE as u64).little_endian().clone();
 }
 
-fn read_bincoded<R: Read, C: DeserializeOwned>(input: R) -> Result<C> {
+/// Reads a message sent by `write_bincoded`, checking the checksum of its frame.
+fn read_bincoded<R: Read, C: DeserializeOwned>(mut input: R) -> Result<C> {
     let cfg: &bincode::Config = &*CONFIG;
-    cfg.deserialize_from(input).map_err(convert_error)
+    let mut buffer = Vec::new();
+    read_frame(&mut input, &mut buffer)?;
+    cfg.deserialize(&buffer).map_err(convert_error)
 }
 
 fn write_bincoded_with_flush<W: Write, S: Serialize>(mut output:  W, data: &S) -> Result<()> {
@@ -28,16 +45,31 @@ fn write_bincoded_with_flush<W: Write, S: Serialize>(mut output:  W, data: &S) -
     output.flush()
 }
 
-fn write_bincoded<W: Write, S: Serialize>(mut output: &mut W, data: &S) -> Result<()>{
+/// Writes `data` as a single frame, see `framing`.
+fn write_bincoded<W: Write, S: Serialize>(output: &mut W, data: &S) -> Result<()>{
     let cfg = &*CONFIG;
-    cfg.serialize_into(&mut output, data).map_err(convert_error)
+    let buffer = cfg.serialize(data).map_err(convert_error)?;
+    write_frame(output, &buffer)
+}
+
+/// The hellos are the only messages sent without a frame, so that peers with an older
+/// encoding can still read them and tell that the versions differ.
+fn write_unframed<W: Write, S: Serialize>(mut output: W, data: &S) -> Result<()> {
+    let cfg = &*CONFIG;
+    cfg.serialize_into(&mut output, data).map_err(convert_error)?;
+    output.flush()
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum Command {
     End,
     SendManifest,
-    SendFile(PortablePath),
+    /// Request the contents of a file, starting at the given byte offset
+    SendFile(PortablePath, u64),
+    /// Like `SendManifest`, but with content hashes even if the sender works in timestamp mode
+    SendHashedManifest,
+    /// Request several complete files, which are sent back to back, each like for `SendFile`
+    SendFiles(Vec<PortablePath>),
 }
 
 
@@ -47,66 +79,460 @@ pub struct PortablePath {
 }
 
 impl PortablePath {
-    pub fn from<A: AsRef<Path>>(path: A) -> PortablePath {
+    /// Converts a relative path into its portable form. Fails for paths that contain segments
+    /// which are not valid UTF-8, since those cannot be represented on the wire.
+    pub fn from<A: AsRef<Path>>(path: A) -> Result<PortablePath> {
         let path = path.as_ref();
-        PortablePath {
-            segments: path.into_iter()
-                .map(|os| String::from(os.to_str().unwrap()))
-                .collect()
+        let mut segments = Vec::new();
+
+        for os in path.iter() {
+            match os.to_str() {
+                Some(segment) => segments.push(String::from(segment)),
+                None => return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Path {} contains a segment that is not valid UTF-8", path.to_string_lossy()),
+                ))
+            }
         }
+
+        Ok(PortablePath { segments })
     }
 
-    pub fn relative_to(&self, root: &Path) -> PathBuf {
+    /// The path below `root` this stands for. Fails if a segment is anything but a plain name
+    /// (see `is_plain_name`), so that a peer can't reach beyond `root`.
+    pub fn relative_to(&self, root: &Path) -> Result<PathBuf> {
         let mut rv = root.to_owned();
 
         for s in &self.segments {
+            if !is_plain_name(s) {
+                return Err(UsyncError::Protocol(format!("Refusing path with segment {:?}, which leaves the tree", s)).into());
+            }
             rv.push(s);
         }
 
-        rv
+        Ok(rv)
     }
 }
 
 #[derive(Deserialize, Serialize)]
 struct FileAttributes {
     size: u64,
-    secs: i64,
-    nanos: u32,
+    modified: Timestamp,
+    owner: Ownership,
+    /// Only sent with `--xattrs`
+    xattrs: Vec<ExtendedAttribute>,
+    /// Only sent with `--crtimes`, where the sender's platform records it
+    created: Option<Timestamp>,
 }
 
 impl FileAttributes {
-    fn new(size: u64, time: SystemTime) -> FileAttributes {
-        let time = FileTime::from(time);
+    fn new(size: u64, modified: Timestamp, owner: Ownership) -> FileAttributes {
         FileAttributes {
             size,
-            secs: time.unix_seconds(),
-            nanos: time.nanoseconds(),
+            modified,
+            owner,
+            xattrs: Vec::new(),
+            created: None,
+        }
+    }
+}
+
+/// Identifies a usync peer at the start of a connection.
+const PROTOCOL_MAGIC: [u8; 4] = *b"USYN";
+/// Version of the wire protocol. Bump this whenever `Command`, the manifest chunks or any other
+/// message exchanged after the handshake changes its encoding.
+const PROTOCOL_VERSION: u32 = 16;
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Hello {
+    magic: [u8; 4],
+    version: u32,
+}
+
+impl Hello {
+    fn current() -> Hello {
+        Hello { magic: PROTOCOL_MAGIC, version: PROTOCOL_VERSION }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.magic != PROTOCOL_MAGIC {
+            Err(UsyncError::VersionMismatch(String::from("Incompatible usync versions: peer did not identify as usync")).into())
+        } else if self.version != PROTOCOL_VERSION {
+            Err(UsyncError::VersionMismatch(format!(
+                "Incompatible usync versions: local protocol version {}, remote protocol version {}",
+                PROTOCOL_VERSION, self.version)).into())
+        } else {
+            Ok(())
         }
     }
+}
+
+fn read_hello<R: Read>(input: R) -> Result<Hello> {
+    let cfg = &*CONFIG;
+    cfg.deserialize_from(input).map_err(convert_error).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            // peers predating the handshake choke on our hello and hang up
+            UsyncError::VersionMismatch(String::from("Incompatible usync versions: peer closed the connection during the version handshake")).into()
+        } else {
+            e
+        }
+    })
+}
 
-    fn to_file_time(&self) -> FileTime {
-        FileTime::from_unix_time(self.secs, self.nanos)
+/// Current time of a peer, exchanged right after the hellos to measure the clock skew between
+/// the two machines.
+#[derive(Debug, Deserialize, Serialize)]
+struct ClockSample {
+    time: SystemTime,
+}
+
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_millis() as i64,
+        Err(before) => -(before.duration().as_millis() as i64),
     }
 }
 
+/// Client side of the version handshake, which must be the first exchange on a connection.
+/// Returns how many milliseconds the peer's clock is ahead of ours.
+fn offer_version<R: Read, W: Write>(mut input: R, mut output: W) -> Result<i64> {
+    write_unframed(&mut output, &Hello::current())?;
+    read_hello(&mut input)?.check()?;
+
+    let sent = SystemTime::now();
+    write_bincoded_with_flush(&mut output, &ClockSample { time: sent })?;
+    let peer: ClockSample = read_bincoded(&mut input)?;
+    let received = SystemTime::now();
+
+    // the peer took its sample somewhere during the round trip, assume the middle
+    let local = (millis_since_epoch(sent) + millis_since_epoch(received)) / 2;
+    Ok(millis_since_epoch(peer.time) - local)
+}
+
+/// Sending side of the version handshake. Our own hello is answered even on a mismatch, so
+/// that both peers can report the incompatibility.
+fn accept_version<R: Read, W: Write>(mut input: R, mut output: W) -> Result<()> {
+    let hello = read_hello(&mut input)?;
+    write_unframed(&mut output, &Hello::current())?;
+    hello.check()?;
+
+    let _peer: ClockSample = read_bincoded(&mut input)?;
+    write_bincoded_with_flush(&mut output, &ClockSample { time: SystemTime::now() })
+}
+
+/// What the connecting side wants to do in a session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SessionMode {
+    /// The connecting side receives files, the accepting side sends them
+    Fetch,
+    /// The connecting side sends files, the accepting side receives them
+    Push,
+}
+
+/// Opens the connection handshake, sent by the accepting side. The nonce is fresh for every
+/// session, so that a proof of the checksum key can't be replayed in another one.
+#[derive(Debug, Deserialize, Serialize)]
+struct AuthChallenge {
+    nonce: ShaSum,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AuthRequest {
+    token: Option<String>,
+    mode: SessionMode,
+    /// Name of the server module to use, `None` for the server's default tree
+    module: Option<String>,
+    /// `ChecksumKey::prove` of the challenge's nonce with the client's `--checksum-seed`, if it
+    /// has one
+    checksum_proof: Option<ShaSum>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+enum AuthResponse {
+    Accepted,
+    Rejected,
+    PushRefused,
+    UnknownModule,
+    ChecksumSeedMismatch,
+}
+
+/// Who may use a tree served by the accepting side, and how.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    /// Token clients must present, if any
+    pub token: Option<String>,
+    /// Whether clients may push files into the tree
+    pub allow_push: bool,
+    /// Key of the seed clients must hash with, if any
+    pub checksum_key: Option<ChecksumKey>,
+}
+
+/// Client side of the connection handshake: asks for `module`, presents `token` to the server
+/// and fails if the server does not accept it, does not support `mode` or hashes with another
+/// checksum seed than `key`.
+fn request_access<R: Read, W: Write>(mut input: R, mut output: W, token: Option<&str>, mode: SessionMode, module: Option<&str>, key: Option<&ChecksumKey>) -> Result<()> {
+    let challenge: AuthChallenge = read_bincoded(&mut input)?;
+    let request = AuthRequest {
+        token: token.map(String::from),
+        mode,
+        module: module.map(String::from),
+        checksum_proof: key.map(|key| key.prove(&challenge.nonce)),
+    };
+    write_bincoded_with_flush(&mut output, &request)?;
+
+    match read_bincoded(&mut input)? {
+        AuthResponse::Accepted => Ok(()),
+        AuthResponse::Rejected => Err(UsyncError::Auth(String::from("Server rejected the authentication token")).into()),
+        AuthResponse::PushRefused => Err(UsyncError::Auth(String::from("Server does not accept pushes")).into()),
+        AuthResponse::UnknownModule => Err(Error::new(ErrorKind::NotFound, match module {
+            Some(module) => format!("Server has no module named {}", module),
+            None => String::from("Server has no default module, a module name is required"),
+        })),
+        AuthResponse::ChecksumSeedMismatch => Err(Error::new(ErrorKind::InvalidInput, "Server hashes with a different checksum seed (see --checksum-seed)")),
+    }
+}
+
+/// Server side of the connection handshake for a single tree, see `grant_module_access`.
+fn grant_access<R: Read, W: Write>(input: R, output: W, access: Access) -> Result<SessionMode> {
+    let ((), mode) = grant_module_access(input, output, |module| match module {
+        None => Some(((), access)),
+        Some(_) => None,
+    })?;
+    Ok(mode)
+}
+
+/// Server side of the connection handshake: challenges the client, reads its request and
+/// resolves the module it asks for with `lookup`, then compares the presented token against
+/// the module's in constant time. If the module does not require a token, any client is
+/// accepted. Pushes are only accepted if the module allows them, and only clients proving
+/// that they hash with the module's checksum seed are, since all their files would seem to
+/// differ otherwise.
+fn grant_module_access<R: Read, W: Write, M, F: FnOnce(Option<&str>) -> Option<(M, Access)>>(mut input: R, mut output: W, lookup: F) -> Result<(M, SessionMode)> {
+    let nonce = random_nonce();
+    write_bincoded_with_flush(&mut output, &AuthChallenge { nonce })?;
+    let request: AuthRequest = read_bincoded(&mut input)?;
+    let (module, access) = match lookup(request.module.as_deref()) {
+        Some(found) => found,
+        None => {
+            write_bincoded_with_flush(&mut output, &AuthResponse::UnknownModule)?;
+            return Err(Error::new(ErrorKind::NotFound, format!(
+                "Client asked for unknown module {}", request.module.as_deref().unwrap_or("(default)"))));
+        }
+    };
+    let accepted = match access.token {
+        None => true,
+        Some(expected) => {
+            let presented = request.token.unwrap_or_default();
+            ring::constant_time::verify_slices_are_equal(expected.as_bytes(), presented.as_bytes()).is_ok()
+        }
+    };
+
+    if !accepted {
+        write_bincoded_with_flush(&mut output, &AuthResponse::Rejected)?;
+        Err(UsyncError::Auth(String::from("Client presented an invalid authentication token")).into())
+    } else if request.mode == SessionMode::Push && !access.allow_push {
+        write_bincoded_with_flush(&mut output, &AuthResponse::PushRefused)?;
+        Err(UsyncError::Auth(String::from("Client tried to push, but pushes are not accepted")).into())
+    } else if !same_checksum_key(request.checksum_proof.as_ref(), access.checksum_key.as_ref(), &nonce) {
+        write_bincoded_with_flush(&mut output, &AuthResponse::ChecksumSeedMismatch)?;
+        Err(Error::new(ErrorKind::InvalidInput, "Client hashes with a different checksum seed (see --checksum-seed)"))
+    } else {
+        write_bincoded_with_flush(&mut output, &AuthResponse::Accepted)?;
+        Ok((module, request.mode))
+    }
+}
+
+/// Whether a client presenting `proof` for `nonce` hashes with `key`.
+fn same_checksum_key(proof: Option<&ShaSum>, key: Option<&ChecksumKey>, nonce: &ShaSum) -> bool {
+    match (proof, key) {
+        (None, None) => true,
+        (Some(proof), Some(key)) => ring::constant_time::verify_slices_are_equal(&key.prove(nonce), proof).is_ok(),
+        _ => false,
+    }
+}
+
+/// Accepting side of the connection setup: checks the protocol version and that the client
+/// may `access` the tree, and returns what the client wants to do.
+pub fn accept_session<R: Read, W: Write>(mut input: R, mut output: W, access: Access) -> Result<SessionMode> {
+    accept_version(&mut input, &mut output)?;
+    grant_access(&mut input, &mut output, access)
+}
+
+/// Like `accept_session`, for a server offering several trees. `lookup` maps the name of the
+/// module the client asks for (`None` for the default) to the module and its access rules,
+/// returning `None` if there is no such module.
+pub fn accept_module_session<R: Read, W: Write, M, F: FnOnce(Option<&str>) -> Option<(M, Access)>>(mut input: R, mut output: W, lookup: F) -> Result<(M, SessionMode)> {
+    accept_version(&mut input, &mut output)?;
+    grant_module_access(&mut input, &mut output, lookup)
+}
+
+/// Connection setup for a client that pushes files into `module` of a server. Once this
+/// succeeds, the client serves the accepting side's commands with `serve_commands`. Returns
+/// the clock skew to the server, see `CommandTransmitter::handshake`.
+pub fn request_push<R: Read, W: Write>(mut input: R, mut output: W, token: Option<&str>, module: Option<&str>, key: Option<&ChecksumKey>) -> Result<i64> {
+    let skew = offer_version(&mut input, &mut output)?;
+    request_access(&mut input, &mut output, token, SessionMode::Push, module, key)?;
+    Ok(skew)
+}
+
 pub struct CommandTransmitter<R: Read, W: Write> {
     root: PathBuf,
     input: BufReader<R>,
-    output: BufWriter<W>
+    output: BufWriter<W>,
+    ownership: OwnershipApplier,
+    algorithm: HashAlgorithm,
+    /// Key received content is verified with, see `--checksum-seed`
+    checksum_key: Option<ChecksumKey>,
+    batch_size: usize,
+    /// Directory incoming files are staged in, instead of next to their target
+    temp_dir: Option<PathBuf>,
+    /// Set if received extended attributes are applied
+    xattrs: Option<XattrApplier>,
+    /// Set if received creation times are applied
+    crtimes: Option<CrtimeApplier>,
+    /// Set if files must leave a minimum of free space on the target
+    space: Option<SpaceGuard>,
+    /// How received files are written: sparse or not, and through what size of buffer
+    copy: CopySettings,
+    /// Set if files shorter on the target are appended to, see `--append`
+    append: Option<AppendMode>,
+    /// Set if files are always requested whole, see `--whole-file`
+    whole_file: bool,
+    /// Set if received files are only moved into place once all arrived, see `--delay-updates`
+    delayed: Option<DelayedUpdates>,
+    /// Set if target files with other hard links are copied before their permissions change
+    unshare: bool,
 }
 
 impl<R: Read, W: Write> CommandTransmitter<R, W> {
-    pub fn new(root: &Path, input: R, output: W) -> CommandTransmitter<R, W> {
+    pub fn new(root: &Path, input: R, output: W, privilege: PrivilegeMode) -> CommandTransmitter<R, W> {
         CommandTransmitter {
             root: root.to_owned(),
             input: BufReader::new(input),
-            output: BufWriter::new(output)
+            output: BufWriter::new(output),
+            ownership: OwnershipApplier::new(privilege),
+            algorithm: HashAlgorithm::Sha256,
+            checksum_key: None,
+            batch_size: 1,
+            temp_dir: None,
+            xattrs: None,
+            crtimes: None,
+            space: None,
+            copy: CopySettings::default(),
+            append: None,
+            whole_file: false,
+            delayed: None,
+            unshare: false,
         }
     }
 
+    /// Applies the extended attributes the sender sends along (with `--xattrs`) to received
+    /// files.
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = if xattrs { Some(XattrApplier::new()) } else { None };
+        self
+    }
+
+    /// Applies the creation times the sender sends along (with `--crtimes`) to received files.
+    pub fn with_crtimes(mut self, crtimes: bool) -> Self {
+        self.crtimes = if crtimes { Some(CrtimeApplier::new()) } else { None };
+        self
+    }
+
+    /// Stages incoming files in `temp_dir` (if given) rather than in the target directory.
+    pub fn with_temp_dir(mut self, temp_dir: Option<&Path>) -> Self {
+        self.temp_dir = temp_dir.map(Path::to_owned);
+        self
+    }
+
+    /// Refuses to receive files that would leave less than `min_free` bytes on the target, or
+    /// in the temp dir.
+    pub fn with_min_free(mut self, min_free: Option<u64>) -> Self {
+        self.space = min_free.map(SpaceGuard::new);
+        self
+    }
+
+    /// Saves received files sparse, seeking over runs of at least `min_hole` zero bytes (if
+    /// given) instead of writing them.
+    pub fn with_sparse(mut self, min_hole: Option<u64>) -> Self {
+        self.copy.min_hole = min_hole;
+        self
+    }
+
+    /// Writes received files through a buffer of `buffer_size` bytes.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.copy.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// Appends to target files shorter than the sender's (if `append` is given), requesting
+    /// only the data they lack, see `--append`.
+    pub fn with_append(mut self, append: Option<AppendMode>) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Requests every file whole (if `whole_file` is set), rather than resuming an interrupted
+    /// transfer of it or appending to it, see `--whole-file`.
+    pub fn with_whole_file(mut self, whole_file: bool) -> Self {
+        self.whole_file = whole_file;
+        self
+    }
+
+    /// Keeps received files staged (if `delay_updates` is set) until `commit`, which moves all
+    /// of them into place, see `--delay-updates`.
+    pub fn with_delay_updates(mut self, delay_updates: bool) -> Self {
+        self.delayed = if delay_updates { Some(DelayedUpdates::new(&self.root)) } else { None };
+        self
+    }
+
+    /// Copies target files that have other hard links before changing their permissions (if
+    /// `linked_target` is set), as with `--stage-then-swap` those links are the live target.
+    pub fn with_linked_target(mut self, linked_target: bool) -> Self {
+        self.unshare = linked_target;
+        self
+    }
+
+    /// Hashes with `key`, which the sender must use as well (see `--checksum-seed`).
+    pub fn with_checksum_key(mut self, key: Option<&ChecksumKey>) -> Self {
+        self.checksum_key = key.cloned();
+        self
+    }
+
+    /// Requests up to `batch_size` files with a single command, saving a round trip per file.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets up the connection: checks that the peer speaks the same protocol version, then
+    /// asks for `module` (only servers have modules) and presents `token` for authentication.
+    /// Fails if the sender hashes with another checksum seed. Must be called before any other
+    /// request. Returns how many milliseconds the sender's clock is ahead of ours (negative if
+    /// it is behind).
+    pub fn handshake(&mut self, token: Option<&str>, module: Option<&str>) -> Result<i64> {
+        let skew = offer_version(&mut self.input, &mut self.output)?;
+        request_access(&mut self.input, &mut self.output, token, SessionMode::Fetch, module, self.checksum_key.as_ref())?;
+        Ok(skew)
+    }
+
     pub fn remote_manifest(&mut self) -> Result<Manifest> {
-        write_bincoded_with_flush(&mut self.output, &Command::SendManifest)?;
-        read_bincoded(&mut self.input)
+        self.request_manifest(&Command::SendManifest)
+    }
+
+    /// Requests the sender's manifest with content hashes for every file, for verification.
+    pub fn hashed_manifest(&mut self) -> Result<Manifest> {
+        self.request_manifest(&Command::SendHashedManifest)
+    }
+
+    fn request_manifest(&mut self, command: &Command) -> Result<Manifest> {
+        write_bincoded_with_flush(&mut self.output, command)?;
+        let input = &mut self.input;
+        let (algorithm, mode, key_id) = read_bincoded(&mut *input)?;
+        let manifest = Manifest::from_chunks(algorithm, mode, key_id, &mut || read_bincoded(&mut *input))?;
+        self.algorithm = algorithm;
+
+        Ok(manifest)
     }
 }
 
@@ -118,9 +544,70 @@ impl <R: Read, W: Write> Drop for CommandTransmitter<R, W> {
 }
 
 
-pub(crate) fn command_handler_loop<R: Read, W: Write, A: FileAccess>(root: &Path, manifest: &Manifest, input: R, output: W, access: &A) -> Result<()> {
+/// Options affecting how the sending side serves files.
+#[derive(Debug, Clone)]
+pub struct SenderSettings {
+    pub privilege: PrivilegeMode,
+    /// Maximum payload throughput in bytes per second
+    pub bwlimit: Option<u64>,
+    /// Token clients must present, if any
+    pub auth_token: Option<String>,
+    /// Whether to send the extended attributes of files
+    pub xattrs: bool,
+    /// Whether to send the creation times of files
+    pub crtimes: bool,
+    /// Size of the buffer files are read through
+    pub buffer_size: usize,
+    /// Key content hashes are keyed with, see `--checksum-seed`
+    pub checksum_key: Option<ChecksumKey>,
+}
+
+impl SenderSettings {
+    pub fn new(cfg: &Configuration) -> SenderSettings {
+        SenderSettings {
+            privilege: cfg.privilege(),
+            bwlimit: cfg.bwlimit(),
+            auth_token: cfg.auth_token().map(String::from),
+            xattrs: cfg.xattrs(),
+            crtimes: cfg.crtimes(),
+            buffer_size: cfg.hash_settings().io_buffer_size(),
+            checksum_key: cfg.hash_settings().checksum_key().cloned(),
+        }
+    }
+}
+
+impl Default for SenderSettings {
+    fn default() -> Self {
+        SenderSettings {
+            privilege: PrivilegeMode::Auto,
+            bwlimit: None,
+            auth_token: None,
+            xattrs: false,
+            crtimes: false,
+            buffer_size: DEFAULT_IO_BUFFER_SIZE,
+            checksum_key: None,
+        }
+    }
+}
+
+/// Sets up the session with a fetching client and serves its commands until it ends it.
+pub fn command_handler_loop<R: Read, W: Write, A: FileAccess>(root: &Path, manifest: &Manifest, mut input: R, mut output: W, access: &A, settings: &SenderSettings) -> Result<()> {
+    let allowed = Access {
+        token: settings.auth_token.clone(),
+        allow_push: false,
+        checksum_key: settings.checksum_key.clone(),
+    };
+    match accept_session(&mut input, &mut output, allowed)? {
+        SessionMode::Fetch => serve_commands(root, manifest, input, output, access, settings),
+        SessionMode::Push => unreachable!("pushes are refused during the handshake"),
+    }
+}
+
+/// Serves the receiving side's commands on an established session, until it ends it.
+pub fn serve_commands<R: Read, W: Write, A: FileAccess>(root: &Path, manifest: &Manifest, input: R, output: W, access: &A, settings: &SenderSettings) -> Result<()> {
     let mut input = BufReader::new(input);
     let mut output = BufWriter::new(output);
+    let mut limiter = settings.bwlimit.map(RateLimiter::new);
     loop {
         let next = read_bincoded(&mut input)?;
         match next {
@@ -128,16 +615,25 @@ pub(crate) fn command_handler_loop<R: Read, W: Write, A: FileAccess>(root: &Path
                 return Ok(());
             }
             Command::SendManifest => {
-                write_bincoded_with_flush(&mut output, &manifest)?;
+                write_bincoded(&mut output, &(manifest.hash_algorithm(), manifest.manifest_mode(), manifest.checksum_key_id()))?;
+                manifest.chunks(&mut |chunk| write_bincoded(&mut output, chunk))?;
             }
-            Command::SendFile(path) => {
-                let file = path.relative_to(root);
-                let meta = access.metadata(&file)?;
-                let attrs = FileAttributes::new(meta.len(), meta.modified()?);
-                let mut reader = access.read(&file)?;
-
-                write_bincoded(&mut output, &attrs)?;
-                std::io::copy(&mut reader, &mut output)?;
+            Command::SendHashedManifest => {
+                let hashed = manifest.with_hashes(root, settings.checksum_key.as_ref())?;
+                write_bincoded(&mut output, &(hashed.hash_algorithm(), hashed.manifest_mode(), hashed.checksum_key_id()))?;
+                hashed.chunks(&mut |chunk| write_bincoded(&mut output, chunk))?;
+            }
+            Command::SendFile(path, offset) => match path.relative_to(root) {
+                Ok(file) => send_file(&file, offset, access, settings, limiter.as_mut(), &mut output)?,
+                Err(e) => refuse_file(&e, &mut output)?,
+            },
+            Command::SendFiles(paths) => {
+                for path in paths {
+                    match path.relative_to(root) {
+                        Ok(file) => send_file(&file, 0, access, settings, limiter.as_mut(), &mut output)?,
+                        Err(e) => refuse_file(&e, &mut output)?,
+                    }
+                }
             }
         }
 
@@ -145,31 +641,1335 @@ pub(crate) fn command_handler_loop<R: Read, W: Write, A: FileAccess>(root: &Path
     }
 }
 
+/// Answer to a request for a file: its attributes (followed by its contents), or why the
+/// sender could not read it.
+type FileResponse = std::result::Result<FileAttributes, String>;
+
+fn open_file<A: FileAccess>(file: &Path, offset: u64, access: &A, settings: &SenderSettings) -> Result<(FileAttributes, A::Read)> {
+    let stat = access.stat(file, settings.privilege)?;
+    let mut attrs = FileAttributes::new(stat.size, stat.modified, stat.owner);
+    if settings.xattrs {
+        attrs.xattrs = access.xattrs(file)?;
+    }
+    if settings.crtimes {
+        attrs.created = stat.created;
+    }
+    Ok((attrs, access.read(file, offset)?))
+}
+
+/// Answers a request for a file that must not be sent with `error`, rather than ending the
+/// session.
+fn refuse_file<W: Write>(error: &Error, output: &mut W) -> Result<()> {
+    warn!("Refused a file request: {}", error);
+    write_bincoded(output, &FileResponse::Err(error.to_string()))
+}
+
+/// Writes the attributes of `file`, followed by its contents from `offset` on as framed data.
+/// A file that can't be read is reported to the receiver, rather than ending the session.
+fn send_file<W: Write, A: FileAccess>(file: &Path, offset: u64, access: &A, settings: &SenderSettings, limiter: Option<&mut RateLimiter>, output: &mut W) -> Result<()> {
+    let (attrs, mut reader) = match open_file(file, offset, access, settings) {
+        Ok(opened) => opened,
+        Err(e) => {
+            warn!("Could not send {}: {}", file.to_string_lossy(), e);
+            return write_bincoded(&mut *output, &FileResponse::Err(e.to_string()));
+        }
+    };
+
+    let size = attrs.size.saturating_sub(offset);
+    write_bincoded(&mut *output, &FileResponse::Ok(attrs))?;
+    let sent = match limiter {
+        Some(limiter) => send_payload(&mut reader, size, settings.buffer_size, ThrottledWrite::new(output, limiter))?,
+        None => send_payload(&mut reader, size, settings.buffer_size, output)?,
+    };
+    if sent < size {
+        warn!("{} changed while it was sent, only {} of {} bytes were sent", file.to_string_lossy(), sent, size);
+    }
+    Ok(())
+}
+
+/// Sends no more than the `size` bytes announced to the receiver as framed data, even if the
+/// file has grown since. Returns how many bytes were sent, fewer if the file has shrunk or
+/// could not be read to its end. The receiver discards such a file, but the data is still
+/// properly ended, so the session goes on.
+fn send_payload<R: Read, W: Write>(reader: R, size: u64, buffer_size: usize, output: W) -> Result<u64> {
+    let mut framed = FramedWrite::new(output);
+    let mut reader = reader.take(size);
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    let mut sent = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Reading failed after {} bytes: {}", sent, e);
+                break;
+            }
+        };
+        framed.write_all(&buffer[..read])?;
+        sent += read as u64;
+    }
+    framed.finish()?;
+    Ok(sent)
+}
+
+impl<R: Read, W: Write> CommandTransmitter<R, W> {
+    fn request_file(&mut self, path: &Path, offset: u64) -> Result<FileAttributes> {
+        write_bincoded_with_flush(&mut self.output, &Command::SendFile(PortablePath::from(path)?, offset))?;
+        self.file_response(path)
+    }
+
+    fn file_response(&mut self, path: &Path) -> Result<FileAttributes> {
+        let response: FileResponse = read_bincoded(&mut self.input)?;
+        response.map_err(|message| Error::new(ErrorKind::Other, format!(
+            "Sender could not read {}: {}", path.to_string_lossy(), message)))
+    }
+
+    /// Where an incomplete transfer of `path` is kept. In the temp dir, the name is derived
+    /// from the whole path, since files from different directories end up side by side.
+    fn partial_path(&self, path: &Path) -> Result<PathBuf> {
+        match &self.temp_dir {
+            Some(temp_dir) => {
+                let id = hash(path.to_string_lossy().as_bytes())?;
+                Ok(temp_dir.join(format!("{}{}", hex::encode(id), PARTIAL_SUFFIX)))
+            }
+            None => Ok(partial_path(&self.root.join(path))),
+        }
+    }
+
+    /// Removes the partial files below `subpath` of the target (all of it if `None`) that
+    /// aren't partial transfers of a file in `source`, and so will never be resumed. Returns
+    /// how many there were. Partial files in the temp dir are left alone, other syncs may
+    /// share it.
+    pub fn remove_stale_partials(&self, source: &Manifest, subpath: Option<&Path>) -> Result<usize> {
+        let wanted: HashSet<PathBuf> = source.files().iter().map(|file| partial_path(&self.root.join(&file.path))).collect();
+        let mut stale = Vec::new();
+        find_partials(&subpath.map_or_else(|| self.root.clone(), |subpath| self.root.join(subpath)), &mut stale)?;
+
+        let mut removed = 0;
+        for partial in stale.into_iter().filter(|partial| !wanted.contains(partial)) {
+            debug!("Removing stale partial transfer {}", partial.to_string_lossy());
+            std::fs::remove_file(&partial)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Checks that `incoming` bytes still fit on the filesystems files are written to.
+    fn check_space(&self, incoming: u64) -> Result<()> {
+        if let Some(guard) = &self.space {
+            guard.check(&self.root, incoming)?;
+            if let Some(temp_dir) = &self.temp_dir {
+                guard.check(temp_dir, incoming)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the file at `path` the sender is sending from `offset` on, and applies its
+    /// attributes. With `--delay-updates`, it is left staged, with its attributes applied.
+    fn receive_file(&mut self, path: &Path, meta: &FileAttributes, offset: u64, expected: Option<&ShaSum>) -> Result<u64> {
+        let target = self.root.join(path);
+        let partial = self.check_space(meta.size.saturating_sub(offset))
+            .and_then(|_| expected.map(|_| self.partial_path(path)).transpose());
+        let mut payload = FramedRead::new(&mut self.input);
+        let saved = match (expected, partial) {
+            (_, Err(e)) => Err(e),
+            (Some(expected), Ok(Some(partial))) =>
+                save_file_with_partial(&target, &partial, &mut payload, offset, meta.size - offset, (self.algorithm, self.checksum_key.as_ref(), expected), self.copy)
+                    .map(|received| (received, Staged::Partial(partial))),
+            _ => save_file_with_tempfile(&target, self.temp_dir.as_deref(), &mut payload, meta.size, self.copy)
+                .map(|(received, staged)| (received, Staged::Temporary(staged))),
+        };
+        // even if saving failed, the rest of the file has to be read to get to the next message
+        let drained = payload.finish();
+        let (received, staged) = saved?;
+        drained?;
+
+        if self.delayed.is_some() {
+            self.apply_attributes(staged.path(), meta)?;
+            self.delayed.as_mut().unwrap().replace(path, staged);
+        } else {
+            staged.move_to(&target)?;
+            self.apply_attributes(&target, meta)?;
+        }
+        Ok(received)
+    }
+
+    /// How many bytes of `path` the target holds already, if the file can be appended to.
+    /// Verifying the result takes the hash it must have, without one the file is transferred
+    /// whole.
+    fn append_offset(&self, path: &Path, expected: Option<&ShaSum>) -> Option<u64> {
+        match self.append {
+            _ if self.whole_file => None,
+            Some(AppendMode::Verify) if expected.is_none() => None,
+            Some(_) => match self.root.join(path).symlink_metadata() {
+                Ok(meta) if meta.is_file() && meta.len() > 0 => Some(meta.len()),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+
+    /// Requests the part of `path` beyond the `offset` bytes the target holds, and appends it.
+    /// Returns `None` if the file has to be transferred whole after all: if it did not grow on
+    /// the sender, or (when `expected` is given) the appended file doesn't hash to it.
+    fn append(&mut self, path: &Path, offset: u64, expected: Option<&ShaSum>) -> Result<Option<u64>> {
+        let meta = self.request_file(path, offset)?;
+        let target = self.root.join(path);
+        if meta.size <= offset {
+            FramedRead::new(&mut self.input).finish()?;
+            debug!("{} did not grow, transferring it whole", path.to_string_lossy());
+            return Ok(None);
+        }
+
+        let size = meta.size - offset;
+        let space = self.space.as_ref().map_or(Ok(()), |guard| guard.check(&self.root, size));
+        let copy = self.copy;
+        let mut payload = FramedRead::new(&mut self.input);
+        let saved = space.and_then(|_| append_to_file(&target, &mut payload, offset, size, copy));
+        let drained = payload.finish();
+        let received = saved?;
+        drained?;
+
+        if let Some(expected) = expected {
+            let actual = hash_keyed(self.algorithm, self.checksum_key.as_ref(), File::open(&target)?, self.copy.buffer_size)?;
+            if &actual != expected {
+                debug!("{} did not start like the sender's file, transferring it whole", path.to_string_lossy());
+                return Ok(None);
+            }
+        }
+        self.apply_attributes(&target, &meta)?;
+        Ok(Some(received))
+    }
+
+    fn apply_attributes(&mut self, target: &Path, meta: &FileAttributes) -> Result<()> {
+        set_file_mtime(target, FileTime::from(meta.modified))?;
+        self.ownership.apply(target, &meta.owner)?;
+        if let Some(applier) = self.xattrs.as_mut() {
+            applier.apply(target, &meta.xattrs);
+        }
+        if let Some(applier) = self.crtimes.as_mut() {
+            applier.apply(target, meta.created);
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is requested on its own rather than in a batch, to resume its partial
+    /// transfer or append to it.
+    fn resumable(&self, path: &Path, expected: Option<&ShaSum>) -> Result<bool> {
+        Ok((expected.is_some() && !self.whole_file && self.partial_path(path)?.exists()) || self.append_offset(path, expected).is_some())
+    }
+}
+
 impl<R: Read, W: Write> Transmitter for CommandTransmitter<R, W> {
-    fn transmit(&mut self, path: &Path) -> Result<()> {
-        write_bincoded_with_flush(&mut self.output, &Command::SendFile(PortablePath::from(path)))?;
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        if let Some(offset) = self.append_offset(path, expected) {
+            let verified = if self.append == Some(AppendMode::Verify) { expected } else { None };
+            if let Some(received) = self.append(path, offset, verified)? {
+                return Ok(received);
+            }
+        }
+        match expected {
+            Some(_) => {
+                // a partial file can only be resumed if the result is verified, otherwise a
+                // leftover from an older version of the file would go unnoticed
+                let mut offset = if self.whole_file {
+                    0
+                } else {
+                    self.partial_path(path)?.metadata().map(|meta| meta.len()).unwrap_or(0)
+                };
+                let mut meta = self.request_file(path, offset)?;
+                if meta.size < offset {
+                    // the file shrank since the interrupted transfer, nothing was sent
+                    FramedRead::new(&mut self.input).finish()?;
+                    offset = 0;
+                    meta = self.request_file(path, offset)?;
+                }
+                match self.receive_file(path, &meta, offset, expected) {
+                    Err(e) if matches!(UsyncError::of(&e), Some(UsyncError::Verification(_))) && offset > 0 => {
+                        // the partial file was left by an older version of the file
+                        debug!("Discarded stale partial transfer of {}", path.to_string_lossy());
+                        let meta = self.request_file(path, 0)?;
+                        self.receive_file(path, &meta, 0, expected)
+                    }
+                    received => received,
+                }
+            }
+            None => {
+                let meta = self.request_file(path, 0)?;
+                self.receive_file(path, &meta, 0, None)
+            }
+        }
+    }
 
-        let meta: FileAttributes = read_bincoded(&mut self.input)?;
-        let path = self.root.join(path);
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
 
-        save_file_with_tempfile(&path, &mut self.input, meta.size)?;
-        set_file_mtime(&path, meta.to_file_time())?;
+    /// Requests all files with one `SendFiles` command, except those with a partial transfer
+    /// to resume or a target file to append to, which are requested one by one.
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        let mut received: Vec<Result<u64>> = files.iter().map(|_| Ok(0)).collect();
+        let mut batch = Vec::new();
+        for (index, (path, expected)) in files.iter().enumerate() {
+            if self.resumable(path, *expected)? {
+                received[index] = match self.transmit(path, *expected) {
+                    Err(e) if !is_file_error(&e) => return Err(e),
+                    result => result,
+                };
+            } else {
+                batch.push(index);
+            }
+        }
+        if batch.is_empty() {
+            return Ok(received);
+        }
+
+        let paths = batch.iter().map(|index| PortablePath::from(&files[*index].0)).collect::<Result<Vec<_>>>()?;
+        write_bincoded_with_flush(&mut self.output, &Command::SendFiles(paths))?;
+
+        // a file that failed (say, hashed wrong) has been read completely, so the rest of the
+        // batch can still be saved
+        for index in batch {
+            let (path, expected) = &files[index];
+            let result = self.file_response(path)
+                .and_then(|meta| self.receive_file(path, &meta, 0, *expected));
+            received[index] = match result {
+                Err(e) if !is_file_error(&e) => return Err(e),
+                result => result,
+            };
+        }
+        Ok(received)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        create_dir_all(self.root.join(path))
+    }
 
+    /// Links are created locally, the sender's manifest already says which files are linked.
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        match self.delayed.as_mut() {
+            Some(delayed) => {
+                delayed.link(existing, path);
+                Ok(())
+            }
+            None => link_below(&self.root, existing, path),
+        }
+    }
+
+    /// Like links, permissions are set locally from what the sender's manifest recorded.
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        if let Some(delayed) = &self.delayed {
+            if delayed.set_permissions(path, mode)? {
+                return Ok(());
+            }
+        }
+        set_mode_below(&self.root, path, mode, self.unshare)
+    }
+
+    /// Moves the files held back by `--delay-updates` into place.
+    fn commit(&mut self) -> Result<()> {
+        if let Some(delayed) = self.delayed.as_mut() {
+            let applied = delayed.apply()?;
+            debug!("Moved {} held back updates into place", applied);
+        }
         Ok(())
     }
 }
 
-fn save_file_with_tempfile<R: Read>(target: &Path, reader: &mut R, size: u64) -> Result<()> {
+/// Suffix of the file a transfer is staged in while it is incomplete. Such files are left out
+/// of manifests.
+pub const PARTIAL_SUFFIX: &str = ".usync-partial";
+
+/// Adds the partial files in and below `dir` to `found`. Symlinks are not followed.
+fn find_partials(dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_partials(&entry.path(), found)?;
+        } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            found.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap().to_owned();
+    name.push(PARTIAL_SUFFIX);
+    target.with_file_name(name)
+}
+
+fn ensure_parent(target: &Path) -> Result<()> {
     let parent = target.parent().unwrap();
     if !parent.exists() {
         create_dir_all(parent)?;
     }
+    Ok(())
+}
 
-    let mut stage_file = NamedTempFile::new_in(parent)?;
-    let mut reader = reader.take(size);
+/// Fails if the payload in `reader` goes on past the `size` bytes announced for `target`.
+/// The sender never sends more than it announced, so more data means the payload is not the
+/// file it claims to be. Checking this makes sure a payload, an empty one in particular, ends
+/// exactly where the next message starts.
+fn expect_end<R: Read>(reader: &mut R, target: &Path, size: u64) -> Result<()> {
+    if reader.read(&mut [0u8; 1])? > 0 {
+        return Err(UsyncError::Protocol(format!(
+            "Transfer of {} went on past the {} bytes announced", target.to_string_lossy(), size)).into());
+    }
+    Ok(())
+}
+
+/// The error for a payload the sender ended after `received` of the `size` bytes it announced
+/// for `target`. The connection is intact, so this is the sender breaking the protocol (usually
+/// because the file shrank while it was read) rather than something a retry could fix.
+fn short_payload(target: &Path, received: u64, size: u64) -> Error {
+    UsyncError::Protocol(format!("Transfer of {} ended after {} of {} bytes", target.to_string_lossy(), received, size)).into()
+}
 
-    std::io::copy(&mut reader, stage_file.as_file_mut())?;
+/// Saves `size` bytes for `target` to a tempfile in `temp_dir` or next to `target`, written as
+/// `copy` says. Returns the tempfile, for the caller to move into place.
+fn save_file_with_tempfile<R: Read>(target: &Path, temp_dir: Option<&Path>, reader: &mut R, size: u64, copy: CopySettings) -> Result<(u64, TempPath)> {
+    ensure_parent(target)?;
 
-    stage_file.persist(target).map_err(|it|it.error)?;
-    Ok(())
+    let mut stage_file = NamedTempFile::new_in(temp_dir.unwrap_or_else(|| target.parent().unwrap()))?;
+
+    let received = copy_to_file(&mut reader.by_ref().take(size), stage_file.as_file_mut(), copy)?;
+    if received < size {
+        return Err(short_payload(target, received, size));
+    }
+    expect_end(reader, target, size)?;
+
+    Ok((received, stage_file.into_temp_path()))
+}
+
+/// Appends the `size` bytes following `offset` to the file at `target` itself, which is cut to
+/// `offset` bytes first, see `--append`.
+fn append_to_file<R: Read>(target: &Path, reader: &mut R, offset: u64, size: u64, copy: CopySettings) -> Result<u64> {
+    let mut file = OpenOptions::new().write(true).open(target)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::End(0))?;
+
+    let received = copy_to_file(&mut reader.by_ref().take(size), &mut file, copy)?;
+    if received < size {
+        return Err(short_payload(target, offset + received, offset + size));
+    }
+    expect_end(reader, target, offset + size)?;
+    Ok(received)
+}
+
+/// Appends the `size` bytes following `offset` to the file at `partial`, which is kept if the
+/// transfer is interrupted so that a later sync can pick up where this one stopped. Once
+/// complete, it must match `expected` (hashed with the algorithm and key given) to be moved
+/// into place by the caller.
+fn save_file_with_partial<R: Read>(target: &Path, partial: &Path, reader: &mut R, offset: u64, size: u64, expected: (HashAlgorithm, Option<&ChecksumKey>, &ShaSum), copy: CopySettings) -> Result<u64> {
+    ensure_parent(target)?;
+
+    let mut stage_file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(partial)?;
+    stage_file.set_len(offset)?;
+    stage_file.seek(SeekFrom::End(0))?;
+
+    let received = copy_to_file(&mut reader.by_ref().take(size), &mut stage_file, copy)?;
+    if received < size {
+        return Err(short_payload(target, offset + received, offset + size));
+    }
+    expect_end(reader, target, offset + size)?;
+
+    let (algorithm, key, expected) = expected;
+    stage_file.seek(SeekFrom::Start(0))?;
+    let actual = hash_keyed(algorithm, key, &mut stage_file, copy.buffer_size)?;
+    if &actual != expected {
+        std::fs::remove_file(partial)?;
+        return Err(UsyncError::Verification(format!(
+            "Hash mismatch for {}: expected {}, received {}",
+            target.to_string_lossy(), hex::encode(expected), hex::encode(actual))).into());
+    }
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod test_protocol {
+    use super::*;
+    use std::time::Duration;
+    use std::cell::Cell;
+    use std::io::Write;
+    use tempfile::TempDir;
+    use crate::config::test_support;
+    use crate::util::pipe;
+
+    struct CountingRead<'a>(File, &'a Cell<u64>);
+
+    impl Read for CountingRead<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = self.0.read(buf)?;
+            self.1.set(self.1.get() + n as u64);
+            Ok(n)
+        }
+    }
+
+    struct CountingAccess(Cell<u64>);
+
+    impl<'a> FileAccess for &'a CountingAccess {
+        type Read = CountingRead<'a>;
+
+        fn stat(&self, path: &Path, privilege: PrivilegeMode) -> Result<FileStat> {
+            DefaultFileAccess.stat(path, privilege)
+        }
+
+        fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+            Ok(CountingRead(DefaultFileAccess.read(path, offset)?, &self.0))
+        }
+    }
+
+    /// Reports the size of `stat` for every file, as if the files changed after they were
+    /// looked at.
+    struct ChangedAccess(PathBuf);
+
+    impl FileAccess for ChangedAccess {
+        type Read = File;
+
+        fn stat(&self, _path: &Path, privilege: PrivilegeMode) -> Result<FileStat> {
+            FileStat::of(&self.0, &self.0.metadata()?, privilege)
+        }
+
+        fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+            DefaultFileAccess.read(path, offset)
+        }
+    }
+
+    /// Encodes a client session: the handshake followed by `commands`.
+    fn session(commands: &[Command]) -> Result<Vec<u8>> {
+        let mut session = Vec::new();
+        write_unframed(&mut session, &Hello::current())?;
+        write_bincoded(&mut session, &ClockSample { time: SystemTime::now() })?;
+        write_bincoded(&mut session, &AuthRequest { token: None, mode: SessionMode::Fetch, module: None, checksum_proof: None })?;
+        for command in commands {
+            write_bincoded(&mut session, command)?;
+        }
+        Ok(session)
+    }
+
+    fn read_payload(response: &mut &[u8]) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        FramedRead::new(response).read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+
+    fn skip_handshake(response: &mut &[u8]) -> Result<()> {
+        assert_eq!(read_hello(&mut *response)?, Hello::current());
+        read_bincoded::<_, ClockSample>(&mut *response)?;
+        read_bincoded::<_, AuthChallenge>(&mut *response)?;
+        assert_eq!(read_bincoded::<_, AuthResponse>(&mut *response)?, AuthResponse::Accepted);
+        Ok(())
+    }
+
+    #[test]
+    fn version_handshake_matches() -> Result<()> {
+        let mut request = Vec::new();
+        write_unframed(&mut request, &Hello::current())?;
+        write_bincoded(&mut request, &ClockSample { time: SystemTime::now() })?;
+        let mut response = Vec::new();
+
+        accept_version(request.as_slice(), &mut response)?;
+        let skew = offer_version(response.as_slice(), std::io::sink())?;
+        assert!(skew.abs() < 1000, "{}", skew);
+        Ok(())
+    }
+
+    #[test]
+    fn version_handshake_measures_clock_skew() -> Result<()> {
+        let mut response = Vec::new();
+        write_unframed(&mut response, &Hello::current())?;
+        write_bincoded(&mut response, &ClockSample { time: SystemTime::now() + Duration::from_secs(3600) })?;
+
+        let skew = offer_version(response.as_slice(), std::io::sink())?;
+        assert!((skew - 3_600_000).abs() < 1000, "{}", skew);
+        Ok(())
+    }
+
+    #[test]
+    fn version_handshake_rejects_other_version() -> Result<()> {
+        let mut request = Vec::new();
+        write_unframed(&mut request, &Hello { magic: PROTOCOL_MAGIC, version: PROTOCOL_VERSION + 1 })?;
+        let mut response = Vec::new();
+
+        let server = accept_version(request.as_slice(), &mut response).unwrap_err();
+        assert_eq!(server.kind(), ErrorKind::InvalidData);
+        assert!(server.to_string().contains("Incompatible usync versions"), "{}", server);
+        assert!(matches!(UsyncError::of(&server), Some(UsyncError::VersionMismatch(_))));
+
+        // the sender still answered with its own version, so a newer client can tell as well
+        let mut response = response.as_slice();
+        assert_eq!(read_hello(&mut response)?, Hello::current());
+        Ok(())
+    }
+
+    #[test]
+    fn version_handshake_rejects_foreign_peer() {
+        let client = offer_version(&b"SSH-2.0-OpenSSH"[..], std::io::sink()).unwrap_err();
+        assert!(client.to_string().contains("did not identify as usync"), "{}", client);
+
+        let client = offer_version(&b""[..], std::io::sink()).unwrap_err();
+        assert!(client.to_string().contains("Incompatible usync versions"), "{}", client);
+    }
+
+    #[test]
+    fn handler_rejects_client_without_handshake() -> Result<()> {
+        let root = TempDir::new()?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+        let mut commands = Vec::new();
+        write_bincoded(&mut commands, &Command::SendManifest)?;
+        write_bincoded(&mut commands, &Command::End)?;
+
+        let error = command_handler_loop(root.path(), &manifest, commands.as_slice(), std::io::sink(), &DefaultFileAccess, &SenderSettings::default()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn resumed_send_reads_only_remainder() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"0123456789")?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+
+        let commands = session(&[Command::SendFile(PortablePath::from("file.txt")?, 6), Command::End])?;
+
+        let access = CountingAccess(Cell::new(0));
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &&access, &SenderSettings::default())?;
+
+        let mut response = response.as_slice();
+        skip_handshake(&mut response)?;
+        let attrs = read_bincoded::<_, FileResponse>(&mut response)?.unwrap();
+        assert_eq!(attrs.size, 10);
+        assert_eq!(read_payload(&mut response)?, b"6789");
+        assert!(response.is_empty());
+        assert_eq!(access.0.get(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batched_files_are_sent_back_to_back() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("a.txt"))?.write_all(b"abc")?;
+        File::create(root.path().join("b.txt"))?.write_all(b"de")?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+
+        let batch = vec![PortablePath::from("a.txt")?, PortablePath::from("b.txt")?];
+        let commands = session(&[Command::SendFiles(batch), Command::End])?;
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut response = response.as_slice();
+        skip_handshake(&mut response)?;
+        assert_eq!(read_bincoded::<_, FileResponse>(&mut response)?.unwrap().size, 3);
+        assert_eq!(read_payload(&mut response)?, b"abc");
+        assert_eq!(read_bincoded::<_, FileResponse>(&mut response)?.unwrap().size, 2);
+        assert_eq!(read_payload(&mut response)?, b"de");
+        assert!(response.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn creation_time_is_sent_with_crtimes() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("a.txt"))?.write_all(b"abc")?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+        let created = crate::file_transfer::crtimes::read(&root.path().join("a.txt").metadata()?);
+
+        for crtimes in &[false, true] {
+            let commands = session(&[Command::SendFile(PortablePath::from("a.txt")?, 0), Command::End])?;
+            let settings = SenderSettings { crtimes: *crtimes, ..SenderSettings::default() };
+            let mut response = Vec::new();
+            command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &settings)?;
+
+            let mut response = response.as_slice();
+            skip_handshake(&mut response)?;
+            let attrs = read_bincoded::<_, FileResponse>(&mut response)?.unwrap();
+            assert_eq!(attrs.created, if *crtimes { created } else { None });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_file_is_reported_to_receiver() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("b.txt"))?.write_all(b"de")?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+
+        let batch = vec![PortablePath::from("missing.txt")?, PortablePath::from("b.txt")?];
+        let commands = session(&[Command::SendFiles(batch), Command::End])?;
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut response = response.as_slice();
+        skip_handshake(&mut response)?;
+        let target = TempDir::new()?;
+        let files = vec![(PathBuf::from("missing.txt"), None), (PathBuf::from("b.txt"), None)];
+        let mut transmitter = CommandTransmitter::new(target.path(), response, std::io::sink(), PrivilegeMode::Auto)
+            .with_batch_size(2);
+        let transferred = transmitter.transmit_batch(&files)?;
+
+        let error = transferred[0].as_ref().unwrap_err();
+        assert!(error.to_string().starts_with("Sender could not read missing.txt"), "{}", error);
+        assert!(is_file_error(error));
+        assert_eq!(*transferred[1].as_ref().unwrap(), 2);
+        assert_eq!(std::fs::read(target.path().join("b.txt"))?, b"de");
+        Ok(())
+    }
+
+    #[test]
+    fn empty_files_keep_the_stream_in_sync() -> Result<()> {
+        let root = TempDir::new()?;
+        let names = ["empty1", "a.txt", "empty2", "empty3", "b.txt", "empty4"];
+        for (index, name) in names.iter().enumerate() {
+            let contents: &[u8] = if name.starts_with("empty") { b"" } else { b"contents" };
+            std::fs::write(root.path().join(name), contents)?;
+            set_file_mtime(root.path().join(name), FileTime::from_unix_time(1_500_000_000 + index as i64, 123))?;
+        }
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+
+        let batch = names[..4].iter().map(PortablePath::from).collect::<Result<Vec<_>>>()?;
+        let commands = session(&[Command::SendFiles(batch), Command::SendFile(PortablePath::from("b.txt")?, 0),
+                                 Command::SendFile(PortablePath::from("empty4")?, 0), Command::End])?;
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut response = response.as_slice();
+        skip_handshake(&mut response)?;
+        let target = TempDir::new()?;
+        let mut transmitter = CommandTransmitter::new(target.path(), response, std::io::sink(), PrivilegeMode::Auto)
+            .with_batch_size(4);
+        let files = names[..4].iter().map(|name| (PathBuf::from(name), None)).collect::<Vec<_>>();
+        let transferred = transmitter.transmit_batch(&files)?.into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(transferred, vec![0, 8, 0, 0]);
+        assert_eq!(transmitter.transmit(Path::new("b.txt"), None)?, 8);
+        assert_eq!(transmitter.transmit(Path::new("empty4"), None)?, 0);
+
+        for name in &names {
+            let (source, target) = (root.path().join(name), target.path().join(name));
+            assert_eq!(std::fs::read(&source)?, std::fs::read(&target)?, "contents of {}", name);
+            assert_eq!(FileTime::from_last_modification_time(&source.metadata()?),
+                       FileTime::from_last_modification_time(&target.metadata()?), "modification time of {}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn payload_past_announced_size_is_rejected() -> Result<()> {
+        let root = TempDir::new()?;
+        let mut response = partial_file_response(0, b"xyz", true);
+        response.extend(file_response(b"de"));
+        let files = vec![(PathBuf::from("empty.txt"), None), (PathBuf::from("b.txt"), None)];
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_batch_size(2);
+        let transferred = transmitter.transmit_batch(&files)?;
+        let error = transferred[0].as_ref().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("past the 0 bytes announced"), "{}", error);
+        assert!(!root.path().join("empty.txt").exists());
+        // the rest of the payload was skipped, the next file is read from where it starts
+        assert_eq!(*transferred[1].as_ref().unwrap(), 2);
+        assert_eq!(std::fs::read(root.path().join("b.txt"))?, b"de");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_batch_requests_files_at_once() -> Result<()> {
+        let root = TempDir::new()?;
+        let mut response = file_response(b"abc");
+        response.extend(file_response(b"de"));
+        let expected = crate::tree::hash(&b"abc"[..])?;
+        let files = vec![(PathBuf::from("a.txt"), Some(&expected)), (PathBuf::from("sub/b.txt"), None)];
+
+        let mut commands = Vec::new();
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), &mut commands, PrivilegeMode::Auto)
+            .with_batch_size(2);
+        let transferred = transmitter.transmit_batch(&files)?.into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(transferred, vec![3, 2]);
+        drop(transmitter);
+
+        let batch = vec![PortablePath::from("a.txt")?, PortablePath::from("sub/b.txt")?];
+        assert_eq!(read_bincoded::<_, Command>(commands.as_slice())?, Command::SendFiles(batch));
+        assert_eq!(std::fs::read(root.path().join("a.txt"))?, b"abc");
+        assert_eq!(std::fs::read(root.path().join("sub/b.txt"))?, b"de");
+        Ok(())
+    }
+
+    fn handshake(server_token: Option<&str>, client_token: Option<&str>) -> (Result<SessionMode>, Result<()>) {
+        session_handshake(server_token, client_token, SessionMode::Fetch, false)
+    }
+
+    fn session_handshake(server_token: Option<&str>, client_token: Option<&str>, mode: SessionMode, allow_push: bool) -> (Result<SessionMode>, Result<()>) {
+        let mut request = Vec::new();
+        write_bincoded(&mut request, &AuthRequest { token: client_token.map(String::from), mode, module: None, checksum_proof: None }).unwrap();
+
+        let mut response = Vec::new();
+        let server = grant_access(request.as_slice(), &mut response, Access { token: server_token.map(String::from), allow_push, checksum_key: None });
+        let client = request_access(response.as_slice(), std::io::sink(), client_token, mode, None, None);
+
+        (server, client)
+    }
+
+    #[test]
+    fn handshake_negotiates_push() {
+        let (server, client) = session_handshake(Some("secret"), Some("secret"), SessionMode::Push, true);
+        assert_eq!(server.unwrap(), SessionMode::Push);
+        assert!(client.is_ok());
+
+        let (server, client) = session_handshake(None, None, SessionMode::Push, false);
+        assert_eq!(server.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(client.unwrap_err().to_string().contains("does not accept pushes"));
+    }
+
+    #[test]
+    fn handshake_selects_module() {
+        let lookup = |module: Option<&str>| match module {
+            Some("photos") => Some(("photos", Access { token: Some(String::from("secret")), ..Access::default() })),
+            _ => None,
+        };
+        let mut request = Vec::new();
+        write_bincoded(&mut request, &AuthRequest { token: Some(String::from("secret")), mode: SessionMode::Fetch, module: Some(String::from("photos")), checksum_proof: None }).unwrap();
+        let mut response = Vec::new();
+        assert_eq!(grant_module_access(request.as_slice(), &mut response, lookup).unwrap(), ("photos", SessionMode::Fetch));
+        assert!(request_access(response.as_slice(), std::io::sink(), Some("secret"), SessionMode::Fetch, Some("photos"), None).is_ok());
+
+        let mut request = Vec::new();
+        write_bincoded(&mut request, &AuthRequest { token: None, mode: SessionMode::Fetch, module: Some(String::from("music")), checksum_proof: None }).unwrap();
+        let mut response = Vec::new();
+        assert_eq!(grant_module_access(request.as_slice(), &mut response, lookup).unwrap_err().kind(), ErrorKind::NotFound);
+        let client = request_access(response.as_slice(), std::io::sink(), None, SessionMode::Fetch, Some("music"), None).unwrap_err();
+        assert!(client.to_string().contains("no module named music"), "{}", client);
+    }
+
+    #[test]
+    fn handshake_rejects_other_checksum_seed() {
+        let cases = [(Some("seed"), Some("seed"), true), (Some("seed"), Some("other"), false), (Some("seed"), None, false), (None, Some("seed"), false)];
+        for (server_seed, client_seed, accepted) in &cases {
+            let server_key = server_seed.map(ChecksumKey::from_seed);
+            let client_key = client_seed.map(ChecksumKey::from_seed);
+            // the client's proof depends on the server's challenge, so both sides run at once
+            let (to_client, from_server) = pipe(4);
+            let (to_server, from_client) = pipe(4);
+            let server = std::thread::spawn(move || grant_access(from_client, to_client, Access { checksum_key: server_key, ..Access::default() }));
+            let client = request_access(from_server, to_server, None, SessionMode::Fetch, None, client_key.as_ref());
+            let server = server.join().unwrap();
+
+            assert_eq!(server.is_ok(), *accepted, "{:?} against {:?}", client_seed, server_seed);
+            match client {
+                Ok(()) => assert!(accepted),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_accepts_matching_token() {
+        let (server, client) = handshake(Some("secret"), Some("secret"));
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn handshake_rejects_wrong_or_missing_token() {
+        for token in &[Some("guess"), None] {
+            let (server, client) = handshake(Some("secret"), *token);
+            let (server, client) = (server.unwrap_err(), client.unwrap_err());
+            assert_eq!(server.kind(), ErrorKind::PermissionDenied);
+            assert_eq!(client.kind(), ErrorKind::PermissionDenied);
+            assert!(matches!(UsyncError::of(&server), Some(UsyncError::Auth(_))));
+            assert!(matches!(UsyncError::of(&client), Some(UsyncError::Auth(_))));
+        }
+    }
+
+    #[test]
+    fn handshake_without_server_token() {
+        let (server, client) = handshake(None, Some("unused"));
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn manifest_is_sent_per_directory() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("a/b"))?;
+        std::fs::create_dir_all(root.path().join("c"))?;
+        File::create(root.path().join("a/b/file.txt"))?.write_all(b"content")?;
+        File::create(root.path().join("top.txt"))?.write_all(b"top")?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+
+        let commands = session(&[Command::SendManifest, Command::End])?;
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut input = response.as_slice();
+        skip_handshake(&mut input)?;
+        let mut messages = 0;
+        let (algorithm, mode, key_id) = read_bincoded(&mut input)?;
+        let received = Manifest::from_chunks(algorithm, mode, key_id, &mut || {
+            messages += 1;
+            read_bincoded(&mut input)
+        })?;
+
+        assert_eq!(messages, 4);
+        assert!(input.is_empty());
+        assert_eq!(bincode::serialize(&received).unwrap(), bincode::serialize(&manifest).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn hashed_manifest_in_timestamp_mode() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abc")?;
+        let settings = test_support::default_settings().with_manifest_mode(crate::config::ManifestMode::TimestampTest);
+        let manifest = Manifest::create_ephemeral(root.path(), &settings)?;
+
+        let commands = session(&[Command::SendHashedManifest, Command::End])?;
+        let mut response = Vec::new();
+        command_handler_loop(root.path(), &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut input = response.as_slice();
+        skip_handshake(&mut input)?;
+        let (algorithm, mode, key_id) = read_bincoded(&mut input)?;
+        let received = Manifest::from_chunks(algorithm, mode, key_id, &mut || read_bincoded(&mut input))?;
+        assert_eq!(received.manifest_mode(), crate::config::ManifestMode::Hash);
+
+        let hashed = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+        assert!(manifest.mismatches(&hashed).len() == 1);
+        assert!(hashed.mismatches(&received).is_empty());
+        Ok(())
+    }
+
+    fn file_response(content: &[u8]) -> Vec<u8> {
+        partial_file_response(content.len() as u64, content, true)
+    }
+
+    /// Response to a request for a file of `size` bytes, of which `content` is sent. Without
+    /// `complete`, the connection drops before the end of the file.
+    fn partial_file_response(size: u64, content: &[u8], complete: bool) -> Vec<u8> {
+        let mut response = Vec::new();
+        let owner = Ownership { uid: 0, gid: 0 };
+        write_bincoded(&mut response, &FileResponse::Ok(FileAttributes::new(size, Timestamp::from(SystemTime::now()), owner))).unwrap();
+        if complete {
+            let mut payload = FramedWrite::new(&mut response);
+            payload.write_all(content).unwrap();
+            payload.finish().unwrap();
+        } else {
+            write_frame(&mut response, content).unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn transmit_verifies_hash() -> Result<()> {
+        let root = TempDir::new()?;
+        let response = file_response(b"abc");
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto);
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), Some(&expected))?, 3);
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_appends_to_shorter_file() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::write(root.path().join("file.txt"), b"ab")?;
+        let response = partial_file_response(3, b"c", true);
+
+        let mut commands = Vec::new();
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), &mut commands, PrivilegeMode::Auto)
+            .with_append(Some(AppendMode::Trust));
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), None)?, 1);
+        drop(transmitter);
+
+        assert_eq!(read_bincoded::<_, Command>(commands.as_slice())?, Command::SendFile(PortablePath::from("file.txt")?, 2));
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn append_falls_back_to_whole_file() -> Result<()> {
+        let root = TempDir::new()?;
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        // the target doesn't start like the sender's file
+        std::fs::write(root.path().join("file.txt"), b"xb")?;
+        let mut response = partial_file_response(3, b"c", true);
+        response.extend(file_response(b"abc"));
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_append(Some(AppendMode::Verify));
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), Some(&expected))?, 3);
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+
+        // the sender's file did not grow, so nothing follows the target's content
+        std::fs::write(root.path().join("file.txt"), b"xyz")?;
+        let mut response = partial_file_response(3, b"", true);
+        response.extend(file_response(b"abc"));
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_append(Some(AppendMode::Trust));
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), None)?, 3);
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn send_file_sends_announced_size() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("short.txt"))?.write_all(b"abc")?;
+        File::create(root.path().join("long.txt"))?.write_all(b"0123456789")?;
+        let settings = SenderSettings::default();
+
+        let mut response = Vec::new();
+        let grown = ChangedAccess(root.path().join("short.txt"));
+        send_file(&root.path().join("long.txt"), 0, &grown, &settings, None, &mut response)?;
+        let mut input = response.as_slice();
+        assert_eq!(read_bincoded::<_, FileResponse>(&mut input)?.unwrap().size, 3);
+        assert_eq!(read_payload(&mut input)?, b"012");
+        assert!(input.is_empty());
+
+        let mut response = Vec::new();
+        let shrunk = ChangedAccess(root.path().join("long.txt"));
+        send_file(&root.path().join("short.txt"), 0, &shrunk, &settings, None, &mut response)?;
+        let mut input = response.as_slice();
+        assert_eq!(read_bincoded::<_, FileResponse>(&mut input)?.unwrap().size, 10);
+        assert_eq!(read_payload(&mut input)?, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn received_file_keeps_exact_mtime() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let mtimes = [FileTime::from_unix_time(1_000_000, 123_456_789), FileTime::from_unix_time(-1000, 987_654_321)];
+        let mut response = Vec::new();
+        for (index, mtime) in mtimes.iter().enumerate() {
+            let file = source.path().join(format!("{}.txt", index));
+            File::create(&file)?.write_all(b"abc")?;
+            set_file_mtime(&file, *mtime)?;
+            send_file(&file, 0, &DefaultFileAccess, &SenderSettings::default(), None, &mut response)?;
+        }
+
+        let mut input = response.as_slice();
+        let mut transmitter = CommandTransmitter::new(target.path(), &mut input, std::io::sink(), PrivilegeMode::Auto);
+        for index in 0..mtimes.len() {
+            transmitter.transmit(Path::new(&format!("{}.txt", index)), None)?;
+        }
+        drop(transmitter);
+
+        let settings = crate::config::test_support::default_settings();
+        let sent = Manifest::create_ephemeral(source.path(), &settings)?;
+        let received = Manifest::create_ephemeral(target.path(), &settings)?;
+        assert_eq!(received.files(), sent.files());
+        assert_eq!(received.transfer_totals(&sent, settings.comparison()).files, 0);
+
+        // the time before the epoch survives the manifest's encoding as well
+        let mut written = Vec::new();
+        sent.write_to(&mut written)?;
+        assert_eq!(Manifest::read_from(written.as_slice(), &settings)?.files(), sent.files());
+        Ok(())
+    }
+
+    /// Reads the first bytes of a file, then fails.
+    struct BrokenAccess;
+
+    impl FileAccess for BrokenAccess {
+        type Read = std::io::Chain<std::io::Take<File>, BrokenRead>;
+
+        fn stat(&self, path: &Path, privilege: PrivilegeMode) -> Result<FileStat> {
+            DefaultFileAccess.stat(path, privilege)
+        }
+
+        fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+            Ok(DefaultFileAccess.read(path, offset)?.take(2).chain(BrokenRead))
+        }
+    }
+
+    struct BrokenRead;
+
+    impl Read for BrokenRead {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+            Err(Error::new(ErrorKind::Other, "input/output error"))
+        }
+    }
+
+    #[test]
+    fn send_file_ends_payload_on_read_error() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abcdef")?;
+
+        let mut response = Vec::new();
+        send_file(&root.path().join("file.txt"), 0, &BrokenAccess, &SenderSettings::default(), None, &mut response)?;
+        response.extend_from_slice(b"next message");
+        let mut input = response.as_slice();
+        assert_eq!(read_bincoded::<_, FileResponse>(&mut input)?.unwrap().size, 6);
+        assert_eq!(read_payload(&mut input)?, b"ab");
+        assert_eq!(input, b"next message");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_discards_short_file() -> Result<()> {
+        let root = TempDir::new()?;
+        let mut response = partial_file_response(5, b"abc", true);
+        response.extend(file_response(b"de"));
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto);
+        let error = transmitter.transmit(Path::new("a.txt"), None).unwrap_err();
+        assert!(matches!(UsyncError::of(&error), Some(UsyncError::Protocol(_))), "{}", error);
+        assert!(!root.path().join("a.txt").exists());
+        assert_eq!(transmitter.transmit(Path::new("b.txt"), None)?, 2);
+        assert_eq!(std::fs::read(root.path().join("b.txt"))?, b"de");
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_discards_corrupted_file() -> Result<()> {
+        let root = TempDir::new()?;
+        let response = file_response(b"abd");
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto);
+        let error = transmitter.transmit(Path::new("file.txt"), Some(&expected)).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("file.txt"), "{}", error);
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_aborts_on_corrupted_payload() -> Result<()> {
+        let root = TempDir::new()?;
+        let mut response = file_response(b"abc");
+        let end_frame = 8;
+        let last = response.len() - end_frame - 1;
+        response[last] ^= 0x10;
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto);
+        let error = transmitter.transmit(Path::new("file.txt"), None).unwrap_err();
+
+        assert_ne!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Corrupted data from peer"), "{}", error);
+        assert!(!root.path().join("file.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_partial_files_are_removed() -> Result<()> {
+        let source = TempDir::new()?;
+        std::fs::write(source.path().join("a.txt"), b"abc")?;
+        let manifest = Manifest::create_ephemeral(source.path(), &test_support::default_settings())?;
+
+        let root = TempDir::new()?;
+        std::fs::create_dir(root.path().join("sub"))?;
+        for name in &["a.txt.usync-partial", "gone.txt.usync-partial", "sub/b.txt.usync-partial", "c.txt"] {
+            std::fs::write(root.path().join(name), b"ab")?;
+        }
+        let transmitter = CommandTransmitter::new(root.path(), &b""[..], std::io::sink(), PrivilegeMode::Auto);
+        assert_eq!(transmitter.remove_stale_partials(&manifest, Some(Path::new("sub")))?, 1);
+        assert!(root.path().join("gone.txt.usync-partial").exists());
+        assert_eq!(transmitter.remove_stale_partials(&manifest, None)?, 1);
+        assert!(root.path().join("a.txt.usync-partial").exists());
+        assert!(!root.path().join("gone.txt.usync-partial").exists());
+        assert!(!root.path().join("sub/b.txt.usync-partial").exists());
+        assert!(root.path().join("c.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_transmit_keeps_partial_file() -> Result<()> {
+        let root = TempDir::new()?;
+        let response = partial_file_response(3, b"ab", false);
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto);
+        let error = transmitter.transmit(Path::new("file.txt"), Some(&expected)).unwrap_err();
+
+        // the connection broke off, unlike a payload the sender ended early
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::read(root.path().join("file.txt.usync-partial"))?, b"ab");
+        assert!(!root.path().join("file.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_resumes_partial_file() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::write(root.path().join("file.txt.usync-partial"), b"ab")?;
+        let response = partial_file_response(3, b"c", true);
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut commands = Vec::new();
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), &mut commands, PrivilegeMode::Auto);
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), Some(&expected))?, 1);
+        drop(transmitter);
+
+        assert_eq!(read_bincoded::<_, Command>(commands.as_slice())?, Command::SendFile(PortablePath::from("file.txt")?, 2));
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        assert!(!root.path().join("file.txt.usync-partial").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn whole_file_transmit_ignores_partial_file() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::write(root.path().join("file.txt.usync-partial"), b"xy")?;
+        let response = file_response(b"abc");
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut commands = Vec::new();
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), &mut commands, PrivilegeMode::Auto)
+            .with_whole_file(true);
+        assert!(!transmitter.resumable(Path::new("file.txt"), Some(&expected))?);
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), Some(&expected))?, 3);
+        drop(transmitter);
+
+        assert_eq!(read_bincoded::<_, Command>(commands.as_slice())?, Command::SendFile(PortablePath::from("file.txt")?, 0));
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        assert!(!root.path().join("file.txt.usync-partial").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_transmit_keeps_partial_in_temp_dir() -> Result<()> {
+        let root = TempDir::new()?;
+        let temp = TempDir::new()?;
+        let response = partial_file_response(3, b"ab", false);
+        let expected = crate::tree::hash(&b"abc"[..])?;
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_temp_dir(Some(temp.path()));
+        transmitter.transmit(Path::new("sub/file.txt"), Some(&expected)).unwrap_err();
+        assert!(!root.path().join("sub/file.txt.usync-partial").exists());
+        let staged: Vec<_> = std::fs::read_dir(temp.path())?.collect::<Result<_>>()?;
+        assert_eq!(staged.len(), 1);
+        assert_eq!(std::fs::read(staged[0].path())?, b"ab");
+
+        let resumed = partial_file_response(3, b"c", true);
+        let mut transmitter = CommandTransmitter::new(root.path(), resumed.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_temp_dir(Some(temp.path()));
+        assert_eq!(transmitter.transmit(Path::new("sub/file.txt"), Some(&expected))?, 1);
+        assert_eq!(std::fs::read(root.path().join("sub/file.txt"))?, b"abc");
+        assert_eq!(std::fs::read_dir(temp.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_stages_in_temp_dir() -> Result<()> {
+        let root = TempDir::new()?;
+        let temp = TempDir::new()?;
+        let response = file_response(b"abc");
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_temp_dir(Some(temp.path()));
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), None)?, 3);
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 1);
+        assert_eq!(std::fs::read_dir(temp.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn delayed_transmit_waits_for_commit() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::write(root.path().join("file.txt"), b"old")?;
+        let response = file_response(b"abc");
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_delay_updates(true);
+        assert_eq!(transmitter.transmit(Path::new("file.txt"), None)?, 3);
+        transmitter.hard_link(Path::new("file.txt"), Path::new("link.txt"))?;
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"old");
+        assert!(!root.path().join("link.txt").exists());
+
+        transmitter.commit()?;
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"abc");
+        assert_eq!(std::fs::read(root.path().join("link.txt"))?, b"abc");
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn delayed_transmit_is_discarded_without_commit() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::write(root.path().join("file.txt"), b"old")?;
+        let response = file_response(b"abc");
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_delay_updates(true);
+        transmitter.transmit(Path::new("file.txt"), None)?;
+        drop(transmitter);
+        assert_eq!(std::fs::read(root.path().join("file.txt"))?, b"old");
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transmit_refuses_file_below_min_free() -> Result<()> {
+        let root = TempDir::new()?;
+        let response = file_response(b"abc");
+
+        let mut transmitter = CommandTransmitter::new(root.path(), response.as_slice(), std::io::sink(), PrivilegeMode::Auto)
+            .with_min_free(Some(u64::MAX));
+        let error = transmitter.transmit(Path::new("file.txt"), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert!(!is_file_error(&error));
+        assert_eq!(std::fs::read_dir(root.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn portable_path_segments() -> Result<()> {
+        let path = PortablePath::from("a/b/c.txt")?;
+        assert_eq!(path.segments, vec!["a", "b", "c.txt"]);
+        assert_eq!(path.relative_to(Path::new("/root"))?, PathBuf::from("/root/a/b/c.txt"));
+        for segments in &[vec![".."], vec!["a", "."], vec!["/etc"], vec!["a/b"], vec![""]] {
+            let path = PortablePath { segments: segments.iter().map(|s| String::from(*s)).collect() };
+            assert!(path.relative_to(Path::new("/root")).is_err(), "{:?}", segments);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn serve_commands_refuses_paths_leaving_the_root() -> Result<()> {
+        let parent = TempDir::new()?;
+        let root = parent.path().join("module");
+        std::fs::create_dir(&root)?;
+        std::fs::write(parent.path().join("x"), b"secret")?;
+        let manifest = Manifest::create_ephemeral(&root, &crate::config::HashSettings::default())?;
+
+        let mut commands = Vec::new();
+        let escaping = PortablePath { segments: vec![String::from(".."), String::from("x")] };
+        write_bincoded(&mut commands, &Command::SendFile(escaping, 0))?;
+        write_bincoded(&mut commands, &Command::End)?;
+        let mut response = Vec::new();
+        serve_commands(&root, &manifest, commands.as_slice(), &mut response, &DefaultFileAccess, &SenderSettings::default())?;
+
+        let mut input = response.as_slice();
+        let refused = read_bincoded::<_, FileResponse>(&mut input)?;
+        assert!(refused.err().is_some_and(|message| message.contains("leaves the tree")));
+        assert!(input.is_empty());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn portable_path_rejects_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new("dir").join(OsStr::from_bytes(b"caf\xe9.txt"));
+        let error = PortablePath::from(&path).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("dir/caf"), "{}", error);
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::tree::{Change, ShaSum};
+use super::Transmitter;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between reconnection attempts, starting at one second and doubling up
+/// to a minute.
+pub struct Backoff {
+    remaining: u32,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(retries: u32) -> Backoff {
+        Backoff { remaining: retries, delay: INITIAL_DELAY }
+    }
+
+    /// Returns how long to wait before the next attempt, or `None` if all retries are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+        Some(delay)
+    }
+}
+
+/// True for errors caused by losing the connection to the peer, which a reconnect may fix.
+pub fn is_transient(error: &Error) -> bool {
+    matches!(error.kind(),
+        ErrorKind::UnexpectedEof |
+        ErrorKind::BrokenPipe |
+        ErrorKind::ConnectionRefused |
+        ErrorKind::ConnectionReset |
+        ErrorKind::ConnectionAborted |
+        ErrorKind::NotConnected |
+        ErrorKind::TimedOut |
+        ErrorKind::Interrupted)
+}
+
+/// Transmitter decorator remembering which files were already received, so that a copy
+/// restarted after a reconnect skips them instead of transferring or counting them again.
+pub struct ResumingTransmitter<'a, T: Transmitter> {
+    inner: T,
+    completed: &'a mut HashSet<PathBuf>,
+}
+
+impl<'a, T: Transmitter> ResumingTransmitter<'a, T> {
+    pub fn new(inner: T, completed: &'a mut HashSet<PathBuf>) -> ResumingTransmitter<'a, T> {
+        ResumingTransmitter { inner, completed }
+    }
+}
+
+impl<T: Transmitter> Transmitter for ResumingTransmitter<'_, T> {
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        let transferred = self.inner.transmit(path, expected)?;
+        self.completed.insert(path.to_owned());
+        Ok(transferred)
+    }
+
+    fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    fn already_transferred(&self, path: &Path) -> bool {
+        self.completed.contains(path)
+    }
+
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        let transferred = self.inner.transmit_batch(files)?;
+        for ((path, _), result) in files.iter().zip(&transferred) {
+            if result.is_ok() {
+                self.completed.insert(path.clone());
+            }
+        }
+        Ok(transferred)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        self.inner.create_directory(path)
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        self.inner.hard_link(existing, path)
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.inner.set_permissions(path, mode)
+    }
+
+    fn itemize(&mut self, path: &Path, change: Change) {
+        self.inner.itemize(path, change)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test_retry {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_limit() {
+        let mut backoff = Backoff::new(8);
+        let delays: Vec<u64> = std::iter::from_fn(|| backoff.next_delay()).map(|d| d.as_secs()).collect();
+
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn no_retries_without_budget() {
+        assert_eq!(Backoff::new(0).next_delay(), None);
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&Error::from(ErrorKind::UnexpectedEof)));
+        assert!(is_transient(&Error::from(ErrorKind::ConnectionReset)));
+        assert!(!is_transient(&Error::from(ErrorKind::InvalidData)));
+        assert!(!is_transient(&Error::from(ErrorKind::PermissionDenied)));
+    }
+
+    struct FlakyTransmitter {
+        fail: bool,
+        sent: Vec<PathBuf>,
+    }
+
+    impl Transmitter for FlakyTransmitter {
+        fn transmit(&mut self, path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            if self.fail {
+                return Err(Error::from(ErrorKind::ConnectionReset));
+            }
+            self.sent.push(path.to_owned());
+            Ok(1)
+        }
+
+        fn create_directory(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completed_files_are_skipped() -> Result<()> {
+        let mut completed = HashSet::new();
+
+        let mut first = ResumingTransmitter::new(FlakyTransmitter { fail: false, sent: vec![] }, &mut completed);
+        first.transmit(Path::new("a"), None)?;
+        first.inner.fail = true;
+        assert!(first.transmit(Path::new("b"), None).is_err());
+
+        let second = ResumingTransmitter::new(FlakyTransmitter { fail: false, sent: vec![] }, &mut completed);
+        assert!(second.already_transferred(Path::new("a")));
+        assert!(!second.already_transferred(Path::new("b")));
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Bytes available to unprivileged users on the filesystem holding `path`, or `None` where
+/// the platform offers no way to tell.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Refuses incoming files that would leave less than a minimum of free space behind
+/// (`--min-free`).
+#[derive(Debug, Copy, Clone)]
+pub struct SpaceGuard {
+    min_free: u64,
+}
+
+impl SpaceGuard {
+    pub fn new(min_free: u64) -> SpaceGuard {
+        SpaceGuard { min_free }
+    }
+
+    /// Fails with `StorageFull` if writing `incoming` more bytes below `dir` would leave less
+    /// than the minimum free on its filesystem.
+    pub fn check(&self, dir: &Path, incoming: u64) -> Result<()> {
+        if let Some(available) = available_space(dir)? {
+            if available.saturating_sub(incoming) < self.min_free {
+                return Err(Error::new(ErrorKind::StorageFull, format!(
+                    "Only {} bytes are free on the filesystem of {}, receiving another {} bytes would leave less than --min-free {} bytes",
+                    available, dir.display(), incoming, self.min_free)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_space {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn guard_allows_files_that_fit() -> Result<()> {
+        let dir = TempDir::new()?;
+        SpaceGuard::new(0).check(dir.path(), 1)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn guard_refuses_files_that_cut_into_the_reserve() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert!(available_space(dir.path())?.is_some());
+        let error = SpaceGuard::new(u64::MAX).check(dir.path(), 1).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert!(error.to_string().contains("--min-free"), "{}", error);
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+
+use crate::util::{copy_buffered, DEFAULT_IO_BUFFER_SIZE};
+
+/// Smallest run of zero bytes turned into a hole unless configured otherwise, the block size
+/// of most filesystems.
+pub const DEFAULT_MIN_HOLE: u64 = 4096;
+/// Largest `--min-hole` accepted, as a block of that size is buffered while writing.
+pub const MAX_MIN_HOLE: u64 = 4 << 20;
+
+/// Writer that seeks over zero bytes instead of writing them, leaving a sparse file. Data is
+/// looked at in blocks of `min_hole` bytes, only blocks that are entirely zero become holes.
+pub struct SparseWrite<'a> {
+    file: &'a mut File,
+    min_hole: usize,
+    buffer: Vec<u8>,
+    /// Zero bytes skipped since the last write
+    hole: u64,
+}
+
+impl<'a> SparseWrite<'a> {
+    pub fn new(file: &'a mut File, min_hole: u64) -> SparseWrite<'a> {
+        let min_hole = min_hole.max(1) as usize;
+        SparseWrite { file, min_hole, buffer: Vec::with_capacity(min_hole), hole: 0 }
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        if block.iter().all(|byte| *byte == 0) && block.len() == self.min_hole {
+            self.hole += block.len() as u64;
+            return Ok(());
+        }
+        if self.hole > 0 {
+            self.file.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        self.file.write_all(block)
+    }
+
+    /// Writes what is still buffered, and extends the file over a trailing hole, which
+    /// seeking alone leaves out.
+    pub fn finish(mut self) -> Result<()> {
+        let rest = std::mem::take(&mut self.buffer);
+        if !rest.is_empty() {
+            self.write_block(&rest)?;
+        }
+        if self.hole > 0 {
+            let end = self.file.seek(SeekFrom::Current(self.hole as i64))?;
+            self.file.set_len(end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for SparseWrite<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let blocks = self.buffer.len() / self.min_hole * self.min_hole;
+        let full = std::mem::take(&mut self.buffer);
+        for block in full[..blocks].chunks(self.min_hole) {
+            self.write_block(block)?;
+        }
+        self.buffer = full[blocks..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+/// How `copy_to_file` writes received data.
+#[derive(Debug, Copy, Clone)]
+pub struct CopySettings {
+    /// Smallest run of zero bytes left as a hole, if files are written sparse
+    pub min_hole: Option<u64>,
+    /// Size of the buffer data is copied through
+    pub buffer_size: usize,
+}
+
+impl Default for CopySettings {
+    fn default() -> Self {
+        CopySettings { min_hole: None, buffer_size: DEFAULT_IO_BUFFER_SIZE }
+    }
+}
+
+/// Copies `reader` to the current position of `file`, leaving holes for zero runs of at least
+/// `settings.min_hole` bytes if given. Returns the number of bytes copied, holes included.
+pub fn copy_to_file<R: Read>(reader: &mut R, file: &mut File, settings: CopySettings) -> Result<u64> {
+    match settings.min_hole {
+        Some(min_hole) => {
+            let mut output = SparseWrite::new(file, min_hole);
+            let copied = copy_buffered(reader, &mut output, settings.buffer_size)?;
+            output.finish()?;
+            Ok(copied)
+        }
+        None => copy_buffered(reader, file, settings.buffer_size),
+    }
+}
+
+#[cfg(test)]
+mod test_sparse {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn with_holes(min_hole: u64) -> CopySettings {
+        CopySettings { min_hole: Some(min_hole), ..CopySettings::default() }
+    }
+
+    fn contents(file: &mut File) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn sparse_copy_keeps_contents() -> Result<()> {
+        let mut data = vec![0u8; 10];
+        data.extend_from_slice(b"abc");
+        data.extend(vec![0u8; 9000]);
+        data.extend_from_slice(b"def");
+        data.extend(vec![0u8; 5000]);
+
+        let mut file = tempfile()?;
+        assert_eq!(copy_to_file(&mut data.as_slice(), &mut file, with_holes(16))?, data.len() as u64);
+        assert_eq!(contents(&mut file)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_hole_extends_file() -> Result<()> {
+        let mut file = tempfile()?;
+        copy_to_file(&mut vec![0u8; 8192].as_slice(), &mut file, with_holes(DEFAULT_MIN_HOLE))?;
+        assert_eq!(file.metadata()?.len(), 8192);
+        assert_eq!(contents(&mut file)?, vec![0u8; 8192]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zero_runs_become_holes() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut data = b"head".to_vec();
+        data.extend(vec![0u8; 1 << 20]);
+        data.extend_from_slice(b"tail");
+
+        let mut dense = tempfile()?;
+        copy_to_file(&mut data.as_slice(), &mut dense, CopySettings::default())?;
+        let mut sparse = tempfile()?;
+        copy_to_file(&mut data.as_slice(), &mut sparse, with_holes(DEFAULT_MIN_HOLE))?;
+
+        assert_eq!(contents(&mut sparse)?, data);
+        assert!(sparse.metadata()?.blocks() < dense.metadata()?.blocks(),
+                "{} blocks sparse, {} dense", sparse.metadata()?.blocks(), dense.metadata()?.blocks());
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::io::Result;
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+
+#[cfg(unix)]
+use super::ownership::FAKE_SUPER_XATTR;
+
+/// An extended attribute of a file, transferred with `--xattrs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedAttribute {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Reads the extended attributes of `path`. The attribute `--fake-super` records ownership in
+/// is left out, since ownership is transferred separately.
+#[cfg(unix)]
+pub fn read(path: &Path) -> Result<Vec<ExtendedAttribute>> {
+    let mut attributes = Vec::new();
+    for name in xattr::list(path)? {
+        let name = match name.into_string() {
+            Ok(name) if name != FAKE_SUPER_XATTR => name,
+            Ok(_) => continue,
+            Err(name) => {
+                debug!("Skipping extended attribute {:?} of {}, its name is not UTF-8", name, path.to_string_lossy());
+                continue;
+            }
+        };
+        if let Some(value) = xattr::get(path, &name)? {
+            attributes.push(ExtendedAttribute { name, value });
+        }
+    }
+    Ok(attributes)
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> Result<Vec<ExtendedAttribute>> {
+    Ok(Vec::new())
+}
+
+/// Sets extended attributes on received files. Attributes that can't be set (typically for
+/// lack of privileges, as with `security.*`) are skipped, warning once per applier.
+#[derive(Default)]
+pub struct XattrApplier {
+    warned: bool,
+}
+
+impl XattrApplier {
+    pub fn new() -> XattrApplier {
+        XattrApplier::default()
+    }
+
+    pub fn apply(&mut self, path: &Path, attributes: &[ExtendedAttribute]) {
+        for attribute in attributes {
+            if let Err(e) = set(path, attribute) {
+                if !self.warned {
+                    warn!("Could not set extended attribute {} on {}: {} (further failures are only logged at debug level)",
+                          attribute.name, path.to_string_lossy(), e);
+                    self.warned = true;
+                } else {
+                    debug!("Could not set extended attribute {} on {}: {}", attribute.name, path.to_string_lossy(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set(path: &Path, attribute: &ExtendedAttribute) -> Result<()> {
+    xattr::set(path, &attribute.name, &attribute.value)
+}
+
+#[cfg(not(unix))]
+fn set(_path: &Path, _attribute: &ExtendedAttribute) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test_xattrs {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn read_and_apply() -> Result<()> {
+        let source = NamedTempFile::new()?;
+        let target = NamedTempFile::new()?;
+        if xattr::set(source.path(), "user.usync.test", b"value").is_err() {
+            // the filesystem of the temp dir does not support user attributes
+            return Ok(());
+        }
+        xattr::set(source.path(), FAKE_SUPER_XATTR, b"0:0")?;
+
+        let attributes = read(source.path())?;
+        assert_eq!(attributes, vec![ExtendedAttribute { name: String::from("user.usync.test"), value: b"value".to_vec() }]);
+
+        let mut applier = XattrApplier::new();
+        applier.apply(target.path(), &attributes);
+        assert_eq!(xattr::get(target.path(), "user.usync.test")?, Some(b"value".to_vec()));
+        assert!(!applier.warned);
+        Ok(())
+    }
+
+    #[test]
+    fn failures_only_warn() -> Result<()> {
+        let target = NamedTempFile::new()?;
+        let mut applier = XattrApplier::new();
+        applier.apply(target.path(), &[ExtendedAttribute { name: String::from("nonsense.namespace"), value: vec![] }]);
+        assert!(applier.warned);
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! usync synchronizes a target directory with a source directory, transferring only files whose
+//! manifest entries differ. Besides the `usync` command line tool, the engine can be used
+//! directly through [`sync`]. Progress of the engine is reported through the `log` crate.
+
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{AppendMode, FilterRule, HashAlgorithm, HashSettings, ManifestLocation, ManifestMode, PrivilegeMode, Selection, TransferLimits};
+use crate::file_transfer::local::LocalTransmitter;
+use crate::tree::{CopyOptions, CopyStats, Manifest};
+use crate::util::{convert_error, Timestamp};
+
+pub mod server;
+pub mod config;
+pub mod tree;
+pub mod util;
+pub mod file_transfer;
+pub mod progress;
+pub mod staging;
+pub mod error;
+pub mod plan;
+
+/// Options for a programmatic [`sync`], built up from the defaults with the builder methods.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    hash: HashSettings,
+    manifest: Option<ManifestLocation>,
+    privilege: PrivilegeMode,
+    xattrs: bool,
+    crtimes: bool,
+    keep_going: bool,
+    selection: Selection,
+    limits: TransferLimits,
+    prune_empty_dirs: bool,
+    subpath: Option<PathBuf>,
+    min_free: Option<u64>,
+    append: Option<AppendMode>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            hash: HashSettings::default(),
+            manifest: None,
+            privilege: PrivilegeMode::Auto,
+            xattrs: false,
+            crtimes: false,
+            keep_going: false,
+            selection: Selection::All,
+            limits: TransferLimits::default(),
+            prune_empty_dirs: false,
+            subpath: None,
+            min_free: None,
+            append: None,
+        }
+    }
+}
+
+impl SyncOptions {
+    pub fn new() -> SyncOptions {
+        SyncOptions::default()
+    }
+
+    /// Compare files by content hash (the default) or by timestamp only.
+    pub fn manifest_mode(mut self, mode: ManifestMode) -> Self {
+        self.hash = self.hash.with_manifest_mode(mode);
+        self
+    }
+
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash = self.hash.with_hash_algorithm(algorithm);
+        self
+    }
+
+    pub fn hash_threads(mut self, threads: usize) -> Self {
+        self.hash = self.hash.with_hash_threads(threads);
+        self
+    }
+
+    /// Excludes paths matching the glob `pattern`. A pattern starting with `/` is matched
+    /// against the path relative to the sync root.
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        let rule = FilterRule::new(false, pattern).map_err(convert_error)?;
+        self.hash = self.hash.with_filter_rule(rule);
+        Ok(self)
+    }
+
+    /// Skips files smaller than `min_size` or larger than `max_size` bytes.
+    pub fn size_range(mut self, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        self.hash = self.hash.with_size_range(min_size, max_size);
+        self
+    }
+
+    /// Skips files last modified before `newer_than` or after `older_than`.
+    pub fn age_range(mut self, newer_than: Option<SystemTime>, older_than: Option<SystemTime>) -> Self {
+        self.hash = self.hash.with_age_range(newer_than.map(Timestamp::from), older_than.map(Timestamp::from));
+        self
+    }
+
+    /// Honors `.usyncignore` files in the source tree, see [`config::IGNORE_FILE`].
+    pub fn ignore_files(mut self, ignore_files: bool) -> Self {
+        self.hash = self.hash.with_ignore_files(ignore_files);
+        self
+    }
+
+    /// Stops descending into directories more than `max_depth` levels below the root.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.hash = self.hash.with_max_depth(max_depth);
+        self
+    }
+
+    /// Treats modification times at most `window` apart as equal, for filesystems that store
+    /// timestamps with less precision than the source.
+    pub fn modify_window(mut self, window: Duration) -> Self {
+        self.hash = self.hash.with_modify_window(window);
+        self
+    }
+
+    /// Treats files with the same size and content hash as equal whatever their modification
+    /// times, see `--ignore-times`. Requires hash mode.
+    pub fn ignore_times(mut self, ignore_times: bool) -> Self {
+        self.hash = self.hash.with_ignore_times(ignore_times);
+        self
+    }
+
+    /// Doesn't descend into directories on other filesystems than `source` (Unix only).
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.hash = self.hash.with_one_file_system(one_file_system);
+        self
+    }
+
+    /// Transfers files that are hard links to each other once, linking them on the target.
+    pub fn hard_links(mut self, hard_links: bool) -> Self {
+        self.hash = self.hash.with_hard_links(hard_links);
+        self
+    }
+
+    /// Updates the permissions of files on the target that differ only in them.
+    pub fn perms(mut self, perms: bool) -> Self {
+        self.hash = self.hash.with_perms(perms);
+        self
+    }
+
+    /// Follows symlinks, copying what they point to. By default, symlinks are skipped.
+    pub fn copy_links(mut self, copy_links: bool) -> Self {
+        self.hash = self.hash.with_copy_links(copy_links);
+        self
+    }
+
+    /// Hashes files through a buffer of `size` bytes instead of the default 64 KiB.
+    pub fn io_buffer_size(mut self, size: usize) -> Self {
+        self.hash = self.hash.with_io_buffer_size(size);
+        self
+    }
+
+    /// Fails the sync on fifos, sockets and device nodes, which are skipped by default.
+    pub fn fail_on_special_files(mut self, fail: bool) -> Self {
+        self.hash = self.hash.with_fail_on_special_files(fail);
+        self
+    }
+
+    /// Re-includes paths matching the glob `pattern` that earlier `exclude` calls excluded.
+    /// The last matching pattern decides; a pattern ending in `/` only matches directories.
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        let rule = FilterRule::new(true, pattern).map_err(convert_error)?;
+        self.hash = self.hash.with_filter_rule(rule);
+        Ok(self)
+    }
+
+    /// Persists the source manifest in this file (relative to the source root, unless absolute)
+    /// so later syncs can skip rehashing. By default the source manifest is not persisted.
+    pub fn manifest_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.manifest = Some(ManifestLocation::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Persists the source manifest in the user's cache directory rather than inside the
+    /// source, which also works for read-only sources.
+    pub fn manifest_in_cache(mut self) -> Self {
+        self.manifest = Some(ManifestLocation::Cache);
+        self
+    }
+
+    pub fn privilege(mut self, privilege: PrivilegeMode) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Copies the extended attributes of files along with their contents.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Copies the creation times of files along with their contents, where the platform
+    /// allows setting them.
+    pub fn crtimes(mut self, crtimes: bool) -> Self {
+        self.crtimes = crtimes;
+        self
+    }
+
+    /// Goes on with the remaining files if one fails to transfer. The failures are listed in
+    /// the report's `failed`, rather than failing the sync.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Only transfers files missing on the target (`Selection::Missing`), or only those
+    /// already on it (`Selection::Existing`).
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Stops the sync once `limits` are reached, see [`CopyStats::limit_reached`].
+    pub fn transfer_limits(mut self, limits: TransferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Doesn't create directories on the target that have no files below them once filters
+    /// are applied.
+    pub fn prune_empty_dirs(mut self, prune_empty_dirs: bool) -> Self {
+        self.prune_empty_dirs = prune_empty_dirs;
+        self
+    }
+
+    /// Only syncs the directory at `subpath` (relative to the source root), which must exist
+    /// in the source.
+    pub fn subpath<P: AsRef<Path>>(mut self, subpath: Option<P>) -> Self {
+        self.subpath = subpath.map(|subpath| subpath.as_ref().to_owned());
+        self
+    }
+
+    /// Stops before a file would leave less than `min_free` bytes free on the target's
+    /// filesystem. Running out of space fails the sync even when keeping going.
+    pub fn min_free(mut self, min_free: Option<u64>) -> Self {
+        self.min_free = min_free;
+        self
+    }
+
+    /// Appends to target files shorter than their source instead of copying them whole,
+    /// checking first that they hold the start of the source with `AppendMode::Verify`.
+    pub fn append(mut self, append: Option<AppendMode>) -> Self {
+        self.append = append;
+        self
+    }
+}
+
+/// Summary of a completed [`sync`].
+pub type SyncReport = CopyStats;
+
+/// Synchronizes the local directory `target` with the local directory `source`.
+pub fn sync(source: &Path, target: &Path, options: SyncOptions) -> Result<SyncReport> {
+    let mut src = match &options.manifest {
+        Some(location) => Manifest::create_persistent(source, &options.hash, location)?,
+        None => Manifest::create_ephemeral(source, &options.hash)?,
+    };
+    if let Some(subpath) = &options.subpath {
+        src.restrict_to(subpath)?;
+    }
+    if options.prune_empty_dirs {
+        src.prune_empty_dirs();
+    }
+    let trg = Manifest::create_ephemeral_below(target, options.subpath.as_deref(), &options.hash)?;
+
+    let mut transmitter = LocalTransmitter::new(source, target, options.privilege)
+        .with_xattrs(options.xattrs)
+        .with_crtimes(options.crtimes)
+        .with_min_free(options.min_free)
+        .with_append(options.append);
+    let copy = CopyOptions {
+        selection: options.selection,
+        keep_going: options.keep_going,
+        limits: options.limits,
+        ..CopyOptions::new(options.hash.comparison())
+    };
+    trg.copy_with(&src, copy, &mut transmitter)
+}
+
+#[cfg(test)]
+mod test_sync {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn sync_reports_transfers() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::create_dir(source.path().join("dir"))?;
+        File::create(source.path().join("dir/a.txt"))?.write_all(b"hello")?;
+        File::create(source.path().join("b.log"))?.write_all(b"ignored")?;
+        std::fs::create_dir(source.path().join("empty"))?;
+
+        let options = SyncOptions::new().exclude("*.log")?;
+        let report = sync(source.path(), target.path(), options.clone())?;
+
+        assert_eq!(report.files_transferred(), 1);
+        assert_eq!(report.bytes_transferred, 5);
+        assert_eq!(report.directories_created, 2);
+        assert!(target.path().join("empty").is_dir());
+        assert_eq!(std::fs::read(target.path().join("dir/a.txt"))?, b"hello");
+        assert!(!target.path().join("b.log").exists());
+
+        let report = sync(source.path(), target.path(), options)?;
+        assert_eq!(report.files_transferred(), 0);
+        assert_eq!(report.files_skipped, 1);
+        Ok(())
+    }
+}
@@ -1,25 +1,27 @@
-use std::io::{Error, ErrorKind, Read, stdin, stdout, Write};
-use std::net::TcpStream;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, stderr, stdin, stdout, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::Stdio;
-use std::sync::mpsc::channel;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::{Configuration, PathDefinition, ProcessRole};
-use crate::file_transfer::*;
-use crate::server::Server;
-use crate::tree::Manifest;
-use crate::util::*;
+use log::{error, info, warn};
 
-mod server;
-mod config;
-mod tree;
-mod util;
-mod file_transfer;
+use usync::config::{AppendMode, Configuration, ManifestLocation, ManifestMode, PathDefinition, ProcessRole, PrivilegeMode, Selection, shell_quote, shell_quote_path};
+use usync::error::UsyncError;
+use usync::file_transfer::*;
+use usync::plan::Plan;
+use usync::progress::{EventTransmitter, ItemizeTransmitter, ProgressTransmitter};
+use usync::server::Server;
+use usync::staging::Staging;
+use usync::tree::{check_root, CopyOptions, CopyStats, FailedTransfer, Manifest};
+use usync::util::*;
 
 #[inline]
 fn non_local_path<A>(path: &PathDefinition) -> Result<A, Error> {
-    Err(Error::new(ErrorKind::Other, format!("Non-local path where local context is required: {}", path)))
+    Err(UsyncError::NonLocalPath(path.to_string()).into())
 }
 
 fn main_as_server(cfg: &Configuration) -> Result<(), Error> { // ! would be better, but hey...
@@ -27,37 +29,366 @@ fn main_as_server(cfg: &Configuration) -> Result<(), Error> { // ! would be bett
     server.run()
 }
 
+fn copy_manifest<T: Transmitter>(cfg: &Configuration, target: &Manifest, source: &Manifest, transmitter: &mut T) -> Result<(), Error> {
+    if let Some(path) = cfg.plan() {
+        return write_plan(cfg, target, source, path);
+    }
+    let stats = if cfg.events() {
+        copy_with_progress(cfg, target, source, &mut EventTransmitter::new(transmitter))?
+    } else {
+        copy_with_progress(cfg, target, source, transmitter)?
+    };
+
+    if cfg.stats() && cfg.role().is_some() {
+        // stdout of a receiver started over ssh carries the protocol
+        eprintln!("{}", stats.summary(cfg.human_readable()));
+    } else if cfg.stats() {
+        println!("{}", stats.summary(cfg.human_readable()));
+    } else {
+        info!("{}", stats.summary(cfg.human_readable()));
+    }
+    if cfg.json_summary() {
+        println!("{}", serde_json::to_string(&stats).map_err(convert_error)?);
+    }
+
+    if !stats.failed.is_empty() {
+        error!("{}", FailedTransfer::report("to transfer", &stats.failed));
+    }
+    if !stats.verification_failed.is_empty() {
+        error!("{}", FailedTransfer::report("verification", &stats.verification_failed));
+    }
+    match (stats.failed.len(), stats.verification_failed.len()) {
+        (0, 0) => Ok(()),
+        (failed, 0) => Err(Error::new(ErrorKind::Other, format!("{} files could not be transferred", failed))),
+        (0, unverified) => Err(Error::new(ErrorKind::InvalidData, format!("{} files failed verification", unverified))),
+        (failed, unverified) => Err(Error::new(ErrorKind::Other, format!(
+            "{} files could not be transferred, {} failed verification", failed, unverified))),
+    }
+}
+
+/// Copies with `--itemize-changes` listing what the copy does, if given.
+fn copy_with_itemize<T: Transmitter>(cfg: &Configuration, target: &Manifest, source: &Manifest, transmitter: &mut T) -> Result<CopyStats, Error> {
+    if cfg.itemize_changes() > 0 {
+        // stdout of a receiver started over ssh carries the protocol
+        let output: Box<dyn Write> = if cfg.role().is_some() { Box::new(stderr()) } else { Box::new(stdout()) };
+        let mut transmitter = ItemizeTransmitter::with_output(transmitter, output)
+            .with_unchanged(cfg.itemize_changes() > 1);
+        copy_from(cfg, target, source, &mut transmitter)
+    } else {
+        copy_from(cfg, target, source, transmitter)
+    }
+}
+
+fn copy_options(cfg: &Configuration) -> CopyOptions {
+    CopyOptions {
+        comparison: cfg.hash_settings().comparison(),
+        selection: cfg.selection(),
+        keep_going: cfg.keep_going(),
+        limits: cfg.transfer_limits(),
+    }
+}
+
+/// Copies what differs between `source` and `target`, or what the plan given with
+/// `--apply-plan` says.
+fn copy_from<T: Transmitter>(cfg: &Configuration, target: &Manifest, source: &Manifest, transmitter: &mut T) -> Result<CopyStats, Error> {
+    match cfg.apply_plan() {
+        Some(path) => {
+            let plan = Plan::read_from(File::open(path)?)?;
+            info!("Applying {} actions of the plan in {}", plan.actions().len(), path.to_string_lossy());
+            plan.apply(transmitter, cfg.keep_going())
+        }
+        None => target.copy_with(source, copy_options(cfg), transmitter),
+    }
+}
+
+/// Writes what copying `source` to `target` would do to `path` (see `--plan`), instead of
+/// doing it.
+fn write_plan(cfg: &Configuration, target: &Manifest, source: &Manifest, path: &Path) -> Result<(), Error> {
+    let plan = Plan::of(target, source, copy_options(cfg))?;
+    if path == Path::new("-") {
+        plan.write_to(stdout().lock())?;
+    } else {
+        plan.write_to(File::create(path)?)?;
+    }
+    info!("Planned {} actions, nothing was changed", plan.actions().len());
+    Ok(())
+}
+
+fn copy_with_progress<T: Transmitter>(cfg: &Configuration, target: &Manifest, source: &Manifest, transmitter: &mut T) -> Result<CopyStats, Error> {
+    if cfg.progress() {
+        let mut transmitter = ProgressTransmitter::new(transmitter, target.selected_transfer_totals(source, cfg.hash_settings().comparison(), cfg.selection()))
+            .with_human_readable(cfg.human_readable());
+        let stats = copy_with_itemize(cfg, target, source, &mut transmitter)?;
+        transmitter.finish();
+        Ok(stats)
+    } else {
+        copy_with_itemize(cfg, target, source, transmitter)
+    }
+}
+
+/// Rehashes the target below `root` and compares it to `source`, which must have content
+/// hashes for all files.
+fn verify_target(cfg: &Configuration, root: &Path, source: &Manifest) -> Result<(), Error> {
+    let settings = cfg.hash_settings().with_manifest_mode(ManifestMode::Hash);
+    let target = Manifest::create_ephemeral_below(root, cfg.subpath(), &settings)?;
+    let failures = target.verification_failures(source);
+
+    if failures.is_empty() {
+        info!("Verified target against source, all files match");
+        return Ok(());
+    }
+
+    error!("{}", FailedTransfer::report("verification", &failures));
+    Err(Error::new(ErrorKind::InvalidData, format!("{} files on the target differ from the source", failures.len())))
+}
+
+/// The manifest of the local source at `root`, as given with `--read-manifest`, or else
+/// built (and stored) as usual.
+fn source_manifest(cfg: &Configuration, root: &Path) -> Result<Manifest, Error> {
+    match cfg.read_manifest() {
+        Some(path) if path == Path::new("-") => Manifest::read_from(stdin().lock(), cfg.hash_settings()),
+        Some(path) => Manifest::read_from(File::open(path)?, cfg.hash_settings()),
+        None => Manifest::create_persistent(root, cfg.hash_settings(), cfg.manifest_location()),
+    }
+}
+
+/// Whether the source is a tar archive rather than a directory.
+fn is_archive(root: &Path) -> bool {
+    root.is_file()
+}
+
+/// Opens the source `root` as tar archive, see `is_archive`.
+fn open_archive(root: &Path) -> Result<archive::TarFileAccess, Error> {
+    archive::TarFileAccess::open(root).map_err(|e| Error::new(e.kind(), format!(
+        "Source path {} is neither a directory nor a readable tar archive: {}", root.to_string_lossy(), e)))
+}
+
+/// Fails early, with a message naming the path, if a local source or target is missing or
+/// not a directory. A file is a valid source, it is read as archive.
+fn check_local_roots(cfg: &Configuration) -> Result<(), Error> {
+    if let Some(PathDefinition::Local(root)) = cfg.default_source() {
+        if !is_archive(root) {
+            check_root(root, "Source path")?;
+        }
+    }
+    if let Some(PathDefinition::Local(root)) = cfg.target_definition() {
+        check_root(root, "Target path")?;
+    }
+    Ok(())
+}
+
 fn main_as_sender<R: Read, W: Write>(cfg: &Configuration, input: R, output: W) -> Result<(), Error> {
     if let PathDefinition::Local(root) = cfg.source() {
-        let manifest = Manifest::create_persistent(
-            &root,
-            false,
-            cfg.hash_settings(),
-            cfg.manifest_path())?;
+        let settings = remote::SenderSettings::new(cfg);
+        if is_archive(root) {
+            let archive = open_archive(root)?;
+            let manifest = archive.manifest(cfg.hash_settings())?;
+            return remote::command_handler_loop(&root, &manifest, input, output, &archive, &settings);
+        }
+        let manifest = source_manifest(cfg, root)?;
+
+        remote::command_handler_loop(&root, &manifest, input, output, &DefaultFileAccess, &settings)
+    } else {
+        non_local_path(cfg.source())
+    }
+}
+
+/// Pushes the local source to a server accepting pushes.
+fn main_as_pushing_sender(cfg: &Configuration, server: &str, module: Option<&str>) -> Result<(), Error> {
+    if let PathDefinition::Local(root) = cfg.source() {
+        let archive = if is_archive(root) { Some(open_archive(root)?) } else { None };
+        let mut manifest = match &archive {
+            Some(archive) => archive.manifest(cfg.hash_settings())?,
+            None => source_manifest(cfg, root)?,
+        };
+        if let Some(subpath) = cfg.subpath() {
+            manifest.restrict_to(subpath)?;
+        }
 
-        remote::command_handler_loop(&root, &manifest, input, output, &DefaultFileAccess)
+        let stream = connect(server, cfg.io_timeout())?;
+        set_io_timeout(&stream, cfg.io_timeout())?;
+        let skew = remote::request_push(&stream, &stream, cfg.auth_token(), module, cfg.hash_settings().checksum_key()).map_err(|e| explain_timeout(e, cfg.io_timeout()))?;
+        check_clock_skew(cfg, skew)?;
+        let settings = remote::SenderSettings::new(cfg);
+        match &archive {
+            Some(archive) => remote::serve_commands(&root, &manifest, &stream, &stream, archive, &settings),
+            None => remote::serve_commands(&root, &manifest, &stream, &stream, &DefaultFileAccess, &settings),
+        }.map_err(|e| explain_timeout(e, cfg.io_timeout()))
     } else {
         non_local_path(cfg.source())
     }
 }
 
+/// Timestamp mode compares modification times taken on two machines, which goes wrong if
+/// their clocks disagree by more than this.
+const CLOCK_SKEW_LIMIT_MS: i64 = 2000;
+
+/// Warns about (or with `--strict-clock`, refuses) a peer whose clock is `skew` milliseconds
+/// off, if the configuration relies on timestamps.
+fn check_clock_skew(cfg: &Configuration, skew: i64) -> Result<(), Error> {
+    info!("Peer clock is offset by {} ms", skew);
+    if cfg.hash_settings().manifest_mode() != ManifestMode::TimestampTest || skew.abs() <= CLOCK_SKEW_LIMIT_MS {
+        return Ok(());
+    }
+
+    let message = format!("Clocks of sender and receiver differ by {:.1}s, timestamp comparisons may be wrong", skew as f64 / 1000.0);
+    if cfg.strict_clock() {
+        Err(Error::new(ErrorKind::Other, message))
+    } else {
+        warn!("{}", message);
+        Ok(())
+    }
+}
+
+/// Runs `sync` on the target `root`, or with `--stage-then-swap` on a staging copy of it that
+/// only replaces `root` if `sync` succeeds.
+fn staged<F: FnOnce(&Path) -> Result<(), Error>>(cfg: &Configuration, root: &Path, sync: F) -> Result<(), Error> {
+    if !cfg.stage_then_swap() {
+        return sync(root);
+    }
+
+    let staging = Staging::prepare(root)?;
+    match sync(staging.root()) {
+        Ok(()) => staging.swap(),
+        Err(e) => {
+            warn!("Sync failed, leaving {} unchanged", root.to_string_lossy());
+            if let Err(cleanup) = staging.discard() {
+                warn!("Could not remove the staging directory: {}", cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
 fn main_as_receiver<R: Read, W: Write>(cfg: &Configuration, input: R, output: W) -> Result<(), Error> {
     if let PathDefinition::Local(root) = cfg.target() {
-        let mut transmitter = remote::CommandTransmitter::new(&root, input, output);
-        let local_manifest = Manifest::create_ephemeral(&root, false, cfg.hash_settings())?;
-        let remote_manifest = transmitter.remote_manifest()?;
-        local_manifest.copy_from(&remote_manifest, &mut transmitter, cfg.verbose())
+        staged(cfg, root, |root| {
+            let local_manifest = Manifest::create_ephemeral_below(root, cfg.subpath(), cfg.hash_settings())?;
+            receive(cfg, root, &local_manifest, input, output, &mut HashSet::new())
+        })
+    } else {
+        non_local_path(cfg.target())
+    }
+}
+
+/// Like `main_as_receiver`, but re-establishes the connection through `connect` if it fails,
+/// up to the configured number of retries. Files received before the failure are not
+/// requested again.
+fn main_as_retrying_receiver<R: Read, W: Write, C: FnMut() -> Result<(R, W), Error>>(cfg: &Configuration, mut connect: C) -> Result<(), Error> {
+    if let PathDefinition::Local(root) = cfg.target() {
+        staged(cfg, root, |root| {
+            let local_manifest = Manifest::create_ephemeral_below(root, cfg.subpath(), cfg.hash_settings())?;
+            let mut completed = HashSet::new();
+            let mut backoff = retry::Backoff::new(cfg.retries());
+
+            loop {
+                let attempt = connect().and_then(|(input, output)| {
+                    receive(cfg, root, &local_manifest, input, output, &mut completed)
+                }).map_err(|e| explain_timeout(e, cfg.io_timeout()));
+
+                match attempt {
+                    Err(e) if retry::is_transient(&e) => match backoff.next_delay() {
+                        Some(delay) => {
+                            if cfg.delay_updates() {
+                                // the files held back were discarded with the connection
+                                completed.clear();
+                            }
+                            warn!("Connection failed: {}, retrying in {}s", e, delay.as_secs());
+                            thread::sleep(delay);
+                        }
+                        None => return Err(e),
+                    },
+                    done => return done,
+                }
+            }
+        })
     } else {
         non_local_path(cfg.target())
     }
 }
 
+fn receive<R: Read, W: Write>(cfg: &Configuration, root: &Path, local_manifest: &Manifest, input: R, output: W, completed: &mut HashSet<PathBuf>) -> Result<(), Error> {
+    let mut transmitter = remote::CommandTransmitter::new(root, input, output, cfg.privilege())
+        .with_batch_size(cfg.batch_size())
+        .with_temp_dir(cfg.temp_dir())
+        .with_min_free(cfg.min_free())
+        .with_sparse(cfg.sparse())
+        .with_append(cfg.append())
+        .with_whole_file(cfg.whole_file())
+        .with_delay_updates(cfg.delay_updates())
+        .with_linked_target(cfg.stage_then_swap())
+        .with_buffer_size(cfg.hash_settings().io_buffer_size())
+        .with_xattrs(cfg.xattrs())
+        .with_crtimes(cfg.crtimes())
+        .with_checksum_key(cfg.hash_settings().checksum_key());
+    // a receiver spawned over ssh has no source, it reads from its standard input
+    let module = match cfg.default_source() {
+        Some(PathDefinition::Server(_, module)) => module.as_deref(),
+        _ => None,
+    };
+    let skew = transmitter.handshake(cfg.auth_token(), module)?;
+    check_clock_skew(cfg, skew)?;
+    let mut remote_manifest = transmitter.remote_manifest()?;
+    if remote_manifest.hash_algorithm() != local_manifest.hash_algorithm() {
+        return Err(UsyncError::Protocol(format!(
+            "Sender hashes with {}, but {} is configured locally",
+            remote_manifest.hash_algorithm(), local_manifest.hash_algorithm())).into());
+    }
+    if remote_manifest.manifest_mode() != local_manifest.manifest_mode() {
+        return Err(UsyncError::Protocol(format!(
+            "Sender builds manifests in {} mode, but {} mode is configured locally",
+            remote_manifest.manifest_mode(), local_manifest.manifest_mode())).into());
+    }
+    if let Some(subpath) = cfg.subpath() {
+        remote_manifest.restrict_to(subpath)?;
+    }
+    if cfg.prune_empty_dirs() {
+        remote_manifest.prune_empty_dirs();
+    }
+    copy_manifest(cfg, local_manifest, &remote_manifest, &mut retry::ResumingTransmitter::new(&mut transmitter, completed))?;
+    let removed = transmitter.remove_stale_partials(&remote_manifest, cfg.subpath())?;
+    if removed > 0 {
+        info!("Removed {} partial transfers of files no longer synced", removed);
+    }
+
+    if cfg.checksum() {
+        let mut source = transmitter.hashed_manifest()?;
+        if let Some(subpath) = cfg.subpath() {
+            source.restrict_to(subpath)?;
+        }
+        verify_target(cfg, root, &source)?;
+    }
+
+    Ok(())
+}
+
 fn main_as_local(cfg: &Configuration) -> Result<(), Error> {
     if let PathDefinition::Local(to) = cfg.target() {
         if let PathDefinition::Local(from) = cfg.source() {
-            let target = Manifest::create_ephemeral(&to, cfg.verbose(), cfg.hash_settings())?;
-            let src = Manifest::create_persistent(&from, cfg.verbose(), cfg.hash_settings(), cfg.manifest_path())?;
-            target.copy_from(&src, &mut local::LocalTransmitter::new(&from, &to), cfg.verbose())
+            staged(cfg, to, |to| {
+                let target = Manifest::create_ephemeral_below(to, cfg.subpath(), cfg.hash_settings())?;
+                let mut src = source_manifest(cfg, from)?;
+                if let Some(subpath) = cfg.subpath() {
+                    src.restrict_to(subpath)?;
+                }
+                if cfg.prune_empty_dirs() {
+                    src.prune_empty_dirs();
+                }
+                let mut transmitter = local::LocalTransmitter::new(from, to, cfg.privilege())
+                    .with_temp_dir(cfg.temp_dir())
+                    .with_min_free(cfg.min_free())
+                    .with_append(cfg.append())
+                    .with_xattrs(cfg.xattrs())
+                    .with_crtimes(cfg.crtimes())
+                    .with_linked_target(cfg.stage_then_swap());
+                copy_manifest(cfg, &target, &src, &mut transmitter)?;
+
+                if cfg.checksum() {
+                    verify_target(cfg, to, &src.with_hashes(from, cfg.hash_settings().checksum_key())?)?;
+                }
+
+                Ok(())
+            })
         } else {
             non_local_path(cfg.source())
         }
@@ -69,23 +400,17 @@ fn main_as_local(cfg: &Configuration) -> Result<(), Error> {
 fn main_as_local_pipe(cfg: &Configuration) -> Result<(), Error> {
     let c1 = cfg.clone();
     let c2 = cfg.clone();
-    let (send_to_receiver, receive_from_sender) = channel();
-    let (send_to_sender, receive_from_receiver) = channel();
+    let (to_receiver, from_sender) = pipe(cfg.pipe_capacity());
+    let (to_sender, from_receiver) = pipe(cfg.pipe_capacity());
 
     let sender = thread::spawn(move || {
-        let output = SendAdapter::new(send_to_receiver);
-        let input = ReceiveAdapter::new(receive_from_receiver);
-
-        main_as_sender(&c1, input, output).unwrap_or_else(|e| {
-            eprintln!("Sender failed with: {}", e);
+        main_as_sender(&c1, from_receiver, to_receiver).unwrap_or_else(|e| {
+            error!("Sender failed with: {}", e);
         });
     });
     let receiver = thread::spawn(move || {
-        let output = SendAdapter::new(send_to_sender);
-        let input = ReceiveAdapter::new(receive_from_sender);
-
-        main_as_receiver(&c2, input, output).unwrap_or_else(|e| {
-            eprintln!("Receive failed: {}", e)
+        main_as_receiver(&c2, from_sender, to_sender).unwrap_or_else(|e| {
+            error!("Receive failed: {}", e)
         });
     });
     sender.join().unwrap();
@@ -93,34 +418,402 @@ fn main_as_local_pipe(cfg: &Configuration) -> Result<(), Error> {
     Ok(())
 }
 
-fn spawn_remote_usync(cfg: &Configuration, role: &str, remote: &str, target_param: &str, target_path: &str) -> Result<std::process::Child, Error> {
+/// Shape of the tree `--benchmark` generates: this many directories, each with the small
+/// and large files below, 36 MiB in all.
+const BENCHMARK_DIRECTORIES: usize = 8;
+const BENCHMARK_SMALL_FILES: usize = 128;
+const BENCHMARK_SMALL_SIZE: usize = 4 * 1024;
+const BENCHMARK_LARGE_FILES: usize = 2;
+const BENCHMARK_LARGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Writes the `--benchmark` tree to `root`. The contents are the same on every run, differ
+/// between files, and are neither compressible to nothing nor aligned to any buffer size.
+/// Returns the number of files and bytes written.
+fn generate_benchmark_tree(root: &Path) -> Result<(u64, u64), Error> {
+    let (mut files, mut bytes) = (0u64, 0u64);
+    for dir in 0..BENCHMARK_DIRECTORIES {
+        let dir_path = root.join(format!("dir{}", dir));
+        std::fs::create_dir(&dir_path)?;
+        let small = (0..BENCHMARK_SMALL_FILES).map(|n| (format!("small{}.dat", n), BENCHMARK_SMALL_SIZE));
+        let large = (0..BENCHMARK_LARGE_FILES).map(|n| (format!("large{}.dat", n), BENCHMARK_LARGE_SIZE));
+        for (name, size) in small.chain(large) {
+            let seed = files as usize;
+            let contents: Vec<u8> = (0..size).map(|i| ((i + seed) * 7919 % 251) as u8).collect();
+            std::fs::write(dir_path.join(name), contents)?;
+            files += 1;
+            bytes += size as u64;
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// Prints how long a `--benchmark` phase over `bytes` took, and the throughput it reached.
+fn report_phase(cfg: &Configuration, phase: &str, bytes: u64, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64().max(1e-6);
+    let rate = (bytes as f64 / seconds) as u64;
+    let (bytes, rate) = if cfg.human_readable() {
+        (format_size(bytes), format!("{}/s", format_size(rate)))
+    } else {
+        (format!("{} bytes", bytes), format!("{} bytes/s", rate))
+    };
+    println!("{:<24} {} in {:.3}s, {}", phase, bytes, seconds, rate);
+}
+
+/// Generates a tree in a temporary directory and times building its manifest in both modes,
+/// and syncing it to an empty directory through `main_as_local_pipe`, with the hash and
+/// buffer settings of `cfg`. The synced tree is checked against the source afterwards, so a
+/// failed sync isn't reported as a fast one.
+fn main_as_benchmark(cfg: &Configuration) -> Result<(), Error> {
+    let temp = tempfile::Builder::new().prefix("usync-benchmark").tempdir_in(cfg.temp_dir().unwrap_or(&std::env::temp_dir()))?;
+    let (source, target) = (temp.path().join("source"), temp.path().join("target"));
+    std::fs::create_dir(&source)?;
+    std::fs::create_dir(&target)?;
+
+    let start = Instant::now();
+    let (files, bytes) = generate_benchmark_tree(&source)?;
+    report_phase(cfg, &format!("Generating {} files", files), bytes, start.elapsed());
+
+    let hashing = cfg.hash_settings().with_manifest_mode(ManifestMode::Hash);
+    let start = Instant::now();
+    let hashed = Manifest::create_ephemeral(&source, &hashing)?;
+    report_phase(cfg, &format!("Manifest ({})", hashing.hash_algorithm()), bytes, start.elapsed());
+
+    let start = Instant::now();
+    Manifest::create_ephemeral(&source, &cfg.hash_settings().with_manifest_mode(ManifestMode::TimestampTest))?;
+    report_phase(cfg, "Manifest (timestamp)", bytes, start.elapsed());
+
+    let start = Instant::now();
+    main_as_local_pipe(&cfg.with_local_roots(&source, &target))?;
+    report_phase(cfg, &format!("Pipe sync ({})", cfg.hash_settings().manifest_mode()), bytes, start.elapsed());
+
+    let mismatches = Manifest::create_ephemeral(&target, &hashing)?.mismatches(&hashed);
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::Other, format!("The benchmark sync left {} files different from the source", mismatches.len())))
+    }
+}
+
+/// How long a failing remote usync gets to finish its stderr output before the failure is
+/// reported without it.
+const REMOTE_STDERR_WAIT: Duration = Duration::from_secs(2);
+
+/// Starts usync in `role` on `remote` through the remote shell. Its stderr is passed on to
+/// ours, and the returned tail of it can explain a failure, see `explain_remote_failure`.
+fn spawn_remote_usync(cfg: &Configuration, role: &str, remote: &str, target_param: &str, target_path: &str) -> Result<(process::Child, OutputTail), Error> {
     let mode = cfg.hash_settings().manifest_mode().to_string();
+    let algorithm = cfg.hash_settings().hash_algorithm().to_string();
 
-    let mut ssh_invoke = vec![remote, "usync",
-                              "--role", role,
-                              target_param, target_path,
-                              "--manifest-file", cfg.manifest_path().to_str().unwrap(),
-                              "--hash-mode", &mode
+    let mut ssh_invoke = vec!["--role", role,
+                              "--hash-mode", &mode,
+                              "--hash-algo", &algorithm
     ];
 
+    match cfg.manifest_location() {
+        ManifestLocation::File(path) => {
+            ssh_invoke.push("--manifest-file");
+            ssh_invoke.push(path.to_str().unwrap());
+        }
+        ManifestLocation::Cache => ssh_invoke.push("--manifest-cache"),
+    }
     if cfg.hash_settings().force_rebuild() {
         ssh_invoke.push("--force-rebuild-manifest")
     }
-    for p in cfg.hash_settings().exclude_patterns() {
-        ssh_invoke.push("--exclude");
-        ssh_invoke.push(p.as_str());
+    if cfg.hash_settings().ignore_files() {
+        ssh_invoke.push("--ignore-files")
     }
-
-    if cfg.verbose() {
-        let stringify = ssh_invoke.join(" ");
-        println!("Spawning process: ssh {}", stringify);
+    let modify_window = cfg.hash_settings().modify_window().as_secs().to_string();
+    if cfg.hash_settings().modify_window() > Duration::from_secs(0) {
+        ssh_invoke.push("--modify-window");
+        ssh_invoke.push(&modify_window);
+    }
+    if cfg.hash_settings().ignore_times() {
+        ssh_invoke.push("--ignore-times")
+    }
+    let temp_dir = cfg.temp_dir().map(|dir| dir.to_string_lossy().into_owned());
+    if let (Some(temp_dir), "receiver") = (&temp_dir, role) {
+        ssh_invoke.push("--temp-dir");
+        ssh_invoke.push(temp_dir);
+    }
+    let min_free = cfg.min_free().map(|space| space.to_string());
+    if let (Some(min_free), "receiver") = (&min_free, role) {
+        ssh_invoke.push("--min-free");
+        ssh_invoke.push(min_free);
+    }
+    let min_hole = cfg.sparse().map(|size| size.to_string());
+    if let (Some(min_hole), "receiver") = (&min_hole, role) {
+        ssh_invoke.push("--sparse");
+        ssh_invoke.push("--min-hole");
+        ssh_invoke.push(min_hole);
+    }
+    let io_buffer_size = cfg.hash_settings().io_buffer_size().to_string();
+    if cfg.hash_settings().io_buffer_size() != DEFAULT_IO_BUFFER_SIZE {
+        ssh_invoke.push("--io-buffer-size");
+        ssh_invoke.push(&io_buffer_size);
+    }
+    if cfg.hash_settings().hard_links() {
+        ssh_invoke.push("--hard-links")
+    }
+    if cfg.hash_settings().perms() {
+        ssh_invoke.push("--perms")
+    }
+    // the seed would be visible to anyone listing processes on either machine, so it follows
+    // on stdin instead
+    if cfg.checksum_seed().is_some() {
+        ssh_invoke.push("--checksum-seed-stdin");
+    }
+    if cfg.hash_settings().copy_links() {
+        ssh_invoke.push("--copy-links")
+    }
+    if cfg.hash_settings().one_file_system() {
+        ssh_invoke.push("--one-file-system")
+    }
+    if cfg.hash_settings().fail_on_special_files() {
+        ssh_invoke.push("--error-on-special-files")
+    }
+    if cfg.stats() && role == "receiver" {
+        ssh_invoke.push("--stats")
+    }
+    if cfg.human_readable() && role == "receiver" {
+        ssh_invoke.push("--human-readable")
+    }
+    if role == "receiver" {
+        for _ in 0..cfg.itemize_changes() {
+            ssh_invoke.push("--itemize-changes")
+        }
+    }
+    if cfg.keep_going() && role == "receiver" {
+        ssh_invoke.push("--keep-going")
+    }
+    if cfg.stage_then_swap() && role == "receiver" {
+        ssh_invoke.push("--stage-then-swap")
+    }
+    if cfg.prune_empty_dirs() && role == "receiver" {
+        ssh_invoke.push("--prune-empty-dirs")
+    }
+    let max_transfer = cfg.transfer_limits().bytes.map(|max| max.to_string());
+    if let (Some(max_transfer), "receiver") = (&max_transfer, role) {
+        ssh_invoke.push("--max-transfer");
+        ssh_invoke.push(max_transfer);
+    }
+    let max_files = cfg.transfer_limits().files.map(|max| max.to_string());
+    if let (Some(max_files), "receiver") = (&max_files, role) {
+        ssh_invoke.push("--max-files");
+        ssh_invoke.push(max_files);
+    }
+    match cfg.selection() {
+        Selection::Missing if role == "receiver" => ssh_invoke.push("--ignore-existing"),
+        Selection::Existing if role == "receiver" => ssh_invoke.push("--existing"),
+        _ => {}
+    }
+    let subpath = cfg.subpath().map(|subpath| subpath.to_string_lossy().into_owned());
+    if let (Some(subpath), "receiver") = (&subpath, role) {
+        ssh_invoke.push("--subpath");
+        ssh_invoke.push(subpath);
+    }
+    match cfg.append() {
+        Some(AppendMode::Trust) if role == "receiver" => ssh_invoke.push("--append"),
+        Some(AppendMode::Verify) if role == "receiver" => ssh_invoke.push("--append-verify"),
+        _ => {}
+    }
+    if cfg.whole_file() && role == "receiver" {
+        ssh_invoke.push("--whole-file")
+    }
+    if cfg.delay_updates() && role == "receiver" {
+        ssh_invoke.push("--delay-updates")
+    }
+    if cfg.checksum() {
+        ssh_invoke.push("--checksum")
+    }
+    if cfg.strict_clock() {
+        ssh_invoke.push("--strict-clock")
+    }
+    if cfg.xattrs() {
+        ssh_invoke.push("--xattrs")
+    }
+    if cfg.crtimes() {
+        ssh_invoke.push("--crtimes")
+    }
+    match cfg.privilege() {
+        PrivilegeMode::Super => ssh_invoke.push("--super"),
+        PrivilegeMode::FakeSuper => ssh_invoke.push("--fake-super"),
+        PrivilegeMode::Auto => {}
+    }
+    let bwlimit = cfg.bwlimit().map(|limit| (limit / 1024).to_string());
+    if let Some(bwlimit) = &bwlimit {
+        ssh_invoke.push("--bwlimit");
+        ssh_invoke.push(bwlimit);
+    }
+    let min_size = cfg.hash_settings().min_size().map(|size| size.to_string());
+    if let Some(min_size) = &min_size {
+        ssh_invoke.push("--min-size");
+        ssh_invoke.push(min_size);
+    }
+    let max_size = cfg.hash_settings().max_size().map(|size| size.to_string());
+    if let Some(max_size) = &max_size {
+        ssh_invoke.push("--max-size");
+        ssh_invoke.push(max_size);
+    }
+    // the cutoffs are passed as absolute times, so that the remote side's clock doesn't matter
+    let newer_than = cfg.hash_settings().newer_than().map(|time| format!("@{}", time.unix_seconds()));
+    if let Some(newer_than) = &newer_than {
+        ssh_invoke.push("--newer-than");
+        ssh_invoke.push(newer_than);
+    }
+    let older_than = cfg.hash_settings().older_than().map(|time| format!("@{}", time.unix_seconds()));
+    if let Some(older_than) = &older_than {
+        ssh_invoke.push("--older-than");
+        ssh_invoke.push(older_than);
+    }
+    let max_depth = cfg.hash_settings().max_depth().map(|depth| depth.to_string());
+    if let Some(max_depth) = &max_depth {
+        ssh_invoke.push("--max-depth");
+        ssh_invoke.push(max_depth);
+    }
+    let rules: Vec<(bool, String)> = cfg.hash_settings().filter_rules().iter()
+        .map(|rule| (rule.is_include(), rule.to_string()))
+        .collect();
+    for (include, glob) in &rules {
+        ssh_invoke.push(if *include { "--include" } else { "--exclude" });
+        ssh_invoke.push(glob);
     }
 
-    process::Command::new("ssh")
-        .args(ssh_invoke)
+    // the remote shell joins the command into one line and parses it again, quoting keeps
+    // paths and patterns intact. Paths are still expanded against the remote home directory
+    // where they ask for it, and relative ones are relative to it, as the command starts there.
+    let mut command = vec![shell_quote_path(cfg.remote_usync_path()), shell_quote(target_param), shell_quote_path(target_path)];
+    command.extend(ssh_invoke.iter().map(|word| shell_quote(word)));
+
+    let (rsh, rsh_args) = cfg.rsh().split_first().unwrap();
+    info!("Spawning process: {} {} {}", cfg.rsh().join(" "), remote, command.join(" "));
+
+    let mut child = process::Command::new(rsh)
+        .args(rsh_args)
+        .arg(remote)
+        .args(command)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(seed) = cfg.checksum_seed() {
+        writeln!(child.stdin.as_mut().unwrap(), "{}", seed)?;
+    }
+    let stderr = OutputTail::follow(child.stderr.take().unwrap(), std::io::stderr());
+    Ok((child, stderr))
+}
+
+/// Adds what the remote `role` last wrote to stderr to `error`. If the remote could not even
+/// start, the error itself is only a broken stream.
+fn explain_remote_failure(error: Error, role: &str, stderr: &OutputTail) -> Error {
+    let lines = stderr.lines(REMOTE_STDERR_WAIT);
+    if lines.is_empty() {
+        error
+    } else {
+        Error::new(error.kind(), format!("{} (remote {} reported: {})", error, role, lines.join(" / ")))
+    }
+}
+
+/// Connects a remote sender to a remote receiver, forwarding the protocol streams between them
+/// through this process. Neither side needs to be able to reach the other directly.
+fn main_as_relay((mut sender, sender_stderr): (process::Child, OutputTail), (mut receiver, receiver_stderr): (process::Child, OutputTail)) -> Result<(), Error> {
+    let mut from_sender = sender.stdout.take().unwrap();
+    let mut to_receiver = receiver.stdin.take().unwrap();
+    let mut from_receiver = receiver.stdout.take().unwrap();
+    let mut to_sender = sender.stdin.take().unwrap();
+
+    // each direction closes its output once its input is exhausted, so an exiting peer
+    // terminates the other one as well
+    let forward = thread::spawn(move || std::io::copy(&mut from_sender, &mut to_receiver));
+    let backward = std::io::copy(&mut from_receiver, &mut to_sender);
+    drop(to_sender);
+    let forward = forward.join().unwrap();
+
+    // a remote that failed tells more about what went wrong than the broken stream it caused
+    wait_for_remote("sender", sender, &sender_stderr)?;
+    wait_for_remote("receiver", receiver, &receiver_stderr)?;
+    backward?;
+    forward?;
+    Ok(())
+}
+
+fn wait_for_remote(role: &str, mut child: process::Child, stderr: &OutputTail) -> Result<(), Error> {
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        let error = Error::new(ErrorKind::Other, format!("Remote {} failed: {}", role, status));
+        Err(explain_remote_failure(error, role, stderr))
+    }
+}
+
+/// Builds the manifest of the local source and writes it to `path` (`-` for stdout), for a
+/// later `--read-manifest`.
+fn main_as_manifest_writer(cfg: &Configuration, path: &Path) -> Result<(), Error> {
+    if let PathDefinition::Local(root) = cfg.source() {
+        let manifest = Manifest::create_persistent(root, cfg.hash_settings(), cfg.manifest_location())?;
+        if path == Path::new("-") {
+            manifest.write_to(stdout().lock())
+        } else {
+            manifest.write_to(File::create(path)?)
+        }
+    } else {
+        non_local_path(cfg.source())
+    }
+}
+
+/// Prints every file in `manifest`, one per line: size (see `--human-readable`), modification
+/// time (seconds since the epoch), content hash (`-` in timestamp mode) and path.
+fn print_listing(cfg: &Configuration, manifest: &Manifest) {
+    for file in manifest.files() {
+        let hash = file.hash.map(hex::encode).unwrap_or_else(|| String::from("-"));
+        let size = if cfg.human_readable() { format_size(file.size) } else { file.size.to_string() };
+        println!("{:>12} {} {} {}", size, file.modification_time, hash, file.path.to_string_lossy());
+    }
+}
+
+/// Lists the files of the local source, see `print_listing`.
+fn main_as_lister(cfg: &Configuration) -> Result<(), Error> {
+    if let PathDefinition::Local(root) = cfg.source() {
+        // the stored manifest is never synced, so it isn't listed either
+        let settings = match cfg.manifest_location() {
+            ManifestLocation::File(path) => cfg.hash_settings().with_additional_exclusion(&root.join(path)),
+            ManifestLocation::Cache => cfg.hash_settings().clone(),
+        };
+        let manifest = Manifest::create_ephemeral(root, &settings)?;
+        print_listing(cfg, &manifest);
+        Ok(())
+    } else {
+        non_local_path(cfg.source())
+    }
+}
+
+/// Lists the files a remote sender or server offers, see `print_listing`. Only the manifest
+/// is requested, nothing is written locally.
+fn main_as_remote_lister(cfg: &Configuration) -> Result<(), Error> {
+    match cfg.source() {
+        PathDefinition::Server(remote, module) => {
+            let stream = connect(remote, cfg.io_timeout())?;
+            set_io_timeout(&stream, cfg.io_timeout())?;
+            list_remote_manifest(cfg, stream.try_clone()?, &stream, module.as_deref())
+                .map_err(|e| explain_timeout(e, cfg.io_timeout()))
+        }
+        PathDefinition::Remote(remote, remote_path) => {
+            let (mut proc, stderr) = spawn_remote_usync(cfg, "sender", remote, "--source", remote_path)?;
+            list_remote_manifest(cfg, proc.stdout.take().unwrap(), proc.stdin.take().unwrap(), None)
+                .map_err(|e| explain_remote_failure(e, "sender", &stderr))?;
+            wait_for_remote("sender", proc, &stderr)
+        }
+        local => Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a remote source", local))),
+    }
+}
+
+fn list_remote_manifest<R: Read, W: Write>(cfg: &Configuration, input: R, output: W, module: Option<&str>) -> Result<(), Error> {
+    // nothing is received, so the root is never used
+    let mut transmitter = remote::CommandTransmitter::new(Path::new("."), input, output, cfg.privilege())
+        .with_checksum_key(cfg.hash_settings().checksum_key());
+    transmitter.handshake(cfg.auth_token(), module)?;
+    print_listing(cfg, &transmitter.remote_manifest()?);
+    Ok(())
 }
 
 fn main_as_controller(cfg: &Configuration) -> Result<(), Error> {
@@ -128,32 +821,81 @@ fn main_as_controller(cfg: &Configuration) -> Result<(), Error> {
     let trg = cfg.target();
 
     match (src, trg) {
-        (PathDefinition::Local(_), PathDefinition::Local(_)) => {
-            if cfg.force_pipeline() {
+        (PathDefinition::Local(from), PathDefinition::Local(_)) => {
+            // only the sender side can read from an archive, and hold back updates
+            if cfg.force_pipeline() || cfg.delay_updates() || is_archive(from) {
                 main_as_local_pipe(cfg)
             } else {
                 main_as_local(cfg)
             }
         },
-        (PathDefinition::Server(remote), PathDefinition::Local(_)) => {
-            let stream = TcpStream::connect(remote)?;
-            main_as_receiver(cfg, &stream, &stream)
+        (PathDefinition::Server(remote, _), PathDefinition::Local(_)) => {
+            main_as_retrying_receiver(cfg, || {
+                let stream = connect(remote, cfg.io_timeout())?;
+                set_io_timeout(&stream, cfg.io_timeout())?;
+                Ok((stream.try_clone()?, stream))
+            })
         }
         (PathDefinition::Remote(remote, remote_path), PathDefinition::Local(_)) => {
-            let proc = spawn_remote_usync(cfg, "sender", remote, "--source", remote_path)?;
-            main_as_receiver(cfg, proc.stdout.unwrap(), proc.stdin.unwrap())
+            let mut stderr = None;
+            main_as_retrying_receiver(cfg, || {
+                let (proc, tail) = spawn_remote_usync(cfg, "sender", remote, "--source", remote_path)?;
+                stderr = Some(tail);
+                Ok((proc.stdout.unwrap(), proc.stdin.unwrap()))
+            }).map_err(|e| match &stderr {
+                Some(tail) => explain_remote_failure(e, "sender", tail),
+                None => e,
+            })
         }
         (PathDefinition::Local(_), PathDefinition::Remote(remote, remote_path)) => {
-            let proc = spawn_remote_usync(cfg, "receiver", remote, "--target", remote_path)?;
+            let (mut proc, stderr) = spawn_remote_usync(cfg, "receiver", remote, "--target", remote_path)?;
 
-            main_as_sender(cfg, proc.stdout.unwrap(), proc.stdin.unwrap())
+            main_as_sender(cfg, proc.stdout.take().unwrap(), proc.stdin.take().unwrap())
+                .map_err(|e| explain_remote_failure(e, "receiver", &stderr))?;
+            // the receiver fails on its own at the end with --keep-going, so it has to be asked
+            wait_for_remote("receiver", proc, &stderr)
+        }
+        (PathDefinition::Local(_), PathDefinition::Server(remote, module)) => {
+            main_as_pushing_sender(cfg, remote, module.as_deref())
+        }
+        (PathDefinition::Remote(from, from_path), PathDefinition::Remote(to, to_path)) => {
+            let sender = spawn_remote_usync(cfg, "sender", from, "--source", from_path)?;
+            let receiver = spawn_remote_usync(cfg, "receiver", to, "--target", to_path)?;
+
+            main_as_relay(sender, receiver)
         }
         _ => Err(Error::new(ErrorKind::Other, format!("Unsupported combination of paths: {} vs {}", src, trg)))
     }
 }
 
+/// Logs to stderr, keeping stdout free for the protocol when running behind ssh. `USYNC_LOG`
+/// overrides the level implied by `--verbose`.
+fn init_logging(cfg: &Configuration) {
+    let default = if cfg.verbose() { "info" } else { "warn" };
+    env_logger::Builder::from_env(env_logger::Env::new().filter_or("USYNC_LOG", default)).init();
+}
+
+/// Syncs each directory a `--source` pattern matches into its own subdirectory of the
+/// target, one after the other, stopping at the first that fails.
+fn main_as_glob_controller(cfg: &Configuration, pattern: &Path) -> Result<(), Error> {
+    for root_cfg in cfg.expand_source_glob(pattern)? {
+        if let (PathDefinition::Local(source), PathDefinition::Local(target)) = (root_cfg.source(), root_cfg.target()) {
+            info!("Syncing {} into {}", source.to_string_lossy(), target.to_string_lossy());
+            std::fs::create_dir_all(target)?;
+        }
+        check_local_roots(&root_cfg)?;
+        main_as_controller(&root_cfg)?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Error> {
     let cfg = Configuration::parse()?;
+    init_logging(&cfg);
+    if let Some(pattern) = cfg.source_glob() {
+        return main_as_glob_controller(&cfg, pattern);
+    }
+    check_local_roots(&cfg)?;
     match cfg.role() {
         Some(ProcessRole::Sender) =>
             main_as_sender(&cfg, stdin(), stdout()),
@@ -161,6 +903,14 @@ fn main() -> Result<(), Error> {
             main_as_receiver(&cfg, stdin(), stdout()),
         Some(ProcessRole::Server) =>
             main_as_server(&cfg),
+        _ if cfg.benchmark() =>
+            main_as_benchmark(&cfg),
+        _ if cfg.list_only() =>
+            main_as_lister(&cfg),
+        _ if cfg.list_remote() =>
+            main_as_remote_lister(&cfg),
+        _ if cfg.write_manifest().is_some() =>
+            main_as_manifest_writer(&cfg, cfg.write_manifest().unwrap()),
         _ =>
             main_as_controller(&cfg)
     }
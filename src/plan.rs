@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::error::UsyncError;
+use crate::file_transfer::{is_file_error, Transmitter};
+use crate::tree::{Change, CopyOptions, CopyStats, Differences, Manifest, ShaSum};
+use crate::util::stays_below;
+
+/// Identifies the first line of a plan file.
+const PLAN_FORMAT: &str = "usync-plan";
+
+/// Version of the plan format, changed whenever the actions or their fields do. Plans of other
+/// versions are refused.
+pub const PLAN_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PlanHeader {
+    format: String,
+    version: u32,
+}
+
+/// A single step of a `Plan`, with paths relative to the sync roots. usync never deletes
+/// from the target, so there is no action for that.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    /// Create the directory at `path`
+    Mkdir { path: PathBuf },
+    /// Transfer the new file at `path`, which must hash to `hash` (hex encoded) if given, and
+    /// set its permissions to `mode` if given
+    Transfer { path: PathBuf, size: u64, hash: Option<String>, mode: Option<u32> },
+    /// Like `Transfer`, but replacing a file that differs as given
+    Overwrite { path: PathBuf, size: u64, hash: Option<String>, mode: Option<u32>, differences: Differences },
+    /// Make `path` a hard link to `existing`, replacing a file that differs as given, if any
+    Link { path: PathBuf, existing: PathBuf, differences: Option<Differences> },
+    /// Set the permissions of the file at `path`, which is left in place, to `mode`
+    Chmod { path: PathBuf, mode: u32, differences: Differences },
+}
+
+impl Action {
+    /// The path the action changes.
+    pub fn path(&self) -> &Path {
+        match self {
+            Action::Mkdir { path } |
+            Action::Transfer { path, .. } |
+            Action::Overwrite { path, .. } |
+            Action::Link { path, .. } |
+            Action::Chmod { path, .. } => path,
+        }
+    }
+
+    /// Fails unless all paths of the action stay below the roots they are joined onto. A plan
+    /// may have been edited since it was written, it must not reach outside the target.
+    fn check_paths(&self) -> Result<()> {
+        let existing = match self {
+            Action::Link { existing, .. } => Some(existing.as_path()),
+            _ => None,
+        };
+        match std::iter::once(self.path()).chain(existing).find(|path| !stays_below(path)) {
+            Some(path) => Err(Error::new(ErrorKind::InvalidData, format!("Path {} leaves the target", path.to_string_lossy()))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a sync would do to the target, computed without doing it (see `--plan`), so that it
+/// can be reviewed and carried out later with `apply` (see `--apply-plan`).
+///
+/// Plans are stored as JSON lines: a header with the format version, followed by one action
+/// per line, in the order the sync would take them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    actions: Vec<Action>,
+}
+
+impl Plan {
+    /// The actions copying `source` to `target` as `options` say takes.
+    pub fn of(target: &Manifest, source: &Manifest, options: CopyOptions) -> Result<Plan> {
+        let mut recorder = PlanRecorder::new(source);
+        target.copy_with(source, options, &mut recorder)?;
+        Ok(Plan { actions: recorder.actions })
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    pub fn write_to<W: Write>(&self, output: W) -> Result<()> {
+        let mut output = BufWriter::new(output);
+        let header = PlanHeader { format: String::from(PLAN_FORMAT), version: PLAN_VERSION };
+        serde_json::to_writer(&mut output, &header).map_err(Error::from)?;
+        writeln!(output)?;
+        for action in &self.actions {
+            serde_json::to_writer(&mut output, action).map_err(Error::from)?;
+            writeln!(output)?;
+        }
+        output.flush()
+    }
+
+    /// Reads a plan `write_to` wrote. Fails if it is in another version of the format.
+    pub fn read_from<R: Read>(input: R) -> Result<Plan> {
+        let mut lines = BufReader::new(input).lines();
+        let header: PlanHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).ok(),
+            None => None,
+        }.filter(|header: &PlanHeader| header.format == PLAN_FORMAT)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not a usync plan, it lacks the header"))?;
+        if header.version != PLAN_VERSION {
+            return Err(UsyncError::VersionMismatch(format!(
+                "Plan has format version {}, but this usync reads version {}", header.version, PLAN_VERSION)).into());
+        }
+
+        let mut actions = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // the header is line 1
+            let action = serde_json::from_str(&line).map_err(Error::from)
+                .and_then(|action: Action| action.check_paths().map(|()| action))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid action in line {} of the plan: {}", index + 2, e)))?;
+            actions.push(action);
+        }
+        Ok(Plan { actions })
+    }
+
+    /// Carries out the actions through `transmitter`, in order, and commits them. With
+    /// `keep_going`, files that fail are listed in the returned stats instead of ending it.
+    pub fn apply<T: Transmitter>(&self, transmitter: &mut T, keep_going: bool) -> Result<CopyStats> {
+        let started = Instant::now();
+        let mut stats = CopyStats::default();
+        for action in &self.actions {
+            let path = action.path();
+            match apply_action(action, transmitter, &mut stats) {
+                Ok(()) => {}
+                Err(e) if keep_going && is_file_error(&e) && !matches!(action, Action::Mkdir { .. }) => {
+                    warn!("Failed to transfer {}: {}", path.to_string_lossy(), e);
+                    stats.record_failure(path, &e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        transmitter.commit()?;
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+}
+
+fn apply_action<T: Transmitter>(action: &Action, transmitter: &mut T, stats: &mut CopyStats) -> Result<()> {
+    match action {
+        Action::Mkdir { path } => {
+            transmitter.create_directory(path)?;
+            stats.directories_created += 1;
+            transmitter.itemize(path, Change::DirectoryCreated);
+        }
+        Action::Transfer { path, hash, mode, .. } | Action::Overwrite { path, hash, mode, .. } => {
+            let expected = hash.as_deref().map(decode_hash).transpose()?;
+            let bytes = transmitter.transmit(path, expected.as_ref())?;
+            if let Some(mode) = mode {
+                transmitter.set_permissions(path, *mode)?;
+            }
+            stats.bytes_transferred += bytes;
+            let differences = match action {
+                Action::Overwrite { differences, .. } => {
+                    stats.files_overwritten += 1;
+                    Some(*differences)
+                }
+                _ => {
+                    stats.files_created += 1;
+                    None
+                }
+            };
+            transmitter.itemize(path, Change::Transferred(differences));
+        }
+        Action::Link { path, existing, differences } => {
+            transmitter.hard_link(existing, path)?;
+            stats.files_linked += 1;
+            if differences.is_some() {
+                stats.files_overwritten += 1;
+            } else {
+                stats.files_created += 1;
+            }
+            transmitter.itemize(path, Change::Linked(*differences));
+        }
+        Action::Chmod { path, mode, differences } => {
+            transmitter.set_permissions(path, *mode)?;
+            stats.files_updated += 1;
+            transmitter.itemize(path, Change::Updated(*differences));
+        }
+    }
+    Ok(())
+}
+
+fn decode_hash(hex_hash: &str) -> Result<ShaSum> {
+    hex::decode(hex_hash).ok()
+        .and_then(|bytes| ShaSum::try_from(bytes.as_slice()).ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid content hash {} in the plan", hex_hash)))
+}
+
+/// Transmitter recording what a copy does as `Action`s, doing none of it. The copy reports
+/// each change through `itemize` once it is done, the calls before tell the details.
+struct PlanRecorder<'a> {
+    /// Size and content hash of every file of the source
+    files: HashMap<PathBuf, (u64, Option<&'a ShaSum>)>,
+    /// Permissions set on the file itemized next
+    mode: Option<u32>,
+    /// What the file itemized next is linked to
+    existing: Option<PathBuf>,
+    actions: Vec<Action>,
+}
+
+impl<'a> PlanRecorder<'a> {
+    fn new(source: &'a Manifest) -> PlanRecorder<'a> {
+        let files = source.files().into_iter()
+            .map(|file| (file.path, (file.size, file.hash)))
+            .collect();
+        PlanRecorder { files, mode: None, existing: None, actions: Vec::new() }
+    }
+
+    fn file(&self, path: &Path) -> (u64, Option<String>) {
+        match self.files.get(path) {
+            Some((size, hash)) => (*size, hash.map(hex::encode)),
+            None => (0, None),
+        }
+    }
+}
+
+impl Transmitter for PlanRecorder<'_> {
+    fn transmit(&mut self, path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+        Ok(self.file(path).0)
+    }
+
+    fn create_directory(&mut self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn hard_link(&mut self, existing: &Path, _path: &Path) -> Result<()> {
+        self.existing = Some(existing.to_owned());
+        Ok(())
+    }
+
+    fn set_permissions(&mut self, _path: &Path, mode: u32) -> Result<()> {
+        self.mode = Some(mode);
+        Ok(())
+    }
+
+    fn itemize(&mut self, path: &Path, change: Change) {
+        let path = path.to_owned();
+        let mode = self.mode.take();
+        let action = match change {
+            Change::Unchanged => return,
+            Change::DirectoryCreated => Action::Mkdir { path },
+            Change::Transferred(None) => {
+                let (size, hash) = self.file(&path);
+                Action::Transfer { path, size, hash, mode }
+            }
+            Change::Transferred(Some(differences)) => {
+                let (size, hash) = self.file(&path);
+                Action::Overwrite { path, size, hash, mode, differences }
+            }
+            Change::Linked(differences) => {
+                let existing = self.existing.take().expect("hard_link precedes the link being itemized");
+                Action::Link { path, existing, differences }
+            }
+            Change::Updated(differences) => {
+                Action::Chmod { path, mode: mode.expect("set_permissions precedes the update being itemized"), differences }
+            }
+        };
+        self.actions.push(action);
+    }
+}
+
+#[cfg(test)]
+mod test_plan {
+    use super::*;
+    use crate::config::{Comparison, HashSettings, ManifestMode, PrivilegeMode};
+    use crate::file_transfer::local::LocalTransmitter;
+    use filetime::{FileTime, set_file_mtime};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn manifests(source: &Path, target: &Path) -> Result<(Manifest, Manifest)> {
+        let settings = HashSettings::default().with_manifest_mode(ManifestMode::Hash);
+        Ok((Manifest::create_ephemeral(source, &settings)?, Manifest::create_ephemeral(target, &settings)?))
+    }
+
+    #[test]
+    fn plan_lists_actions_without_doing_them() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        std::fs::create_dir(source.path().join("sub"))?;
+        std::fs::write(source.path().join("sub/new.txt"), b"abc")?;
+        std::fs::write(source.path().join("changed.txt"), b"new")?;
+        std::fs::write(target.path().join("changed.txt"), b"older")?;
+        for root in &[&source, &target] {
+            set_file_mtime(root.path().join("changed.txt"), FileTime::from_unix_time(1_000_000_000, 0))?;
+        }
+
+        let (source_manifest, target_manifest) = manifests(source.path(), target.path())?;
+        let plan = Plan::of(&target_manifest, &source_manifest, CopyOptions::new(Comparison::Timestamps(Duration::from_secs(0))))?;
+        let hash = |contents: &[u8]| crate::tree::hash(contents).map(hex::encode).ok();
+        assert_eq!(plan.actions(), &[
+            Action::Mkdir { path: PathBuf::from("sub") },
+            Action::Transfer { path: PathBuf::from("sub/new.txt"), size: 3, hash: hash(b"abc"), mode: None },
+            Action::Overwrite { path: PathBuf::from("changed.txt"), size: 3, hash: hash(b"new"), mode: None,
+                differences: Differences { content: true, size: true, time: false, perms: false } },
+        ][..]);
+        assert!(!target.path().join("sub").exists());
+        assert_eq!(std::fs::read(target.path().join("changed.txt"))?, b"older");
+
+        let stats = plan.apply(&mut LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto), false)?;
+        assert_eq!((stats.files_created, stats.files_overwritten, stats.directories_created), (1, 1, 1));
+        assert_eq!(std::fs::read(target.path().join("sub/new.txt"))?, b"abc");
+        assert_eq!(std::fs::read(target.path().join("changed.txt"))?, b"new");
+        Ok(())
+    }
+
+    #[test]
+    fn plan_round_trips_and_checks_version() -> Result<()> {
+        let plan = Plan { actions: vec![
+            Action::Mkdir { path: PathBuf::from("sub") },
+            Action::Link { path: PathBuf::from("b"), existing: PathBuf::from("a"), differences: None },
+            Action::Chmod { path: PathBuf::from("c"), mode: 0o640, differences: Differences { content: false, size: false, time: false, perms: true } },
+        ] };
+        let mut stored = Vec::new();
+        plan.write_to(&mut stored)?;
+        assert!(stored.starts_with(b"{\"format\":\"usync-plan\",\"version\":1}\n{\"action\":\"mkdir\",\"path\":\"sub\"}\n"));
+        assert_eq!(Plan::read_from(stored.as_slice())?, plan);
+
+        let error = Plan::read_from(&b"{\"format\":\"usync-plan\",\"version\":0}\n"[..]).unwrap_err();
+        assert!(matches!(UsyncError::of(&error), Some(UsyncError::VersionMismatch(_))), "{}", error);
+        let error = Plan::read_from(&b"{\"action\":\"mkdir\",\"path\":\"sub\"}\n"[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_leaving_the_target_is_refused() -> Result<()> {
+        let differences = r#""differences":{"content":false,"size":false,"time":false,"perms":true}"#;
+        let actions = [
+            format!(r#"{{"action":"chmod","path":"../victim/f","mode":511,{}}}"#, differences),
+            String::from(r#"{"action":"transfer","path":"/tmp/victim/f","size":1,"hash":null,"mode":null}"#),
+            String::from(r#"{"action":"mkdir","path":"sub/../.."}"#),
+            String::from(r#"{"action":"link","path":"b","existing":"../a","differences":null}"#),
+            String::from(r#"{"action":"mkdir","path":""}"#),
+        ];
+        for action in &actions {
+            let plan = format!("{{\"format\":\"usync-plan\",\"version\":1}}\n{}\n", action);
+            let error = Plan::read_from(plan.as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert!(error.to_string().contains("line 2"), "{}", error);
+        }
+        Ok(())
+    }
+}
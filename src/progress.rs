@@ -0,0 +1,529 @@
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Result, Stderr, Stdout, Write, stderr, stdout};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::file_transfer::Transmitter;
+use crate::tree::{Change, ShaSum, TransferTotals};
+use crate::util::format_size;
+
+/// Span of recent progress the transfer rate, and thus the ETA, is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Moving average of the transfer rate, over the samples of the last `RATE_WINDOW`.
+struct Throughput {
+    /// Bytes done so far at each sample, oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    fn new() -> Throughput {
+        Throughput { samples: VecDeque::new() }
+    }
+
+    fn record(&mut self, at: Instant, bytes_done: u64) {
+        self.samples.push_back((at, bytes_done));
+        // the oldest sample inside the window is kept as its starting point
+        while self.samples.len() > 2 && at.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second, once there are samples far enough apart to tell.
+    fn rate(&self) -> Option<f64> {
+        let (first, first_bytes) = self.samples.front()?;
+        let (last, last_bytes) = self.samples.back()?;
+        let seconds = last.duration_since(*first).as_secs_f64();
+        if seconds > 0.0 {
+            Some((last_bytes - first_bytes) as f64 / seconds)
+        } else {
+            None
+        }
+    }
+
+    /// Time until `remaining` more bytes are done at the current rate.
+    fn eta(&self, remaining: u64) -> Option<Duration> {
+        self.rate().filter(|rate| *rate > 0.0).map(|rate| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+/// Formats `duration` as hours, minutes and seconds, leaving out leading zero units.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+/// Running tally of a copy, rendered on stdout at most once per `interval`.
+pub struct Progress {
+    total: TransferTotals,
+    done: TransferTotals,
+    throughput: Throughput,
+    tty: bool,
+    interval: Duration,
+    last_render: Option<Instant>,
+    human_readable: bool,
+}
+
+impl Progress {
+    pub fn new(total: TransferTotals) -> Progress {
+        let tty = stdout().is_terminal();
+        Progress {
+            total,
+            done: TransferTotals::default(),
+            throughput: Throughput::new(),
+            tty,
+            interval: if tty { Duration::from_millis(100) } else { Duration::from_secs(1) },
+            last_render: None,
+            human_readable: false,
+        }
+    }
+
+    /// Renders sizes and rates with binary suffixes, see `format_size`.
+    pub fn with_human_readable(mut self, human_readable: bool) -> Progress {
+        self.human_readable = human_readable;
+        self
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        self.done.files += 1;
+        self.done.bytes += bytes;
+        self.throughput.record(Instant::now(), self.done.bytes);
+
+        let due = match self.last_render {
+            None => true,
+            Some(last) => last.elapsed() >= self.interval,
+        };
+        if due {
+            self.render();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.render();
+        if self.tty {
+            println!();
+        }
+    }
+
+    fn line(&self) -> String {
+        let mut line = if self.human_readable {
+            format!("{}/{} files, {}/{}", self.done.files, self.total.files, format_size(self.done.bytes), format_size(self.total.bytes))
+        } else {
+            format!("{}/{} files, {}/{} bytes", self.done.files, self.total.files, self.done.bytes, self.total.bytes)
+        };
+        if let Some(rate) = self.throughput.rate() {
+            if self.human_readable {
+                line.push_str(&format!(", {}/s", format_size(rate as u64)));
+            } else {
+                line.push_str(&format!(", {:.0} bytes/s", rate));
+            }
+        }
+        let remaining = self.total.bytes.saturating_sub(self.done.bytes);
+        if let Some(eta) = self.throughput.eta(remaining).filter(|_| remaining > 0) {
+            line.push_str(&format!(", ETA {}", format_duration(eta)));
+        }
+        line
+    }
+
+    fn render(&mut self) {
+        if self.tty {
+            print!("\r{}", self.line());
+            let _ = stdout().flush();
+        } else {
+            println!("{}", self.line());
+        }
+        self.last_render = Some(Instant::now());
+    }
+}
+
+/// Transmitter decorator that reports each completed transfer to a `Progress`.
+pub struct ProgressTransmitter<'a, T: Transmitter> {
+    inner: &'a mut T,
+    progress: Progress,
+}
+
+impl<'a, T: Transmitter> ProgressTransmitter<'a, T> {
+    pub fn new(inner: &'a mut T, total: TransferTotals) -> ProgressTransmitter<'a, T> {
+        ProgressTransmitter { inner, progress: Progress::new(total) }
+    }
+
+    /// Renders the progress with binary suffixes, see `Progress::with_human_readable`.
+    pub fn with_human_readable(mut self, human_readable: bool) -> Self {
+        self.progress = self.progress.with_human_readable(human_readable);
+        self
+    }
+
+    pub fn finish(&mut self) {
+        self.progress.finish()
+    }
+}
+
+impl<T: Transmitter> Transmitter for ProgressTransmitter<'_, T> {
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        let bytes = self.inner.transmit(path, expected)?;
+        self.progress.record(bytes);
+        Ok(bytes)
+    }
+
+    fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    fn already_transferred(&self, path: &Path) -> bool {
+        self.inner.already_transferred(path)
+    }
+
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        let transferred = self.inner.transmit_batch(files)?;
+        for bytes in transferred.iter().flatten() {
+            self.progress.record(*bytes);
+        }
+        Ok(transferred)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        self.inner.create_directory(path)
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        self.inner.hard_link(existing, path)?;
+        self.progress.record(0);
+        Ok(())
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.inner.set_permissions(path, mode)
+    }
+
+    fn itemize(&mut self, path: &Path, change: Change) {
+        self.inner.itemize(path, change)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+}
+
+/// One line of the `--events` stream.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Transfer { path: &'a str, bytes: u64 },
+    Directory { path: &'a str },
+    HardLink { path: &'a str, existing: &'a str },
+    Permissions { path: &'a str, mode: String },
+    Error { path: &'a str, message: String },
+}
+
+/// Transmitter decorator that writes every transfer, created directory and failure as a JSON
+/// line (to stderr, unless constructed `with_output`), so other tools can follow a sync.
+pub struct EventTransmitter<'a, T: Transmitter, W: Write = Stderr> {
+    inner: &'a mut T,
+    output: W,
+}
+
+impl<'a, T: Transmitter> EventTransmitter<'a, T> {
+    pub fn new(inner: &'a mut T) -> EventTransmitter<'a, T> {
+        EventTransmitter::with_output(inner, stderr())
+    }
+}
+
+impl<'a, T: Transmitter, W: Write> EventTransmitter<'a, T, W> {
+    pub fn with_output(inner: &'a mut T, output: W) -> EventTransmitter<'a, T, W> {
+        EventTransmitter { inner, output }
+    }
+
+    /// Losing an event must not fail the sync, so write errors are ignored.
+    fn emit(&mut self, event: &Event) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(self.output, "{}", line).and_then(|_| self.output.flush());
+        }
+    }
+}
+
+impl<T: Transmitter, W: Write> Transmitter for EventTransmitter<'_, T, W> {
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        let name = path.to_string_lossy();
+        match self.inner.transmit(path, expected) {
+            Ok(bytes) => {
+                self.emit(&Event::Transfer { path: &name, bytes });
+                Ok(bytes)
+            }
+            Err(e) => {
+                self.emit(&Event::Error { path: &name, message: e.to_string() });
+                Err(e)
+            }
+        }
+    }
+
+    fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    fn already_transferred(&self, path: &Path) -> bool {
+        self.inner.already_transferred(path)
+    }
+
+    /// A failed batch is reported as an error for each of its files, since it is not known
+    /// which of them were received.
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        match self.inner.transmit_batch(files) {
+            Ok(transferred) => {
+                for ((path, _), result) in files.iter().zip(&transferred) {
+                    let path = path.to_string_lossy();
+                    match result {
+                        Ok(bytes) => self.emit(&Event::Transfer { path: &path, bytes: *bytes }),
+                        Err(e) => self.emit(&Event::Error { path: &path, message: e.to_string() }),
+                    }
+                }
+                Ok(transferred)
+            }
+            Err(e) => {
+                for (path, _) in files {
+                    self.emit(&Event::Error { path: &path.to_string_lossy(), message: e.to_string() });
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        let name = path.to_string_lossy();
+        match self.inner.create_directory(path) {
+            Ok(()) => {
+                self.emit(&Event::Directory { path: &name });
+                Ok(())
+            }
+            Err(e) => {
+                self.emit(&Event::Error { path: &name, message: e.to_string() });
+                Err(e)
+            }
+        }
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        let name = path.to_string_lossy();
+        match self.inner.hard_link(existing, path) {
+            Ok(()) => {
+                self.emit(&Event::HardLink { path: &name, existing: &existing.to_string_lossy() });
+                Ok(())
+            }
+            Err(e) => {
+                self.emit(&Event::Error { path: &name, message: e.to_string() });
+                Err(e)
+            }
+        }
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        let name = path.to_string_lossy();
+        match self.inner.set_permissions(path, mode) {
+            Ok(()) => {
+                self.emit(&Event::Permissions { path: &name, mode: format!("{:o}", mode) });
+                Ok(())
+            }
+            Err(e) => {
+                self.emit(&Event::Error { path: &name, message: e.to_string() });
+                Err(e)
+            }
+        }
+    }
+
+    fn itemize(&mut self, path: &Path, change: Change) {
+        self.inner.itemize(path, change)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+}
+
+/// Transmitter decorator that lists what the copy does, one line per item with its
+/// `Change` code (to stdout, unless constructed `with_output`), see `--itemize-changes`.
+/// Unchanged files are only listed `with_unchanged`.
+pub struct ItemizeTransmitter<'a, T: Transmitter, W: Write = Stdout> {
+    inner: &'a mut T,
+    output: W,
+    unchanged: bool,
+}
+
+impl<'a, T: Transmitter> ItemizeTransmitter<'a, T> {
+    pub fn new(inner: &'a mut T) -> ItemizeTransmitter<'a, T> {
+        ItemizeTransmitter::with_output(inner, stdout())
+    }
+}
+
+impl<'a, T: Transmitter, W: Write> ItemizeTransmitter<'a, T, W> {
+    pub fn with_output(inner: &'a mut T, output: W) -> ItemizeTransmitter<'a, T, W> {
+        ItemizeTransmitter { inner, output, unchanged: false }
+    }
+
+    pub fn with_unchanged(mut self, unchanged: bool) -> Self {
+        self.unchanged = unchanged;
+        self
+    }
+}
+
+impl<T: Transmitter, W: Write> Transmitter for ItemizeTransmitter<'_, T, W> {
+    fn transmit(&mut self, path: &Path, expected: Option<&ShaSum>) -> Result<u64> {
+        self.inner.transmit(path, expected)
+    }
+
+    fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    fn already_transferred(&self, path: &Path) -> bool {
+        self.inner.already_transferred(path)
+    }
+
+    fn transmit_batch(&mut self, files: &[(PathBuf, Option<&ShaSum>)]) -> Result<Vec<Result<u64>>> {
+        self.inner.transmit_batch(files)
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        self.inner.create_directory(path)
+    }
+
+    fn hard_link(&mut self, existing: &Path, path: &Path) -> Result<()> {
+        self.inner.hard_link(existing, path)
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.inner.set_permissions(path, mode)
+    }
+
+    /// Like events, a line that can't be written must not fail the sync.
+    fn itemize(&mut self, path: &Path, change: Change) {
+        if change == Change::Unchanged && !self.unchanged {
+            return;
+        }
+        let slash = if change == Change::DirectoryCreated { "/" } else { "" };
+        let _ = writeln!(self.output, "{} {}{}", change, path.to_string_lossy(), slash);
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod test_progress {
+    use super::*;
+
+    #[test]
+    fn rate_averages_recent_samples() {
+        let start = Instant::now();
+        let mut throughput = Throughput::new();
+        throughput.record(start, 0);
+        assert_eq!(throughput.rate(), None);
+
+        throughput.record(start + Duration::from_secs(2), 2000);
+        assert_eq!(throughput.rate(), Some(1000.0));
+        assert_eq!(throughput.eta(5000), Some(Duration::from_secs(5)));
+
+        // a stall long ago no longer counts once newer samples span the window
+        throughput.record(start + Duration::from_secs(30), 2000);
+        throughput.record(start + Duration::from_secs(31), 2500);
+        throughput.record(start + Duration::from_secs(41), 7500);
+        assert_eq!(throughput.rate(), Some(500.0));
+    }
+
+    #[test]
+    fn stalled_transfer_has_no_eta() {
+        let start = Instant::now();
+        let mut throughput = Throughput::new();
+        throughput.record(start, 100);
+        throughput.record(start + Duration::from_secs(1), 100);
+        assert_eq!(throughput.rate(), Some(0.0));
+        assert_eq!(throughput.eta(1), None);
+    }
+
+    #[test]
+    fn human_readable_line() {
+        let mut progress = Progress::new(TransferTotals { files: 2, bytes: 3 << 20 }).with_human_readable(true);
+        progress.done = TransferTotals { files: 1, bytes: 1536 };
+        assert_eq!(progress.line(), "1/2 files, 1.5 KiB/3.0 MiB");
+        progress.human_readable = false;
+        assert_eq!(progress.line(), "1/2 files, 1536/3145728 bytes");
+    }
+
+    #[test]
+    fn durations_are_formatted_by_unit() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m05s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 4)), "3h00m04s");
+    }
+}
+
+#[cfg(test)]
+mod test_events {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    struct FixedTransmitter;
+
+    impl Transmitter for FixedTransmitter {
+        fn transmit(&mut self, path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            if path == Path::new("broken") {
+                Err(Error::new(ErrorKind::Other, "disk full"))
+            } else {
+                Ok(1234)
+            }
+        }
+
+        fn create_directory(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_are_json_lines() {
+        let mut inner = FixedTransmitter;
+        let mut output = Vec::new();
+        {
+            let mut transmitter = EventTransmitter::with_output(&mut inner, &mut output);
+            transmitter.create_directory(Path::new("a")).unwrap();
+            transmitter.transmit(Path::new("a/b.txt"), None).unwrap();
+            assert!(transmitter.transmit(Path::new("broken"), None).is_err());
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "{\"event\":\"directory\",\"path\":\"a\"}\n",
+            "{\"event\":\"transfer\",\"path\":\"a/b.txt\",\"bytes\":1234}\n",
+            "{\"event\":\"error\",\"path\":\"broken\",\"message\":\"disk full\"}\n",
+        ));
+    }
+
+    #[test]
+    fn itemized_lines() {
+        for (unchanged, expected) in &[
+            (false, "cd+++++++++ a/\n>f+++++++++ a/b.txt\n"),
+            (true, "cd+++++++++ a/\n>f+++++++++ a/b.txt\n.f          c.txt\n"),
+        ] {
+            let mut inner = FixedTransmitter;
+            let mut output = Vec::new();
+            {
+                let mut transmitter = ItemizeTransmitter::with_output(&mut inner, &mut output).with_unchanged(*unchanged);
+                transmitter.itemize(Path::new("a"), Change::DirectoryCreated);
+                transmitter.itemize(Path::new("a/b.txt"), Change::Transferred(None));
+                transmitter.itemize(Path::new("c.txt"), Change::Unchanged);
+            }
+            assert_eq!(String::from_utf8(output).unwrap(), *expected);
+        }
+    }
+}
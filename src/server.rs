@@ -1,135 +1,674 @@
-use crate::file_transfer::{FileAccess, remote};
+use crate::file_transfer::{FileAccess, FileStat, remote};
+use crate::file_transfer::remote::{Access, CommandTransmitter, SenderSettings, SessionMode};
 use std::path::{Path, PathBuf};
 use std::fs::{Metadata, File};
 use std::io::{Result, Read, Error, ErrorKind};
-use std::sync::{Arc, Mutex};
-use memmap::Mmap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::cmp::min;
-use std::collections::HashMap;
-use std::net::TcpListener;
-use crate::config::Configuration;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{TcpListener, TcpStream};
+use crate::config::{AppendMode, Configuration, HashSettings, ManifestLocation, PrivilegeMode};
 use crate::config::PathDefinition::Local;
 use crate::tree::Manifest;
+use crate::error::UsyncError;
+use crate::util::{explain_timeout, set_io_timeout};
 use std::thread;
+use log::{error, info, warn};
+
+/// Set when the process receives SIGINT, making `Server::run` stop accepting connections.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// How often the accept loop checks for a shutdown request, and how often a shutdown checks
+/// whether all connections have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct Server {
     listener: TcpListener,
+    state: Arc<ServerState>,
+    in_flight: Arc<Connections>,
+    /// Connections handled at once, further clients wait in the listen backlog
+    max_connections: Option<usize>,
+    shutdown_timeout: Option<Duration>,
+}
+
+/// Counts the connections in flight, like a semaphore whose permits are `InFlight` guards:
+/// the accept loop waits for one to be released once `--max-connections` are taken.
+struct Connections {
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn new() -> Connections {
+        Connections { open: Mutex::new(0), closed: Condvar::new() }
+    }
+
+    fn open(&self) -> usize {
+        *self.open.lock().unwrap()
+    }
+
+    /// Waits up to `timeout` for fewer than `max` connections to be open. Returns whether
+    /// there are.
+    fn wait_below(&self, max: usize, timeout: Duration) -> bool {
+        let open = self.open.lock().unwrap();
+        let (open, _) = self.closed.wait_timeout_while(open, timeout, |open| *open >= max).unwrap();
+        *open < max
+    }
+}
+
+/// Counts a connection as in flight for as long as it is alive.
+struct InFlight(Arc<Connections>);
+
+impl InFlight {
+    fn new(connections: &Arc<Connections>) -> InFlight {
+        *connections.open.lock().unwrap() += 1;
+        InFlight(connections.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+        self.0.closed.notify_all();
+    }
+}
+
+#[cfg(unix)]
+fn install_shutdown_handler() -> Result<()> {
+    extern "C" fn request_shutdown(_signal: libc::c_int) {
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }
+
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGINT, handler) } == libc::SIG_ERR {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_shutdown_handler() -> Result<()> {
+    // without a handler, Ctrl-C terminates the server immediately
+    Ok(())
+}
+
+/// Waits until no connection is in flight, or `timeout` expires. Returns whether all
+/// connections finished.
+fn wait_for_connections(in_flight: &Connections, timeout: Option<Duration>) -> bool {
+    let started = Instant::now();
+    loop {
+        let wait = match timeout {
+            Some(timeout) => POLL_INTERVAL.min(timeout.saturating_sub(started.elapsed())),
+            None => POLL_INTERVAL,
+        };
+        if in_flight.wait_below(1, wait) {
+            return true;
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            return false;
+        }
+    }
+}
+
+/// A tree the server offers, with the access rules of its clients.
+struct Module {
     root: PathBuf,
-    manifest: Arc<Manifest>,
-    verbose: bool
+    /// Manifest of the served tree and when it was built. It is replaced after every push, and
+    /// with `--refresh-interval` once it gets too old.
+    manifest: Mutex<(Arc<Manifest>, Instant)>,
+    access: Access,
+    /// Held while receiving a push, so that concurrent pushes don't interleave
+    push_lock: Mutex<()>,
+}
+
+impl Module {
+    fn new(root: &Path, cfg: &Configuration, access: Access) -> Result<Module> {
+        let manifest = Manifest::create_persistent(root, cfg.hash_settings(), cfg.manifest_location())?;
+        if access.allow_push && access.token.is_none() {
+            warn!("Accepting pushes without an auth token, anyone can write to {}", root.to_string_lossy());
+        }
+
+        Ok(Module {
+            root: root.to_owned(),
+            manifest: Mutex::new((Arc::new(manifest), Instant::now())),
+            access,
+            push_lock: Mutex::new(()),
+        })
+    }
+
+    /// The manifest of the served tree, rebuilt first if it is older than `max_age`. The lock
+    /// is held while rebuilding, so that connections arriving meanwhile wait for the result
+    /// rather than rebuilding it as well.
+    fn current_manifest(&self, max_age: Option<Duration>, hash: &HashSettings, location: &ManifestLocation) -> Result<Arc<Manifest>> {
+        let mut current = self.manifest.lock().unwrap();
+        if max_age.is_some_and(|max_age| current.1.elapsed() >= max_age) {
+            info!("Refreshing the manifest of {}", self.root.to_string_lossy());
+            *current = (Arc::new(Manifest::create_persistent(&self.root, hash, location)?), Instant::now());
+        }
+        Ok(current.0.clone())
+    }
+
+    fn replace_manifest(&self, manifest: Manifest) {
+        *self.manifest.lock().unwrap() = (Arc::new(manifest), Instant::now());
+    }
+}
+
+/// Everything a connection handler needs, shared between the connection threads.
+struct ServerState {
+    /// The served trees by name, the tree given with --source under `None`
+    modules: HashMap<Option<String>, Module>,
+    hash: HashSettings,
+    manifest_location: ManifestLocation,
+    /// Age after which a module's manifest is rebuilt before serving it
+    refresh_interval: Option<Duration>,
+    sender: SenderSettings,
+    privilege: PrivilegeMode,
+    registry: CachedFileRegistry,
+    batch_size: usize,
+    temp_dir: Option<PathBuf>,
+    /// Free space pushed files must leave on the module's filesystem
+    min_free: Option<u64>,
+    /// Smallest run of zeros left as a hole in pushed files, if they are saved sparse
+    min_hole: Option<u64>,
+    /// Set if pushed files shorter in the module are appended to, see `--append`
+    append: Option<AppendMode>,
+    /// Set if pushed files are only moved into place once all arrived, see `--delay-updates`
+    delay_updates: bool,
+    io_timeout: Option<Duration>,
+    /// Whether pushes go on with the remaining files after one fails
+    keep_going: bool,
 }
 
 impl Server {
+    /// Serves connections until the process receives SIGINT, then waits for the open
+    /// connections to finish (at most for the configured shutdown timeout) and returns.
     pub fn run(&self) -> Result<()> {
-        let registry = Arc::new(CachedFileRegistry::new());
-        loop {
-            let (conn, sa) = self.listener.accept()?;
-            let root = self.root.clone();
-            let manifest = self.manifest.clone();
-            let registry = registry.clone();
-
-            let verbose = self.verbose;
-            if verbose {
-                println!("Accepted connection {}", sa);
+        install_shutdown_handler()?;
+        // polling, so that a shutdown request is noticed even while no client connects
+        self.listener.set_nonblocking(true)?;
+
+        let mut at_limit = false;
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            if let Some(max) = self.max_connections {
+                // clients arriving meanwhile wait in the listen backlog
+                if !self.in_flight.wait_below(max, POLL_INTERVAL) {
+                    if !at_limit {
+                        info!("{} connections open, waiting for one to finish", max);
+                        at_limit = true;
+                    }
+                    continue;
+                }
+                at_limit = false;
             }
+            let (conn, sa) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            conn.set_nonblocking(false)?;
+            let state = self.state.clone();
+            let in_flight = InFlight::new(&self.in_flight);
+
+            info!("Accepted connection {}", sa);
             thread::spawn(move || {
-                match remote::command_handler_loop(&root, manifest.as_ref(), &conn, &conn, registry.as_ref()) {
-                    Ok(_) => if verbose {
-                        println!("Finished sending to {}", sa)
-                    },
-                    Err(err) => eprintln!("Command loop failed for {} with {}", sa, err),
+                let _in_flight = in_flight;
+                match state.handle(&conn) {
+                    Ok(SessionMode::Fetch) => info!("Finished sending to {}", sa),
+                    Ok(SessionMode::Push) => info!("Finished receiving from {}", sa),
+                    Err(err) => error!("Command loop failed for {} with {}", sa, err),
                 }
             });
         }
+
+        info!("Shutting down, waiting for {} open connections", self.in_flight.open());
+        if !wait_for_connections(&self.in_flight, self.shutdown_timeout) {
+            warn!("Shutdown timeout expired, abandoning {} open connections", self.in_flight.open());
+        }
+        Ok(())
     }
 
     pub fn new(cfg: &Configuration) -> Result<Server> {
-        if let Local(root) = cfg.source() {
-            let root = root.to_owned();
-            let verbose = cfg.verbose();
-            let manifest = Arc::new(Manifest::create_persistent(&root, verbose, cfg.hash_settings(), cfg.manifest_path())?);
-            let listener = TcpListener::bind(format!("0.0.0.0:{}", cfg.server_port()))?;
-
-            Ok(Server{ listener, root, manifest, verbose})
-        } else {
-            Err(Error::new(ErrorKind::Other, "local path to serve from required"))
+        let mut modules = HashMap::new();
+        match cfg.default_source() {
+            Some(Local(root)) => {
+                let access = Access {
+                    token: cfg.auth_token().map(String::from),
+                    allow_push: cfg.accept_push(),
+                    checksum_key: cfg.hash_settings().checksum_key().cloned(),
+                };
+                modules.insert(None, Module::new(root, cfg, access)?);
+            }
+            Some(source) => return Err(UsyncError::NonLocalPath(source.to_string()).into()),
+            None => (),
+        }
+        for definition in cfg.modules() {
+            let access = Access {
+                token: definition.auth_token.clone().or_else(|| cfg.auth_token().map(String::from)),
+                allow_push: !definition.read_only,
+                checksum_key: cfg.hash_settings().checksum_key().cloned(),
+            };
+            info!("Serving module {} from {}", definition.name, definition.path.to_string_lossy());
+            modules.insert(Some(definition.name.clone()), Module::new(&definition.path, cfg, access)?);
+        }
+
+        // the IPv6 wildcard accepts IPv4 clients as well, unless the host has no IPv6 at all
+        let listener = TcpListener::bind(format!("[::]:{}", cfg.server_port()))
+            .or_else(|_| TcpListener::bind(format!("0.0.0.0:{}", cfg.server_port())))?;
+        Ok(Server {
+            listener,
+            state: Arc::new(ServerState {
+                modules,
+                hash: cfg.hash_settings().clone(),
+                manifest_location: cfg.manifest_location().clone(),
+                refresh_interval: cfg.refresh_interval(),
+                sender: SenderSettings::new(cfg),
+                privilege: cfg.privilege(),
+                registry: CachedFileRegistry::new(cfg.cache_limit()),
+                batch_size: cfg.batch_size(),
+                temp_dir: cfg.temp_dir().map(Path::to_owned),
+                min_free: cfg.min_free(),
+                min_hole: cfg.sparse(),
+                append: cfg.append(),
+                delay_updates: cfg.delay_updates(),
+                io_timeout: cfg.io_timeout(),
+                keep_going: cfg.keep_going(),
+            }),
+            in_flight: Arc::new(Connections::new()),
+            max_connections: cfg.max_connections(),
+            shutdown_timeout: cfg.shutdown_timeout(),
+        })
+    }
+}
+
+impl ServerState {
+    fn current_manifest(&self, module: &Module) -> Result<Arc<Manifest>> {
+        module.current_manifest(self.refresh_interval, &self.hash, &self.manifest_location)
+    }
+
+    fn handle(&self, conn: &TcpStream) -> Result<SessionMode> {
+        set_io_timeout(conn, self.io_timeout)?;
+        self.serve(conn).map_err(|e| explain_timeout(e, self.io_timeout))
+    }
+
+    fn serve(&self, conn: &TcpStream) -> Result<SessionMode> {
+        let (module, mode) = remote::accept_module_session(conn, conn, |name| {
+            self.modules.get(&name.map(String::from)).map(|module| (module, module.access.clone()))
+        })?;
+        match mode {
+            SessionMode::Fetch => {
+                let manifest = self.current_manifest(module)?;
+                remote::serve_commands(&module.root, manifest.as_ref(), conn, conn, &self.registry, &self.sender)?;
+            }
+            SessionMode::Push => self.receive(module, conn)?,
         }
+
+        Ok(mode)
+    }
+
+    /// Ingests a push into the served tree, then rebuilds the manifest so that later fetches
+    /// see the new contents.
+    fn receive(&self, module: &Module, conn: &TcpStream) -> Result<()> {
+        let _push = module.push_lock.lock().unwrap();
+        let current = self.current_manifest(module)?;
+
+        let stats = {
+            let mut transmitter = CommandTransmitter::new(&module.root, conn, conn, self.privilege)
+                .with_batch_size(self.batch_size)
+                .with_temp_dir(self.temp_dir.as_deref())
+                .with_min_free(self.min_free)
+                .with_sparse(self.min_hole)
+                .with_append(self.append)
+                .with_delay_updates(self.delay_updates)
+                .with_buffer_size(self.sender.buffer_size)
+                .with_xattrs(self.sender.xattrs)
+                .with_crtimes(self.sender.crtimes)
+                .with_checksum_key(self.hash.checksum_key());
+            let pushed = transmitter.remote_manifest()?;
+            if pushed.hash_algorithm() != current.hash_algorithm() {
+                return Err(UsyncError::Protocol(format!(
+                    "Client hashes with {}, but the server uses {}", pushed.hash_algorithm(), current.hash_algorithm())).into());
+            }
+            if pushed.manifest_mode() != current.manifest_mode() {
+                return Err(UsyncError::Protocol(format!(
+                    "Client builds manifests in {} mode, but the server uses {} mode", pushed.manifest_mode(), current.manifest_mode())).into());
+            }
+            if self.keep_going {
+                current.copy_from_keep_going(&pushed, self.hash.comparison(), &mut transmitter)?
+            } else {
+                current.copy_from(&pushed, self.hash.comparison(), &mut transmitter)?
+            }
+        };
+        info!("{}", stats);
+
+        module.replace_manifest(Manifest::create_persistent(&module.root, &self.hash, &self.manifest_location)?);
+        Ok(())
     }
 }
 
 struct CachedFileEntry {
-    mapping: Mmap,
-    metadata: Metadata
+    file: File,
+    metadata: Metadata,
+}
+
+impl CachedFileEntry {
+    fn is_current(&self, meta: &Metadata) -> Result<bool> {
+        Ok(meta.len() == self.metadata.len() && meta.modified()? == self.metadata.modified()?)
+    }
 }
 
+/// Registry contents, with entries tagged by the tick of their last use so the least recently
+/// used ones can be evicted once the size of the cached files exceeds the configured limit.
+struct CacheState {
+    entries: HashMap<PathBuf, (Arc<CachedFileEntry>, u64)>,
+    by_use: BTreeMap<u64, PathBuf>,
+    cached: u64,
+    tick: u64,
+}
+
+/// Open files of the served tree, shared between connections. They are read with positioned
+/// reads rather than mapped, so a file truncated by another program while it is served fails
+/// the transfer instead of the server.
 struct CachedFileRegistry {
-    inner: Mutex<HashMap<PathBuf, Arc<CachedFileEntry>>>
+    inner: Mutex<CacheState>,
+    limit: Option<u64>,
 }
 
 struct ReadAdapter {
     entry: Arc<CachedFileEntry>,
-    size: usize
+    size: u64
 }
 
 impl Read for ReadAdapter {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let mapping = self.entry.as_ref().mapping.as_ref();
-        let mapping = &mapping[(self.size)..];
-        let len = min(buf.len(), mapping.len());
-        buf[..len].copy_from_slice(&mapping[..len]);
-        self.size += len;
-        Ok(len)
+        // never read past the advertised size, even if the file grew since it was opened
+        let remaining = self.entry.metadata.len() - self.size;
+        let len = min(buf.len() as u64, remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = read_at(&self.entry.file, &mut buf[..len], self.size)?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!(
+                "File shrank to {} bytes while it was read", self.size)));
+        }
+        self.size += read as u64;
+        Ok(read)
     }
 }
 
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
 impl FileAccess for CachedFileRegistry {
     type Read = ReadAdapter;
 
-    fn metadata(&self, path: &Path) -> Result<Metadata> {
-        let mut inner = self.inner.lock().unwrap();
-        match inner.get(path) {
-            Some(v) => Ok(v.metadata.clone()),
-            None => {
-                let arc = Arc::new(CachedFileRegistry::new_entry(path)?);
-                inner.insert(path.to_owned(), arc.clone());
-                Ok(arc.metadata.clone())
-            }
-        }
+    fn stat(&self, path: &Path, privilege: PrivilegeMode) -> Result<FileStat> {
+        FileStat::of(path, &self.entry(path)?.metadata, privilege)
     }
 
-    fn read(&self, path: &Path) -> Result<Self::Read> {
-        let mut inner = self.inner.lock().unwrap();
-        match inner.get(path) {
-            Some(v) => Ok(ReadAdapter {
-                entry: v.clone(),
-                size: 0
-            }),
-            None => {
-                let arc = Arc::new(CachedFileRegistry::new_entry(path)?);
-                inner.insert(path.to_owned(), arc.clone());
-                Ok(ReadAdapter {
-                    entry: arc,
-                    size: 0
-                })
-            }
-        }
+    fn read(&self, path: &Path, offset: u64) -> Result<Self::Read> {
+        let entry = self.entry(path)?;
+        let size = min(offset, entry.metadata.len());
+
+        Ok(ReadAdapter { entry, size })
     }
 }
 
 impl CachedFileRegistry {
-    fn new() -> CachedFileRegistry {
+    fn new(limit: Option<u64>) -> CachedFileRegistry {
         CachedFileRegistry {
-            inner: Mutex::new(HashMap::new())
+            inner: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                cached: 0,
+                tick: 0,
+            }),
+            limit,
+        }
+    }
+
+    fn entry(&self, path: &Path) -> Result<Arc<CachedFileEntry>> {
+        let mut inner = self.inner.lock().unwrap();
+        let state = &mut *inner;
+        state.tick += 1;
+        let tick = state.tick;
+
+        if let Some((entry, last_use)) = state.entries.get_mut(path) {
+            if entry.is_current(&path.metadata()?)? {
+                state.by_use.remove(last_use);
+                state.by_use.insert(tick, path.to_owned());
+                *last_use = tick;
+                return Ok(entry.clone());
+            }
+
+            // the file changed on disk since it was opened, drop the stale snapshot
+            let (stale, last_use) = state.entries.remove(path).unwrap();
+            state.by_use.remove(&last_use);
+            state.cached -= stale.metadata.len();
         }
+
+        let entry = Arc::new(CachedFileRegistry::new_entry(path)?);
+        let size = entry.metadata.len();
+        if let Some(limit) = self.limit {
+            // entries still referenced by a ReadAdapter stay open until that adapter is dropped
+            while state.cached + size > limit {
+                let oldest = match state.by_use.keys().next() {
+                    Some(oldest) => *oldest,
+                    None => break,
+                };
+                let evicted = state.by_use.remove(&oldest).unwrap();
+                let (evicted, _) = state.entries.remove(&evicted).unwrap();
+                state.cached -= evicted.metadata.len();
+            }
+        }
+
+        state.cached += size;
+        state.by_use.insert(tick, path.to_owned());
+        state.entries.insert(path.to_owned(), (entry.clone(), tick));
+        Ok(entry)
     }
 
     fn new_entry(path: &Path) -> Result<CachedFileEntry> {
         let file = File::open(path)?;
-        let map = unsafe { memmap::Mmap::map(&file)? };
-        let meta = file.metadata()?;
-        Ok(CachedFileEntry {
-            mapping: map,
-            metadata: meta
-        })
+        let metadata = file.metadata()?;
+        Ok(CachedFileEntry { file, metadata })
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn file_of_size(root: &TempDir, name: &str, size: usize) -> Result<PathBuf> {
+        let path = root.path().join(name);
+        File::create(&path)?.write_all(&vec![b'x'; size])?;
+        Ok(path)
+    }
+
+    fn cached(registry: &CachedFileRegistry) -> Vec<PathBuf> {
+        let inner = registry.inner.lock().unwrap();
+        inner.by_use.values().cloned().collect()
+    }
+
+    #[test]
+    fn unlimited_keeps_everything() -> Result<()> {
+        let root = TempDir::new()?;
+        let registry = CachedFileRegistry::new(None);
+        for name in &["a", "b", "c"] {
+            registry.stat(&file_of_size(&root, name, 100)?, PrivilegeMode::Auto)?;
+        }
+
+        assert_eq!(cached(&registry).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() -> Result<()> {
+        let root = TempDir::new()?;
+        let a = file_of_size(&root, "a", 100)?;
+        let b = file_of_size(&root, "b", 100)?;
+        let c = file_of_size(&root, "c", 100)?;
+        let registry = CachedFileRegistry::new(Some(250));
+
+        registry.stat(&a, PrivilegeMode::Auto)?;
+        registry.stat(&b, PrivilegeMode::Auto)?;
+        registry.stat(&a, PrivilegeMode::Auto)?;
+        let mut reader = registry.read(&c, 0)?;
+
+        assert_eq!(cached(&registry), vec![a, c]);
+        assert_eq!(registry.inner.lock().unwrap().cached, 200);
+
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content.len(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn file_truncated_between_reads_fails() -> Result<()> {
+        let root = TempDir::new()?;
+        let a = file_of_size(&root, "a", 100_000)?;
+        let registry = CachedFileRegistry::new(None);
+        let mut reader = registry.read(&a, 0)?;
+
+        let mut head = [0u8; 10];
+        reader.read_exact(&mut head)?;
+        File::create(&a)?;
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn changed_file_is_reopened() -> Result<()> {
+        let root = TempDir::new()?;
+        let a = file_of_size(&root, "a", 100)?;
+        let registry = CachedFileRegistry::new(None);
+        assert_eq!(registry.stat(&a, PrivilegeMode::Auto)?.size, 100);
+
+        file_of_size(&root, "a", 40)?;
+        let mut content = Vec::new();
+        registry.read(&a, 0)?.read_to_end(&mut content)?;
+
+        assert_eq!(content.len(), 40);
+        assert_eq!(registry.stat(&a, PrivilegeMode::Auto)?.size, 40);
+        assert_eq!(registry.inner.lock().unwrap().cached, 40);
+        Ok(())
+    }
+
+    #[test]
+    fn touched_file_is_reopened() -> Result<()> {
+        let root = TempDir::new()?;
+        let a = file_of_size(&root, "a", 10)?;
+        let registry = CachedFileRegistry::new(None);
+        registry.stat(&a, PrivilegeMode::Auto)?;
+
+        File::create(&a)?.write_all(b"yyyyyyyyyy")?;
+        filetime::set_file_mtime(&a, filetime::FileTime::from_unix_time(1_000_000, 0))?;
+        let mut content = Vec::new();
+        registry.read(&a, 0)?.read_to_end(&mut content)?;
+
+        assert_eq!(content, b"yyyyyyyyyy");
+        Ok(())
+    }
+
+    #[test]
+    fn evicted_entry_stays_readable() -> Result<()> {
+        let root = TempDir::new()?;
+        let a = file_of_size(&root, "a", 100)?;
+        let b = file_of_size(&root, "b", 100)?;
+        let registry = CachedFileRegistry::new(Some(150));
+
+        let mut reader = registry.read(&a, 0)?;
+        registry.stat(&b, PrivilegeMode::Auto)?;
+        assert_eq!(cached(&registry), vec![b]);
+
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, vec![b'x'; 100]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_module {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn refreshes_stale_manifest() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("a.txt"))?;
+        let cfg = Configuration::parse_from(&["usync", "--role", "server", "--source", root.path().to_str().unwrap()])?;
+        let module = Module::new(root.path(), &cfg, Access::default())?;
+        File::create(root.path().join("b.txt"))?;
+
+        let files = |max_age| -> Result<usize> {
+            Ok(module.current_manifest(max_age, cfg.hash_settings(), cfg.manifest_location())?.files().len())
+        };
+        assert_eq!(files(None)?, 1);
+        assert_eq!(files(Some(Duration::from_secs(3600)))?, 1);
+        assert_eq!(files(Some(Duration::from_secs(0)))?, 2);
+        assert_eq!(files(None)?, 2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+
+    #[test]
+    fn waits_for_connections_in_flight() {
+        let in_flight = Arc::new(Connections::new());
+        let connection = InFlight::new(&in_flight);
+
+        assert!(!wait_for_connections(&in_flight, Some(Duration::from_millis(10))));
+
+        let finishing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(connection);
+        });
+        assert!(wait_for_connections(&in_flight, None));
+        finishing.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_connections {
+    use super::*;
+
+    #[test]
+    fn limit_waits_for_connection_to_finish() {
+        let connections = Arc::new(Connections::new());
+        let first = InFlight::new(&connections);
+        let _second = InFlight::new(&connections);
+        assert_eq!(connections.open(), 2);
+        assert!(connections.wait_below(3, Duration::from_millis(10)));
+        assert!(!connections.wait_below(2, Duration::from_millis(10)));
+
+        let finishing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(first);
+        });
+        let started = Instant::now();
+        assert!(connections.wait_below(2, Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(connections.open(), 1);
+        finishing.join().unwrap();
     }
 }
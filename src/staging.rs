@@ -0,0 +1,273 @@
+use std::fs::{create_dir, hard_link, read_dir, remove_dir_all, rename, set_permissions, symlink_metadata};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use filetime::{set_file_mtime, FileTime};
+use log::{debug, info, warn};
+
+/// Suffix of the directory a staged sync is written to, next to its target.
+const STAGING_SUFFIX: &str = ".usync-stage";
+/// Suffix the previous target is renamed to while the staged tree takes its place, where the
+/// two can't be exchanged at once.
+const OLD_SUFFIX: &str = ".usync-old";
+
+/// A copy of a target directory that a sync writes to instead, so that the target only
+/// changes once the whole sync succeeded (`--stage-then-swap`). The copy sits next to the
+/// target and starts out as hard links to its files, so unchanged files cost neither space
+/// nor transfers. This relies on transmitters replacing files rather than writing into them,
+/// and copying them before changing their permissions (see `with_linked_target`).
+pub struct Staging {
+    target: PathBuf,
+    staging: PathBuf,
+}
+
+impl Staging {
+    /// Sets up the staging directory for `target`, replacing what an earlier run that did not
+    /// finish may have left there. If `target` is a symlink, the directory it points to is
+    /// staged and swapped, the link itself stays as it is.
+    pub fn prepare(target: &Path) -> Result<Staging> {
+        if symlink_metadata(target)?.file_type().is_symlink() {
+            debug!("Target {} is a symlink, staging the directory it points to", target.to_string_lossy());
+        }
+        let target = target.canonicalize()?;
+        let staging = sibling(&target, STAGING_SUFFIX)?;
+        if symlink_metadata(&staging).is_ok() {
+            info!("Removing leftover staging directory {}", staging.to_string_lossy());
+            remove_dir_all(&staging)?;
+        }
+
+        debug!("Staging {} in {}", target.to_string_lossy(), staging.to_string_lossy());
+        if let Err(e) = link_tree(&target, &staging) {
+            let _ = remove_dir_all(&staging);
+            return Err(e);
+        }
+        Ok(Staging { target, staging })
+    }
+
+    /// The directory the sync is to write to.
+    #[inline]
+    pub fn root(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Moves the staged tree into the place of the target, and removes the previous target.
+    /// Where the system can exchange the two in one step, the target path is never missing.
+    /// Otherwise the previous target is moved aside first, and put back if the staged tree
+    /// can't be moved.
+    pub fn swap(self) -> Result<()> {
+        if exchange(&self.staging, &self.target)? {
+            info!("Swapped staged tree into {}", self.target.to_string_lossy());
+            // the staging path now holds the previous target
+            return remove_dir_all(&self.staging);
+        }
+
+        let old = sibling(&self.target, OLD_SUFFIX)?;
+        if symlink_metadata(&old).is_ok() {
+            remove_dir_all(&old)?;
+        }
+
+        rename(&self.target, &old)?;
+        if let Err(e) = rename(&self.staging, &self.target) {
+            rename(&old, &self.target)?;
+            return Err(e);
+        }
+        info!("Swapped staged tree into {}", self.target.to_string_lossy());
+        remove_dir_all(&old)
+    }
+
+    /// Removes the staging directory, leaving the target as it was.
+    pub fn discard(self) -> Result<()> {
+        remove_dir_all(&self.staging)
+    }
+}
+
+/// Atomically exchanges the directories at `a` and `b`. Returns `false` if the system or the
+/// file system does not support that.
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let rc = unsafe { libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE) };
+    if rc == 0 {
+        return Ok(true);
+    }
+    let error = Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+            debug!("Cannot exchange directories in one step: {}", error);
+            Ok(false)
+        }
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// The path next to `path` with `suffix` appended to its name, hidden on Unix.
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!(
+        "Cannot stage {}, it has no parent directory", path.to_string_lossy())))?;
+    let mut sibling = std::ffi::OsString::from(".");
+    sibling.push(name);
+    sibling.push(suffix);
+    Ok(path.with_file_name(sibling))
+}
+
+/// Recreates the directories of `source` at `target`, with hard links to its files and copies
+/// of its symlinks. Special files are left out, they could not be synced anyway.
+fn link_tree(source: &Path, target: &Path) -> Result<()> {
+    create_dir(target)?;
+    for entry in read_dir(source)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = target.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            link_tree(&from, &to)?;
+        } else if file_type.is_file() {
+            hard_link(&from, &to)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&from, &to)?;
+        } else {
+            warn!("Leaving {} out of the staged tree, it is a special file", from.to_string_lossy());
+        }
+    }
+
+    let meta = source.metadata()?;
+    set_permissions(target, meta.permissions())?;
+    set_file_mtime(target, FileTime::from_last_modification_time(&meta))
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> Result<()> {
+    warn!("Leaving symlink {} out of the staged tree", from.to_string_lossy());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_staging {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use tempfile::TempDir;
+
+    fn target(dir: &TempDir) -> Result<PathBuf> {
+        let target = dir.path().join("target");
+        std::fs::create_dir_all(target.join("sub"))?;
+        write(target.join("a.txt"), "old a")?;
+        write(target.join("sub/b.txt"), "old b")?;
+        Ok(target)
+    }
+
+    #[test]
+    fn swap_replaces_target() -> Result<()> {
+        let dir = TempDir::new()?;
+        let target = target(&dir)?;
+
+        let staging = Staging::prepare(&target)?;
+        assert_eq!(read_to_string(staging.root().join("sub/b.txt"))?, "old b");
+        // a transmitter replaces a file instead of writing into it
+        std::fs::remove_file(staging.root().join("a.txt"))?;
+        write(staging.root().join("a.txt"), "new a")?;
+        write(staging.root().join("c.txt"), "new c")?;
+        assert_eq!(read_to_string(target.join("a.txt"))?, "old a");
+        assert!(!target.join("c.txt").exists());
+
+        staging.swap()?;
+        assert_eq!(read_to_string(target.join("a.txt"))?, "new a");
+        assert_eq!(read_to_string(target.join("sub/b.txt"))?, "old b");
+        assert_eq!(read_to_string(target.join("c.txt"))?, "new c");
+        assert_eq!(read_dir(dir.path())?.count(), 1, "staging and old target are gone");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn exchange_swaps_directories() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        create_dir(&a)?;
+        create_dir(&b)?;
+        write(a.join("from_a"), "")?;
+        if exchange(&a, &b)? {
+            assert!(b.join("from_a").exists());
+            assert!(!a.join("from_a").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn swap_keeps_symlinked_target() -> Result<()> {
+        let dir = TempDir::new()?;
+        let real = target(&dir)?;
+        let link = dir.path().join("current");
+        std::os::unix::fs::symlink("target", &link)?;
+
+        let staging = Staging::prepare(&link)?;
+        assert!(staging.root().starts_with(dir.path().canonicalize()?));
+        write(staging.root().join("c.txt"), "new c")?;
+        staging.swap()?;
+
+        assert_eq!(std::fs::read_link(&link)?, PathBuf::from("target"));
+        assert_eq!(read_to_string(link.join("c.txt"))?, "new c");
+        assert_eq!(read_to_string(real.join("a.txt"))?, "old a");
+        assert_eq!(read_dir(dir.path())?.count(), 2, "staging and old target are gone");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn discard_keeps_target_permissions() -> Result<()> {
+        use crate::config::PrivilegeMode;
+        use crate::file_transfer::local::LocalTransmitter;
+        use crate::file_transfer::Transmitter;
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let target = target(&dir)?;
+        set_permissions(target.join("a.txt"), Permissions::from_mode(0o644))?;
+
+        let staging = Staging::prepare(&target)?;
+        LocalTransmitter::new(dir.path(), staging.root(), PrivilegeMode::Auto)
+            .with_linked_target(true)
+            .set_permissions(Path::new("a.txt"), 0o600)?;
+        assert_eq!(staging.root().join("a.txt").metadata()?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(read_to_string(staging.root().join("a.txt"))?, "old a");
+        // the sync failed
+        staging.discard()?;
+
+        assert_eq!(target.join("a.txt").metadata()?.permissions().mode() & 0o777, 0o644);
+        Ok(())
+    }
+
+    #[test]
+    fn discard_keeps_target() -> Result<()> {
+        let dir = TempDir::new()?;
+        let target = target(&dir)?;
+        // left over from an interrupted run
+        create_dir(dir.path().join(".target.usync-stage"))?;
+        write(dir.path().join(".target.usync-stage/stale.txt"), "stale")?;
+
+        let staging = Staging::prepare(&target)?;
+        assert!(!staging.root().join("stale.txt").exists());
+        write(staging.root().join("c.txt"), "new c")?;
+        staging.discard()?;
+
+        assert!(!target.join("c.txt").exists());
+        assert_eq!(read_to_string(target.join("a.txt"))?, "old a");
+        assert_eq!(read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
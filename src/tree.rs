@@ -1,31 +1,69 @@
 use std::ffi::OsStr;
-use std::fs::{File, read_dir, symlink_metadata, Metadata};
-use std::io::{Error, ErrorKind, Read, Result, BufReader, BufWriter, empty};
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-
+use std::fs::{DirEntry, File, create_dir_all, read_dir, symlink_metadata, Metadata};
+use std::io::{Error, ErrorKind, Read, Result, BufReader, BufWriter, Write, empty};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use log::{debug, info, warn};
 use ring::digest::{Context, SHA256};
-use serde::{Serialize, Deserialize};
-
-use crate::config::{ManifestMode, HashSettings};
-use crate::util::{Named, find_named};
-use crate::file_transfer::Transmitter;
-
-type ShaSum = [u8; 32];
-
-#[derive(Debug, Serialize, Deserialize)]
+use ring::hmac;
+use serde::{Serialize, Serializer, Deserialize};
+
+use crate::config::{Comparison, Selection, TransferLimits, ManifestMode, ManifestLocation, HashSettings, HashAlgorithm, IgnoreStack, IGNORE_FILE};
+use crate::util::{Named, Timestamp, cache_dir, format_size, index_by_name, is_plain_name, DEFAULT_IO_BUFFER_SIZE};
+use crate::file_transfer::{Transmitter, file_mode, is_file_error};
+use crate::file_transfer::remote::PARTIAL_SUFFIX;
+use crate::error::UsyncError;
+
+pub(crate) type ShaSum = [u8; 32];
+
+/// Identifies a file as a usync manifest, at the start of what `Manifest::write_to` writes.
+const MANIFEST_MAGIC: [u8; 4] = *b"USMF";
+/// Version of the manifest format. Bump this whenever the encoding of `Manifest`, or any of
+/// the entries it holds, changes, so that manifests written before are rebuilt rather than
+/// misread.
+const MANIFEST_VERSION: u32 = 2;
+/// How many levels of directories a manifest received from a peer may nest. Reassembling it
+/// recurses once per level, so without a bound a peer could exhaust the stack.
+const MAX_RECEIVED_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileEntry {
     name: String,
-    modification_time: SystemTime,
+    modification_time: Timestamp,
     file_size: u64,
     hash_value: ShaSum,
+    /// Files of a manifest with the same link group are hard links to the same inode. Only
+    /// recorded with `--hard-links`.
+    link_group: Option<u64>,
+    /// Permission bits of the file, see `file_mode`. Only recorded with `--perms`.
+    mode: Option<u32>,
 }
 
 impl PartialEq for FileEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.file_size == other.file_size &&
-            self.modification_time == other.modification_time &&
-            self.hash_value == other.hash_value
+        self.matches(other, Comparison::Timestamps(Duration::from_secs(0)))
+    }
+}
+
+/// Whether the two timestamps are at most `window` apart. Filesystems store timestamps with
+/// different resolution, so a copy's mtime may be truncated compared to the original.
+fn same_time(left: Timestamp, right: Timestamp, window: Duration) -> bool {
+    left.distance(&right) <= window
+}
+
+/// Whether the two timestamps count as equal under `comparison`.
+fn times_match(left: Timestamp, right: Timestamp, comparison: Comparison) -> bool {
+    match comparison {
+        Comparison::Timestamps(window) => same_time(left, right, window),
+        Comparison::ContentOnly => true,
     }
 }
 
@@ -36,24 +74,84 @@ impl Named for FileEntry {
 }
 
 impl FileEntry {
-    fn new(path: &Path, meta: &Metadata, verbose: bool, settings: &HashSettings) -> Result<FileEntry> {
+    /// Equality, with the modification times compared as `comparison` says.
+    fn matches(&self, other: &FileEntry, comparison: Comparison) -> bool {
+        self.content_matches(other, comparison) && self.mode == other.mode
+    }
+
+    /// Equality of all but the permissions, which can be updated without transferring the file.
+    fn content_matches(&self, other: &FileEntry, comparison: Comparison) -> bool {
+        self.file_size == other.file_size &&
+            times_match(self.modification_time, other.modification_time, comparison) &&
+            self.hash_value == other.hash_value
+    }
+
+    /// The content hash a transferred copy of this file must have, if known. Manifests built in
+    /// timestamp mode carry no content hashes.
+    fn expected_hash(&self) -> Option<&ShaSum> {
+        if self.hash_value == [0u8; 32] {
+            None
+        } else {
+            Some(&self.hash_value)
+        }
+    }
+
+    fn new(path: &Path, meta: &Metadata, settings: &HashSettings) -> Result<FileEntry> {
         let hash_value = if settings.manifest_mode() == ManifestMode::Hash {
-            hash(File::open(path)?)?
+            hash_keyed(settings.hash_algorithm(), settings.checksum_key(), File::open(path)?, settings.io_buffer_size())?
         } else {
             [0u8; 32]
         };
 
         let name = filename_to_string(path.file_name());
 
-        if verbose {
-            println!("Hashed file {} into {}", path.to_string_lossy(), hex::encode(&hash_value))
-        }
+        debug!("Hashed file {} into {}", path.to_string_lossy(), hex::encode(hash_value));
 
         Ok(FileEntry {
             name,
-            modification_time: meta.modified()?,
+            modification_time: Timestamp::modified(meta),
             file_size: meta.len(),
             hash_value,
+            link_group: None,
+            mode: recorded_mode(meta, settings),
+        })
+    }
+}
+
+/// The permissions of a file as a manifest built with `settings` records them.
+fn recorded_mode(meta: &Metadata, settings: &HashSettings) -> Option<u32> {
+    if settings.perms() {
+        Some(file_mode(meta))
+    } else {
+        None
+    }
+}
+
+/// Why a stored manifest no longer describes its tree, found by `DirectoryEntry::validate` at
+/// the first path that differs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Staleness {
+    Missing,
+    NotADirectory,
+    DirectoryModified,
+    Added,
+    FileModified,
+    FileResized,
+    ModeChanged,
+    Removed,
+}
+
+impl Display for Staleness {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Staleness::Missing => "the directory no longer exists",
+            Staleness::NotADirectory => "the directory is no longer a directory",
+            Staleness::DirectoryModified => "the directory's modification time differs",
+            Staleness::Added => "it is not in the manifest",
+            Staleness::FileModified => "the file's modification time differs",
+            Staleness::FileResized => "the file's size differs",
+            Staleness::ModeChanged => "the file's permissions differ",
+            Staleness::Removed => "entries of the manifest are gone from the directory",
         })
     }
 }
@@ -61,120 +159,264 @@ impl FileEntry {
 #[derive(Debug, Serialize, Deserialize)]
 struct DirectoryEntry {
     name: String,
-    modification_time: SystemTime,
+    modification_time: Timestamp,
     subdirs: Vec<DirectoryEntry>,
     files: Vec<FileEntry>,
     hash_value: ShaSum,
 }
 
 impl DirectoryEntry {
-    fn validate0(&self, path: &mut PathBuf, settings: &HashSettings) -> Result<bool> {
+    /// Checks the tree at `path` against this entry. Stops at the first difference, with
+    /// `path` left at where it was found. Entries are walked as `create` walks them, see
+    /// `walk_entry`.
+    fn validate0(&self, root: &Path, path: &mut PathBuf, settings: &HashSettings, ignores: &mut IgnoreStack, guard: &mut CycleGuard) -> Result<Option<Staleness>> {
         if !path.exists() {
-            return Ok(false);
+            return Ok(Some(Staleness::Missing));
         }
 
         let meta = path.metadata()?;
-        let mtime = meta.modified()?;
-
-        if !meta.is_dir() || mtime != self.modification_time {
-            return Ok(false);
+        if !meta.is_dir() {
+            return Ok(Some(Staleness::NotADirectory));
+        }
+        if !same_time(Timestamp::modified(&meta), self.modification_time, settings.modify_window()) {
+            return Ok(Some(Staleness::DirectoryModified));
         }
 
+        let subdirs = index_by_name(&self.subdirs);
+        let files = index_by_name(&self.files);
+        let scoped = settings.ignore_files() && ignores.enter(path)?;
         let mut examined_count = 0usize;
         for entry in path.read_dir()? {
             let entry = entry?;
             let name = entry.file_name();
             path.push(&name);
 
-            if settings.is_excluded(path.as_ref()) {
-                path.pop();
-                continue;
-            }
-
-            examined_count += 1;
-            if entry.metadata()?.is_dir() {
-                let found = find_named(self.subdirs.as_slice(), name.to_string_lossy());
-                match found {
-                    None => return Ok(false),
-                    Some(o) => {
-                        if !o.validate0(path, settings)? {
-                            return Ok(false);
+            match DirectoryEntry::walk_entry(root, path, &entry, settings, ignores, guard)? {
+                Walked::Skipped => {}
+                Walked::Directory => {
+                    examined_count += 1;
+                    match subdirs.get(name.to_string_lossy().as_ref()) {
+                        None => return Ok(Some(Staleness::Added)),
+                        Some(o) => {
+                            if let Some(staleness) = o.validate0(root, path, settings, ignores, guard)? {
+                                return Ok(Some(staleness));
+                            }
+                            guard.leave();
                         }
-                        path.pop();
                     }
                 }
-            } else {
-                let found = find_named(self.files.as_slice(), &name.to_string_lossy());
-                match found {
-                    None => return Ok(false),
-                    Some(o) => {
-                        let meta = path.metadata()?;
-                        let mismatch =
-                            meta.modified()? != o.modification_time ||
-                                meta.len() != o.file_size;
-                        if mismatch {
-                            return Ok(false);
+                Walked::File(meta) => {
+                    examined_count += 1;
+                    match files.get(name.to_string_lossy().as_ref()) {
+                        None => return Ok(Some(Staleness::Added)),
+                        Some(o) => {
+                            if !same_time(Timestamp::modified(&meta), o.modification_time, settings.modify_window()) {
+                                return Ok(Some(Staleness::FileModified));
+                            }
+                            if meta.len() != o.file_size {
+                                return Ok(Some(Staleness::FileResized));
+                            }
+                            if recorded_mode(&meta, settings) != o.mode {
+                                return Ok(Some(Staleness::ModeChanged));
+                            }
                         }
-                        path.pop();
                     }
                 }
             }
+            path.pop();
         }
-        let count_match = examined_count == (self.subdirs.len() + self.files.len());
-
-        Ok(count_match)
+        if scoped {
+            ignores.leave();
+        }
+        if examined_count != self.subdirs.len() + self.files.len() {
+            return Ok(Some(Staleness::Removed));
+        }
+        Ok(None)
     }
 
+    /// Checks that this entry still describes the tree at `path`. If not, the error names the
+    /// first path that differs, and how.
+    fn validate(&self, path: &mut PathBuf, settings: &HashSettings) -> std::result::Result<(), String> {
+        let root = path.clone();
+        let mut guard = match root.metadata() {
+            Ok(meta) => CycleGuard::for_root(&root, &meta, settings),
+            Err(e) => return Err(format!("{} could not be checked: {}", root.to_string_lossy(), e)),
+        };
+        match self.validate0(&root, path, settings, &mut IgnoreStack::new(), &mut guard) {
+            Ok(None) => Ok(()),
+            Ok(Some(staleness)) => Err(format!("{}: {}", path.to_string_lossy(), staleness)),
+            Err(e) => Err(format!("{} could not be checked: {}", path.to_string_lossy(), e)),
+        }
+    }
 
-    fn validate(&self, path: &mut PathBuf, settings: &HashSettings) -> bool {
-        self.validate0(path, settings).unwrap_or(false)
+    /// Equality, with the modification times compared as `comparison` says.
+    fn matches(&self, other: &DirectoryEntry, comparison: Comparison) -> bool {
+        times_match(self.modification_time, other.modification_time, comparison) &&
+            self.hash_value == other.hash_value
     }
 
-    fn copy_from<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, verbose: bool) -> Result<()> {
-        self.copy_subdirs(path, &source, transmitter, verbose)?;
-        self.copy_files(path, &source, transmitter, verbose)?;
+    fn copy_from<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, run: &mut CopyRun) -> Result<()> {
+        self.copy_subdirs(path, &source, transmitter, run)?;
+        self.copy_files(path, &source, transmitter, run)?;
         Ok(())
     }
 
-    fn copy_files<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, verbose: bool) -> Result<()> {
+    fn copy_files<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, run: &mut CopyRun) -> Result<()> {
+        // (file, how it differs from the file on the target, None if it is new)
+        let mut pending: Vec<(&FileEntry, Option<Differences>)> = Vec::new();
+        // (file, how it differs from the file on the target, the path to link it to)
+        let mut linked: Vec<(&FileEntry, Option<Differences>, PathBuf)> = Vec::new();
+        let files = index_by_name(&self.files);
         for source_file in &source.files {
-            let existing_file = find_named(self.files.as_slice(), &source_file.name);
-            let this_path = path.join(&source_file.name);
-
-            match existing_file {
-                None => {
-                    if verbose {
-                        println!("Transmitting new file: {}", &this_path.to_string_lossy())
-                    }
-                    transmitter.transmit(&this_path)?
+            let differences = match files.get(source_file.name.as_str()) {
+                None if run.selection == Selection::Existing => {
+                    run.leave_out(&path.join(&source_file.name), 1);
+                    continue;
+                }
+                None => None,
+                Some(existing) if run.selection == Selection::Missing && !existing.matches(source_file, run.comparison) => {
+                    run.leave_out(&path.join(&source_file.name), 1);
+                    continue;
                 }
+                Some(existing) if !existing.content_matches(source_file, run.comparison) => Some(Differences::between(existing, source_file)),
                 Some(existing) => {
-                    if existing != source_file {
-                        if verbose {
-                            println!("Overwriting changed file: {}", &this_path.to_string_lossy());
+                    let this_path = path.join(&source_file.name);
+                    match source_file.mode {
+                        Some(mode) if existing.mode != source_file.mode => {
+                            info!("Updating permissions of {}", this_path.to_string_lossy());
+                            match transmitter.set_permissions(&this_path, mode) {
+                                Ok(()) => {
+                                    run.stats.files_updated += 1;
+                                    transmitter.itemize(&this_path, Change::Updated(Differences::between(existing, source_file)));
+                                }
+                                Err(e) => run.file_failed(&this_path, e)?,
+                            }
+                        }
+                        _ => {
+                            run.stats.files_skipped += 1;
+                            transmitter.itemize(&this_path, Change::Unchanged);
+                        }
+                    }
+                    if let Some(group) = source_file.link_group {
+                        run.links.entry(group).or_insert_with(|| path.join(&source_file.name));
+                    }
+                    continue;
+                }
+            };
+
+            let this_path = path.join(&source_file.name);
+            let transferred = transmitter.already_transferred(&this_path);
+            if transferred {
+                debug!("{} was transferred by an earlier attempt", this_path.to_string_lossy());
+            }
+            match source_file.link_group {
+                Some(group) => match run.links.get(&group) {
+                    Some(existing) => linked.push((source_file, differences, existing.clone())),
+                    None => {
+                        run.links.insert(group, this_path);
+                        if !transferred {
+                            pending.push((source_file, differences));
                         }
-                        transmitter.transmit(&this_path)?
                     }
+                },
+                None if !transferred => pending.push((source_file, differences)),
+                None => {}
+            }
+        }
+
+        let mut rest = pending.as_slice();
+        while !rest.is_empty() {
+            let allowed = run.allowance(rest.iter().map(|(file, _)| file.file_size).take(transmitter.batch_size().max(1)));
+            if allowed == 0 {
+                break;
+            }
+            let (batch, later) = rest.split_at(allowed);
+            rest = later;
+            let files: Vec<(PathBuf, Option<&ShaSum>)> = batch.iter().map(|(file, differences)| {
+                let this_path = path.join(&file.name);
+                if differences.is_none() {
+                    info!("Transmitting new file: {}", &this_path.to_string_lossy());
+                } else {
+                    info!("Overwriting changed file: {}", &this_path.to_string_lossy());
+                }
+                (this_path, file.expected_hash())
+            }).collect();
+
+            let transferred = if let [(this_path, expected)] = files.as_slice() {
+                vec![transmitter.transmit(this_path, *expected)]
+            } else {
+                transmitter.transmit_batch(&files)?
+            };
+
+            for (((this_path, _), (file, differences)), result) in files.iter().zip(batch).zip(transferred) {
+                let result = match (result, file.mode) {
+                    (Ok(bytes), Some(mode)) => transmitter.set_permissions(this_path, mode).map(|()| bytes),
+                    (result, _) => result,
+                };
+                match result {
+                    Ok(bytes) => {
+                        run.stats.bytes_transferred += bytes;
+                        run.stats.count_transferred(differences.is_none());
+                        transmitter.itemize(this_path, Change::Transferred(*differences));
+                    }
+                    Err(e) => run.file_failed(this_path, e)?,
+                }
+            }
+        }
+
+        for (file, _) in rest {
+            run.defer(1, file.file_size);
+        }
+
+        // only now are the files of this directory that others link to in place
+        for (file, differences, existing) in linked {
+            if run.allowance(std::iter::once(0)) == 0 {
+                // the file linked to may not have been transferred
+                run.defer(1, 0);
+                continue;
+            }
+            let this_path = path.join(&file.name);
+            info!("Linking {} to {}", this_path.to_string_lossy(), existing.to_string_lossy());
+            match transmitter.hard_link(&existing, &this_path) {
+                Ok(()) => {
+                    run.stats.files_linked += 1;
+                    run.stats.count_transferred(differences.is_none());
+                    transmitter.itemize(&this_path, Change::Linked(differences));
                 }
+                Err(e) => run.file_failed(&this_path, e)?,
             }
         }
 
         Ok(())
     }
 
-    fn copy_subdirs<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, verbose: bool) -> Result<()> {
+    fn copy_subdirs<T: Transmitter>(&self, path: &Path, source: &DirectoryEntry, transmitter: &mut T, run: &mut CopyRun) -> Result<()> {
+        let subdirs = index_by_name(&self.subdirs);
         for source_dir in &source.subdirs {
-            let existing_subdir = find_named(self.subdirs.as_slice(), &source_dir.name);
+            let existing_subdir = subdirs.get(source_dir.name.as_str());
             let this_path = path.join(&source_dir.name);
 
             match existing_subdir {
+                None if run.selection == Selection::Existing => run.leave_out(&this_path, source_dir.file_count()),
+                None if run.limit_reached() => {
+                    let mut totals = TransferTotals::default();
+                    DirectoryEntry::empty(&source_dir.name).count_transfers(source_dir, run.comparison, run.selection, &mut totals, &mut HashSet::new());
+                    run.defer(totals.files, totals.bytes);
+                }
                 None => {
+                    info!("Creating new directory: {}", &this_path.to_string_lossy());
+                    transmitter.create_directory(&this_path)?;
+                    run.stats.directories_created += 1;
+                    transmitter.itemize(&this_path, Change::DirectoryCreated);
                     let subdir = DirectoryEntry::empty(&source_dir.name);
-                    subdir.copy_from(&this_path, source_dir, transmitter, verbose)?;
+                    subdir.copy_from(&this_path, source_dir, transmitter, run)?;
                 }
                 Some(existing) => {
-                    if existing != source_dir {
-                        existing.copy_from(&this_path, source_dir, transmitter, verbose)?;
+                    if !existing.matches(source_dir, run.comparison) {
+                        existing.copy_from(&this_path, source_dir, transmitter, run)?;
+                    } else {
+                        run.stats.files_skipped += source_dir.file_count();
+                        source_dir.itemize_unchanged(&this_path, transmitter);
                     }
                 }
             }
@@ -183,21 +425,177 @@ impl DirectoryEntry {
         Ok(())
     }
 
+    /// Reports all files below this directory, found unchanged at `path`, to `transmitter`.
+    fn itemize_unchanged<T: Transmitter>(&self, path: &Path, transmitter: &mut T) {
+        for subdir in &self.subdirs {
+            subdir.itemize_unchanged(&path.join(&subdir.name), transmitter);
+        }
+        for file in &self.files {
+            transmitter.itemize(&path.join(&file.name), Change::Unchanged);
+        }
+    }
+
+    fn file_count(&self) -> u64 {
+        self.files.len() as u64 + self.subdirs.iter().map(DirectoryEntry::file_count).sum::<u64>()
+    }
+
+    /// Mirrors `copy_from`, with `links` holding the link groups seen so far, whose further
+    /// members are linked without transferring any bytes.
+    fn count_transfers(&self, source: &DirectoryEntry, comparison: Comparison, selection: Selection, totals: &mut TransferTotals, links: &mut HashSet<u64>) {
+        let files = index_by_name(&self.files);
+        for source_file in &source.files {
+            let needed = match files.get(source_file.name.as_str()) {
+                None => selection != Selection::Existing,
+                Some(_) if selection == Selection::Missing => false,
+                Some(existing) => !existing.content_matches(source_file, comparison),
+            };
+            let first_of_group = source_file.link_group.is_none_or(|group| links.insert(group));
+            if needed {
+                totals.files += 1;
+                if first_of_group {
+                    totals.bytes += source_file.file_size;
+                }
+            }
+        }
+
+        let subdirs = index_by_name(&self.subdirs);
+        for source_dir in &source.subdirs {
+            match subdirs.get(source_dir.name.as_str()) {
+                None if selection == Selection::Existing => {}
+                None => DirectoryEntry::empty(&source_dir.name).count_transfers(source_dir, comparison, selection, totals, links),
+                Some(existing) => if !existing.matches(source_dir, comparison) {
+                    existing.count_transfers(source_dir, comparison, selection, totals, links)
+                }
+            }
+        }
+    }
+
+    /// Collects all files of `source` that are missing here, or differ in size or content
+    /// hash, with what is wrong with them. Both trees must be hashed with the same algorithm.
+    fn mismatches(&self, path: &Path, source: &DirectoryEntry, found: &mut Vec<FailedTransfer>) {
+        let files = index_by_name(&self.files);
+        for source_file in &source.files {
+            let message = match files.get(source_file.name.as_str()) {
+                None => String::from("missing on the target"),
+                Some(existing) if existing.file_size != source_file.file_size =>
+                    format!("{} bytes on the target, {} bytes on the source", existing.file_size, source_file.file_size),
+                Some(existing) if existing.hash_value != source_file.hash_value =>
+                    format!("content hash {} on the target, {} on the source", hex::encode(existing.hash_value), hex::encode(source_file.hash_value)),
+                Some(_) => continue,
+            };
+            found.push(FailedTransfer { path: path.join(&source_file.name), message });
+        }
+
+        let subdirs = index_by_name(&self.subdirs);
+        for source_dir in &source.subdirs {
+            let this_path = path.join(&source_dir.name);
+            match subdirs.get(source_dir.name.as_str()) {
+                None => DirectoryEntry::empty(&source_dir.name).mismatches(&this_path, source_dir, found),
+                Some(existing) => existing.mismatches(&this_path, source_dir, found),
+            }
+        }
+    }
+
+    /// Copy of this tree with content hashes filled in for files that were recorded without
+    /// one (in timestamp mode). `path` is the location of this directory on disk.
+    fn with_hashes(&self, path: &mut PathBuf, algorithm: HashAlgorithm, key: Option<&ChecksumKey>) -> Result<DirectoryEntry> {
+        let mut files = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            let mut file = file.clone();
+            if file.expected_hash().is_none() {
+                path.push(&file.name);
+                file.hash_value = hash_keyed(algorithm, key, File::open(&path)?, DEFAULT_IO_BUFFER_SIZE)?;
+                path.pop();
+            }
+            files.push(file);
+        }
+
+        let mut subdirs = Vec::with_capacity(self.subdirs.len());
+        for subdir in &self.subdirs {
+            path.push(&subdir.name);
+            subdirs.push(subdir.with_hashes(path, algorithm, key)?);
+            path.pop();
+        }
+
+        Ok(DirectoryEntry {
+            name: self.name.clone(),
+            modification_time: self.modification_time,
+            subdirs,
+            files,
+            hash_value: self.hash_value,
+        })
+    }
+
+    /// Removes the subdirectories without files below them, deepest first, and returns
+    /// whether this directory is left without any content itself.
+    fn prune_empty_dirs(&mut self) -> bool {
+        self.subdirs.retain_mut(|subdir| !subdir.prune_empty_dirs());
+        self.subdirs.is_empty() && self.files.is_empty()
+    }
+
+    /// Drops everything but the subdirectory `names` leads to and what is below it. Returns
+    /// whether there is such a subdirectory.
+    fn restrict_to(&mut self, names: &[&OsStr]) -> bool {
+        let (name, rest) = match names.split_first() {
+            Some(split) => split,
+            None => return true,
+        };
+        self.files.clear();
+        self.subdirs.retain(|subdir| OsStr::new(&subdir.name) == *name);
+        match self.subdirs.first_mut() {
+            Some(subdir) => subdir.restrict_to(rest),
+            None => false,
+        }
+    }
+
+    fn collect_files<'a>(&'a self, path: &mut PathBuf, found: &mut Vec<ManifestFile<'a>>) {
+        for file in &self.files {
+            found.push(ManifestFile {
+                path: path.join(&file.name),
+                size: file.file_size,
+                modification_time: file.modification_time,
+                hash: file.expected_hash(),
+            });
+        }
+
+        for subdir in &self.subdirs {
+            path.push(&subdir.name);
+            subdir.collect_files(path, found);
+            path.pop();
+        }
+    }
+
+    /// Placeholder for a directory missing on the target. Its timestamp is fixed, so it never
+    /// compares equal to a real directory and never depends on when it was created.
     fn empty(name: &str) -> DirectoryEntry {
         DirectoryEntry {
             name: String::from(name),
-            modification_time: SystemTime::now(),
+            modification_time: Timestamp::UNIX_EPOCH,
             subdirs: Vec::new(),
             files: Vec::new(),
             hash_value: hash(empty()).unwrap(),
         }
     }
 
-    pub fn new<S: AsRef<OsStr>>(path: S, verbose: bool, settings: &HashSettings) -> Result<DirectoryEntry> {
-        DirectoryEntry::create(&mut PathBuf::from(path.as_ref()), verbose, settings)
+    pub fn new<S: AsRef<OsStr>>(path: S, settings: &HashSettings) -> Result<DirectoryEntry> {
+        let mut pb = PathBuf::from(path.as_ref());
+        let meta = pb.metadata()?;
+        let mut guard = CycleGuard::for_root(&pb, &meta, settings);
+        let root = pb.clone();
+        DirectoryEntry::create(&root, &mut pb, settings, &mut IgnoreStack::new(), &mut guard, &[])
+    }
+
+    /// Like `new`, but only walks the directories named by `only` in turn and what is below
+    /// the last of them, leaving the other contents of the directories leading there out.
+    fn new_below<S: AsRef<OsStr>>(path: S, only: &[&OsStr], settings: &HashSettings) -> Result<DirectoryEntry> {
+        let mut pb = PathBuf::from(path.as_ref());
+        let meta = pb.metadata()?;
+        let mut guard = CycleGuard::for_root(&pb, &meta, settings);
+        let root = pb.clone();
+        DirectoryEntry::create(&root, &mut pb, settings, &mut IgnoreStack::new(), &mut guard, only)
     }
 
-    fn create(pb: &mut PathBuf, verbose: bool, settings: &HashSettings) -> Result<DirectoryEntry> {
+    fn create(root: &Path, pb: &mut PathBuf, settings: &HashSettings, ignores: &mut IgnoreStack, guard: &mut CycleGuard, only: &[&OsStr]) -> Result<DirectoryEntry> {
         let dir = {
             let mut v = Vec::new();
             for de in read_dir(&pb)? {
@@ -208,47 +606,50 @@ impl DirectoryEntry {
         };
 
         let mut subdirs: Vec<DirectoryEntry> = Vec::new();
-        let mut files: Vec<FileEntry> = Vec::new();
-        let mut hash_input: Vec<u8> = Vec::new();
-        let modification_time = pb.metadata()?.modified()?;
+        let mut pending_files: Vec<(PathBuf, Metadata)> = Vec::new();
+        let mut link_groups: Vec<Option<u64>> = Vec::new();
+        let mut order: Vec<EntryKind> = Vec::new();
+        let modification_time = Timestamp::modified(&pb.metadata()?);
         let name = filename_to_string(pb.file_name());
+        let scoped = settings.ignore_files() && ignores.enter(pb)?;
 
         for entry in dir {
+            if only.first().is_some_and(|name| entry.file_name() != *name) {
+                continue;
+            }
             pb.push(entry.file_name());
 
-            if settings.is_excluded(pb.as_ref()) {
-                if verbose {
-                    println!("Excluding file {}", pb.to_string_lossy())
+            match DirectoryEntry::walk_entry(root, pb, &entry, settings, ignores, guard)? {
+                Walked::Skipped => {}
+                Walked::Directory => {
+                    subdirs.push(DirectoryEntry::create(root, pb, settings, ignores, guard, only.get(1..).unwrap_or(&[]))?);
+                    order.push(EntryKind::Directory);
+                    guard.leave();
                 }
-            } else {
-                let meta = symlink_metadata(&pb)?;
-                let file_type = meta.file_type();
-
-                if file_type.is_symlink() {
-                    if verbose {
-                        println!("Skipping symlink {}", pb.to_string_lossy())
-                    }
-                } else if file_type.is_dir() {
-                    let subtree = DirectoryEntry::create(pb, verbose, settings)?;
-                    hash_input.extend(subtree.name.as_bytes());
-                    hash_input.extend(&subtree.hash_value);
-                    subdirs.push(subtree);
-                } else {
-                    let file = FileEntry::new(pb, &meta, verbose, settings)?;
-                    hash_input.extend(file.name.as_bytes());
-                    hash_input.extend(&file.file_size.to_le_bytes());
-                    hash_input.extend(&file.hash_value);
-                    files.push(file);
+                // only the directory is wanted, not a file of the same name
+                Walked::File(_) if !only.is_empty() => {}
+                Walked::File(meta) => {
+                    link_groups.push(if settings.hard_links() { guard.link_group(&meta) } else { None });
+                    pending_files.push((pb.clone(), meta));
+                    order.push(EntryKind::File);
                 }
             }
 
             pb.pop();
         }
 
-        let hash_value = hash(hash_input.as_slice())?;
-        if verbose {
-            println!("Hashed directory {} into {}", pb.to_string_lossy(), hex::encode(&hash_value))
+        if scoped {
+            ignores.leave();
+        }
+
+        // files may be hashed out of order, so the directory hash is assembled afterwards in
+        // the original (sorted) order of entries
+        let mut files = hash_files(pending_files, settings)?;
+        for (file, link_group) in files.iter_mut().zip(link_groups) {
+            file.link_group = link_group;
         }
+        let hash_value = directory_hash(settings.hash_algorithm(), &order, &subdirs, &files)?;
+        debug!("Hashed directory {} into {}", pb.to_string_lossy(), hex::encode(hash_value));
 
         Ok(DirectoryEntry {
             name,
@@ -260,176 +661,2338 @@ impl DirectoryEntry {
     }
 }
 
-impl PartialEq for DirectoryEntry {
-    fn eq(&self, other: &Self) -> bool {
-        other.modification_time == self.modification_time &&
-            other.hash_value == self.hash_value
-    }
+/// How the walk of a tree treats one of its entries, see `DirectoryEntry::walk_entry`.
+enum Walked {
+    /// Not part of the manifest: excluded, ignored, a symlink or special file, left out for
+    /// its size or age, or a directory the `CycleGuard` keeps the walk out of
+    Skipped,
+    /// A directory to walk, entered into the `CycleGuard` already. It must be left once the
+    /// walk of the directory is done.
+    Directory,
+    /// A file, with the metadata the manifest records
+    File(Metadata),
 }
 
-impl Named for DirectoryEntry {
-    fn name(&self) -> &str {
-        &self.name
-    }
-}
+impl DirectoryEntry {
+    /// Decides how the walk of `root` treats `entry`, found at `path`. Building a manifest and
+    /// validating a stored one both decide through this, so that a manifest still validates
+    /// against the tree it was built from.
+    fn walk_entry(root: &Path, path: &Path, entry: &DirEntry, settings: &HashSettings, ignores: &IgnoreStack, guard: &mut CycleGuard) -> Result<Walked> {
+        let ignored = settings.ignore_files() && (entry.file_name() == IGNORE_FILE || ignores.is_ignored(path));
+        let is_dir = if settings.copy_links() { path.is_dir() } else { entry.file_type()?.is_dir() };
+        if ignored || settings.is_excluded_below(root, path, is_dir) {
+            debug!("Excluding file {}", path.to_string_lossy());
+            return Ok(Walked::Skipped);
+        }
+        if !is_dir && entry.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            debug!("Skipping partial transfer {}", path.to_string_lossy());
+            return Ok(Walked::Skipped);
+        }
 
-#[derive(Serialize, Deserialize)]
-pub struct Manifest(DirectoryEntry);
+        // only names that take part in the sync must be representable in a manifest
+        if entry.file_name().to_str().is_none() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("File name is not valid UTF-8: {}", path.to_string_lossy())));
+        }
 
-impl Manifest {
-    pub fn create_ephemeral<S: AsRef<OsStr>>(root: S, verbose: bool, settings: &HashSettings) -> Result<Manifest> {
-        let de = DirectoryEntry::new(root.as_ref(), verbose, settings)?;
+        // with --copy-links, a symlink stands for its target, unless that can't be read
+        let meta = match symlink_metadata(path)? {
+            link if settings.copy_links() && link.file_type().is_symlink() => path.metadata().unwrap_or_else(|e| {
+                warn!("Not following symlink {}: {}", path.to_string_lossy(), e);
+                link
+            }),
+            meta => meta,
+        };
+        let file_type = meta.file_type();
 
-        Ok(Manifest(de))
+        if file_type.is_symlink() {
+            debug!("Skipping symlink {}", path.to_string_lossy());
+        } else if file_type.is_dir() {
+            if guard.enter(path, &meta, settings.max_depth()) {
+                return Ok(Walked::Directory);
+            }
+        } else if !file_type.is_file() {
+            if settings.fail_on_special_files() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Not a regular file: {}", path.to_string_lossy())));
+            }
+            warn!("Skipping special file {}", path.to_string_lossy());
+        } else if settings.is_excluded_size(meta.len()) {
+            debug!("Skipping {} due to its size", path.to_string_lossy());
+        } else if settings.is_excluded_age(Timestamp::modified(&meta)) {
+            debug!("Skipping {} due to its modification time", path.to_string_lossy());
+        } else {
+            return Ok(Walked::File(meta));
+        }
+        Ok(Walked::Skipped)
     }
+}
 
-    pub fn create_persistent<S: AsRef<OsStr>>(root: S, verbose: bool, settings: &HashSettings, manifest_path: &Path) -> Result<Manifest> {
-        let manifest_path = manifest_file(root.as_ref(), manifest_path);
-        let settings = settings.with_additional_exclusion(manifest_path.as_path());
+/// Keeps the walk from following a directory hardlink, a bind mount or (with `--copy-links`) a
+/// symlink back into a directory it is inside of, which would otherwise recurse until the
+/// stack overflows. Directories are identified by device and inode, which is only available
+/// on Unix. Elsewhere only the optional depth limit applies. With `--hard-links`, files are
+/// tracked by inode as well, to find the ones linked to each other.
+struct CycleGuard {
+    #[cfg(unix)]
+    ancestors: HashSet<(u64, u64)>,
+    /// The directories in `ancestors`, innermost last
+    #[cfg(unix)]
+    entered: Vec<(u64, u64)>,
+    /// Device the walk is restricted to, with `--one-file-system`
+    #[cfg(unix)]
+    device: Option<u64>,
+    /// Link groups of the files with several hard links, by device and inode
+    #[cfg(unix)]
+    link_groups: HashMap<(u64, u64), u64>,
+    depth: usize,
+}
 
-        if verbose {
-            println!("Resolved manifest path to {}", manifest_path.as_path().to_string_lossy());
+impl CycleGuard {
+    fn new() -> CycleGuard {
+        CycleGuard {
+            #[cfg(unix)]
+            ancestors: HashSet::new(),
+            #[cfg(unix)]
+            entered: Vec::new(),
+            #[cfg(unix)]
+            device: None,
+            #[cfg(unix)]
+            link_groups: HashMap::new(),
+            depth: 0,
         }
+    }
 
-        let mut res = Manifest::load(manifest_path.as_path(), &settings);
-        if res.is_ok() {
-            let m = res.as_ref().unwrap();
-            if !m.0.validate(&mut PathBuf::from(root.as_ref()), &settings) {
-                res = Err(Error::new(ErrorKind::Other, "Manifest validation failed"))
-            }
+    /// A guard for the walk of `root` (described by `meta`) with `settings`.
+    fn for_root(root: &Path, meta: &Metadata, settings: &HashSettings) -> CycleGuard {
+        let mut guard = CycleGuard::new();
+        if settings.one_file_system() {
+            guard.stay_on_device_of(meta);
         }
+        guard.visit(root, meta);
+        guard
+    }
 
-        res.or_else(|e| {
-            if verbose {
-                println!("Manifest file not usable: {}", e)
-            }
-            let de = DirectoryEntry::new(root.as_ref(), verbose, &settings);
-            de.and_then(|e| {
-                let manifest = Manifest(e);
+    /// The link group of the file `meta` belongs to, if it has more than one hard link.
+    #[cfg(unix)]
+    fn link_group(&mut self, meta: &Metadata) -> Option<u64> {
+        use std::os::unix::fs::MetadataExt;
 
-                manifest.save(verbose, &manifest_path)?;
+        if meta.nlink() < 2 {
+            return None;
+        }
+        let next = self.link_groups.len() as u64;
+        Some(*self.link_groups.entry((meta.dev(), meta.ino())).or_insert(next))
+    }
 
-                Ok(manifest)
-            })
-        })
+    #[cfg(not(unix))]
+    fn link_group(&mut self, _meta: &Metadata) -> Option<u64> {
+        None
     }
 
-    pub fn copy_from<T: Transmitter>(&self, source: &Manifest, transmitter: &mut T, verbose: bool) -> Result<()> {
-        let path = PathBuf::new();
-        let source = &source.0;
-        self.0.copy_from(&path, source, transmitter, verbose)?;
+    /// Restricts the walk to the filesystem the root (described by `meta`) is on.
+    #[cfg(unix)]
+    fn stay_on_device_of(&mut self, meta: &Metadata) {
+        use std::os::unix::fs::MetadataExt;
 
-        Ok(())
+        self.device = Some(meta.dev());
     }
 
-    fn save(&self, verbose: bool, manifest_path: &Path) -> Result<()> {
-        if verbose {
-            println!("Opening file {} for saving manifest", manifest_path.to_string_lossy());
-        }
+    #[cfg(not(unix))]
+    fn stay_on_device_of(&mut self, _meta: &Metadata) {
+        warn!("--one-file-system is not supported on this platform");
+    }
 
-        let file = File::create(manifest_path)?;
-        let r = bincode::serialize_into(BufWriter::new(file), self);
-        r.map_err(|e| Error::new(ErrorKind::Other, e))?;
+    #[cfg(unix)]
+    fn is_foreign_device(&self, path: &Path, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
 
-        if verbose {
-            println!("Saved manifest file to {}", manifest_path.to_string_lossy());
+        if self.device.is_some_and(|device| device != meta.dev()) {
+            info!("Not descending into {}, it is on a different filesystem", path.to_string_lossy());
+            true
+        } else {
+            false
         }
+    }
 
-        Ok(())
+    #[cfg(not(unix))]
+    fn is_foreign_device(&self, _path: &Path, _meta: &Metadata) -> bool {
+        false
     }
 
-    fn load<S: AsRef<Path>>(file: S, cfg: &HashSettings) -> Result<Manifest> {
-        if cfg.force_rebuild() {
-            return Err(Error::new(ErrorKind::Other, "Forced rebuild of manifest"));
+    /// Records the directory `meta` belongs to as being walked, returning false if the walk is
+    /// inside of it already.
+    #[cfg(unix)]
+    fn visit(&mut self, path: &Path, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let id = (meta.dev(), meta.ino());
+        if self.ancestors.insert(id) {
+            self.entered.push(id);
+            true
+        } else {
+            warn!("Not descending into {}, it leads back to a directory containing it", path.to_string_lossy());
+            false
         }
-        let file = File::open(file)?;
-        bincode::deserialize_from(BufReader::new(file))
-            .map_err(|e| Error::new(ErrorKind::Other, e))
     }
-}
 
-#[cfg(test)]
-mod test_tree_hashing {
-    use super::*;
-    use crate::config::test_support;
-    use ring::test::from_hex;
-    use std::cmp::min;
-    use tempfile::{NamedTempFile, TempDir};
-    use std::io::{Write, Seek, SeekFrom};
-    use filetime::{set_file_mtime, FileTime};
-    use std::fs::create_dir;
-    use std::time::UNIX_EPOCH;
+    #[cfg(not(unix))]
+    fn visit(&mut self, _path: &Path, _meta: &Metadata) -> bool {
+        true
+    }
 
-    fn unhex(str: &str) -> [u8; 32] {
-        let vec = from_hex(str).unwrap();
-        let mut rv = [0u8; 32];
-        rv.copy_from_slice(vec.as_slice());
-        rv
+    /// Checks whether the walk may descend into the directory at `path`, and if so, tracks
+    /// it until the matching `leave`.
+    fn enter(&mut self, path: &Path, meta: &Metadata, max_depth: Option<usize>) -> bool {
+        if max_depth.is_some_and(|max| self.depth >= max) {
+            warn!("Not descending into {}, it is nested deeper than the maximum depth", path.to_string_lossy());
+            return false;
+        }
+
+        if self.is_foreign_device(path, meta) || !self.visit(path, meta) {
+            return false;
+        }
+
+        self.depth += 1;
+        true
     }
 
+    fn leave(&mut self) {
+        self.depth -= 1;
+        #[cfg(unix)]
+        {
+            if let Some(id) = self.entered.pop() {
+                self.ancestors.remove(&id);
+            }
+        }
+    }
+}
 
-    struct RepeatA(usize);
+enum EntryKind {
+    Directory,
+    File,
+}
 
-    impl Read for RepeatA {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-            if self.0 == 0 {
-                Ok(0usize)
-            } else {
-                let take = min(self.0, buf.len());
-                self.0 -= take;
-                let buf = &mut buf[..take];
-                for v in buf.iter_mut() {
-                    *v = 'a' as u8;
+/// Hashes a directory from its entries, `order` telling whether the next one in sorted order is
+/// the next of `subdirs` or of `files`.
+fn directory_hash(algorithm: HashAlgorithm, order: &[EntryKind], subdirs: &[DirectoryEntry], files: &[FileEntry]) -> Result<ShaSum> {
+    let mut hash_input: Vec<u8> = Vec::new();
+    let mut subdir_iter = subdirs.iter();
+    let mut file_iter = files.iter();
+    for kind in order {
+        match kind {
+            EntryKind::Directory => {
+                let subtree = subdir_iter.next().unwrap();
+                hash_input.extend(subtree.name.as_bytes());
+                hash_input.extend(&subtree.hash_value);
+            }
+            EntryKind::File => {
+                let file = file_iter.next().unwrap();
+                hash_input.extend(file.name.as_bytes());
+                hash_input.extend(&file.file_size.to_le_bytes());
+                hash_input.extend(&file.hash_value);
+                if let Some(mode) = file.mode {
+                    hash_input.extend(&mode.to_le_bytes());
                 }
-                Ok(take)
             }
         }
     }
 
-    #[test]
-    fn hash_small_subtree() -> Result<()> {
-        let root = TempDir::new()?;
-        let mut cursor = root.path().join(Path::new("subdir"));
-        create_dir(&cursor)?;
-        cursor.push("file1.txt");
-        let mut file = File::create(&cursor)?;
-        file.write_all(b"abc")?;
-        cursor.pop();
-        cursor.pop();
-        cursor.push("file2.txt");
-        file = File::create(&cursor)?;
-        file.write_all(b"def")?;
-
-        let dir = DirectoryEntry::new(root.path(), false, &test_support::default_settings())?;
+    hash_with(algorithm, hash_input.as_slice())
+}
 
-        assert_eq!(dir.files[0].hash_value, unhex("cb8379ac2098aa165029e3938a51da0bcecfc008fd6795f401178647f96c5b34"));
-        assert_eq!(dir.subdirs[0].files[0].hash_value, unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+/// A file for `Manifest::create_from_files`, which is not read from a directory on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedFile {
+    /// Location relative to the manifest root
+    pub path: PathBuf,
+    pub size: u64,
+    pub modification_time: Timestamp,
+    /// Permission bits, recorded with `--perms`
+    pub mode: u32,
+}
 
-        assert_eq!(dir.hash_value, unhex("b178872f99aa86b175afb23e34943eb04a40f3ae6940e14b89f2608813135abb"));
+/// The tree of directories implied by the paths of listed files.
+#[derive(Default)]
+struct ListedDirectory<'a> {
+    entries: BTreeMap<&'a str, Listed<'a>>,
+}
 
+enum Listed<'a> {
+    Directory(ListedDirectory<'a>),
+    File(&'a ListedFile),
+}
 
-        Ok(())
+impl<'a> ListedDirectory<'a> {
+    /// The directory at `path` (relative to this one), created if it isn't known yet.
+    fn directory(&mut self, path: &'a Path) -> Result<&mut ListedDirectory<'a>> {
+        let mut dir = self;
+        for component in path.components() {
+            let name = match component.as_os_str().to_str() {
+                Some(".") => continue,
+                Some(name) if matches!(component, std::path::Component::Normal(_)) => name,
+                Some(_) => return Err(Error::new(ErrorKind::InvalidData, format!("Listed path is not relative: {}", path.to_string_lossy()))),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("File name is not valid UTF-8: {}", path.to_string_lossy()))),
+            };
+            dir = match dir.entries.entry(name).or_insert_with(|| Listed::Directory(ListedDirectory::default())) {
+                Listed::Directory(dir) => dir,
+                Listed::File(_) => return Err(Error::new(ErrorKind::InvalidData, format!("{} is listed as file and directory", path.to_string_lossy()))),
+            };
+        }
+        Ok(dir)
     }
 
-    #[test]
-    fn test_hash_single_file() -> Result<()> {
-        let mut file = NamedTempFile::new()?;
-        file.write_all(b"abc")?;
-        file.seek(SeekFrom::Start(0))?;
-        file.flush()?;
-        set_file_mtime(file.path(), FileTime::from(UNIX_EPOCH))?;
-        let settings = test_support::default_settings();
-
-        let generated = FileEntry::new(file.path(), &file.as_file().metadata()?, false, &settings)?;
+    fn insert(&mut self, file: &'a ListedFile) -> Result<()> {
+        let name = file.path.file_name().and_then(OsStr::to_str)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid file name: {}", file.path.to_string_lossy())))?;
+        let dir = self.directory(file.path.parent().unwrap_or_else(|| Path::new("")))?;
+        match dir.entries.insert(name, Listed::File(file)) {
+            Some(Listed::Directory(_)) => Err(Error::new(ErrorKind::InvalidData, format!("{} is listed as file and directory", file.path.to_string_lossy()))),
+            _ => Ok(()),
+        }
+    }
 
-        assert_eq!(filename_to_string(file.path().file_name()), generated.name);
-        assert_eq!(UNIX_EPOCH, generated.modification_time);
+    /// Creates the entry for this directory, named `name`, at `path` relative to the root. A
+    /// directory doesn't exist on its own, so its modification time is the newest of its
+    /// contents.
+    fn create<R: Read, F: FnMut(&Path) -> Result<R>>(&self, name: &str, path: &mut PathBuf, settings: &HashSettings, open: &mut F) -> Result<DirectoryEntry> {
+        let mut subdirs = Vec::new();
+        let mut files = Vec::new();
+        let mut order = Vec::new();
+        for (name, entry) in &self.entries {
+            path.push(name);
+            match entry {
+                Listed::Directory(dir) => {
+                    subdirs.push(dir.create(name, path, settings, open)?);
+                    order.push(EntryKind::Directory);
+                }
+                Listed::File(file) => {
+                    let hash_value = if settings.manifest_mode() == ManifestMode::Hash {
+                        hash_keyed(settings.hash_algorithm(), settings.checksum_key(), open(path)?, settings.io_buffer_size())?
+                    } else {
+                        [0u8; 32]
+                    };
+                    files.push(FileEntry {
+                        name: name.to_string(),
+                        modification_time: file.modification_time,
+                        file_size: file.size,
+                        hash_value,
+                        link_group: None,
+                        mode: if settings.perms() { Some(file.mode) } else { None },
+                    });
+                    order.push(EntryKind::File);
+                }
+            }
+            path.pop();
+        }
+
+        let modification_time = files.iter().map(|file| file.modification_time)
+            .chain(subdirs.iter().map(|dir| dir.modification_time))
+            .max()
+            .unwrap_or(Timestamp::UNIX_EPOCH);
+        let hash_value = directory_hash(settings.hash_algorithm(), &order, &subdirs, &files)?;
+        Ok(DirectoryEntry {
+            name: name.to_string(),
+            modification_time,
+            subdirs,
+            files,
+            hash_value,
+        })
+    }
+}
+
+/// Creates the entries for `files`, hashing up to `settings.hash_threads()` of them
+/// concurrently. The result is in the same order as the input.
+fn hash_files(files: Vec<(PathBuf, Metadata)>, settings: &HashSettings) -> Result<Vec<FileEntry>> {
+    let threads = min(settings.hash_threads(), files.len());
+    if threads <= 1 || settings.manifest_mode() != ManifestMode::Hash {
+        return files.iter()
+            .map(|(path, meta)| FileEntry::new(path, meta, settings))
+            .collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<Result<FileEntry>>>> = files.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= files.len() {
+                    break;
+                }
+                let (path, meta) = &files[index];
+                *results[index].lock().unwrap() = Some(FileEntry::new(path, meta, settings));
+            });
+        }
+    });
+
+    results.into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+impl PartialEq for DirectoryEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches(other, Comparison::Timestamps(Duration::from_secs(0)))
+    }
+}
+
+impl Named for DirectoryEntry {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A file recorded in a manifest, as returned by `Manifest::files`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ManifestFile<'a> {
+    /// Location relative to the manifest root
+    pub path: PathBuf,
+    pub size: u64,
+    pub modification_time: Timestamp,
+    /// Content hash, if the manifest was built in hash mode
+    pub hash: Option<&'a ShaSum>,
+}
+
+/// A directory tree together with the algorithm its hashes were computed with, whether its
+/// files were hashed at all or recorded by timestamp, and the `ChecksumKey::id` of the key
+/// they were hashed with, if any.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest(DirectoryEntry, HashAlgorithm, ManifestMode, Option<ChecksumKeyId>);
+
+/// Number of files and bytes a copy is going to transfer.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TransferTotals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// How `Manifest::copy_with` copies: what it compares, which files it transfers, and when it
+/// stops.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CopyOptions {
+    pub comparison: Comparison,
+    pub selection: Selection,
+    /// Whether to go on with the remaining files after one fails (`--keep-going`)
+    pub keep_going: bool,
+    pub limits: TransferLimits,
+}
+
+impl CopyOptions {
+    /// Transfers all files that differ as `comparison` says, without limits, stopping at the
+    /// first failure.
+    pub fn new(comparison: Comparison) -> CopyOptions {
+        CopyOptions { comparison, selection: Selection::All, keep_going: false, limits: TransferLimits::default() }
+    }
+}
+
+/// State of a `Manifest::copy_from`, passed along through the directories.
+struct CopyRun {
+    comparison: Comparison,
+    selection: Selection,
+    /// Whether to go on with the remaining files after one fails (`--keep-going`)
+    keep_going: bool,
+    limits: TransferLimits,
+    stats: CopyStats,
+    /// Maps link groups of the source to a path on the target already holding the group's
+    /// content, so that further members are linked rather than transferred.
+    links: HashMap<u64, PathBuf>,
+    started: Instant,
+}
+
+impl CopyRun {
+    fn new(options: CopyOptions) -> CopyRun {
+        CopyRun {
+            comparison: options.comparison,
+            selection: options.selection,
+            keep_going: options.keep_going,
+            limits: options.limits,
+            stats: CopyStats::default(),
+            links: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// How many of the files of the given sizes can be transferred, one after the other,
+    /// before a limit is reached. The file reaching a limit is still transferred completely.
+    /// Once a limit is reached, nothing more is transferred in this run.
+    fn allowance<I: Iterator<Item=u64>>(&mut self, sizes: I) -> usize {
+        if self.limit_reached() {
+            return 0;
+        }
+        let (mut files, mut bytes) = (self.stats.files_transferred(), self.stats.bytes_transferred);
+        let mut allowed = 0;
+        for size in sizes {
+            if self.exceeds_limits(files, bytes) {
+                break;
+            }
+            files += 1;
+            bytes += size;
+            allowed += 1;
+        }
+        allowed
+    }
+
+    /// Whether a limit was reached by what has been transferred so far.
+    fn limit_reached(&mut self) -> bool {
+        if !self.stats.limit_reached && self.exceeds_limits(self.stats.files_transferred(), self.stats.bytes_transferred) {
+            info!("Transfer limit reached, leaving the remaining files for a later run");
+            self.stats.limit_reached = true;
+        }
+        self.stats.limit_reached
+    }
+
+    fn exceeds_limits(&self, files: u64, bytes: u64) -> bool {
+        self.limits.files.is_some_and(|max| files >= max) || self.limits.bytes.is_some_and(|max| bytes >= max)
+    }
+
+    /// Records `files` of `bytes` in total that were not transferred as a limit was reached.
+    fn defer(&mut self, files: u64, bytes: u64) {
+        self.stats.files_remaining += files;
+        self.stats.bytes_remaining += bytes;
+    }
+
+    /// Records that the `files` at or below `path` differ from the target, but are not
+    /// transferred as `selection` says.
+    fn leave_out(&mut self, path: &Path, files: u64) {
+        debug!("Leaving out {}, as {:?} files are selected", path.to_string_lossy(), self.selection);
+        self.stats.files_left_out += files;
+    }
+
+    /// Records that `path` could not be transferred, if the copy is to keep going and the
+    /// error only concerns that file. Otherwise, the error ends the copy.
+    fn file_failed(&mut self, path: &Path, error: Error) -> Result<()> {
+        if !self.keep_going || !is_file_error(&error) {
+            return Err(error);
+        }
+        warn!("Failed to transfer {}: {}", path.to_string_lossy(), error);
+        self.stats.record_failure(path, &error);
+        Ok(())
+    }
+}
+
+/// How a file that `Manifest::copy_from` replaced differed from the one on the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Differences {
+    /// The content hashes differ (only known for manifests built in hash mode)
+    pub content: bool,
+    pub size: bool,
+    /// The modification times differ, even if within the modify window
+    pub time: bool,
+    /// The permissions differ (only known for manifests built with `--perms`)
+    pub perms: bool,
+}
+
+impl Differences {
+    fn between(existing: &FileEntry, source: &FileEntry) -> Differences {
+        Differences {
+            content: existing.hash_value != source.hash_value,
+            size: existing.file_size != source.file_size,
+            time: existing.modification_time != source.modification_time,
+            perms: existing.mode != source.mode,
+        }
+    }
+}
+
+/// What `Manifest::copy_from` did with an item of the source, as reported through
+/// `Transmitter::itemize`. Displays as the code `--itemize-changes` lists, which follows
+/// rsync's `YXcstpoguax` format and is kept stable:
+///
+/// - `.f         `: file left alone, it matches the target
+/// - `>f+++++++++`: file transferred, it was missing on the target
+/// - `>fcstp.....`: file transferred over one that differs, `c` if in content, `s` if in
+///   size, `t` if in modification time and `p` if in permissions (`.` where it doesn't)
+/// - `hf+++++++++`, `hfcstp.....`: like `>f`, but created as hard link (`--hard-links`)
+/// - `.f...p.....`: file left in place, only its permissions updated (`--perms`)
+/// - `cd+++++++++`: directory created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Unchanged,
+    /// Transferred, replacing a file that differs as given, if there was one
+    Transferred(Option<Differences>),
+    /// Linked to a file transferred before, replacing a file that differs as given, if any
+    Linked(Option<Differences>),
+    /// Kept, with the permissions updated as they differ
+    Updated(Differences),
+    DirectoryCreated,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (update, differences) = match self {
+            Change::Unchanged => return f.write_str(".f         "),
+            Change::DirectoryCreated => return f.write_str("cd+++++++++"),
+            Change::Transferred(differences) => ('>', *differences),
+            Change::Linked(differences) => ('h', *differences),
+            Change::Updated(differences) => ('.', Some(*differences)),
+        };
+        match differences {
+            None => write!(f, "{}f+++++++++", update),
+            Some(d) => {
+                let flag = |set: bool, flag: char| if set { flag } else { '.' };
+                write!(f, "{}f{}{}{}{}.....", update, flag(d.content, 'c'), flag(d.size, 's'), flag(d.time, 't'), flag(d.perms, 'p'))
+            }
+        }
+    }
+}
+
+/// A file `Manifest::copy_from` could not transfer, with `--keep-going`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FailedTransfer {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl FailedTransfer {
+    /// Lists `failures` below a line saying how many files failed to `what`, as in
+    /// "2 files failed verification:".
+    pub fn report(what: &str, failures: &[FailedTransfer]) -> String {
+        let mut report = format!("{} files failed {}:", failures.len(), what);
+        for failure in failures {
+            report.push_str(&format!("\n  {}: {}", failure.path.to_string_lossy(), failure.message));
+        }
+        report
+    }
+}
+
+/// What a `Manifest::copy_from` did.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct CopyStats {
+    pub files_created: u64,
+    pub files_overwritten: u64,
+    pub files_skipped: u64,
+    pub bytes_transferred: u64,
+    pub directories_created: u64,
+    /// Transferred files that were created as hard links (see `--hard-links`)
+    pub files_linked: u64,
+    /// Files left in place, but with their permissions updated (see `--perms`)
+    pub files_updated: u64,
+    /// Files that differ, but were not transferred (see `--ignore-existing` and `--existing`)
+    pub files_left_out: u64,
+    /// Whether the copy stopped early at a limit of `--max-transfer` or `--max-files`
+    pub limit_reached: bool,
+    /// Files (and their bytes) left for a later run as a limit was reached
+    pub files_remaining: u64,
+    pub bytes_remaining: u64,
+    /// Files that could not be transferred, only ever filled with `--keep-going`
+    pub failed: Vec<FailedTransfer>,
+    /// Files that were received, but did not match their expected hash (as with `failed`)
+    pub verification_failed: Vec<FailedTransfer>,
+    /// Wall time the copy took, in seconds in the JSON summary
+    #[serde(rename = "elapsed_seconds", serialize_with = "serialize_seconds")]
+    pub elapsed: Duration,
+}
+
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl CopyStats {
+    fn count_transferred(&mut self, is_new: bool) {
+        if is_new {
+            self.files_created += 1;
+        } else {
+            self.files_overwritten += 1;
+        }
+    }
+
+    /// Records that `path` failed with `error`, as a verification failure if it is one.
+    pub fn record_failure(&mut self, path: &Path, error: &Error) {
+        let failure = FailedTransfer { path: path.to_owned(), message: error.to_string() };
+        match UsyncError::of(error) {
+            Some(UsyncError::Verification(_)) => self.verification_failed.push(failure),
+            _ => self.failed.push(failure),
+        }
+    }
+
+    #[inline]
+    pub fn files_transferred(&self) -> u64 {
+        self.files_created + self.files_overwritten
+    }
+
+    /// Average transfer rate in bytes per second over the whole copy.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes_transferred as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// The summary line, with sizes and rate given with binary suffixes if `human_readable`,
+    /// see `format_size`. The `Display` form gives them in bytes.
+    pub fn summary(&self, human_readable: bool) -> String {
+        let (bytes, rate) = if human_readable {
+            (format_size(self.bytes_transferred), format!("{}/s", format_size(self.throughput() as u64)))
+        } else {
+            (format!("{} bytes", self.bytes_transferred), format!("{:.0} bytes/s", self.throughput()))
+        };
+        let mut summary = format!("Transferred {} files ({} new, {} overwritten, {}) in {:.1}s ({}), {} files unchanged, {} directories created",
+            self.files_transferred(), self.files_created, self.files_overwritten, bytes,
+            self.elapsed.as_secs_f64(), rate, self.files_skipped, self.directories_created);
+        if self.files_linked > 0 {
+            summary.push_str(&format!(", {} files hard linked", self.files_linked));
+        }
+        if self.files_updated > 0 {
+            summary.push_str(&format!(", {} files with permissions updated", self.files_updated));
+        }
+        if self.files_left_out > 0 {
+            summary.push_str(&format!(", {} files left out", self.files_left_out));
+        }
+        if self.limit_reached {
+            let remaining = if human_readable { format_size(self.bytes_remaining) } else { format!("{} bytes", self.bytes_remaining) };
+            summary.push_str(&format!(", stopped at the transfer limit with {} files ({}) remaining", self.files_remaining, remaining));
+        }
+        if !self.failed.is_empty() {
+            summary.push_str(&format!(", {} files failed", self.failed.len()));
+        }
+        if !self.verification_failed.is_empty() {
+            summary.push_str(&format!(", {} files failed verification", self.verification_failed.len()));
+        }
+        summary
+    }
+}
+
+impl Display for CopyStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.summary(false))
+    }
+}
+
+/// A single directory of a manifest, without its subdirectories. Manifests are exchanged as a
+/// pre-order sequence of these, so no single message has to hold the entire tree.
+#[derive(Serialize, Deserialize)]
+pub struct DirectoryChunk<'a> {
+    name: Cow<'a, str>,
+    modification_time: Timestamp,
+    hash_value: ShaSum,
+    files: Cow<'a, [FileEntry]>,
+    subdir_count: u64,
+}
+
+impl DirectoryEntry {
+    fn chunks<F: FnMut(&DirectoryChunk) -> Result<()>>(&self, emit: &mut F) -> Result<()> {
+        emit(&DirectoryChunk {
+            name: Cow::Borrowed(&self.name),
+            modification_time: self.modification_time,
+            hash_value: self.hash_value,
+            files: Cow::Borrowed(&self.files),
+            subdir_count: self.subdirs.len() as u64,
+        })?;
+
+        for subdir in &self.subdirs {
+            subdir.chunks(emit)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reassembles the directory (and those below it) from the chunks a peer sent. The names
+    /// of its files and subdirectories end up joined onto the root, so anything but a plain
+    /// name is refused. The name of the root itself is never used. `depth` is the number of
+    /// directories above this one, see `MAX_RECEIVED_DEPTH`.
+    fn from_chunks<F: FnMut() -> Result<DirectoryChunk<'static>>>(next: &mut F, depth: usize) -> Result<DirectoryEntry> {
+        if depth > MAX_RECEIVED_DEPTH {
+            return Err(UsyncError::Protocol(format!("Manifest nests directories deeper than {} levels", MAX_RECEIVED_DEPTH)).into());
+        }
+        let chunk = next()?;
+        let mut subdirs = Vec::new();
+
+        for _ in 0..chunk.subdir_count {
+            let subdir = DirectoryEntry::from_chunks(next, depth + 1)?;
+            check_entry_name(&subdir.name)?;
+            subdirs.push(subdir);
+        }
+        for file in chunk.files.iter() {
+            check_entry_name(&file.name)?;
+        }
+
+        Ok(DirectoryEntry {
+            name: chunk.name.into_owned(),
+            modification_time: chunk.modification_time,
+            subdirs,
+            files: chunk.files.into_owned(),
+            hash_value: chunk.hash_value,
+        })
+    }
+}
+
+/// Fails unless `name`, received as the name of a manifest entry, is a plain name.
+fn check_entry_name(name: &str) -> Result<()> {
+    if !is_plain_name(name) {
+        return Err(UsyncError::Protocol(format!("Manifest entry named {:?} would leave the tree", name)).into());
+    }
+    Ok(())
+}
+
+/// Fails with an error naming `root` unless it is an existing directory. `what` starts the
+/// message, e.g. "Source path". A root that is a symlink to a directory counts as that
+/// directory: manifests list its contents and transfers go through the link, which is never
+/// replaced itself (unlike symlinks below the root, see `--copy-links`).
+pub fn check_root<P: AsRef<Path>>(root: P, what: &str) -> Result<()> {
+    let root = root.as_ref();
+    match std::fs::metadata(root) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => Err(Error::new(ErrorKind::InvalidInput, format!("{} {} is not a directory", what, root.to_string_lossy()))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::new(ErrorKind::NotFound, format!("{} {} does not exist", what, root.to_string_lossy()))),
+        Err(e) => Err(Error::new(e.kind(), format!("{} {} is not accessible: {}", what, root.to_string_lossy(), e))),
+    }
+}
+
+/// The names of the directories leading to `subpath`, which must be relative to a root.
+fn subpath_names(subpath: &Path) -> Result<Vec<&OsStr>> {
+    let mut names = Vec::new();
+    for component in subpath.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir => {}
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "{} is not a path relative to the root", subpath.to_string_lossy()))),
+        }
+    }
+    Ok(names)
+}
+
+impl Manifest {
+    pub fn create_ephemeral<S: AsRef<OsStr>>(root: S, settings: &HashSettings) -> Result<Manifest> {
+        check_root(root.as_ref(), "Manifest root")?;
+        let de = DirectoryEntry::new(root.as_ref(), settings)?;
+
+        Ok(Manifest(de, settings.hash_algorithm(), settings.manifest_mode(), settings.checksum_key_id()))
+    }
+
+    /// Like `create_ephemeral`, but with `subpath` only walks the directory at that path and
+    /// what is below it, giving the manifest `restrict_to` leaves (`--subpath`). Unlike that,
+    /// a missing `subpath` is no error, it is left out like the rest of the tree.
+    pub fn create_ephemeral_below<S: AsRef<OsStr>>(root: S, subpath: Option<&Path>, settings: &HashSettings) -> Result<Manifest> {
+        let subpath = match subpath {
+            Some(subpath) => subpath,
+            None => return Manifest::create_ephemeral(root, settings),
+        };
+        check_root(root.as_ref(), "Manifest root")?;
+        let de = DirectoryEntry::new_below(root.as_ref(), &subpath_names(subpath)?, settings)?;
+
+        Ok(Manifest(de, settings.hash_algorithm(), settings.manifest_mode(), settings.checksum_key_id()))
+    }
+
+    /// Builds a manifest of files that don't come from a directory tree on disk, like the
+    /// members of an archive. Directories are implied by the paths of the files, `directories`
+    /// only needs to list the empty ones. `open` provides the contents of a file (by its path
+    /// relative to the root) for hashing. The exclusions, size limits and maximum depth of
+    /// `settings` apply.
+    pub fn create_from_files<R: Read, F: FnMut(&Path) -> Result<R>>(files: &[ListedFile], directories: &[PathBuf], settings: &HashSettings, mut open: F) -> Result<Manifest> {
+        let mut root = ListedDirectory::default();
+        for dir in directories {
+            let depth = dir.components().count();
+            let excluded = dir.ancestors()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| settings.is_excluded_directory(dir));
+            if excluded || settings.max_depth().is_some_and(|max| depth > max) {
+                continue;
+            }
+            root.directory(dir)?;
+        }
+        for file in files {
+            let depth = file.path.components().count().saturating_sub(1);
+            let excluded_dir = file.path.ancestors().skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| settings.is_excluded_directory(dir));
+            if excluded_dir || settings.is_excluded(&file.path) || settings.is_excluded_size(file.size) ||
+                settings.is_excluded_age(file.modification_time) || settings.max_depth().is_some_and(|max| depth > max) {
+                debug!("Excluding file {}", file.path.to_string_lossy());
+                continue;
+            }
+            root.insert(file)?;
+        }
+
+        let de = root.create("", &mut PathBuf::new(), settings, &mut open)?;
+        Ok(Manifest(de, settings.hash_algorithm(), settings.manifest_mode(), settings.checksum_key_id()))
+    }
+
+    pub fn create_persistent<S: AsRef<OsStr>>(root: S, settings: &HashSettings, location: &ManifestLocation) -> Result<Manifest> {
+        check_root(root.as_ref(), "Manifest root")?;
+        let manifest_path = match location {
+            ManifestLocation::File(path) => manifest_file(root.as_ref(), path),
+            ManifestLocation::Cache => cached_manifest_file(&cache_dir()?, root.as_ref())?,
+        };
+        let settings = settings.with_additional_exclusion(manifest_path.as_path());
+
+        debug!("Resolved manifest path to {}", manifest_path.as_path().to_string_lossy());
+
+        let mut res = Manifest::load(manifest_path.as_path(), &settings);
+        if res.is_ok() {
+            let m = res.as_ref().unwrap();
+            if let Err(reason) = m.0.validate(&mut PathBuf::from(root.as_ref()), &settings) {
+                res = Err(UsyncError::ManifestValidation(format!("Manifest validation failed at {}", reason)).into())
+            }
+        }
+
+        res.or_else(|e| {
+            info!("Manifest file not usable: {}", e);
+            let de = DirectoryEntry::new(root.as_ref(), &settings);
+            de.and_then(|e| {
+                let manifest = Manifest(e, settings.hash_algorithm(), settings.manifest_mode(), settings.checksum_key_id());
+
+                manifest.save(&manifest_path)?;
+
+                Ok(manifest)
+            })
+        })
+    }
+
+    /// Emits this manifest as a pre-order sequence of directory chunks.
+    pub fn chunks<F: FnMut(&DirectoryChunk) -> Result<()>>(&self, emit: &mut F) -> Result<()> {
+        self.0.chunks(emit)
+    }
+
+    /// Reassembles a manifest hashed with `algorithm` in `mode`, keyed with the key `key_id`
+    /// identifies (if any), from the chunks produced by `chunks`.
+    pub fn from_chunks<F: FnMut() -> Result<DirectoryChunk<'static>>>(algorithm: HashAlgorithm, mode: ManifestMode, key_id: Option<ChecksumKeyId>, next: &mut F) -> Result<Manifest> {
+        Ok(Manifest(DirectoryEntry::from_chunks(next, 0)?, algorithm, mode, key_id))
+    }
+
+    #[inline]
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.1
+    }
+
+    #[inline]
+    pub fn manifest_mode(&self) -> ManifestMode {
+        self.2
+    }
+
+    /// Identifies the key the content hashes are keyed with (see `--checksum-seed`), without
+    /// revealing it.
+    #[inline]
+    pub fn checksum_key_id(&self) -> Option<&ChecksumKeyId> {
+        self.3.as_ref()
+    }
+
+    /// Computes how much `copy_from(source, ..)` will transfer into this manifest's tree.
+    /// Counts what `copy_from` with the same `comparison` is going to transfer.
+    pub fn transfer_totals(&self, source: &Manifest, comparison: Comparison) -> TransferTotals {
+        self.selected_transfer_totals(source, comparison, Selection::All)
+    }
+
+    /// Like `transfer_totals`, counting what `copy_with` with the same `selection` is going
+    /// to transfer, whatever the limits.
+    pub fn selected_transfer_totals(&self, source: &Manifest, comparison: Comparison, selection: Selection) -> TransferTotals {
+        let mut totals = TransferTotals::default();
+        self.0.count_transfers(&source.0, comparison, selection, &mut totals, &mut HashSet::new());
+        totals
+    }
+
+    /// Transfers the files that differ between `source` and this manifest, with modification
+    /// times compared as `comparison` says.
+    pub fn copy_from<T: Transmitter>(&self, source: &Manifest, comparison: Comparison, transmitter: &mut T) -> Result<CopyStats> {
+        self.copy_with(source, CopyOptions::new(comparison), transmitter)
+    }
+
+    /// Like `copy_from`, but going on with the remaining files if one fails to transfer. The
+    /// failures are listed in the returned stats. Losing the connection still ends the copy.
+    pub fn copy_from_keep_going<T: Transmitter>(&self, source: &Manifest, comparison: Comparison, transmitter: &mut T) -> Result<CopyStats> {
+        self.copy_with(source, CopyOptions { keep_going: true, ..CopyOptions::new(comparison) }, transmitter)
+    }
+
+    /// Like `copy_from`, as `options` say. Files `options.selection` doesn't pick are counted
+    /// as left out. Once `options.limits` are reached the copy ends successfully, with the
+    /// files it did not get to counted as remaining. A copy that ends successfully is
+    /// committed, see `Transmitter::commit`.
+    pub fn copy_with<T: Transmitter>(&self, source: &Manifest, options: CopyOptions, transmitter: &mut T) -> Result<CopyStats> {
+        let mut run = CopyRun::new(options);
+        self.0.copy_from(&PathBuf::new(), &source.0, transmitter, &mut run)?;
+        transmitter.commit()?;
+        run.stats.elapsed = run.started.elapsed();
+        Ok(run.stats)
+    }
+
+    /// Returns this manifest with content hashes for all files, hashing those recorded in
+    /// timestamp mode from their current contents below `root`, keyed with `key` like the
+    /// manifest itself was.
+    pub fn with_hashes<P: AsRef<Path>>(&self, root: P, key: Option<&ChecksumKey>) -> Result<Manifest> {
+        let entry = self.0.with_hashes(&mut root.as_ref().to_owned(), self.1, key)?;
+        Ok(Manifest(entry, self.1, ManifestMode::Hash, self.3.clone()))
+    }
+
+    /// Drops the directories that have no files, not even in their subdirectories, so that
+    /// copying from this manifest doesn't create them on the target (`--prune-empty-dirs`).
+    pub fn prune_empty_dirs(&mut self) {
+        self.0.prune_empty_dirs();
+    }
+
+    /// Reduces this manifest to the directory at `subpath` (relative to the root) and what is
+    /// below it, so that copying from it only syncs that part of the tree (`--subpath`). The
+    /// directories leading there are kept, without their other contents. Fails if there is no
+    /// such directory.
+    pub fn restrict_to(&mut self, subpath: &Path) -> Result<()> {
+        if !self.0.restrict_to(&subpath_names(subpath)?) {
+            return Err(Error::new(ErrorKind::NotFound, format!(
+                "There is no directory {} in the source", subpath.to_string_lossy())));
+        }
+        Ok(())
+    }
+
+    /// All files in this manifest, in a stable order: the files of a directory sorted by name,
+    /// followed by the contents of its subdirectories.
+    pub fn files(&self) -> Vec<ManifestFile<'_>> {
+        let mut found = Vec::new();
+        self.0.collect_files(&mut PathBuf::new(), &mut found);
+        found
+    }
+
+    /// Relative paths of all files of `source` that are missing from this manifest or whose
+    /// size or content hash differ. Both manifests need content hashes, see `with_hashes`.
+    pub fn mismatches(&self, source: &Manifest) -> Vec<PathBuf> {
+        self.verification_failures(source).into_iter().map(|failure| failure.path).collect()
+    }
+
+    /// Like `mismatches`, with what is wrong with each file.
+    pub fn verification_failures(&self, source: &Manifest) -> Vec<FailedTransfer> {
+        let mut found = Vec::new();
+        self.0.mismatches(&PathBuf::new(), &source.0, &mut found);
+        found
+    }
+
+    fn save(&self, manifest_path: &Path) -> Result<()> {
+        debug!("Opening file {} for saving manifest", manifest_path.to_string_lossy());
+
+        self.write_to(File::create(manifest_path)?)?;
+
+        debug!("Saved manifest file to {}", manifest_path.to_string_lossy());
+
+        Ok(())
+    }
+
+    fn load<S: AsRef<Path>>(file: S, cfg: &HashSettings) -> Result<Manifest> {
+        if cfg.force_rebuild() {
+            return Err(Error::new(ErrorKind::Other, "Forced rebuild of manifest"));
+        }
+        Manifest::read_from(File::open(file)?, cfg)
+    }
+
+    /// Writes the manifest in the format of stored manifest files (see `--write-manifest`).
+    pub fn write_to<W: Write>(&self, output: W) -> Result<()> {
+        let mut output = BufWriter::new(output);
+        output.write_all(&MANIFEST_MAGIC)?;
+        output.write_all(&MANIFEST_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut output, self).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        output.flush()
+    }
+
+    /// Reads a manifest written by `write_to`, which must be hashed with the algorithm and in
+    /// the mode `cfg` configures. Unlike a stored manifest, it is not checked against the tree
+    /// it describes.
+    pub fn read_from<R: Read>(input: R, cfg: &HashSettings) -> Result<Manifest> {
+        let mut input = BufReader::new(input);
+        let mut header = [0u8; 8];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            // too short to even hold the header, which the check of the magic reports
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => header = [0u8; 8],
+            Err(e) => return Err(e),
+        }
+        if header[..4] != MANIFEST_MAGIC {
+            return Err(UsyncError::VersionMismatch(String::from("Not a usync manifest, or one written by a version of usync without versioned manifests")).into());
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if version != MANIFEST_VERSION {
+            return Err(UsyncError::VersionMismatch(format!(
+                "Manifest has format version {}, but this usync reads version {}", version, MANIFEST_VERSION)).into());
+        }
+
+        let manifest: Manifest = bincode::deserialize_from(input)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        if manifest.1 != cfg.hash_algorithm() {
+            return Err(UsyncError::ManifestValidation(format!(
+                "Manifest was hashed with {}, but {} is configured", manifest.1, cfg.hash_algorithm())).into());
+        }
+        if manifest.2 != cfg.manifest_mode() {
+            return Err(UsyncError::ManifestValidation(format!(
+                "Manifest was built in {} mode, but {} mode is configured", manifest.2, cfg.manifest_mode())).into());
+        }
+        match (&manifest.3, cfg.checksum_key()) {
+            (None, Some(_)) => return Err(UsyncError::ManifestValidation(String::from("Manifest was hashed without a checksum seed, but one is configured")).into()),
+            (Some(_), None) => return Err(UsyncError::ManifestValidation(String::from("Manifest was hashed with a checksum seed, but none is configured")).into()),
+            (Some(stored), Some(key)) if !key.is_identified_by(stored) => return Err(UsyncError::ManifestValidation(String::from("Manifest was hashed with a different checksum seed")).into()),
+            _ => {}
+        }
+
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod test_tree_hashing {
+    use super::*;
+    use crate::config::test_support;
+    use ring::test::from_hex;
+    use tempfile::{NamedTempFile, TempDir};
+    use std::io::{Write, Seek, SeekFrom};
+    use filetime::{set_file_mtime, FileTime};
+    use std::fs::create_dir;
+
+    fn unhex(str: &str) -> [u8; 32] {
+        let vec = from_hex(str).unwrap();
+        let mut rv = [0u8; 32];
+        rv.copy_from_slice(vec.as_slice());
+        rv
+    }
+
+
+    struct RepeatA(usize);
+
+    impl Read for RepeatA {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.0 == 0 {
+                Ok(0usize)
+            } else {
+                let take = min(self.0, buf.len());
+                self.0 -= take;
+                let buf = &mut buf[..take];
+                for v in buf.iter_mut() {
+                    *v = 'a' as u8;
+                }
+                Ok(take)
+            }
+        }
+    }
+
+    #[test]
+    fn hash_small_subtree() -> Result<()> {
+        let root = TempDir::new()?;
+        let mut cursor = root.path().join(Path::new("subdir"));
+        create_dir(&cursor)?;
+        cursor.push("file1.txt");
+        let mut file = File::create(&cursor)?;
+        file.write_all(b"abc")?;
+        cursor.pop();
+        cursor.pop();
+        cursor.push("file2.txt");
+        file = File::create(&cursor)?;
+        file.write_all(b"def")?;
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings())?;
+
+        assert_eq!(dir.files[0].hash_value, unhex("cb8379ac2098aa165029e3938a51da0bcecfc008fd6795f401178647f96c5b34"));
+        assert_eq!(dir.subdirs[0].files[0].hash_value, unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+
+        assert_eq!(dir.hash_value, unhex("b178872f99aa86b175afb23e34943eb04a40f3ae6940e14b89f2608813135abb"));
+
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_transfers_are_left_out() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("a.txt"))?;
+        File::create(root.path().join("b.txt.usync-partial"))?;
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings())?;
+        let names: Vec<&str> = dir.files.iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["a.txt"]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reject_non_utf8_names() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = TempDir::new()?;
+        File::create(root.path().join(OsStr::from_bytes(b"caf\xe9.txt")))?;
+
+        let error = DirectoryEntry::new(root.path(), &test_support::default_settings()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let settings = test_support::default_settings().with_filter_rule(crate::config::FilterRule::new(false, "*.txt").unwrap());
+        assert!(DirectoryEntry::new(root.path(), &settings)?.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_files_outside_size_range() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("lock"))?;
+        File::create(root.path().join("data.txt"))?.write_all(b"0123456789")?;
+        File::create(root.path().join("huge.log"))?.write_all(&[0u8; 2048])?;
+
+        let settings = test_support::default_settings().with_size_range(Some(1), Some(1024));
+        let dir = DirectoryEntry::new(root.path(), &settings)?;
+
+        let names: Vec<&str> = dir.files.iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["data.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_or_file_root() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?;
+        let settings = test_support::default_settings();
+
+        let error = Manifest::create_ephemeral(root.path().join("missing"), &settings).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert!(error.to_string().ends_with("missing does not exist"), "{}", error);
+        let error = Manifest::create_persistent(root.path().join("file.txt"), &settings, &ManifestLocation::Cache).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().ends_with("file.txt is not a directory"), "{}", error);
+        assert!(check_root(root.path(), "Target path").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_files_outside_age_window() -> Result<()> {
+        let root = TempDir::new()?;
+        for (name, mtime) in &[("old.txt", 1_000), ("recent.txt", 5_000), ("future.txt", 9_000_000_000)] {
+            File::create(root.path().join(name))?;
+            set_file_mtime(root.path().join(name), FileTime::from_unix_time(*mtime, 0))?;
+        }
+
+        let newer = test_support::default_settings().with_age_range(Some(Timestamp::from_unix_time(2_000, 0)), None);
+        let names: Vec<String> = Manifest::create_ephemeral(root.path(), &newer)?.files().iter()
+            .map(|file| file.path.to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["future.txt", "recent.txt"]);
+
+        let older = test_support::default_settings().with_age_range(None, Some(Timestamp::from(std::time::SystemTime::now())));
+        let names: Vec<String> = Manifest::create_ephemeral(root.path(), &older)?.files().iter()
+            .map(|file| file.path.to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["old.txt", "recent.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn honor_ignore_files() -> Result<()> {
+        let root = TempDir::new()?;
+        create_dir(root.path().join("sub"))?;
+        std::fs::write(root.path().join("sub").join(IGNORE_FILE), "*.tmp\n")?;
+        File::create(root.path().join("sub/keep.txt"))?;
+        File::create(root.path().join("sub/drop.tmp"))?;
+        File::create(root.path().join("top.tmp"))?;
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings().with_ignore_files(true))?;
+        let names: Vec<&str> = dir.subdirs[0].files.iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["keep.txt"]);
+        assert_eq!(dir.files[0].name(), "top.tmp");
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings())?;
+        assert_eq!(dir.subdirs[0].files.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn list_files_in_order() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("b/c"))?;
+        std::fs::write(root.path().join("b/c/deep.txt"), b"abc")?;
+        std::fs::write(root.path().join("b/mid.txt"), b"")?;
+        std::fs::write(root.path().join("top.txt"), b"")?;
+
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+        let files = manifest.files();
+        let paths: Vec<&Path> = files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("top.txt"), Path::new("b/mid.txt"), Path::new("b/c/deep.txt")]);
+        assert_eq!(files[2].size, 3);
+        assert_eq!(files[2].hash, Some(&unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")));
+
+        let settings = test_support::default_settings().with_manifest_mode(ManifestMode::TimestampTest);
+        let manifest = Manifest::create_ephemeral(root.path(), &settings)?;
+        assert!(manifest.files().iter().all(|f| f.hash.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn root_without_file_name() -> Result<()> {
+        let root = TempDir::new()?;
+        create_dir(root.path().join("sub"))?;
+        File::create(root.path().join("file.txt"))?;
+
+        let dir = DirectoryEntry::new(root.path().join("sub/..").as_os_str(), &test_support::default_settings())?;
+        assert_eq!(dir.name, "");
+        assert_eq!(dir.files[0].name, "file.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn stop_at_max_depth() -> Result<()> {
+        let root = TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("a/b/c"))?;
+        File::create(root.path().join("a/b/c/deep.txt"))?;
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings().with_max_depth(Some(2)))?;
+        assert_eq!(dir.subdirs[0].subdirs[0].name(), "b");
+        assert!(dir.subdirs[0].subdirs[0].subdirs.is_empty());
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings().with_max_depth(Some(0)))?;
+        assert!(dir.subdirs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refuse_visited_directory() -> Result<()> {
+        let root = TempDir::new()?;
+        let meta = root.path().metadata()?;
+        let mut guard = CycleGuard::new();
+
+        assert!(guard.enter(root.path(), &meta, None));
+        assert!(!guard.enter(root.path(), &meta, None));
+        guard.leave();
+        assert_eq!(guard.depth, 0);
+
+        // once left, a directory may be walked again, say through another symlink
+        assert!(guard.enter(root.path(), &meta, None));
+        guard.leave();
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stay_on_device() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = TempDir::new()?;
+        let meta = root.path().metadata()?;
+        let mut guard = CycleGuard::new();
+        guard.stay_on_device_of(&meta);
+        assert!(guard.enter(root.path(), &meta, None));
+        guard.leave();
+
+        let mut guard = CycleGuard::new();
+        guard.device = Some(meta.dev() + 1);
+        assert!(!guard.enter(root.path(), &meta, None));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn preserve_hard_links() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        use crate::config::PrivilegeMode;
+        use crate::file_transfer::local::LocalTransmitter;
+
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        create_dir(source.path().join("sub"))?;
+        File::create(source.path().join("sub/a.txt"))?.write_all(b"linked")?;
+        std::fs::hard_link(source.path().join("sub/a.txt"), source.path().join("b.txt"))?;
+        std::fs::hard_link(source.path().join("sub/a.txt"), source.path().join("sub/c.txt"))?;
+
+        let settings = test_support::default_settings().with_hard_links(true);
+        let source_manifest = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target_manifest = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        let stats = target_manifest.copy_from(&source_manifest, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+
+        assert_eq!(stats.files_created, 3);
+        assert_eq!(stats.files_linked, 2);
+        assert_eq!(stats.bytes_transferred, 6);
+        let inode = target.path().join("sub/a.txt").metadata()?.ino();
+        assert_eq!(target.path().join("b.txt").metadata()?.ino(), inode);
+        assert_eq!(target.path().join("sub/c.txt").metadata()?.ino(), inode);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn permission_change_updates_target_without_transfer() -> Result<()> {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+        use crate::config::PrivilegeMode;
+        use crate::file_transfer::local::LocalTransmitter;
+
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+        for root in &[&source, &target] {
+            create_dir(root.path().join("sub"))?;
+            File::create(root.path().join("sub/a.txt"))?.write_all(b"same")?;
+            set_file_mtime(root.path().join("sub/a.txt"), mtime)?;
+            set_file_mtime(root.path().join("sub"), mtime)?;
+            set_permissions(root.path().join("sub/a.txt"), Permissions::from_mode(0o644))?;
+        }
+        let settings = test_support::default_settings().with_perms(true);
+        let unchanged = Manifest::create_ephemeral(source.path(), &settings)?;
+        set_permissions(source.path().join("sub/a.txt"), Permissions::from_mode(0o750))?;
+        let source_manifest = Manifest::create_ephemeral(source.path(), &settings)?;
+        assert_ne!(unchanged.0.hash_value, source_manifest.0.hash_value);
+        // without --perms, nothing tells the files apart
+        let plain = test_support::default_settings();
+        assert_eq!(Manifest::create_ephemeral(source.path(), &plain)?.0.hash_value,
+                   Manifest::create_ephemeral(target.path(), &plain)?.0.hash_value);
+
+        let target_manifest = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = ItemizingTransmitter::default();
+        let stats = target_manifest.copy_from(&source_manifest, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+        assert_eq!(stats.files_transferred(), 0);
+        assert_eq!(stats.files_updated, 1);
+        assert_eq!(transmitter.0, vec![".f...p..... sub/a.txt"]);
+
+        let mut transmitter = LocalTransmitter::new(source.path(), target.path(), PrivilegeMode::Auto);
+        target_manifest.copy_from(&source_manifest, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+        assert_eq!(target.path().join("sub/a.txt").metadata()?.permissions().mode() & 0o7777, 0o750);
+        let synced = Manifest::create_ephemeral(target.path(), &settings)?;
+        assert_eq!(synced.0.hash_value, source_manifest.0.hash_value);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn copy_links_follows_symlinks() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let root = TempDir::new()?;
+        create_dir(root.path().join("dir"))?;
+        File::create(root.path().join("dir/a.txt"))?.write_all(b"abc")?;
+        symlink("dir/a.txt", root.path().join("file_link"))?;
+        symlink("dir", root.path().join("dir_link"))?;
+        symlink("..", root.path().join("dir/loop"))?;
+        symlink("missing", root.path().join("dangling"))?;
+
+        let paths = |settings: &HashSettings| -> Result<Vec<PathBuf>> {
+            let manifest = Manifest::create_ephemeral(root.path(), settings)?;
+            Ok(manifest.files().iter().map(|file| file.path.clone()).collect())
+        };
+        let settings = test_support::default_settings();
+        assert_eq!(paths(&settings)?, vec![PathBuf::from("dir/a.txt")]);
+        assert_eq!(paths(&settings.with_copy_links(true))?,
+                   vec![PathBuf::from("file_link"), PathBuf::from("dir/a.txt"), PathBuf::from("dir_link/a.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skip_special_files() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("regular.txt"))?;
+        let _socket = std::os::unix::net::UnixListener::bind(root.path().join("socket"))?;
+
+        let dir = DirectoryEntry::new(root.path(), &test_support::default_settings())?;
+        assert_eq!(dir.files.len(), 1);
+        assert_eq!(dir.files[0].name, "regular.txt");
+
+        let strict = test_support::default_settings().with_fail_on_special_files(true);
+        assert_eq!(DirectoryEntry::new(root.path(), &strict).unwrap_err().kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinked_root_is_followed() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new()?;
+        let real = dir.path().join("real");
+        create_dir_all(real.join("sub"))?;
+        File::create(real.join("sub/a.txt"))?.write_all(b"abc")?;
+        let link = dir.path().join("link");
+        symlink("real", &link)?;
+
+        let settings = test_support::default_settings();
+        check_root(&link, "Target path")?;
+        let through_link = Manifest::create_ephemeral(&link, &settings)?;
+        assert_eq!(through_link.0.hash_value, Manifest::create_ephemeral(&real, &settings)?.0.hash_value);
+        assert_eq!(through_link.files().len(), 1);
+
+        // a manifest stored through the link is found valid through it again (once the root's
+        // mtime no longer changes by the manifest file being created)
+        let location = ManifestLocation::File(PathBuf::from(".usync.manifest"));
+        Manifest::create_persistent(&link, &settings, &location)?;
+        Manifest::create_persistent(&link, &settings, &location)?;
+        assert!(real.join(".usync.manifest").is_file());
+        let stored = real.join(".usync.manifest").metadata()?.modified()?;
+        Manifest::create_persistent(&link, &settings, &location)?;
+        assert_eq!(real.join(".usync.manifest").metadata()?.modified()?, stored);
+        assert!(symlink_metadata(&link)?.file_type().is_symlink());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_finds_mismatches() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        for root in &[&source, &target] {
+            create_dir(root.path().join("sub"))?;
+            File::create(root.path().join("same.txt"))?.write_all(b"same")?;
+        }
+        File::create(source.path().join("sub/changed.txt"))?.write_all(b"abc")?;
+        File::create(target.path().join("sub/changed.txt"))?.write_all(b"abd")?;
+        File::create(source.path().join("missing.txt"))?.write_all(b"gone")?;
+        File::create(target.path().join("extra.txt"))?.write_all(b"extra")?;
+
+        let timestamps = test_support::default_settings().with_manifest_mode(ManifestMode::TimestampTest);
+        let source = Manifest::create_ephemeral(source.path(), &timestamps)?.with_hashes(source.path(), None)?;
+        let target = Manifest::create_ephemeral(target.path(), &test_support::default_settings())?;
+
+        assert_eq!(target.mismatches(&source), vec![PathBuf::from("missing.txt"), PathBuf::from("sub/changed.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn verification_report_lists_every_corrupted_file() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        for root in &[&source, &target] {
+            create_dir(root.path().join("sub"))?;
+            for name in &["intact.txt", "flipped.txt", "sub/truncated.txt", "sub/deleted.txt"] {
+                File::create(root.path().join(name))?.write_all(b"content")?;
+            }
+        }
+        File::create(target.path().join("flipped.txt"))?.write_all(b"c0ntent")?;
+        File::create(target.path().join("sub/truncated.txt"))?.write_all(b"cont")?;
+        std::fs::remove_file(target.path().join("sub/deleted.txt"))?;
+
+        let source = Manifest::create_ephemeral(source.path(), &test_support::default_settings())?;
+        let target = Manifest::create_ephemeral(target.path(), &test_support::default_settings())?;
+        let report = FailedTransfer::report("verification", &target.verification_failures(&source));
+
+        assert!(report.starts_with("3 files failed verification:\n"), "{}", report);
+        assert!(report.contains("\n  flipped.txt: content hash "), "{}", report);
+        assert!(report.contains("\n  sub/truncated.txt: 4 bytes on the target, 7 bytes on the source"), "{}", report);
+        assert!(report.contains("\n  sub/deleted.txt: missing on the target"), "{}", report);
+        assert!(!report.contains("intact.txt"), "{}", report);
+        Ok(())
+    }
+
+    #[test]
+    fn verification_failures_are_counted_apart() {
+        let mut stats = CopyStats::default();
+        stats.record_failure(Path::new("a"), &UsyncError::Verification(String::from("Hash mismatch for a")).into());
+        stats.record_failure(Path::new("b"), &Error::new(ErrorKind::PermissionDenied, "denied"));
+        stats.record_failure(Path::new("c"), &UsyncError::Verification(String::from("Hash mismatch for c")).into());
+
+        assert_eq!(stats.failed.len(), 1);
+        assert_eq!(stats.verification_failed.iter().map(|failure| failure.path.as_path()).collect::<Vec<_>>(), vec![Path::new("a"), Path::new("c")]);
+        assert!(stats.summary(false).ends_with(", 1 files failed, 2 files failed verification"));
+    }
+
+    #[test]
+    fn count_pending_transfers() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        create_dir(source.path().join("subdir"))?;
+        File::create(source.path().join("subdir/new.txt"))?.write_all(b"12345")?;
+        File::create(source.path().join("same.txt"))?.write_all(b"abc")?;
+        File::create(target.path().join("same.txt"))?.write_all(b"abc")?;
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        set_file_mtime(source.path().join("same.txt"), mtime)?;
+        set_file_mtime(target.path().join("same.txt"), mtime)?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+
+        assert_eq!(target.transfer_totals(&source, Comparison::Timestamps(Duration::from_secs(0))), TransferTotals { files: 1, bytes: 5 });
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_hashing_is_deterministic() -> Result<()> {
+        let root = TempDir::new()?;
+        for i in 0..32 {
+            File::create(root.path().join(format!("file{:02}.txt", i)))?.write_all(format!("content {}", i).as_bytes())?;
+            if i % 8 == 0 {
+                create_dir(root.path().join(format!("file{:02}.dir", i)))?;
+            }
+        }
+
+        let sequential = DirectoryEntry::new(root.path(), &test_support::default_settings())?;
+        let parallel = DirectoryEntry::new(root.path(), &test_support::default_settings().with_hash_threads(4))?;
+
+        assert_eq!(sequential.hash_value, parallel.hash_value);
+        let names: Vec<&str> = parallel.files.iter().map(|f| f.name.as_str()).collect();
+        let expected: Vec<String> = (0..32).map(|i| format!("file{:02}.txt", i)).collect();
+        assert_eq!(names, expected);
+        Ok(())
+    }
+
+    struct NullTransmitter;
+
+    impl Transmitter for NullTransmitter {
+        fn transmit(&mut self, _path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            Ok(7)
+        }
+
+        fn create_directory(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn files_transferred_by_an_earlier_attempt_are_not_counted() -> Result<()> {
+        use crate::file_transfer::retry::ResumingTransmitter;
+
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        File::create(source.path().join("a.txt"))?.write_all(b"a")?;
+        File::create(source.path().join("b.txt"))?.write_all(b"b")?;
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+
+        let mut completed = HashSet::new();
+        completed.insert(PathBuf::from("a.txt"));
+        let mut transmitter = ResumingTransmitter::new(NullTransmitter, &mut completed);
+        let stats = target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+
+        assert_eq!((stats.files_created, stats.bytes_transferred), (1, 7));
+        assert!(completed.contains(Path::new("b.txt")));
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingTransmitter(Vec<PathBuf>);
+
+    impl Transmitter for RecordingTransmitter {
+        fn transmit(&mut self, _path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            Ok(0)
+        }
+
+        fn create_directory(&mut self, path: &Path) -> Result<()> {
+            self.0.push(path.to_owned());
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Records the lines `--itemize-changes -ii` would list.
+    #[derive(Default)]
+    struct ItemizingTransmitter(Vec<String>);
+
+    impl Transmitter for ItemizingTransmitter {
+        fn transmit(&mut self, _path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            Ok(0)
+        }
+
+        fn create_directory(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn itemize(&mut self, path: &Path, change: Change) {
+            self.0.push(format!("{} {}", change, path.to_string_lossy()));
+        }
+    }
+
+    #[test]
+    fn copy_itemizes_changes() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        for root in &[&source, &target] {
+            create_dir(root.path().join("same"))?;
+            File::create(root.path().join("same/a.txt"))?.write_all(b"a")?;
+            set_file_mtime(root.path().join("same/a.txt"), mtime)?;
+            set_file_mtime(root.path().join("same"), mtime)?;
+            File::create(root.path().join("touched.txt"))?.write_all(b"t")?;
+        }
+        File::create(source.path().join("changed.txt"))?.write_all(b"new")?;
+        File::create(target.path().join("changed.txt"))?.write_all(b"old")?;
+        set_file_mtime(target.path().join("changed.txt"), mtime)?;
+        set_file_mtime(target.path().join("touched.txt"), mtime)?;
+        create_dir(source.path().join("new"))?;
+        File::create(source.path().join("new/b.txt"))?.write_all(b"b")?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = ItemizingTransmitter::default();
+        target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+
+        let mut lines = transmitter.0;
+        lines.sort();
+        assert_eq!(lines, vec![
+            ".f          same/a.txt",
+            ">f+++++++++ new/b.txt",
+            ">f..t...... touched.txt",
+            ">fc.t...... changed.txt",
+            "cd+++++++++ new",
+        ]);
+        assert_eq!(Change::Linked(Some(Differences { content: false, size: true, time: false, perms: false })).to_string(), "hf.s.......");
+        assert_eq!(Change::Updated(Differences { content: false, size: false, time: false, perms: true }).to_string(), ".f...p.....");
+        Ok(())
+    }
+
+    #[test]
+    fn copy_creates_empty_directories() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        create_dir(source.path().join("empty"))?;
+        create_dir(source.path().join("outer"))?;
+        create_dir(source.path().join("outer/inner"))?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = RecordingTransmitter::default();
+        target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+
+        assert_eq!(transmitter.0, vec![PathBuf::from("empty"), PathBuf::from("outer"), PathBuf::from("outer/inner")]);
+        Ok(())
+    }
+
+    #[test]
+    fn pruned_copy_skips_directories_without_files() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        create_dir_all(source.path().join("empty/nested"))?;
+        create_dir_all(source.path().join("filtered/deeper"))?;
+        File::create(source.path().join("filtered/deeper/skip.log"))?.write_all(b"log")?;
+        create_dir_all(source.path().join("kept/empty"))?;
+        create_dir_all(source.path().join("kept/deeper"))?;
+        File::create(source.path().join("kept/deeper/a.txt"))?.write_all(b"a")?;
+
+        let settings = test_support::default_settings().with_exclude_pattern(glob::Pattern::new("*.log").unwrap());
+        let mut source = Manifest::create_ephemeral(source.path(), &settings)?;
+        source.prune_empty_dirs();
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = RecordingTransmitter::default();
+        target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+
+        assert_eq!(transmitter.0, vec![PathBuf::from("kept"), PathBuf::from("kept/deeper")]);
+        Ok(())
+    }
+
+    #[test]
+    fn restricted_copy_syncs_subtree_only() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        File::create(source.path().join("top.txt"))?.write_all(b"top")?;
+        create_dir_all(source.path().join("a/b/c"))?;
+        create_dir_all(source.path().join("a/other"))?;
+        File::create(source.path().join("a/a.txt"))?.write_all(b"a")?;
+        File::create(source.path().join("a/b/b.txt"))?.write_all(b"b")?;
+        File::create(source.path().join("a/b/c/c.txt"))?.write_all(b"c")?;
+
+        let settings = test_support::default_settings();
+        let mut restricted = Manifest::create_ephemeral(source.path(), &settings)?;
+        restricted.restrict_to(Path::new("./a/b"))?;
+        let files: Vec<_> = restricted.files().into_iter().map(|file| file.path).collect();
+        assert_eq!(files, vec![PathBuf::from("a/b/b.txt"), PathBuf::from("a/b/c/c.txt")]);
+
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let mut transmitter = RecordingTransmitter::default();
+        target.copy_from(&restricted, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+        assert_eq!(transmitter.0, vec![PathBuf::from("a"), PathBuf::from("a/b"), PathBuf::from("a/b/c")]);
+
+        for missing in &["a/nothing", "a/a.txt", "top.txt/b"] {
+            let mut manifest = Manifest::create_ephemeral(source.path(), &settings)?;
+            assert_eq!(manifest.restrict_to(Path::new(missing)).unwrap_err().kind(), ErrorKind::NotFound);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn target_walk_stays_in_subpath() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = TempDir::new()?;
+        create_dir_all(root.path().join("a/b"))?;
+        create_dir_all(root.path().join("other"))?;
+        File::create(root.path().join("top.txt"))?;
+        File::create(root.path().join("a/a.txt"))?;
+        File::create(root.path().join("a/b/b.txt"))?;
+        // would fail a walk of the whole tree
+        File::create(root.path().join("other").join(OsStr::from_bytes(b"caf\xe9.txt")))?;
+
+        let settings = test_support::default_settings();
+        let manifest = Manifest::create_ephemeral_below(root.path(), Some(Path::new("./a/b")), &settings)?;
+        let files: Vec<_> = manifest.files().into_iter().map(|file| file.path).collect();
+        assert_eq!(files, vec![PathBuf::from("a/b/b.txt")]);
+
+        let manifest = Manifest::create_ephemeral_below(root.path(), Some(Path::new("a/missing")), &settings)?;
+        assert!(manifest.files().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn limited_copy_stops_early() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        create_dir_all(source.path().join("x"))?;
+        create_dir_all(source.path().join("y"))?;
+        File::create(source.path().join("x/1.txt"))?.write_all(b"1")?;
+        File::create(source.path().join("y/2.txt"))?.write_all(b"22")?;
+        File::create(source.path().join("3.txt"))?.write_all(b"333")?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let unlimited = CopyOptions::new(Comparison::Timestamps(Duration::from_secs(0)));
+
+        let options = CopyOptions { limits: TransferLimits { files: Some(1), bytes: None }, ..unlimited };
+        let mut transmitter = RecordingTransmitter::default();
+        let stats = target.copy_with(&source, options, &mut transmitter)?;
+        assert!(stats.limit_reached);
+        assert_eq!((stats.files_created, stats.files_remaining, stats.bytes_remaining), (1, 2, 5));
+        assert_eq!(transmitter.0, vec![PathBuf::from("x")]);
+        assert!(stats.summary(false).contains("2 files (5 bytes) remaining"), "{}", stats);
+
+        // the file reaching the limit is transferred completely, each of them taking 7 bytes
+        let options = CopyOptions { limits: TransferLimits { files: None, bytes: Some(8) }, ..unlimited };
+        let stats = target.copy_with(&source, options, &mut NullTransmitter)?;
+        assert_eq!((stats.files_created, stats.files_remaining), (2, 1));
+
+        let options = CopyOptions { limits: TransferLimits { files: Some(3), bytes: None }, ..unlimited };
+        let stats = target.copy_with(&source, options, &mut RecordingTransmitter::default())?;
+        assert!(!stats.limit_reached);
+        assert_eq!(stats.files_created, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_placeholder_is_stable() {
+        assert_eq!(DirectoryEntry::empty("a").modification_time, DirectoryEntry::empty("a").modification_time);
+    }
+
+    #[test]
+    fn copy_collects_stats() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        for root in &[&source, &target] {
+            create_dir(root.path().join("same"))?;
+            File::create(root.path().join("same/a.txt"))?.write_all(b"a")?;
+            set_file_mtime(root.path().join("same/a.txt"), mtime)?;
+            set_file_mtime(root.path().join("same"), mtime)?;
+            File::create(root.path().join("changed.txt"))?.write_all(root.path().to_string_lossy().as_bytes())?;
+        }
+        create_dir(source.path().join("new"))?;
+        File::create(source.path().join("new/b.txt"))?.write_all(b"b")?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let stats = target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut NullTransmitter)?;
+
+        assert_eq!(stats, CopyStats {
+            files_created: 1,
+            files_overwritten: 1,
+            files_skipped: 1,
+            bytes_transferred: 14,
+            directories_created: 1,
+            files_linked: 0,
+            files_updated: 0,
+            files_left_out: 0,
+            limit_reached: false,
+            files_remaining: 0,
+            bytes_remaining: 0,
+            failed: Vec::new(),
+            verification_failed: Vec::new(),
+            elapsed: stats.elapsed,
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn selected_copy_leaves_out_files() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        for root in &[&source, &target] {
+            File::create(root.path().join("same.txt"))?.write_all(b"a")?;
+            set_file_mtime(root.path().join("same.txt"), mtime)?;
+            File::create(root.path().join("changed.txt"))?.write_all(root.path().to_string_lossy().as_bytes())?;
+        }
+        create_dir(source.path().join("new"))?;
+        File::create(source.path().join("new/b.txt"))?.write_all(b"b")?;
+        File::create(source.path().join("new.txt"))?.write_all(b"new")?;
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        let comparison = Comparison::Timestamps(Duration::from_secs(0));
+
+        let mut transmitter = RecordingTransmitter::default();
+        let options = CopyOptions { selection: Selection::Missing, ..CopyOptions::new(comparison) };
+        let stats = target.copy_with(&source, options, &mut transmitter)?;
+        assert_eq!((stats.files_created, stats.files_overwritten, stats.files_skipped, stats.files_left_out), (2, 0, 1, 1));
+        assert_eq!(transmitter.0, vec![PathBuf::from("new")]);
+        assert_eq!(target.selected_transfer_totals(&source, comparison, Selection::Missing), TransferTotals { files: 2, bytes: 4 });
+
+        let mut transmitter = RecordingTransmitter::default();
+        let options = CopyOptions { selection: Selection::Existing, ..CopyOptions::new(comparison) };
+        let stats = target.copy_with(&source, options, &mut transmitter)?;
+        assert_eq!((stats.files_created, stats.files_overwritten, stats.files_skipped, stats.files_left_out), (0, 1, 1, 2));
+        assert!(transmitter.0.is_empty());
+        assert_eq!(target.selected_transfer_totals(&source, comparison, Selection::Existing).files, 1);
+        Ok(())
+    }
+
+    /// Fails the files named `unreadable.txt` as a sender would for a file it can't read, and
+    /// those named `lost.txt` as if the connection dropped.
+    struct FailingTransmitter {
+        batch_size: usize,
+    }
+
+    impl Transmitter for FailingTransmitter {
+        fn transmit(&mut self, path: &Path, _expected: Option<&ShaSum>) -> Result<u64> {
+            match path.file_name().and_then(OsStr::to_str) {
+                Some("unreadable.txt") => Err(Error::new(ErrorKind::PermissionDenied, "permission denied")),
+                Some("lost.txt") => Err(Error::from(ErrorKind::ConnectionReset)),
+                _ => Ok(1),
+            }
+        }
+
+        fn create_directory(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn hard_link(&mut self, _existing: &Path, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_permissions(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn batch_size(&self) -> usize {
+            self.batch_size
+        }
+    }
+
+    #[test]
+    fn keep_going_records_failed_files() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        for name in &["a.txt", "unreadable.txt", "z.txt"] {
+            File::create(source.path().join(name))?.write_all(b"x")?;
+        }
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        for batch_size in 1..=3 {
+            let mut transmitter = FailingTransmitter { batch_size };
+            let error = target.copy_from(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+            let stats = target.copy_from_keep_going(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut transmitter)?;
+            assert_eq!(stats.files_created, 2, "batch size {}", batch_size);
+            assert_eq!(stats.failed, vec![FailedTransfer {
+                path: PathBuf::from("unreadable.txt"),
+                message: String::from("permission denied"),
+            }]);
+            assert!(stats.to_string().ends_with(", 1 files failed"), "{}", stats);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_going_stops_on_lost_connection() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        for name in &["a.txt", "lost.txt", "z.txt"] {
+            File::create(source.path().join(name))?.write_all(b"x")?;
+        }
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+        for batch_size in 1..=3 {
+            let error = target.copy_from_keep_going(&source, Comparison::Timestamps(Duration::from_secs(0)), &mut FailingTransmitter { batch_size }).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn skip_files_within_modify_window() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        File::create(source.path().join("a.txt"))?.write_all(b"abc")?;
+        File::create(target.path().join("a.txt"))?.write_all(b"abc")?;
+        set_file_mtime(source.path().join("a.txt"), FileTime::from_unix_time(1_000_001, 500_000_000))?;
+        set_file_mtime(target.path().join("a.txt"), FileTime::from_unix_time(1_000_000, 0))?;
+
+        let settings = test_support::default_settings().with_manifest_mode(ManifestMode::TimestampTest);
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+
+        assert_eq!(target.transfer_totals(&source, Comparison::Timestamps(Duration::from_secs(1))).files, 1);
+        assert_eq!(target.transfer_totals(&source, Comparison::Timestamps(Duration::from_secs(2))).files, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_times_compares_content_only() -> Result<()> {
+        let source = TempDir::new()?;
+        let target = TempDir::new()?;
+        File::create(source.path().join("same.txt"))?.write_all(b"abc")?;
+        File::create(target.path().join("same.txt"))?.write_all(b"abc")?;
+        File::create(source.path().join("changed.txt"))?.write_all(b"new")?;
+        File::create(target.path().join("changed.txt"))?.write_all(b"old")?;
+        for name in &["same.txt", "changed.txt"] {
+            set_file_mtime(source.path().join(name), FileTime::from_unix_time(2_000_000, 0))?;
+            set_file_mtime(target.path().join(name), FileTime::from_unix_time(1_000_000, 0))?;
+        }
+
+        let settings = test_support::default_settings();
+        let source = Manifest::create_ephemeral(source.path(), &settings)?;
+        let target = Manifest::create_ephemeral(target.path(), &settings)?;
+
+        assert_eq!(target.transfer_totals(&source, settings.comparison()).files, 2);
+        let content_only = settings.with_ignore_times(true).comparison();
+        assert_eq!(content_only, Comparison::ContentOnly);
+        assert_eq!(target.transfer_totals(&source, content_only), TransferTotals { files: 1, bytes: 3 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_single_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"abc")?;
+        file.seek(SeekFrom::Start(0))?;
+        file.flush()?;
+        set_file_mtime(file.path(), FileTime::from(Timestamp::UNIX_EPOCH))?;
+        let settings = test_support::default_settings();
+
+        let generated = FileEntry::new(file.path(), &file.as_file().metadata()?, &settings)?;
+
+        assert_eq!(filename_to_string(file.path().file_name()), generated.name);
+        assert_eq!(Timestamp::UNIX_EPOCH, generated.modification_time);
         assert_eq!(3, generated.file_size);
         assert_eq!(unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"), generated.hash_value);
 
         Ok(())
     }
 
+    #[test]
+    fn hash_independent_of_buffer_size() -> Result<()> {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        for algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            assert_eq!(hash_buffered(*algorithm, data.as_slice(), 7)?, hash_with(*algorithm, data.as_slice())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_blake3_vectors() -> Result<()> {
+        let value = hash_with(HashAlgorithm::Blake3, &b"abc"[..])?;
+        assert_eq!(value, unhex("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"));
+        let value = hash_with(HashAlgorithm::Blake3, empty())?;
+        assert_eq!(value, unhex("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_round_trip() -> Result<()> {
+        let root = TempDir::new()?;
+        create_dir(root.path().join("dir"))?;
+        File::create(root.path().join("dir/file.txt"))?.write_all(b"abc")?;
+        let settings = test_support::default_settings();
+        let manifest = Manifest::create_ephemeral(root.path(), &settings)?;
+
+        let mut written = Vec::new();
+        manifest.write_to(&mut written)?;
+        let read = Manifest::read_from(written.as_slice(), &settings)?;
+        assert_eq!(read.files(), manifest.files());
+        assert!(Manifest::read_from(&written[..written.len() / 2], &settings).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_manifest_is_rebuilt() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abc")?;
+        let settings = test_support::default_settings();
+        let manifest = Manifest::create_ephemeral(root.path(), &settings)?;
+
+        let unversioned = bincode::serialize(&manifest).unwrap();
+        let error = Manifest::read_from(unversioned.as_slice(), &settings).map(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Not a usync manifest"), "{}", error);
+        assert!(Manifest::read_from(&b"US"[..], &settings).is_err());
+
+        let mut newer = MANIFEST_MAGIC.to_vec();
+        newer.extend_from_slice(&(MANIFEST_VERSION + 1).to_le_bytes());
+        newer.extend_from_slice(&unversioned);
+        let error = Manifest::read_from(newer.as_slice(), &settings).map(|_| ()).unwrap_err();
+        assert!(error.to_string().contains("format version"), "{}", error);
+
+        let manifest_path = root.path().join(".manifest");
+        std::fs::write(&manifest_path, &unversioned)?;
+        let rebuilt = Manifest::create_persistent(root.path(), &settings, &ManifestLocation::File(manifest_path.clone()))?;
+        assert_eq!(rebuilt.files(), manifest.files());
+        assert!(std::fs::read(&manifest_path)?.starts_with(&MANIFEST_MAGIC));
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_seed_keys_content_hashes() -> Result<()> {
+        let key = ChecksumKey::from_seed("seed");
+        let other = ChecksumKey::from_seed("other seed");
+        for algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let keyed = hash_keyed(*algorithm, Some(&key), &b"abc"[..], 2)?;
+            assert_eq!(keyed, hash_keyed(*algorithm, Some(&key), &b"abc"[..], DEFAULT_IO_BUFFER_SIZE)?);
+            assert_ne!(keyed, hash_with(*algorithm, &b"abc"[..])?);
+            assert_ne!(keyed, hash_keyed(*algorithm, Some(&other), &b"abc"[..], 2)?);
+        }
+        let id = key.id();
+        assert!(key.is_identified_by(&id));
+        assert!(!other.is_identified_by(&id));
+        assert_ne!(key.id(), id, "ids are salted");
+        assert_eq!(format!("{:?}", key), "ChecksumKey(..)");
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_records_checksum_seed() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abc")?;
+        let keyed = test_support::default_settings().with_checksum_key(Some(ChecksumKey::from_seed("seed")));
+        let manifest = Manifest::create_ephemeral(root.path(), &keyed)?;
+        assert!(keyed.checksum_key().unwrap().is_identified_by(manifest.checksum_key_id().unwrap()));
+        assert_ne!(manifest.files()[0].hash, Some(&hash(&b"abc"[..])?));
+
+        let mut written = Vec::new();
+        manifest.write_to(&mut written)?;
+        assert!(Manifest::read_from(written.as_slice(), &keyed).is_ok());
+        let other = keyed.with_checksum_key(Some(ChecksumKey::from_seed("other")));
+        for settings in &[test_support::default_settings(), other] {
+            let error = Manifest::read_from(written.as_slice(), settings).err().unwrap();
+            assert!(error.to_string().contains("checksum seed"), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cached_manifest_is_named_after_canonical_root() -> Result<()> {
+        let cache = TempDir::new()?;
+        let root = TempDir::new()?;
+        let other = TempDir::new()?;
+        create_dir_all(root.path().join("sub"))?;
+
+        let manifest_path = cached_manifest_file(cache.path(), root.path().as_os_str())?;
+        assert!(manifest_path.starts_with(cache.path().join("usync")));
+        assert!(!manifest_path.starts_with(root.path()));
+        assert_eq!(cached_manifest_file(cache.path(), root.path().join("sub/..").as_os_str())?, manifest_path);
+        assert_ne!(cached_manifest_file(cache.path(), other.path().as_os_str())?, manifest_path);
+        Ok(())
+    }
+
+    #[test]
+    fn validation_names_first_difference() -> Result<()> {
+        let root = TempDir::new()?;
+        create_dir(root.path().join("sub"))?;
+        let file = root.path().join("sub/a.txt");
+        File::create(&file)?.write_all(b"abc")?;
+        let settings = test_support::default_settings();
+        let manifest = Manifest::create_ephemeral(root.path(), &settings)?;
+        let validate = || manifest.0.validate(&mut root.path().to_owned(), &settings);
+        assert_eq!(validate(), Ok(()));
+
+        let mtime = FileTime::from_last_modification_time(&file.metadata()?);
+        File::create(&file)?.write_all(b"abcd")?;
+        set_file_mtime(&file, FileTime::from_unix_time(1_000_000_000, 0))?;
+        assert_eq!(validate(), Err(format!("{}: the file's modification time differs", file.to_string_lossy())));
+        set_file_mtime(&file, mtime)?;
+        assert_eq!(validate(), Err(format!("{}: the file's size differs", file.to_string_lossy())));
+
+        File::create(&file)?.write_all(b"abc")?;
+        set_file_mtime(&file, mtime)?;
+        File::create(root.path().join("sub/b.txt"))?;
+        let sub = root.path().join("sub");
+        set_file_mtime(&sub, FileTime::from_unix_time(1_000_000_000, 0))?;
+        assert_eq!(validate(), Err(format!("{}: the directory's modification time differs", sub.to_string_lossy())));
+        Ok(())
+    }
+
+    /// A tree with a file the manifest keeps, for the entries a walk skips to be added to.
+    fn tree_with_kept_file() -> Result<TempDir> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("kept.txt"))?.write_all(b"kept")?;
+        Ok(root)
+    }
+
+    /// Asserts that a manifest of `root` built with `settings` validates against it, and only
+    /// lists the files `kept`.
+    fn assert_validates(root: &TempDir, settings: &HashSettings, kept: &[&str]) -> Result<()> {
+        let manifest = Manifest::create_ephemeral(root.path(), settings)?;
+        let files = manifest.files().into_iter().map(|file| file.path).collect::<Vec<_>>();
+        assert_eq!(files, kept.iter().map(PathBuf::from).collect::<Vec<_>>());
+        assert_eq!(manifest.0.validate(&mut root.path().to_owned(), settings), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn validation_skips_ignored_files() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        File::create(root.path().join(IGNORE_FILE))?.write_all(b"*.tmp\n")?;
+        File::create(root.path().join("scratch.tmp"))?;
+        assert_validates(&root, &test_support::default_settings().with_ignore_files(true), &["kept.txt"])
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn validation_skips_symlinks() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        std::os::unix::fs::symlink("kept.txt", root.path().join("link.txt"))?;
+        assert_validates(&root, &test_support::default_settings(), &["kept.txt"])
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn validation_skips_special_files() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        let _socket = std::os::unix::net::UnixListener::bind(root.path().join("socket"))?;
+        assert_validates(&root, &test_support::default_settings(), &["kept.txt"])
+    }
+
+    #[test]
+    fn validation_skips_files_left_out_by_size() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        File::create(root.path().join("large.bin"))?.write_all(&[0; 100])?;
+        assert_validates(&root, &test_support::default_settings().with_size_range(None, Some(10)), &["kept.txt"])
+    }
+
+    #[test]
+    fn validation_skips_files_left_out_by_age() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        let old = root.path().join("old.txt");
+        File::create(&old)?;
+        set_file_mtime(&old, FileTime::from_unix_time(1_000_000_000, 0))?;
+        let settings = test_support::default_settings().with_age_range(Some(Timestamp::from_unix_time(1_500_000_000, 0)), None);
+        assert_validates(&root, &settings, &["kept.txt"])
+    }
+
+    #[test]
+    fn validation_skips_directories_below_max_depth() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        create_dir_all(root.path().join("a/b"))?;
+        File::create(root.path().join("a/b/deep.txt"))?;
+        assert_validates(&root, &test_support::default_settings().with_max_depth(Some(1)), &["kept.txt"])
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn validation_skips_directory_cycles() -> Result<()> {
+        let root = tree_with_kept_file()?;
+        create_dir(root.path().join("sub"))?;
+        std::os::unix::fs::symlink("..", root.path().join("sub/loop"))?;
+        assert_validates(&root, &test_support::default_settings().with_copy_links(true), &["kept.txt"])
+    }
+
+    #[test]
+    fn reject_manifest_with_other_algorithm() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abc")?;
+        let manifest_path = root.path().join(".manifest");
+        let sha256 = test_support::default_settings();
+        let blake3 = sha256.with_hash_algorithm(HashAlgorithm::Blake3);
+
+        let location = ManifestLocation::File(manifest_path.clone());
+        Manifest::create_persistent(root.path(), &sha256, &location)?;
+        assert!(Manifest::load(&manifest_path, &sha256).is_ok());
+        assert!(Manifest::load(&manifest_path, &blake3).is_err());
+
+        let mut written = Vec::new();
+        Manifest::create_ephemeral(root.path(), &sha256)?.write_to(&mut written)?;
+        assert!(Manifest::read_from(written.as_slice(), &blake3).is_err());
+
+        let rebuilt = Manifest::create_persistent(root.path(), &blake3, &location)?;
+        assert_eq!(rebuilt.hash_algorithm(), HashAlgorithm::Blake3);
+        assert_eq!(rebuilt.0.files[0].hash_value, unhex("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_manifest_with_other_mode() -> Result<()> {
+        let root = TempDir::new()?;
+        File::create(root.path().join("file.txt"))?.write_all(b"abc")?;
+        let manifest_path = root.path().join(".manifest");
+        let hash = test_support::default_settings();
+        let timestamps = hash.with_manifest_mode(ManifestMode::TimestampTest);
+
+        let location = ManifestLocation::File(manifest_path.clone());
+        Manifest::create_persistent(root.path(), &timestamps, &location)?;
+        assert!(Manifest::load(&manifest_path, &timestamps).is_ok());
+        assert!(Manifest::load(&manifest_path, &hash).is_err());
+
+        let rebuilt = Manifest::create_persistent(root.path(), &hash, &location)?;
+        assert_eq!(rebuilt.manifest_mode(), ManifestMode::Hash);
+        assert_eq!(rebuilt.0.files[0].hash_value, unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        Ok(())
+    }
+
+    /// Replays the chunks of `manifest`, as a pushing client would send them, with `tamper`
+    /// applied to them.
+    fn replay_chunks<F: FnMut(&mut Vec<DirectoryChunk<'static>>)>(manifest: &Manifest, mut tamper: F) -> Result<Manifest> {
+        let mut chunks = Vec::new();
+        manifest.chunks(&mut |chunk| {
+            chunks.push(DirectoryChunk {
+                name: Cow::Owned(chunk.name.to_string()),
+                files: Cow::Owned(chunk.files.to_vec()),
+                ..*chunk
+            });
+            Ok(())
+        })?;
+        tamper(&mut chunks);
+        let mut chunks = chunks.into_iter();
+        Manifest::from_chunks(manifest.hash_algorithm(), manifest.manifest_mode(), None, &mut || {
+            chunks.next().ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "no more chunks"))
+        })
+    }
+
+    #[test]
+    fn pushed_manifest_leaving_the_tree_is_refused() -> Result<()> {
+        let root = TempDir::new()?;
+        create_dir(root.path().join("d"))?;
+        File::create(root.path().join("d/f"))?;
+        let manifest = Manifest::create_ephemeral(root.path(), &test_support::default_settings())?;
+        assert_eq!(replay_chunks(&manifest, |_| {})?.files().len(), 1);
+
+        for name in &["..", "/etc", "a/../.."] {
+            let error = replay_chunks(&manifest, |chunks| chunks[1].name = Cow::Owned(name.to_string())).map(|_| ()).unwrap_err();
+            assert!(matches!(UsyncError::of(&error), Some(UsyncError::Protocol(_))), "{}", error);
+            let error = replay_chunks(&manifest, |chunks| chunks[1].files.to_mut()[0].name = name.to_string()).map(|_| ()).unwrap_err();
+            assert!(matches!(UsyncError::of(&error), Some(UsyncError::Protocol(_))), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pushed_manifest_nested_too_deep_is_refused() {
+        let mut sent = 0;
+        let error = Manifest::from_chunks(HashAlgorithm::Sha256, ManifestMode::Hash, None, &mut || {
+            sent += 1;
+            Ok(DirectoryChunk {
+                name: Cow::Owned(String::from("d")),
+                modification_time: Timestamp::UNIX_EPOCH,
+                hash_value: [0; 32],
+                files: Cow::Owned(Vec::new()),
+                subdir_count: 1,
+            })
+        }).map(|_| ()).unwrap_err();
+
+        assert!(matches!(UsyncError::of(&error), Some(UsyncError::Protocol(_))), "{}", error);
+        assert_eq!(sent, MAX_RECEIVED_DEPTH + 1);
+    }
+
     #[test]
     fn test_vectors() -> Result<()> {
         let value = hash(&b"abc"[..])?;
@@ -444,21 +3007,117 @@ mod test_tree_hashing {
 }
 
 
-fn hash<R: Read>(mut input: R) -> Result<ShaSum> {
-    let mut sha256 = Context::new(&SHA256);
+pub(crate) fn hash<R: Read>(input: R) -> Result<ShaSum> {
+    hash_with(HashAlgorithm::Sha256, input)
+}
+
+pub(crate) fn hash_with<R: Read>(algorithm: HashAlgorithm, input: R) -> Result<ShaSum> {
+    hash_buffered(algorithm, input, DEFAULT_IO_BUFFER_SIZE)
+}
+
+/// Like `hash_with`, reading through a buffer of `buffer_size` bytes.
+pub(crate) fn hash_buffered<R: Read>(algorithm: HashAlgorithm, input: R, buffer_size: usize) -> Result<ShaSum> {
+    hash_keyed(algorithm, None, input, buffer_size)
+}
+
+/// Like `hash_buffered`, keyed with `key` if given: SHA-256 becomes HMAC-SHA256, BLAKE3 uses
+/// its keyed mode.
+pub(crate) fn hash_keyed<R: Read>(algorithm: HashAlgorithm, key: Option<&ChecksumKey>, mut input: R, buffer_size: usize) -> Result<ShaSum> {
     let mut rv: ShaSum = [0u8; 32];
-    let mut buffer = [0u8; 65536];
-    let mut received = input.read(&mut buffer)?;
-    while received != 0 {
-        sha256.update(&buffer[..received]);
-        received = input.read(&mut buffer)?;
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    let mut feed = |update: &mut dyn FnMut(&[u8])| -> Result<()> {
+        let mut received = input.read(&mut buffer)?;
+        while received != 0 {
+            update(&buffer[..received]);
+            received = input.read(&mut buffer)?;
+        }
+        Ok(())
+    };
+
+    match (algorithm, key) {
+        (HashAlgorithm::Sha256, None) => {
+            let mut sha256 = Context::new(&SHA256);
+            feed(&mut |data| sha256.update(data))?;
+            sha256.finish().as_ref().read_exact(&mut rv)?;
+        }
+        (HashAlgorithm::Sha256, Some(key)) => {
+            let mut hmac = hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, &key.0));
+            feed(&mut |data| hmac.update(data))?;
+            hmac.sign().as_ref().read_exact(&mut rv)?;
+        }
+        (HashAlgorithm::Blake3, key) => {
+            let mut blake3 = match key {
+                Some(key) => blake3::Hasher::new_keyed(&key.0),
+                None => blake3::Hasher::new(),
+            };
+            feed(&mut |data| { blake3.update(data); })?;
+            rv.copy_from_slice(blake3.finalize().as_bytes());
+        }
     }
-    sha256.finish().as_ref().read_exact(&mut rv)?;
+
     Ok(rv)
 }
 
+/// Key content hashes are computed with, derived from a `--checksum-seed` shared by both sides
+/// of a sync, so that the hashes in a manifest can't be precomputed for content someone
+/// wants to probe for. It is never written anywhere, manifests only record an `id` of it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChecksumKey([u8; 32]);
+
+impl ChecksumKey {
+    pub fn from_seed(seed: &str) -> ChecksumKey {
+        let mut key = [0u8; 32];
+        blake3::derive_key("usync checksum seed", seed.as_bytes(), &mut key);
+        ChecksumKey(key)
+    }
+
+    /// Identifies the key without revealing it, with a fresh random salt. Each id differs, so
+    /// ids can't be precomputed for likely seeds, or matched against each other.
+    pub fn id(&self) -> ChecksumKeyId {
+        let salt = random_nonce();
+        ChecksumKeyId { salt, mac: self.prove(&salt) }
+    }
+
+    /// Whether `id` was made by `id` of this key.
+    pub fn is_identified_by(&self, id: &ChecksumKeyId) -> bool {
+        ring::constant_time::verify_slices_are_equal(&self.prove(&id.salt), &id.mac).is_ok()
+    }
+
+    /// Proves knowing the key to a peer that sent `nonce`, which it can check with a key of its
+    /// own. Nothing reusable is revealed as long as every challenge has a fresh nonce.
+    pub fn prove(&self, nonce: &ShaSum) -> ShaSum {
+        let mut message = b"usync checksum key proof".to_vec();
+        message.extend_from_slice(nonce);
+        hash_keyed(HashAlgorithm::Sha256, Some(self), message.as_slice(), DEFAULT_IO_BUFFER_SIZE).unwrap()
+    }
+}
+
+/// What manifests record about the `ChecksumKey` their hashes were keyed with, see
+/// `ChecksumKey::id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumKeyId {
+    salt: ShaSum,
+    mac: ShaSum,
+}
+
+/// 32 random bytes, for salts and challenges.
+pub fn random_nonce() -> ShaSum {
+    use ring::rand::SecureRandom;
+
+    let mut nonce = [0u8; 32];
+    ring::rand::SystemRandom::new().fill(&mut nonce).expect("the system's random number generator failed");
+    nonce
+}
+
+impl std::fmt::Debug for ChecksumKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChecksumKey(..)")
+    }
+}
+
+/// The name of an entry. Roots like `/` or `sub/..` have no file name, they are named "".
 fn filename_to_string(filename: Option<&OsStr>) -> String {
-    String::from(filename.unwrap().to_string_lossy())
+    filename.map(|name| String::from(name.to_string_lossy())).unwrap_or_default()
 }
 
 fn manifest_file(root: &OsStr, cfg_path: &Path) -> PathBuf {
@@ -472,4 +3131,14 @@ fn manifest_file(root: &OsStr, cfg_path: &Path) -> PathBuf {
     }
 
     manifest_path
+}
+
+/// The manifest of the tree at `root` in the cache directory `cache`. Its name is derived
+/// from the canonicalized root, so that any path leading to the tree finds the same manifest.
+fn cached_manifest_file(cache: &Path, root: &OsStr) -> Result<PathBuf> {
+    let root = Path::new(root).canonicalize()?;
+    let id = hash(root.to_string_lossy().as_bytes())?;
+    let dir = cache.join("usync");
+    create_dir_all(&dir)?;
+    Ok(dir.join(format!("{}.manifest", hex::encode(id))))
 }
\ No newline at end of file
@@ -1,9 +1,106 @@
-use std::sync::mpsc::{Sender, Receiver};
-use std::io::{Read, Error, Write, ErrorKind};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::io::{BufRead, BufReader, Read, Error, Write, ErrorKind};
 use std::cmp::min;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::path::{Component, Path, PathBuf};
 
+use filetime::FileTime;
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+/// Turns `e` into an I/O error, keeping its kind where one can be told: an I/O error is
+/// returned as it is, a bincode error takes the kind of the I/O error it stems from (so that a
+/// message cut short is `UnexpectedEof`), or `InvalidData` if the message was malformed.
+/// Anything else becomes `Other`.
 pub fn convert_error<E>(e: E) -> Error where E: Into<Box<dyn std::error::Error+Send+Sync>> {
-    Error::new(ErrorKind::Other, e)
+    let e = match e.into().downcast::<Error>() {
+        Ok(io) => return *io,
+        Err(e) => e,
+    };
+    let kind = if let Some(bincode) = e.downcast_ref::<bincode::Error>() {
+        bincode_error_kind(bincode)
+    } else if let Some(bincode) = e.downcast_ref::<bincode::ErrorKind>() {
+        bincode_error_kind(bincode)
+    } else {
+        ErrorKind::Other
+    };
+    Error::new(kind, e)
+}
+
+fn bincode_error_kind(e: &bincode::ErrorKind) -> ErrorKind {
+    match e {
+        bincode::ErrorKind::Io(io) => io.kind(),
+        bincode::ErrorKind::InvalidUtf8Encoding(_) |
+        bincode::ErrorKind::InvalidBoolEncoding(_) |
+        bincode::ErrorKind::InvalidCharEncoding |
+        bincode::ErrorKind::InvalidTagEncoding(_) |
+        bincode::ErrorKind::SizeLimit |
+        bincode::ErrorKind::Custom(_) => ErrorKind::InvalidData,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// The user's cache directory: `$XDG_CACHE_HOME`, or else `~/.cache` (`%LOCALAPPDATA%` on
+/// Windows).
+pub fn cache_dir() -> Result<PathBuf, Error> {
+    let from_env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    #[cfg(windows)]
+    let dir = from_env("LOCALAPPDATA");
+    #[cfg(not(windows))]
+    let dir = from_env("XDG_CACHE_HOME")
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| from_env("HOME").map(|home| home.join(".cache")));
+    dir.ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not determine the cache directory, neither XDG_CACHE_HOME nor HOME is set"))
+}
+
+/// Applies `timeout` to reads and writes on `stream`. Without a timeout, a stalled peer blocks
+/// the connection forever.
+pub fn set_io_timeout(stream: &TcpStream, timeout: Option<Duration>) -> Result<(), Error> {
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+/// Connects to `address`, trying each of the addresses it resolves to in turn (on dual-stack
+/// hosts typically both an IPv6 and an IPv4 one) until one accepts the connection. With a
+/// `timeout`, each attempt gives up after that long.
+pub fn connect(address: &str, timeout: Option<Duration>) -> Result<TcpStream, Error> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} did not resolve to any address", address)));
+    }
+    connect_any(&addresses, timeout)
+}
+
+fn connect_any(addresses: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream, Error> {
+    let mut last_error = None;
+    for address in addresses {
+        let attempt = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(address, timeout),
+            None => TcpStream::connect(address),
+        };
+        match attempt {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("Could not connect to {}: {}", address, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| Error::from(ErrorKind::NotFound)))
+}
+
+/// Replaces the error a socket reports once its timeout (see `set_io_timeout`) expires with a
+/// `TimedOut` error saying so. Other errors are returned as they are.
+pub fn explain_timeout(e: Error, timeout: Option<Duration>) -> Error {
+    match timeout {
+        Some(timeout) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Error::new(
+            ErrorKind::TimedOut, format!("Peer did not respond within {} seconds", timeout.as_secs())),
+        _ => e,
+    }
 }
 
 pub struct ReceiveAdapter {
@@ -44,14 +141,26 @@ impl Read for ReceiveAdapter {
     }
 }
 
-pub struct SendAdapter(Sender<Vec<u8>>);
+/// Writing end of an in-process pipe. Writes block while the channel is full, so a fast
+/// writer can't queue up unbounded memory ahead of a slow reader.
+pub struct SendAdapter(SyncSender<Vec<u8>>);
 
 impl SendAdapter {
-    pub fn new(sender: Sender<Vec<u8>>) -> SendAdapter {
+    pub fn new(sender: SyncSender<Vec<u8>>) -> SendAdapter {
         SendAdapter(sender)
     }
 }
 
+/// Default number of writes an in-process pipe holds before the writer has to wait
+/// (`--pipe-capacity`).
+pub const DEFAULT_PIPE_CAPACITY: usize = 16;
+
+/// Creates an in-process pipe holding up to `capacity` writes that were not read yet.
+pub fn pipe(capacity: usize) -> (SendAdapter, ReceiveAdapter) {
+    let (sender, receiver) = sync_channel(capacity.max(1));
+    (SendAdapter::new(sender), ReceiveAdapter::new(receiver))
+}
+
 impl Write for SendAdapter {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         const MAX_TRANSFERABLE_UNIT: usize = 16 << 20;
@@ -66,19 +175,40 @@ impl Write for SendAdapter {
     }
 }
 
+#[cfg(test)]
+mod test_convert_error {
+    use super::*;
+
+    #[test]
+    fn keeps_io_error_kind() {
+        let error = convert_error(Error::new(ErrorKind::PermissionDenied, "no access"));
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "no access");
+        assert_eq!(convert_error("plain message").kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn maps_bincode_errors() {
+        let truncated = bincode::deserialize::<(u64, u64)>(&[0u8; 12]).unwrap_err();
+        assert_eq!(convert_error(truncated).kind(), ErrorKind::UnexpectedEof);
+        let malformed = bincode::deserialize::<bool>(&[7u8]).unwrap_err();
+        assert_eq!(convert_error(malformed).kind(), ErrorKind::InvalidData);
+        let malformed = bincode::deserialize::<String>(&[1u8, 0, 0, 0, 0, 0, 0, 0, 0xff]).unwrap_err();
+        assert_eq!(convert_error(malformed).kind(), ErrorKind::InvalidData);
+    }
+}
+
 #[cfg(test)]
 mod test_adapt {
     use super::*;
     use std::io::Error;
     use std::thread::JoinHandle;
     use std::io::Read;
-    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     fn spawn_sut() -> (SendAdapter, JoinHandle<usize>){
-        let (s, r) = channel();
-        let sender = SendAdapter::new(s);
+        let (sender, mut r) = pipe(DEFAULT_PIPE_CAPACITY);
         let handle = std::thread::spawn(move || {
-            let mut r = ReceiveAdapter::new(r);
             let mut consumed = 0usize;
             let mut got = 1usize;
             let mut buffer = [0u8;1024];
@@ -98,6 +228,32 @@ mod test_adapt {
         (sender, handle)
     }
 
+    #[test]
+    fn full_pipe_blocks_writer() -> Result<(), Error> {
+        let (mut send, mut receive) = pipe(1);
+        send.write_all(b"first")?;
+
+        let written = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let written = written.clone();
+            std::thread::spawn(move || {
+                send.write_all(b"second").unwrap();
+                written.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!written.load(Ordering::SeqCst));
+
+        let mut buffer = [0u8; 5];
+        receive.read_exact(&mut buffer)?;
+        writer.join().unwrap();
+        assert!(written.load(Ordering::SeqCst));
+        let mut rest = Vec::new();
+        receive.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"second");
+        Ok(())
+    }
+
     #[test]
     fn transfer_nothing() -> Result<(), Error> {
         let receive = {
@@ -142,18 +298,363 @@ mod test_adapt {
     }
 }
 
-pub trait Named {
-    fn name(&self) -> &str;
+/// A modification time as manifests record it and received files are given it: seconds
+/// relative to the Unix epoch and nanoseconds. Manifests and the file attributes sent along
+/// with a transfer both use it, so a copy's mtime reads back exactly as the source's was
+/// recorded, and times before the epoch survive as well.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+impl Timestamp {
+    pub const UNIX_EPOCH: Timestamp = Timestamp { secs: 0, nanos: 0 };
+
+    pub fn from_unix_time(secs: i64, nanos: u32) -> Timestamp {
+        let file_time = FileTime::from_unix_time(secs, nanos);
+        Timestamp { secs: file_time.unix_seconds(), nanos: file_time.nanoseconds() }
+    }
+
+    /// The modification time in `meta`, at the precision the platform reports it.
+    pub fn modified(meta: &std::fs::Metadata) -> Timestamp {
+        Timestamp::from(FileTime::from_last_modification_time(meta))
+    }
+
+    #[inline]
+    pub fn unix_seconds(&self) -> i64 {
+        self.secs
+    }
+
+    #[inline]
+    pub fn nanoseconds(&self) -> u32 {
+        self.nanos
+    }
+
+    /// How far apart the two timestamps are, in either direction.
+    pub fn distance(&self, other: &Timestamp) -> Duration {
+        let (early, late) = if self <= other { (self, other) } else { (other, self) };
+        let mut secs = late.secs.wrapping_sub(early.secs) as u64;
+        let nanos = if late.nanos >= early.nanos {
+            late.nanos - early.nanos
+        } else {
+            secs -= 1;
+            late.nanos + 1_000_000_000 - early.nanos
+        };
+        Duration::new(secs, nanos)
+    }
+}
+
+/// Seconds since the epoch with nine decimals, negative before it: half a second before the
+/// epoch is `-0.500000000`.
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.secs < 0 && self.nanos > 0 {
+            write!(f, "-{}.{:09}", -(self.secs + 1), 1_000_000_000 - self.nanos)
+        } else {
+            write!(f, "{}.{:09}", self.secs, self.nanos)
+        }
+    }
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(time: FileTime) -> Self {
+        Timestamp { secs: time.unix_seconds(), nanos: time.nanoseconds() }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        Timestamp::from(FileTime::from_system_time(time))
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(time: Timestamp) -> Self {
+        FileTime::from_unix_time(time.secs, time.nanos)
+    }
+}
+
+#[cfg(test)]
+mod test_timestamp {
+    use super::*;
+
+    #[test]
+    fn distance_in_both_directions() {
+        let early = Timestamp::from_unix_time(-1, 900_000_000);
+        let late = Timestamp::from_unix_time(1, 100_000_000);
+        assert_eq!(early.distance(&late), Duration::from_millis(1200));
+        assert_eq!(late.distance(&early), Duration::from_millis(1200));
+        assert_eq!(late.distance(&late), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn round_trips_through_file_time() {
+        let before_epoch = SystemTime::UNIX_EPOCH - Duration::new(10, 250);
+        let time = Timestamp::from(before_epoch);
+        assert_eq!((time.unix_seconds(), time.nanoseconds()), (-11, 999_999_750));
+        assert_eq!(Timestamp::from(FileTime::from(time)), time);
+
+        let encoded = bincode::serialize(&time).unwrap();
+        assert_eq!(bincode::deserialize::<Timestamp>(&encoded).unwrap(), time);
+    }
+
+    #[test]
+    fn displays_as_decimal_seconds() {
+        assert_eq!(Timestamp::from_unix_time(1_600_000_000, 5).to_string(), "1600000000.000000005");
+        assert_eq!(Timestamp::UNIX_EPOCH.to_string(), "0.000000000");
+        let half_before_epoch = Timestamp::from(SystemTime::UNIX_EPOCH - Duration::from_millis(500));
+        assert_eq!(half_before_epoch.to_string(), "-0.500000000");
+        assert_eq!(Timestamp::from_unix_time(-11, 999_999_750).to_string(), "-10.000000250");
+        assert_eq!(Timestamp::from_unix_time(-2, 0).to_string(), "-2.000000000");
+    }
+}
+
+/// Suffixes of `format_size`, each 1024 times the one before.
+const SIZE_UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// Renders a byte count with a binary suffix and one decimal, such as `1.5 MiB`, for
+/// `--human-readable` output. Counts below 1 KiB are given exactly, as `512 B`.
+pub fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // values that would round up to 1024.0 are shown in the next unit instead
+    while value >= 1023.95 && unit < SIZE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, SIZE_UNITS[unit])
+}
+
+#[cfg(test)]
+mod test_format_size {
+    use super::*;
+
+    #[test]
+    fn sizes_at_unit_boundaries() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size((1 << 20) - 1), "1.0 MiB");
+        assert_eq!(format_size(1 << 20), "1.0 MiB");
+        assert_eq!(format_size(5 << 30), "5.0 GiB");
+        assert_eq!(format_size(u64::MAX), "16.0 EiB");
+    }
+}
+
+/// Default size of the buffers files are hashed and copied through (`--io-buffer-size`).
+pub const DEFAULT_IO_BUFFER_SIZE: usize = 64 << 10;
+/// Largest `--io-buffer-size` accepted, as every open file and connection allocates buffers
+/// of that size.
+pub const MAX_IO_BUFFER_SIZE: usize = 64 << 20;
+
+/// Like `std::io::copy`, but through a buffer of `buffer_size` bytes rather than a fixed one.
+pub fn copy_buffered<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W, buffer_size: usize) -> Result<u64, Error> {
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod test_copy {
+    use super::*;
+
+    /// Counts the writes it gets.
+    struct CountingWrite(Vec<u8>, usize);
+
+    impl Write for CountingWrite {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.1 += 1;
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copy_uses_buffer_size() -> Result<(), Error> {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut output = CountingWrite(Vec::new(), 0);
+        assert_eq!(copy_buffered(&mut data.as_slice(), &mut output, 100)?, 1000);
+        assert_eq!(output.0, data);
+        assert_eq!(output.1, 10);
+        Ok(())
+    }
+}
+
+/// Token bucket limiting throughput to `rate` bytes per second. The bucket holds at most one
+/// second worth of tokens, so short bursts are smoothed over rather than paced per write.
+pub struct RateLimiter {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter { rate: rate.max(1), tokens: 0.0, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Waits until at least one byte may be sent and returns how many of `wanted` bytes may be
+    /// sent right now.
+    pub fn acquire(&mut self, wanted: usize) -> usize {
+        self.refill();
+        if self.tokens < 1.0 {
+            let missing = 1.0 - self.tokens;
+            std::thread::sleep(Duration::from_secs_f64(missing / self.rate as f64));
+            self.refill();
+        }
+
+        let granted = min(wanted, self.tokens as usize).max(1);
+        self.tokens -= granted as f64;
+        granted
+    }
+}
+
+/// Writer that passes data through a `RateLimiter` before handing it to the wrapped writer.
+pub struct ThrottledWrite<'a, W: Write> {
+    inner: W,
+    limiter: &'a mut RateLimiter,
+}
+
+impl<'a, W: Write> ThrottledWrite<'a, W> {
+    pub fn new(inner: W, limiter: &'a mut RateLimiter) -> ThrottledWrite<'a, W> {
+        ThrottledWrite { inner, limiter }
+    }
 }
 
-pub fn find_named<T: Named, S : AsRef<str>>(all: &[T], name: S) -> Option<&T> {
-    let name = name.as_ref();
-    for candidate in all {
-        if candidate.name() == name {
-            return Some(candidate);
+impl<W: Write> Write for ThrottledWrite<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
         }
+        let granted = self.limiter.acquire(buf.len());
+        self.inner.write(&buf[..granted])
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test_timeout {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn stalled_peer_times_out() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut stream = TcpStream::connect(listener.local_addr()?)?;
+        let _peer = listener.accept()?;
+        let timeout = Some(Duration::from_millis(50));
+        set_io_timeout(&stream, timeout)?;
+
+        let error = stream.read(&mut [0u8; 1]).map_err(|e| explain_timeout(e, timeout)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(error.to_string().contains("did not respond"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_falls_back_to_next_address() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let closed = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+        let stream = connect_any(&[closed, listener.local_addr()?], Some(Duration::from_secs(1)))?;
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+        assert!(connect_any(&[closed], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn connect_resolves_names() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = format!("localhost:{}", listener.local_addr()?.port());
+
+        // localhost may well resolve to ::1 first, which nobody listens on
+        let stream = connect(&address, None)?;
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+        Ok(())
     }
-    None
+
+    #[test]
+    fn other_errors_are_kept() {
+        let error = explain_timeout(Error::from(ErrorKind::ConnectionReset), Some(Duration::from_secs(1)));
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+}
+
+#[cfg(test)]
+mod test_throttle {
+    use super::*;
+
+    #[test]
+    fn limits_throughput() -> Result<(), Error> {
+        let mut limiter = RateLimiter::new(1_000_000);
+        let mut sink = Vec::new();
+        let start = Instant::now();
+
+        ThrottledWrite::new(&mut sink, &mut limiter).write_all(&vec![0u8; 500_000])?;
+
+        let elapsed = start.elapsed();
+        assert_eq!(sink.len(), 500_000);
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        Ok(())
+    }
+
+    #[test]
+    fn grants_at_most_requested() {
+        let mut limiter = RateLimiter::new(1_000_000);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(limiter.acquire(10), 10);
+    }
+}
+
+pub trait Named {
+    fn name(&self) -> &str;
+}
+
+/// Maps the names of `all` to the items, so that repeated lookups don't scan `all` each time.
+pub fn index_by_name<T: Named>(all: &[T]) -> HashMap<&str, &T> {
+    all.iter().map(|item| (item.name(), item)).collect()
+}
+
+/// Whether `name` is a single plain path component: not empty, `.` or `..`, and without a
+/// root or separators. Names a peer sends must be one before they are joined onto a root.
+pub fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(part)), None) if part.to_str() == Some(name))
+}
+
+/// Whether `path` is a non-empty relative path of plain names only, so that it stays below
+/// any root it is joined onto. Paths a peer or a stored plan gives must be one.
+pub fn stays_below(path: &Path) -> bool {
+    path.components().next().is_some() && path.components().all(|part| matches!(part, Component::Normal(_)))
 }
 
 #[cfg(test)]
@@ -167,12 +668,82 @@ mod test_named {
     }
 
     #[test]
-    fn find_successful() {
-        assert_eq!(find_named(&["a", "b", "c", "d"], "c"), Some(&"c"))
+    fn index_finds_all() {
+        let index = index_by_name(&["a", "b", "c"]);
+        assert_eq!(index.get("b"), Some(&&"b"));
+        assert_eq!(index.get("q"), None);
+        assert_eq!(index.len(), 3);
+    }
+}
+/// How many of the last lines an `OutputTail` keeps.
+const TAIL_LINES: usize = 10;
+
+/// Follows what a child process writes to stderr, passing it on while keeping its last lines.
+/// These explain a failing child (say, "usync: command not found") far better than the broken
+/// stream it leaves behind.
+#[derive(Clone)]
+pub struct OutputTail {
+    state: Arc<(Mutex<TailState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct TailState {
+    lines: VecDeque<String>,
+    closed: bool,
+}
+
+impl OutputTail {
+    /// Copies `input` to `output` line by line on a background thread, until `input` ends.
+    pub fn follow<R: Read + Send + 'static, W: Write + Send + 'static>(input: R, mut output: W) -> OutputTail {
+        let tail = OutputTail { state: Arc::new((Mutex::new(TailState::default()), Condvar::new())) };
+        let state = tail.state.clone();
+        thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            let mut line = Vec::new();
+            while matches!(input.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                let _ = output.write_all(&line).and_then(|_| output.flush());
+                let mut tail = state.0.lock().unwrap();
+                if tail.lines.len() == TAIL_LINES {
+                    tail.lines.pop_front();
+                }
+                tail.lines.push_back(String::from_utf8_lossy(&line).trim_end().to_owned());
+                line.clear();
+            }
+            state.0.lock().unwrap().closed = true;
+            state.1.notify_all();
+        });
+        tail
     }
 
+    /// The last lines of the input, once it has ended or at most `timeout` from now.
+    pub fn lines(&self, timeout: Duration) -> Vec<String> {
+        let (lock, closed) = &*self.state;
+        let (tail, _) = closed.wait_timeout_while(lock.lock().unwrap(), timeout, |tail| !tail.closed).unwrap();
+        tail.lines.iter().filter(|line| !line.is_empty()).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test_tail {
+    use super::*;
+    use std::io::Cursor;
+
     #[test]
-    fn find_unsuccessful() {
-        assert_eq!(find_named(&["a", "b", "c", "d"], "q"), None)
+    fn keeps_last_lines() {
+        let input: String = (0..15).map(|i| format!("line {}\n", i)).collect();
+        let tail = OutputTail::follow(Cursor::new(input.clone().into_bytes()), std::io::sink());
+
+        let lines = tail.lines(Duration::from_secs(5));
+        assert_eq!(lines.len(), TAIL_LINES);
+        assert_eq!(lines[0], "line 5");
+        assert_eq!(lines[TAIL_LINES - 1], "line 14");
+    }
+
+    #[test]
+    fn gives_up_on_open_input() {
+        let (_sender, receiver) = std::sync::mpsc::channel();
+        let tail = OutputTail::follow(ReceiveAdapter::new(receiver), std::io::sink());
+
+        assert!(tail.lines(Duration::from_millis(10)).is_empty());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,276 @@
+//! End-to-end tests of the remote protocol: a sender thread serving a source tree with
+//! `command_handler_loop`, and a `CommandTransmitter` syncing a target from it, connected
+//! through the in-process pipes a pipelined local sync uses.
+
+use std::fs::{create_dir_all, read, read_dir, remove_file, write};
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use filetime::{set_file_mtime, FileTime};
+use tempfile::TempDir;
+
+use usync::config::{HashSettings, ManifestMode, PrivilegeMode};
+use usync::file_transfer::remote::{command_handler_loop, CommandTransmitter, SenderSettings};
+use usync::file_transfer::DefaultFileAccess;
+use usync::tree::{ChecksumKey, CopyStats, Manifest};
+use usync::util::{pipe, ReceiveAdapter, SendAdapter};
+
+/// Writes the pipes hold before the writing side waits.
+const PIPE_CAPACITY: usize = 16;
+
+type Receiver = CommandTransmitter<ReceiveAdapter, SendAdapter>;
+
+/// Serves `source` from a sender thread, and runs `receive` on a transmitter for `target`
+/// connected to it. The transmitter is dropped when `receive` returns, which ends the session.
+/// Returns the results of both sides.
+fn connect<T, F>(source: &Path, target: &Path, settings: &HashSettings, sender: SenderSettings, receive: F) -> (Result<T>, Result<()>)
+    where F: FnOnce(Receiver) -> Result<T> {
+    let (to_receiver, from_sender) = pipe(PIPE_CAPACITY);
+    let (to_sender, from_receiver) = pipe(PIPE_CAPACITY);
+
+    let root = source.to_owned();
+    let sender_settings = settings.clone();
+    let sender = thread::spawn(move || {
+        let manifest = Manifest::create_ephemeral(&root, &sender_settings)?;
+        command_handler_loop(&root, &manifest, from_receiver, to_receiver, &DefaultFileAccess, &sender)
+    });
+
+    let received = receive(CommandTransmitter::new(target, from_sender, to_sender, PrivilegeMode::Auto));
+    (received, sender.join().expect("sender thread panicked"))
+}
+
+/// Syncs the target of `receiver` with the sender's manifest, the way a receiving usync does.
+fn pull(receiver: &mut Receiver, target: &Path, settings: &HashSettings) -> Result<CopyStats> {
+    let local = Manifest::create_ephemeral(target, settings)?;
+    receiver.handshake(None, None)?;
+    let remote = receiver.remote_manifest()?;
+    local.copy_from(&remote, settings.comparison(), receiver)
+}
+
+/// Syncs `target` from `source` over the protocol, with default options on both sides.
+fn sync(source: &Path, target: &Path, settings: &HashSettings) -> Result<CopyStats> {
+    let (received, sent) = connect(source, target, settings, SenderSettings::default(), |mut receiver| {
+        pull(&mut receiver, target, settings)
+    });
+    sent?;
+    received
+}
+
+/// Data that is neither compressible to nothing nor aligned to any buffer size.
+fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7919 % 251) as u8).collect()
+}
+
+fn write_file(root: &Path, path: &str, contents: &[u8], mtime: FileTime) -> Result<()> {
+    let path = root.join(path);
+    create_dir_all(path.parent().unwrap())?;
+    write(&path, contents)?;
+    set_file_mtime(&path, mtime)
+}
+
+/// A tree with nested and empty directories, an empty file and files larger than the buffers
+/// data passes through, with modification times down to the nanosecond.
+fn source_tree() -> Result<TempDir> {
+    let source = TempDir::new()?;
+    write_file(source.path(), "top.txt", b"top level", FileTime::from_unix_time(1_500_000_000, 123_456_789))?;
+    write_file(source.path(), "dir/large.bin", &data(300_000), FileTime::from_unix_time(1_600_000_000, 1))?;
+    write_file(source.path(), "dir/sub/empty.txt", b"", FileTime::from_unix_time(1_000_000_000, 999_999_999))?;
+    write_file(source.path(), "dir/sub/small.txt", b"small", FileTime::from_unix_time(1_000_000_000, 0))?;
+    create_dir_all(source.path().join("empty/nested"))?;
+    Ok(source)
+}
+
+/// Entries of the directory `dir`, sorted by name.
+fn entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Asserts that `target` holds the same directories and files as `source`, with the same
+/// contents and modification times.
+fn assert_same_tree(source: &Path, target: &Path) -> Result<()> {
+    let names = |paths: &[PathBuf]| paths.iter().map(|path| path.file_name().unwrap().to_owned()).collect::<Vec<_>>();
+    let (expected, actual) = (entries(source)?, entries(target)?);
+    assert_eq!(names(&expected), names(&actual), "entries of {}", target.display());
+
+    for (source, target) in expected.iter().zip(&actual) {
+        if source.is_dir() {
+            assert!(target.is_dir(), "{} is a directory", target.display());
+            assert_same_tree(source, target)?;
+        } else {
+            assert!(read(source)? == read(target)?, "contents of {}", target.display());
+            assert_eq!(FileTime::from_last_modification_time(&source.metadata()?),
+                       FileTime::from_last_modification_time(&target.metadata()?),
+                       "modification time of {}", target.display());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn sync_reproduces_tree() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+
+    let stats = sync(source.path(), target.path(), &HashSettings::default())?;
+    assert_eq!(stats.files_created, 4);
+    assert_eq!(stats.files_overwritten, 0);
+    assert_eq!(stats.bytes_transferred, 300_000 + 9 + 5);
+    assert_eq!(stats.directories_created, 4);
+    assert_same_tree(source.path(), target.path())
+}
+
+#[test]
+fn resync_transfers_changed_files_only() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+    sync(source.path(), target.path(), &HashSettings::default())?;
+
+    write_file(source.path(), "dir/sub/small.txt", b"changed", FileTime::from_unix_time(1_700_000_000, 42))?;
+    remove_file(source.path().join("top.txt"))?;
+    let stats = sync(source.path(), target.path(), &HashSettings::default())?;
+    assert_eq!(stats.files_overwritten, 1);
+    assert_eq!(stats.files_created, 0);
+    assert_eq!(stats.files_skipped, 2);
+    assert_eq!(stats.bytes_transferred, 7);
+    assert_eq!(read(target.path().join("dir/sub/small.txt"))?, b"changed");
+    // files missing from the source are left alone
+    assert!(target.path().join("top.txt").exists());
+
+    let stats = sync(source.path(), target.path(), &HashSettings::default())?;
+    assert_eq!(stats.files_transferred(), 0);
+    Ok(())
+}
+
+#[test]
+fn sync_in_timestamp_mode() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+    let settings = HashSettings::default().with_manifest_mode(ManifestMode::TimestampTest);
+
+    assert_eq!(sync(source.path(), target.path(), &settings)?.files_transferred(), 4);
+    assert_same_tree(source.path(), target.path())?;
+    assert_eq!(sync(source.path(), target.path(), &settings)?.files_transferred(), 0);
+    Ok(())
+}
+
+#[test]
+fn batched_staged_and_sparse_receive() -> Result<()> {
+    let source = source_tree()?;
+    let mut holes = data(1000);
+    holes.extend(vec![0u8; 64 * 1024]);
+    holes.extend(data(1000));
+    write_file(source.path(), "dir/holes.bin", &holes, FileTime::from_unix_time(1_234_567_890, 5))?;
+    let target = TempDir::new()?;
+    let temp = TempDir::new()?;
+    let settings = HashSettings::default();
+
+    let sender = SenderSettings { buffer_size: 1000, ..SenderSettings::default() };
+    let (received, sent) = connect(source.path(), target.path(), &settings, sender, |receiver| {
+        let mut receiver = receiver
+            .with_batch_size(3)
+            .with_temp_dir(Some(temp.path()))
+            .with_sparse(Some(4096))
+            .with_buffer_size(777);
+        pull(&mut receiver, target.path(), &settings)
+    });
+    sent?;
+    assert_eq!(received?.files_created, 5);
+    assert_same_tree(source.path(), target.path())?;
+    assert_eq!(read_dir(temp.path())?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn empty_files_round_trip() -> Result<()> {
+    let source = TempDir::new()?;
+    for index in 0..12 {
+        let contents = if index % 3 == 0 { data(1000 + index) } else { Vec::new() };
+        write_file(source.path(), &format!("dir{}/file{}", index % 2, index), &contents, FileTime::from_unix_time(1_400_000_000 + index as i64, 77))?;
+    }
+    let settings = HashSettings::default();
+
+    // in batches, and one file at a time
+    for batch_size in &[5, 1] {
+        let target = TempDir::new()?;
+        let (received, sent) = connect(source.path(), target.path(), &settings, SenderSettings::default(), |receiver| {
+            pull(&mut receiver.with_batch_size(*batch_size), target.path(), &settings)
+        });
+        sent?;
+        let stats = received?;
+        assert_eq!(stats.files_created, 12);
+        assert_eq!(stats.bytes_transferred, 1000 + 1003 + 1006 + 1009);
+        assert_same_tree(source.path(), target.path())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn hashed_manifest_verifies_target() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+    let settings = HashSettings::default().with_manifest_mode(ManifestMode::TimestampTest);
+
+    let (received, sent) = connect(source.path(), target.path(), &settings, SenderSettings::default(), |mut receiver| {
+        pull(&mut receiver, target.path(), &settings)?;
+        receiver.hashed_manifest()
+    });
+    sent?;
+    let hashed = received?;
+    assert_eq!(hashed.manifest_mode(), ManifestMode::Hash);
+
+    let received = Manifest::create_ephemeral(target.path(), &HashSettings::default())?;
+    assert!(received.mismatches(&hashed).is_empty());
+    write(target.path().join("top.txt"), b"tampered")?;
+    let tampered = Manifest::create_ephemeral(target.path(), &HashSettings::default())?;
+    assert_eq!(tampered.mismatches(&hashed), vec![PathBuf::from("top.txt")]);
+    Ok(())
+}
+
+#[test]
+fn wrong_token_transfers_nothing() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+    let settings = HashSettings::default();
+
+    let sender = SenderSettings { auth_token: Some(String::from("secret")), ..SenderSettings::default() };
+    let (received, sent) = connect(source.path(), target.path(), &settings, sender, |mut receiver| {
+        receiver.handshake(Some("guess"), None)
+    });
+    assert_eq!(received.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(sent.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(read_dir(target.path())?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn checksum_seed_must_match() -> Result<()> {
+    let source = source_tree()?;
+    let target = TempDir::new()?;
+    let key = ChecksumKey::from_seed("shared");
+    let settings = HashSettings::default().with_checksum_key(Some(key.clone()));
+
+    let sender = SenderSettings { checksum_key: Some(key.clone()), ..SenderSettings::default() };
+    let (received, sent) = connect(source.path(), target.path(), &settings, sender, |receiver| {
+        let mut receiver = receiver.with_checksum_key(Some(&key));
+        pull(&mut receiver, target.path(), &settings)?;
+        receiver.hashed_manifest()
+    });
+    sent?;
+    let hashed = received?;
+    assert!(key.is_identified_by(hashed.checksum_key_id().unwrap()));
+    assert!(Manifest::create_ephemeral(target.path(), &settings)?.mismatches(&hashed).is_empty());
+    assert_same_tree(source.path(), target.path())?;
+
+    let other = TempDir::new()?;
+    let sender = SenderSettings { checksum_key: Some(ChecksumKey::from_seed("other")), ..SenderSettings::default() };
+    let (received, sent) = connect(source.path(), other.path(), &settings, sender, |receiver| {
+        receiver.with_checksum_key(Some(&key)).handshake(None, None)
+    });
+    assert_eq!(received.unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(sent.unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(read_dir(other.path())?.count(), 0);
+    Ok(())
+}